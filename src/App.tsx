@@ -2,7 +2,7 @@ import type { InjectedAccountWithMeta } from "@polkadot/extension-inject/types";
 import { AlertCircle, Loader2 } from "lucide-react";
 import { useEffect, useMemo, useState } from "react";
 import { Link, Outlet, useLocation } from "react-router";
-import { default as initWasm } from "wasm-commp";
+import { default as initWasm, selfTest } from "wasm-commp";
 import { useCtx } from "./GlobalCtx";
 import { GlobalCtxProvider } from "./GlobalCtxProvider";
 import { ConnectWallet } from "./components/ConnectWallet";
@@ -15,6 +15,7 @@ function App() {
   const [accounts, setAccounts] = useState<InjectedAccountWithMeta[]>([]);
   const [selectedAccount, setSelectedAccount] = useState<InjectedAccountWithMeta | null>(null);
   const [loaded, setLoaded] = useState<boolean>(false);
+  const [selfTestError, setSelfTestError] = useState<string | null>(null);
 
   const location = useLocation();
 
@@ -25,6 +26,20 @@ function App() {
     initWasm()
       .then(() => {
         console.log("WASM module initialized");
+        // Catch a miscompiled or mismatched build before it produces bad piece CIDs
+        try {
+          const report = selfTest();
+          if (!report.passed) {
+            console.error("CommP self-test failed", report.results);
+            const failed = report.results.filter((result: { passed: boolean }) => !result.passed);
+            setSelfTestError(
+              `${failed.length} of ${report.results.length} reference vectors failed`,
+            );
+          }
+        } catch (err) {
+          console.error("CommP self-test could not run", err);
+          setSelfTestError(`${err}`);
+        }
         setLoaded(true);
       })
       .catch((err) => {
@@ -66,10 +81,26 @@ function App() {
           </div>
           <WsAddressInput />
         </div>
+        {selfTestError && (
+          <div className="mb-4 p-4 bg-red-50 text-red-700 rounded-lg flex items-center gap-2">
+            <AlertCircle className="h-5 w-5" />
+            <span>
+              The CommP self-test failed ({selfTestError}), uploads are disabled until the WASM
+              module is rebuilt.
+            </span>
+          </div>
+        )}
         {accounts.length === 0 ? (
           <ConnectWallet onConnect={setAccounts} />
         ) : (
-          <Inner context={{ accounts, selectedAccount, setSelectedAccount }} />
+          <Inner
+            context={{
+              accounts,
+              selectedAccount,
+              setSelectedAccount,
+              selfTestPassed: selfTestError === null,
+            }}
+          />
         )}
       </div>
       <Toaster />
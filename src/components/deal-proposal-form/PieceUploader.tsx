@@ -2,8 +2,9 @@ import { FileText, Loader2, Upload } from "lucide-react";
 import { useCallback, useState } from "react";
 import { useDropzone } from "react-dropzone";
 import { type UseControllerProps, useController } from "react-hook-form";
-import { commpFromBytes, paddedPieceSize } from "wasm-commp";
+import { commpFromBytes, isCar, paddedPieceSize, PiecePipeline, prepareCar } from "wasm-commp";
 import { generateCar as generateCarV2 } from "../../lib/car/v2";
+import { RUST_CAR_PIPELINE, STORE_CAR_FILES } from "../../lib/consts";
 import Collapsible from "../Collapsible";
 import { DisabledInputInfo } from "./DisabledInputInfo";
 import type { FormValues, Piece } from "./types";
@@ -12,55 +13,109 @@ interface FileUploaderProps extends UseControllerProps<FormValues> {
   error?: string;
 }
 
+// Trickle DAGs put the start of the file right below the root, which suits media playback.
+type DagLayout = "balanced" | "trickle";
+
 export function PieceUploader({ error, ...props }: FileUploaderProps) {
   const {
     field: { onChange, value },
   } = useController(props);
   const [isProcessing, setIsProcessing] = useState<boolean>(false);
+  const [layout, setLayout] = useState<DagLayout>("balanced");
 
   const onDrop = useCallback(
-    (acceptedFiles: File[]) => {
-      return new Promise<void>((resolve, reject) => {
-        const file = acceptedFiles[0];
-        if (!file) {
-          reject("No files were passed in.");
+    async (acceptedFiles: File[]) => {
+      const file = acceptedFiles[0];
+      if (!file) {
+        throw "No files were passed in.";
+      }
+
+      setIsProcessing(true);
+      try {
+        // CAR files built elsewhere are stored as they are, keeping their roots, when the
+        // provider supports it, otherwise they're wrapped like any other file
+        if (
+          STORE_CAR_FILES &&
+          isCar(new Uint8Array(await file.slice(0, 64 * 1024).arrayBuffer()))
+        ) {
+          const prepared = prepareCar(new Uint8Array(await file.arrayBuffer()));
+          onChange({
+            pieceCid: prepared.pieceCid,
+            payloadCid: prepared.rootCid,
+            size: Number.parseInt(prepared.pieceSize),
+            // Unchanged unless an index had to be added
+            file: prepared.reindexed ? new File([prepared.car], file.name) : file,
+          });
           return;
         }
 
-        const reader = new FileReader();
-        setIsProcessing(true);
-
-        reader.onloadend = async (e) => {
-          if (!e.target?.result) {
-            reject("Failed to load file contents");
-            return;
+        // A provider storing CAR files as they are is sent the CAR built here, in any layout and
+        // with the file's name and modification time, which retrievals then restore
+        if (STORE_CAR_FILES) {
+          const pipeline = PiecePipeline.withFileInfo(
+            true,
+            { layout },
+            {
+              name: file.name,
+              mtime: {
+                seconds: Math.floor(file.lastModified / 1000),
+                nanos: (file.lastModified % 1000) * 1_000_000,
+              },
+            },
+          );
+          const chunks: Uint8Array[] = [];
+          const reader = file.stream().getReader();
+          for (;;) {
+            const { done, value } = await reader.read();
+            if (done) break;
+            chunks.push(pipeline.write(value));
           }
+          const result = pipeline.finish();
+
+          onChange({
+            pieceCid: result.pieceCid,
+            payloadCid: result.rootCid,
+            size: Number.parseInt(result.pieceSize),
+            file: new File([result.header, ...chunks, result.trailer], `${file.name}.car`),
+          });
+          return;
+        }
 
-          try {
-            const content = new Uint8Array(e.target.result as ArrayBuffer);
-            const [rootCid, v2Bytes] = await generateCarV2(content);
-
-            const pieceSize = Number.parseInt(paddedPieceSize(v2Bytes));
-            const cid = commpFromBytes(v2Bytes);
-
-            onChange({
-              pieceCid: cid,
-              payloadCid: rootCid.toString(),
-              size: pieceSize,
-              file: file,
-            });
-            resolve();
-          } catch (err) {
-            reject(err);
-          } finally {
-            setIsProcessing(false);
+        // Otherwise the CAR file is only needed for its piece commitment, it isn't kept. The
+        // provider builds the same balanced CAR from the uploaded file
+        if (RUST_CAR_PIPELINE) {
+          const pipeline = new PiecePipeline(false);
+          const reader = file.stream().getReader();
+          for (;;) {
+            const { done, value } = await reader.read();
+            if (done) break;
+            pipeline.write(value);
           }
-        };
+          const result = pipeline.finish();
 
-        reader.readAsArrayBuffer(file);
-      });
+          onChange({
+            pieceCid: result.pieceCid,
+            payloadCid: result.rootCid,
+            size: Number.parseInt(result.pieceSize),
+            file: file,
+          });
+          return;
+        }
+
+        const content = new Uint8Array(await file.arrayBuffer());
+        const [rootCid, v2Bytes] = await generateCarV2(content);
+
+        onChange({
+          pieceCid: commpFromBytes(v2Bytes),
+          payloadCid: rootCid.toString(),
+          size: Number.parseInt(paddedPieceSize(v2Bytes)),
+          file: file,
+        });
+      } finally {
+        setIsProcessing(false);
+      }
     },
-    [onChange],
+    [onChange, layout],
   );
 
   const { getRootProps, getInputProps, isDragActive } = useDropzone({
@@ -74,6 +129,18 @@ export function PieceUploader({ error, ...props }: FileUploaderProps) {
 
   return (
     <div>
+      {STORE_CAR_FILES && (
+        <select
+          id={`${props.name}-layout`}
+          className="w-full p-2 mb-2 border border-gray-300 rounded-md shadow-sm focus:border-blue-500 focus:ring-1 focus:ring-blue-500"
+          value={layout}
+          onChange={(e) => setLayout(e.target.value as DagLayout)}
+          disabled={isProcessing}
+        >
+          <option value="balanced">Balanced DAG layout</option>
+          <option value="trickle">Trickle DAG layout (streaming media)</option>
+        </select>
+      )}
       <div
         {...getRootProps()}
         className={`flex items-center p-4 border-2 border-dashed rounded-lg transition-colors cursor-pointer hover:border-blue-400 ${
@@ -40,12 +40,12 @@ export function DealList() {
   const handleDownload = useCallback(
     async (deal: Deal) => {
       try {
-        await downloadDeal(papiTypedApi, deal);
+        await downloadDeal(papiTypedApi, deal, undefined, deals);
       } catch (err) {
         console.error("Download failed:", err);
       }
     },
-    [papiTypedApi],
+    [papiTypedApi, deals],
   );
 
   if (loading)
@@ -0,0 +1,43 @@
+// Checkpoints of the piece ranges retrieved so far, kept in IndexedDB so that a download
+// interrupted by a closed tab or a dropped connection resumes where it stopped.
+const DB_NAME = "delia";
+const STORE_NAME = "checkpoints";
+
+let db: Promise<IDBDatabase> | null = null;
+
+function openDb(): Promise<IDBDatabase> {
+  if (!db) {
+    db = new Promise((resolve, reject) => {
+      const request = indexedDB.open(DB_NAME, 1);
+      request.onupgradeneeded = () => request.result.createObjectStore(STORE_NAME);
+      request.onsuccess = () => resolve(request.result);
+      request.onerror = () => {
+        db = null;
+        reject(request.error);
+      };
+    });
+  }
+  return db;
+}
+
+async function run<T>(
+  mode: IDBTransactionMode,
+  operation: (store: IDBObjectStore) => IDBRequest<T>,
+): Promise<T> {
+  const store = (await openDb()).transaction(STORE_NAME, mode).objectStore(STORE_NAME);
+  return new Promise((resolve, reject) => {
+    const request = operation(store);
+    request.onsuccess = () => resolve(request.result);
+    request.onerror = () => reject(request.error);
+  });
+}
+
+// The `get`/`set`/`delete` interface `retrieveResumable` expects
+export const checkpointStore = {
+  get: (key: string): Promise<Uint8Array | undefined> =>
+    run("readonly", (store) => store.get(key)),
+  set: async (key: string, value: Uint8Array) => {
+    await run("readwrite", (store) => store.put(value, key));
+  },
+  delete: (key: string): Promise<undefined> => run("readwrite", (store) => store.delete(key)),
+};
@@ -17,6 +17,18 @@ export const hoursToBlocks = (nBlocks: number) => 60 * minutesToBlocks(nBlocks);
 export const daysToBlocks = (nBlocks: number) => 24 * hoursToBlocks(nBlocks);
 export const monthsToBlocks = (nBlocks: number) => 30 * daysToBlocks(nBlocks);
 export const DEAL_LIST_PAGE_SIZE = 10;
+// Whether CAR files are uploaded and stored as they are: the ones picked by the user, and the
+// ones built here from any other file, in the DAG layout picked for it. Providers wrap every
+// upload in a CAR of their own, so this needs a provider storing CAR uploads verbatim, or the
+// deal's piece CID won't match
+export const STORE_CAR_FILES = import.meta.env.VITE_STORE_CAR_FILES === "true";
+// Whether piece CIDs are calculated by the single-pass `PiecePipeline` of `wasm-commp` instead
+// of `lib/car/v2.ts` and `commpFromBytes`. Off until the Rust CAR builder is proven to write the
+// same bytes as the TypeScript one, as the provider's CAR has to match the one committed to
+export const RUST_CAR_PIPELINE = import.meta.env.VITE_RUST_CAR_PIPELINE === "true";
+// Public trustless gateways the service worker fetches `ipfs/<cid>/<path>` content from, in
+// order. Providers only serve whole pieces, so content is only found once announced to IPFS
+export const IPFS_GATEWAYS = ["https://trustless-gateway.link", "https://ipfs.io"];
 
 export const fetchMaxProveCommitDurationConst = async (
   papiTypedApi: TypedApi<typeof polkaStorage>,
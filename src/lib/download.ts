@@ -1,37 +1,141 @@
 import { type Multiaddr, multiaddr } from "@multiformats/multiaddr";
 import { fileTypeFromBuffer } from "file-type";
 import { CID } from "multiformats";
+import {
+  CarStreamVerifier,
+  FileExportStream,
+  RetrievalVerifier,
+  retrieveFromProviders,
+  retrieveResumable,
+} from "wasm-commp";
 import type { PolkaStorageApi } from "../GlobalCtx";
+import { checkpointStore } from "./checkpointStore";
 import type { Deal } from "./deals";
 
-export async function downloadDeal(api: PolkaStorageApi | null, deal: Deal) {
+// The payload CID isn't stored on-chain, without it the blocks are checked against the only
+// root of the CAR file, which the piece CID commits to. The other deals for the same piece
+// among `deals` are replicas, the piece is fetched from all their providers at once
+export async function downloadDeal(
+  api: PolkaStorageApi | null,
+  deal: Deal,
+  payloadCid?: string,
+  deals: Deal[] = [],
+) {
   if (!api) throw new Error("API not ready");
 
-  // look up provider addr
-  const maddr = await getProviderMultiaddr(api, deal.value.provider);
-  const { address, port } = maddr.nodeAddress();
-
-  // fetch the raw piece
+  // look up the provider addrs, replicas whose provider can't be reached are skipped
   const pieceCid = CID.decode(deal.value.piece_cid.asBytes()).toString();
-  const res = await fetch(`http://${address}:${port}/api/v0/download/${pieceCid}`);
-  if (!res.ok) throw new Error(res.statusText);
-  const blob = await res.blob();
-
-  // sniff the bytes for a magic number
-  const buffer = await blob.arrayBuffer();
-  const uint8 = new Uint8Array(buffer);
-  const ft = await fileTypeFromBuffer(uint8);
+  const replicas = new Set(
+    deals
+      .filter((other) => CID.decode(other.value.piece_cid.asBytes()).toString() === pieceCid)
+      .map((other) => other.value.provider),
+  );
+  replicas.delete(deal.value.provider);
+  const urls = [await getProviderUrl(api, deal.value.provider)];
+  for (const provider of replicas) {
+    try {
+      urls.push(await getProviderUrl(api, provider));
+    } catch (e) {
+      console.warn(`Skipping replica provider ${provider}: ${e}`);
+    }
+  }
+
+  // fetch the piece, the CAR file the deal was made with, and check it against the on-chain
+  // piece CID. From a single provider, the ranges are checkpointed once their blocks are
+  // checked so an interrupted download resumes. From several, the ranges are spread over
+  // them, and the ones a provider gets wrong are fetched again from the others.
+  // The blocks are checked and the original file extracted range by range, so a bad block
+  // stops the download as soon as it's received. The piece verifier finds where the CAR file
+  // ends from its header and rejects anything but zero padding after it. Payloads other than
+  // a single file, such as directories or CAR files of several roots, can't be extracted, the
+  // verified CAR file is saved instead
+  const pieceVerifier = new RetrievalVerifier(pieceCid, BigInt(deal.value.piece_size));
+  const blockVerifier = new CarStreamVerifier(payloadCid);
+  const exporter = new FileExportStream();
+  const parts: Uint8Array[] = [];
+  const carParts: Uint8Array[] = [];
+  let badBlock: unknown;
+  let notAFile: unknown;
+  const onRange = (range: Uint8Array) => {
+    try {
+      pieceVerifier.write(range);
+      blockVerifier.write(range);
+    } catch (e) {
+      badBlock = e;
+      throw e;
+    }
+    carParts.push(range);
+    if (notAFile !== undefined) return;
+    try {
+      const data = exporter.write(range);
+      if (data.length > 0) parts.push(data);
+    } catch (e) {
+      notAFile = e;
+    }
+  };
+  try {
+    if (urls.length > 1) {
+      const piece = await retrieveFromProviders(pieceCid, BigInt(deal.value.piece_size), urls);
+      const reports: { url: string; corrupt: boolean }[] = piece.providers;
+      for (const report of reports.filter((report) => report.corrupt)) {
+        console.warn(`Provider ${report.url} returned bad data for piece ${pieceCid}`);
+      }
+      onRange(piece.data);
+    } else {
+      await retrieveResumable(
+        urls[0],
+        pieceCid,
+        BigInt(deal.value.piece_size),
+        checkpointStore,
+        undefined,
+        onRange,
+      );
+    }
+  } catch (e) {
+    if (badBlock !== undefined) throw new Error(`The provider returned a bad block: ${badBlock}`);
+    throw new Error(`Failed to retrieve the deal's data, retry to resume the download: ${e}`);
+  }
+
+  let piece: { dataSize: number | bigint };
+  try {
+    piece = pieceVerifier.finish();
+    blockVerifier.finish();
+  } catch (e) {
+    throw new Error(`The provider returned an incomplete or bad file: ${e}`);
+  }
+  let info: { name?: string; cid: string } | undefined;
+  if (notAFile === undefined) {
+    try {
+      info = exporter.finish();
+    } catch (e) {
+      notAFile = e;
+    }
+  }
+  if (!info) {
+    console.warn(`The payload of ${pieceCid} isn't a single file, saving its CAR file:`, notAFile);
+    const car = new Blob(carParts).slice(0, Number(piece.dataSize));
+    createDownloadTrigger(`${pieceCid}.car`, new Blob([car], { type: "application/vnd.ipld.car" }));
+    return;
+  }
+
+  // sniff the first bytes for a magic number
+  const head = new Uint8Array(await new Blob(parts).slice(0, 4100).arrayBuffer());
+  const ft = await fileTypeFromBuffer(head);
   // fallback to nothing if unknown
   const ext = ft?.ext ?? "";
-  const mime = ft?.mime ?? blob.type;
+  const mime = ft?.mime ?? "application/octet-stream";
 
-  // re‑wrap in a Blob with the correct mime
-  const finalBlob = new Blob([uint8], { type: mime });
+  const finalBlob = new Blob(parts, { type: mime });
 
-  // trigger download with the right extension
-  ext === ""
-    ? createDownloadTrigger(`${pieceCid}`, finalBlob)
-    : createDownloadTrigger(`${pieceCid}.${ext}`, finalBlob);
+  // trigger download with the stored name, or the right extension. Only pieces whose CAR was
+  // built and uploaded by the client have a name, see `STORE_CAR_FILES`
+  if (info.name) {
+    createDownloadTrigger(info.name, finalBlob);
+  } else {
+    ext === ""
+      ? createDownloadTrigger(`${pieceCid}`, finalBlob)
+      : createDownloadTrigger(`${pieceCid}.${ext}`, finalBlob);
+  }
 }
 
 export function createDownloadTrigger(title: string, blob: Blob) {
@@ -51,6 +155,11 @@ export function createDownloadTrigger(title: string, blob: Blob) {
   document.body.removeChild(a);
 }
 
+async function getProviderUrl(api: PolkaStorageApi, provider: string): Promise<string> {
+  const { address, port } = (await getProviderMultiaddr(api, provider)).nodeAddress();
+  return `http://${address}:${port}`;
+}
+
 async function getProviderMultiaddr(api: PolkaStorageApi, provider: string): Promise<Multiaddr> {
   const providerInfo = await api.query.StorageProvider.StorageProviders.getValue(provider);
   if (!providerInfo) throw new Error(`Provider info for ${provider} not found on-chain`);
@@ -25,6 +25,17 @@ const router = createHashRouter([
   },
 ]);
 
+// Serve verified IPFS content below `ipfs/`, see `src/sw.ts`
+if ("serviceWorker" in navigator) {
+  const script = import.meta.env.DEV ? "src/sw.ts" : "sw.js";
+  navigator.serviceWorker
+    .register(`${import.meta.env.BASE_URL}${script}`, {
+      type: "module",
+      scope: import.meta.env.BASE_URL,
+    })
+    .catch((err) => console.error("Failed to register the service worker", err));
+}
+
 // biome-ignore lint/style/noNonNullAssertion: If there is no `root` there is no page
 createRoot(document.getElementById("root")!).render(
   <StrictMode>
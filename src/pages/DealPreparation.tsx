@@ -1,7 +1,7 @@
 import type { Multiaddr } from "@multiformats/multiaddr";
 import type { InjectedAccountWithMeta } from "@polkadot/extension-inject/types";
 import type { TypeRegistry } from "@polkadot/types";
-import { Loader2 } from "lucide-react";
+import { AlertCircle, Loader2 } from "lucide-react";
 import { useEffect, useState } from "react";
 import { toast } from "react-hot-toast";
 import { useOutletContext } from "react-router";
@@ -18,6 +18,8 @@ import { loadWrapper } from "../lib/loadWrapper";
 
 type OutletContextType = {
   accounts: InjectedAccountWithMeta[];
+  // Whether the CommP implementation passed its self-test, see `App`
+  selfTestPassed: boolean;
 };
 
 type DealInfo = {
@@ -89,7 +91,7 @@ async function executeDeal(
 }
 
 export function DealPreparation() {
-  const { accounts } = useOutletContext<OutletContextType>();
+  const { accounts, selfTestPassed } = useOutletContext<OutletContextType>();
   const { latestFinalizedBlock, collatorWsProvider, registry, papiTypedApi } = useCtx();
   const [maxProveCommitDuration, setMaxProveCommitDuration] = useState<number>(
     DEFAULT_MAX_PROVE_COMMIT_DURATION,
@@ -132,6 +134,16 @@ export function DealPreparation() {
     );
   };
 
+  // Piece CIDs calculated by a broken CommP implementation would be rejected by every provider
+  if (!selfTestPassed) {
+    return (
+      <div className="mb-4 p-4 bg-red-50 text-red-700 rounded-lg flex items-center gap-2">
+        <AlertCircle className="h-5 w-5" />
+        <span>Uploads are disabled because the CommP self-test failed.</span>
+      </div>
+    );
+  }
+
   if (!latestFinalizedBlock) {
     return (
       <div className="text-center py-8">
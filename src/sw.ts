@@ -0,0 +1,192 @@
+// In-browser IPFS gateway: answers `ipfs/<cid>/<path>` requests below the app's base path with
+// content fetched from trustless gateways, verified block by block before it reaches the page.
+import { default as initWasm, PathExportStream } from "wasm-commp";
+import { IPFS_GATEWAYS } from "./lib/consts";
+
+// The service worker types clash with the DOM ones the rest of the app is checked against
+type ExtendableEvent = Event & { waitUntil(promise: Promise<unknown>): void };
+type FetchEvent = Event & { request: Request; respondWith(response: Promise<Response>): void };
+const worker = self as unknown as {
+  location: Location;
+  skipWaiting(): Promise<void>;
+  clients: { claim(): Promise<void> };
+  addEventListener(type: "install" | "activate", listener: (event: ExtendableEvent) => void): void;
+  addEventListener(type: "fetch", listener: (event: FetchEvent) => void): void;
+};
+
+type DirectoryEntry = { name: string; cid: string };
+// A byte range, the end being exclusive, open ended if unset
+type ByteRange = { start: bigint; end?: bigint };
+type PathContent =
+  | { type: "file"; name?: string; cid: string; size: number }
+  | { type: "directory"; cid: string; entries: DirectoryEntry[] };
+
+const wasmReady = initWasm();
+const ipfsPrefix = `${import.meta.env.BASE_URL}ipfs/`;
+// The content is untrusted and served from the app's origin, so it's sandboxed in an opaque
+// origin without scripts, where it can't reach the app's storage, service worker or wallet,
+// and it's never sniffed as another type
+const ISOLATION_HEADERS = {
+  "Content-Security-Policy": "sandbox",
+  "X-Content-Type-Options": "nosniff",
+};
+
+worker.addEventListener("install", (event) => event.waitUntil(worker.skipWaiting()));
+worker.addEventListener("activate", (event) => event.waitUntil(worker.clients.claim()));
+worker.addEventListener("fetch", (event) => {
+  const url = new URL(event.request.url);
+  if (url.origin !== worker.location.origin || !url.pathname.startsWith(ipfsPrefix)) return;
+  event.respondWith(serve(url.pathname, parseRange(event.request.headers.get("Range"))));
+});
+
+// Only single `bytes=<first>-[<last>]` ranges are served as ranges, the whole file being sent
+// for others, as the `Range` header allows
+function parseRange(header: string | null): ByteRange | undefined {
+  const match = header?.match(/^bytes=(\d+)-(\d*)$/);
+  if (!match) return undefined;
+  const start = BigInt(match[1]);
+  if (match[2] === "") return { start };
+  const end = BigInt(match[2]) + 1n;
+  return start < end ? { start, end } : undefined;
+}
+
+async function serve(pathname: string, range?: ByteRange): Promise<Response> {
+  await wasmReady;
+  const errors: string[] = [];
+  // every gateway is tried in turn until one starts sending verified content
+  for (const gateway of IPFS_GATEWAYS) {
+    try {
+      return await fromGateway(pathname, gateway, range);
+    } catch (e) {
+      errors.push(`${gateway}: ${e}`);
+    }
+  }
+  return new Response(`Failed to retrieve ${pathname}\n${errors.join("\n")}`, {
+    status: 502,
+    headers: { ...ISOLATION_HEADERS, "Content-Type": "text/plain; charset=utf-8" },
+  });
+}
+
+async function fromGateway(
+  pathname: string,
+  gateway: string,
+  range?: ByteRange,
+): Promise<Response> {
+  // ranges are requested as `entity-bytes`, the gateway only sending the blocks holding them
+  const stream = new PathExportStream(pathname, range?.start, range?.end);
+  const res = await fetch(stream.url(gateway), { headers: { Accept: stream.accept } });
+  if (!res.ok) throw new Error(`${res.status} ${res.statusText}`);
+  if (!res.body) throw new Error("Empty response");
+  const reader = res.body.getReader();
+
+  // the headers need the content type, which may need the first bytes of the file, and the
+  // size of the file for ranges
+  const head: Uint8Array[] = [];
+  while (stream.contentType === undefined || (range && stream.size === undefined)) {
+    const { done, value } = await reader.read();
+    if (done) break;
+    const data = stream.write(value);
+    if (data.length > 0) head.push(data);
+    if (stream.isDirectory) {
+      return directory(pathname, gateway, stream, reader, range);
+    }
+  }
+  const contentType = stream.contentType ?? "application/octet-stream";
+  const headers: Record<string, string> = {
+    ...ISOLATION_HEADERS,
+    "Content-Type": contentType,
+    "Accept-Ranges": "bytes",
+  };
+  const size = stream.size;
+  let status = 200;
+  if (range && size !== undefined) {
+    if (range.start >= size) {
+      await reader.cancel();
+      return new Response(null, {
+        status: 416,
+        headers: { ...ISOLATION_HEADERS, "Content-Range": `bytes */${size}` },
+      });
+    }
+    const end = range.end === undefined || range.end > size ? size : range.end;
+    headers["Content-Range"] = `bytes ${range.start}-${end - 1n}/${size}`;
+    status = 206;
+  }
+
+  const body = new ReadableStream<Uint8Array>({
+    start(controller) {
+      for (const data of head) controller.enqueue(data);
+    },
+    async pull(controller) {
+      try {
+        // the stream isn't pulled again until something is enqueued
+        for (;;) {
+          const { done, value } = await reader.read();
+          if (done) {
+            stream.finish();
+            controller.close();
+            return;
+          }
+          const data = stream.write(value);
+          if (data.length > 0) {
+            controller.enqueue(data);
+            return;
+          }
+        }
+      } catch (e) {
+        await reader.cancel();
+        controller.error(e);
+      }
+    },
+    cancel() {
+      return reader.cancel();
+    },
+  });
+  return new Response(body, { status, headers });
+}
+
+async function directory(
+  pathname: string,
+  gateway: string,
+  stream: PathExportStream,
+  reader: ReadableStreamDefaultReader<Uint8Array>,
+  range?: ByteRange,
+): Promise<Response> {
+  for (;;) {
+    const { done, value } = await reader.read();
+    if (done) break;
+    stream.write(value);
+  }
+  const content: PathContent = stream.finish();
+  if (content.type !== "directory") throw new Error("Expected a directory");
+
+  // relative links in the directory are resolved against its path
+  if (!pathname.endsWith("/")) {
+    return new Response(null, {
+      status: 301,
+      headers: { ...ISOLATION_HEADERS, Location: `${pathname}/` },
+    });
+  }
+  if (content.entries.some((entry) => entry.name === "index.html")) {
+    return fromGateway(`${pathname}index.html`, gateway, range);
+  }
+
+  const items = content.entries
+    .map((entry) => {
+      const href = escapeHtml(encodeURIComponent(entry.name));
+      return `<li><a href="${href}">${escapeHtml(entry.name)}</a></li>`;
+    })
+    .join("");
+  const title = escapeHtml(decodeURIComponent(pathname.slice(ipfsPrefix.length - 1)));
+  return new Response(
+    `<!doctype html><meta charset="utf-8"><title>${title}</title><h1>${title}</h1><ul>${items}</ul>`,
+    { headers: { ...ISOLATION_HEADERS, "Content-Type": "text/html; charset=utf-8" } },
+  );
+}
+
+function escapeHtml(text: string): string {
+  return text
+    .replaceAll("&", "&amp;")
+    .replaceAll("<", "&lt;")
+    .replaceAll(">", "&gt;")
+    .replaceAll('"', "&quot;");
+}
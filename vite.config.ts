@@ -6,4 +6,17 @@ import { defineConfig } from "vite";
 export default defineConfig({
   plugins: [react(), tailwindcss()],
   base: "/delia/", // GitHub Pages repository name
+  build: {
+    rollupOptions: {
+      // The service worker of `src/sw.ts` is served as `sw.js`, at the root of its scope
+      input: { main: "index.html", sw: "src/sw.ts" },
+      output: {
+        entryFileNames: (chunk) => (chunk.name === "sw" ? "sw.js" : "assets/[name]-[hash].js"),
+      },
+    },
+  },
+  server: {
+    // Lets the service worker served from `src/sw.ts` in development control the whole app
+    headers: { "Service-Worker-Allowed": "/" },
+  },
 });
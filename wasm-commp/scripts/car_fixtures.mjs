@@ -0,0 +1,48 @@
+// Runs `generateCar` of `src/lib/car/v2.ts` on the inputs of the golden tests in
+// `src/car/tests.rs` and writes what it returns under `src/car/fixtures/ts/`: the CARv2 files
+// of the small inputs and, for every input, its root CID, length and SHA2-256.
+//
+// Usage, from the repository root after `pnpm install`: node wasm-commp/scripts/car_fixtures.mjs
+import { createHash } from "node:crypto";
+import { mkdirSync, writeFileSync } from "node:fs";
+import { createServer } from "vite";
+
+const CHUNK_SIZE = 256 * 1024;
+const MAX_LINKS = 174;
+const OUT_DIR = "wasm-commp/src/car/fixtures/ts";
+
+const pattern = (len) => Uint8Array.from({ length: len }, (_, i) => i % 251);
+
+const INPUTS = [
+  { name: "empty", data: new Uint8Array(0), full: true },
+  { name: "one_byte", data: pattern(1), full: true },
+  { name: "hello", data: new TextEncoder().encode("hello world\n"), full: true },
+  { name: "single_full_leaf", data: pattern(CHUNK_SIZE) },
+  { name: "two_leaves", data: pattern(CHUNK_SIZE + 1) },
+  { name: "ten_leaves", data: pattern(10 * CHUNK_SIZE - 7) },
+  // Two levels of stems, with the same leaf repeated
+  { name: "deduplicated_leaves", data: new Uint8Array(MAX_LINKS * CHUNK_SIZE + 1) },
+];
+
+const server = await createServer({ server: { middlewareMode: true }, appType: "custom" });
+try {
+  const { generateCar } = await server.ssrLoadModule("/src/lib/car/v2.ts");
+  mkdirSync(OUT_DIR, { recursive: true });
+
+  const manifest = [];
+  for (const { name, data, full } of INPUTS) {
+    const [root, car] = await generateCar(data);
+    if (full) {
+      writeFileSync(`${OUT_DIR}/${name}.car`, car);
+    }
+    manifest.push({
+      name,
+      rootCid: root.toString(),
+      length: car.length,
+      sha256: createHash("sha256").update(car).digest("hex"),
+    });
+  }
+  writeFileSync(`${OUT_DIR}/manifest.json`, `${JSON.stringify(manifest, null, 2)}\n`);
+} finally {
+  await server.close();
+}
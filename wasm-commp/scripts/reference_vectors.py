@@ -0,0 +1,82 @@
+#!/usr/bin/env python3
+"""Generates the CommP vectors of `src/self_test.rs`.
+
+The zero vectors are the reference vectors of the self-test, also listed by go-commp-utils.
+The pattern ones are only checked in the tests of the crate, until they come from
+rust-fil-proofs or go-fil-commp-hashhash.
+
+An implementation of the piece commitment that shares no code with the crate: the data is
+zero-padded up to the unpadded piece size, Fr32 padded (two zero bits after every 254 bits)
+and hashed into a binary Merkle tree with SHA-256, truncated to 254 bits.
+
+Usage: python3 wasm-commp/scripts/reference_vectors.py
+"""
+import hashlib
+
+# (data size, fill, padded piece size)
+VECTORS = [
+    (127, "Zero", 128),
+    (128, "Zero", 256),
+    (1017, "Zero", 2048),
+    (2032, "Zero", 2048),
+    (2033, "Zero", 4096),
+    (65536, "Zero", 131072),
+    (1048576, "Zero", 2097152),
+    (96, "Pattern", 128),
+    (127, "Pattern", 128),
+    (128, "Pattern", 256),
+    (254, "Pattern", 256),
+    (255, "Pattern", 512),
+    (1016, "Pattern", 1024),
+    (1017, "Pattern", 2048),
+    (1023, "Pattern", 2048),
+    (1024, "Pattern", 2048),
+    (1025, "Pattern", 2048),
+    (2032, "Pattern", 2048),
+    (2033, "Pattern", 4096),
+    (4095, "Pattern", 8192),
+    (4096, "Pattern", 8192),
+    (4097, "Pattern", 8192),
+    (65535, "Pattern", 131072),
+    (65536, "Pattern", 131072),
+    (65537, "Pattern", 131072),
+]
+
+
+def data(size, fill):
+    if fill == "Zero":
+        return bytes(size)
+    return bytes(i % 251 for i in range(size))
+
+
+def fr32_pad(block):
+    """Pads 127 bytes into 4 field elements of 32 bytes."""
+    value = int.from_bytes(block, "little")
+    padded = 0
+    for i in range(4):
+        padded |= ((value >> (254 * i)) & ((1 << 254) - 1)) << (256 * i)
+    return padded.to_bytes(128, "little")
+
+
+def hash_pair(left, right):
+    digest = bytearray(hashlib.sha256(left + right).digest())
+    digest[31] &= 0b0011_1111
+    return bytes(digest)
+
+
+def commp(payload, piece_size):
+    unpadded = piece_size // 128 * 127
+    payload += bytes(unpadded - len(payload))
+    padded = b"".join(fr32_pad(payload[i : i + 127]) for i in range(0, unpadded, 127))
+    layer = [padded[i : i + 32] for i in range(0, len(padded), 32)]
+    while len(layer) > 1:
+        layer = [hash_pair(layer[i], layer[i + 1]) for i in range(0, len(layer), 2)]
+    return layer[0].hex()
+
+
+for size, fill, piece_size in VECTORS:
+    commitment = commp(data(size, fill), piece_size)
+    if fill == "Zero":
+        print(f'    vector({size}, {piece_size}, "{commitment}"),')
+    else:
+        print(f'        ({size}, {piece_size}, "{commitment}"),')
@@ -0,0 +1,436 @@
+//! Streaming generation of CARv2 files from file contents.
+use std::io::{self, Write};
+
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use wasm_bindgen::prelude::*;
+
+use super::{header_len, CarV2Output, CarV2Writer};
+use crate::{
+    ipld::Block,
+    unixfs::{
+        directory::directory,
+        pb::{PbLink, UnixTime},
+        DagBuilder, DagOptions, DagOptionsError, FileLink, Layout, Metadata, Splitter,
+    },
+};
+
+/// The UnixFS DAG of a file being written, whose blocks go to a [`CarV2Writer`].
+pub(crate) struct FileDag {
+    options: DagOptions,
+    metadata: Metadata,
+    dag: DagBuilder,
+    splitter: Splitter,
+    /// The data not yet cut into leaves, at most a chunk.
+    chunk: Vec<u8>,
+    leaves: u64,
+    /// The first leaf, held back while it may be the root, which the metadata replaces.
+    first_leaf: Option<Block>,
+}
+
+impl FileDag {
+    /// Starts an empty file, `options` must be valid.
+    pub(crate) fn new(options: DagOptions, metadata: Metadata) -> Self {
+        Self {
+            options,
+            metadata,
+            dag: DagBuilder::new(&options),
+            splitter: Splitter::new(options.chunker, options.chunk_size),
+            chunk: vec![],
+            leaves: 0,
+            first_leaf: None,
+        }
+    }
+
+    /// Appends `data` to the file.
+    pub(crate) fn write<W: Write>(
+        &mut self,
+        writer: &mut CarV2Writer<W>,
+        mut data: &[u8],
+    ) -> io::Result<()> {
+        let max_size = self.splitter.max_size();
+        while !data.is_empty() {
+            let take = (max_size - self.chunk.len()).min(data.len());
+            self.chunk.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            // The cut can only be decided once a whole chunk is buffered
+            if self.chunk.len() == max_size {
+                let len = self.splitter.cut(&self.chunk);
+                self.write_leaf(writer, len)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the first `len` buffered bytes as a leaf.
+    fn write_leaf<W: Write>(&mut self, writer: &mut CarV2Writer<W>, len: usize) -> io::Result<()> {
+        let rest = self.chunk.split_off(len);
+        let chunk = std::mem::replace(&mut self.chunk, rest);
+        let (leaf, link) = self.options.leaf(chunk);
+        self.dag.push(link);
+        if self.leaves == 0 && !self.metadata.is_empty() {
+            self.first_leaf = Some(leaf);
+        } else {
+            if let Some(first_leaf) = self.first_leaf.take() {
+                writer.write_block(&first_leaf)?;
+            }
+            writer.write_block(&leaf)?;
+        }
+        self.leaves += 1;
+        Ok(())
+    }
+
+    /// Writes the last leaf and the stem nodes, returning the link to the file root.
+    pub(crate) fn finish<W: Write>(mut self, writer: &mut CarV2Writer<W>) -> io::Result<FileLink> {
+        // A balanced empty file is a single empty leaf, a trickle one an empty root
+        let empty = self.leaves == 0 && self.chunk.is_empty();
+        if empty && self.options.layout == Layout::Balanced {
+            self.write_leaf(writer, 0)?;
+        }
+        while !self.chunk.is_empty() {
+            let len = self.splitter.cut(&self.chunk);
+            self.write_leaf(writer, len)?;
+        }
+
+        let (mut root, mut stems) = self.dag.finish().expect("balanced DAGs have a leaf");
+        if !self.metadata.is_empty() {
+            let version = self.options.version();
+            // The root is the last stem, or the only leaf
+            if let Some(stem) = stems.last_mut().filter(|stem| stem.cid == root.cid) {
+                (*stem, root) = self.metadata.apply(version, stem, &root)?;
+            } else {
+                let leaf = self.first_leaf.take().expect("a single leaf is held back");
+                let (block, link) = self.metadata.apply(version, &leaf, &root)?;
+                stems.push(block);
+                root = link;
+            }
+        }
+
+        if let Some(first_leaf) = self.first_leaf.take() {
+            writer.write_block(&first_leaf)?;
+        }
+        for stem in &stems {
+            writer.write_block(stem)?;
+        }
+        Ok(root)
+    }
+}
+
+/// Builds the CARv2 file of a UnixFS file DAG as the file contents are written.
+///
+/// Leaves are written out as soon as they are complete, only the current chunk, the stem
+/// nodes and the index entries are kept in memory.
+pub struct CarV2Builder<W: Write> {
+    writer: CarV2Writer<W>,
+    options: DagOptions,
+    /// The name the file is wrapped under.
+    name: Option<String>,
+    file: FileDag,
+}
+
+/// What is kept of a file besides its contents.
+///
+/// Only CAR files built by this crate carry it. The deal uploader only sends the CAR it built
+/// when the provider stores uploaded CAR files as they are (`VITE_STORE_CAR_FILES`), otherwise
+/// it sends the raw file and the provider's own CAR of it comes back without a name or metadata.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FileInfo {
+    /// When set, the file is wrapped in a directory holding it under this name, and the
+    /// root of the payload is the directory.
+    pub name: Option<String>,
+    /// UnixFS 1.5 POSIX permission bits, such as `0o644`.
+    pub mode: Option<u32>,
+    /// UnixFS 1.5 modification time.
+    pub mtime: Option<UnixTime>,
+}
+
+impl<W: Write> CarV2Builder<W> {
+    /// Creates a builder writing the block sections to `out`, with the default DAG options.
+    pub fn new(out: W) -> Self {
+        Self::with_options(out, DagOptions::default()).expect("default options are valid")
+    }
+
+    /// Creates a builder writing the block sections to `out`.
+    pub fn with_options(out: W, options: DagOptions) -> Result<Self, DagOptionsError> {
+        Self::with_info(out, options, FileInfo::default())
+    }
+
+    /// Creates a builder writing the block sections to `out`, keeping the name and metadata
+    /// of the file.
+    pub fn with_info(out: W, options: DagOptions, info: FileInfo) -> Result<Self, DagOptionsError> {
+        options.validate()?;
+        if let Some(name) = &info.name {
+            if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+                return Err(DagOptionsError::InvalidFileName(name.clone()));
+            }
+        }
+        let metadata = Metadata {
+            mode: info.mode,
+            mtime: info.mtime,
+        };
+        Ok(Self {
+            writer: CarV2Writer::new(out),
+            options,
+            name: info.name,
+            file: FileDag::new(options, metadata),
+        })
+    }
+
+    /// Size of the headers returned on finish, which only depends on the root CID format,
+    /// directories having the same CIDs as files.
+    pub fn header_len(&self) -> usize {
+        header_len(self.options.root_cid_len())
+    }
+
+    /// The output the block sections are written to.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.writer.get_mut()
+    }
+
+    /// Appends `data` to the file.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write(&mut self.writer, data)
+    }
+
+    /// Writes the last leaf and the stem nodes, completing the file, then its directory if
+    /// it is wrapped.
+    pub fn finish(mut self) -> io::Result<CarV2Output<W>> {
+        let mut root = self.file.finish(&mut self.writer)?;
+        if let Some(name) = self.name {
+            let entry = PbLink {
+                cid: root.cid,
+                name,
+                tsize: root.tsize,
+            };
+            let (link, blocks) = directory(self.options.version(), vec![entry])
+                .expect("a single entry can't collide with another");
+            for block in &blocks {
+                self.writer.write_block(block)?;
+            }
+            root = link;
+        }
+        self.writer.finish(root.cid)
+    }
+}
+
+/// Generates the CARv2 file of `data` in memory, returning its root CID and bytes.
+pub fn generate_car_v2(data: &[u8]) -> io::Result<(Cid, Vec<u8>)> {
+    generate_car_v2_with_options(data, DagOptions::default())
+}
+
+/// Generates the CARv2 file of `data` in memory with the given DAG options.
+pub fn generate_car_v2_with_options(
+    data: &[u8],
+    options: DagOptions,
+) -> io::Result<(Cid, Vec<u8>)> {
+    let mut builder = CarV2Builder::with_options(vec![], options)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    builder.write(data)?;
+    let output = builder.finish()?;
+
+    let mut car = output.header;
+    car.extend_from_slice(&output.out);
+    car.extend_from_slice(&output.index);
+    Ok((output.root, car))
+}
+
+/// A CARv2 file generated in memory.
+#[wasm_bindgen]
+pub struct GeneratedCar {
+    root_cid: String,
+    bytes: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl GeneratedCar {
+    /// The payload root CID.
+    #[wasm_bindgen(getter, js_name = "rootCid")]
+    pub fn root_cid(&self) -> String {
+        self.root_cid.clone()
+    }
+
+    /// The CARv2 file bytes.
+    #[wasm_bindgen(getter)]
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+/// Generates a CARv2 file from a raw file buffer.
+///
+/// Builds a balanced UnixFS DAG of 256KiB raw leaves, writes it as a CARv1 payload and
+/// appends a `MultihashIndexSorted` index.
+///
+/// # Arguments
+/// * `data` - The raw file contents.
+///
+/// # Returns
+/// The root CID and the CARv2 bytes.
+#[wasm_bindgen(js_name = "generateCarV2")]
+pub fn generate_car_v2_js(data: &[u8]) -> Result<GeneratedCar, JsValue> {
+    let (root, bytes) = generate_car_v2(data).map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    info!("Root CID: {}", root);
+
+    Ok(GeneratedCar {
+        root_cid: root.to_string(),
+        bytes,
+    })
+}
+
+/// Parses DAG options passed from JS, `undefined` standing for the defaults.
+pub(crate) fn dag_options(options: JsValue) -> Result<DagOptions, JsValue> {
+    if options.is_undefined() || options.is_null() {
+        return Ok(DagOptions::default());
+    }
+    let options: DagOptions = serde_wasm_bindgen::from_value(options)?;
+    options
+        .validate()
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    Ok(options)
+}
+
+/// Parses the file info passed from JS, `undefined` standing for none.
+pub(crate) fn file_info(info: JsValue) -> Result<FileInfo, JsValue> {
+    if info.is_undefined() || info.is_null() {
+        return Ok(FileInfo::default());
+    }
+    Ok(serde_wasm_bindgen::from_value(info)?)
+}
+
+/// Generates a CARv2 file from a raw file buffer with a custom DAG shape.
+///
+/// # Arguments
+/// * `data` - The raw file contents.
+/// * `options` - The DAG options, e.g.
+///   `{ chunkSize: 262144, maxLinks: 174, rawLeaves: false, cidVersion: 0 }` to match
+///   `ipfs add`. Missing fields take the `generateCarV2` defaults.
+///
+/// # Returns
+/// The root CID and the CARv2 bytes.
+#[wasm_bindgen(js_name = "generateCarV2WithOptions")]
+pub fn generate_car_v2_with_options_js(
+    data: &[u8],
+    options: JsValue,
+) -> Result<GeneratedCar, JsValue> {
+    let (root, bytes) = generate_car_v2_with_options(data, dag_options(options)?)
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    info!("Root CID: {}", root);
+
+    Ok(GeneratedCar {
+        root_cid: root.to_string(),
+        bytes,
+    })
+}
+
+/// The parts of a streamed CARv2 file, to be assembled as
+/// `[header, ...written chunks, trailer]`.
+#[wasm_bindgen]
+pub struct CarV2Parts {
+    root_cid: String,
+    header: Vec<u8>,
+    trailer: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl CarV2Parts {
+    /// The payload root CID.
+    #[wasm_bindgen(getter, js_name = "rootCid")]
+    pub fn root_cid(&self) -> String {
+        self.root_cid.clone()
+    }
+
+    /// The pragma, CARv2 header and CARv1 header, which go first in the file.
+    #[wasm_bindgen(getter)]
+    pub fn header(&self) -> Vec<u8> {
+        self.header.clone()
+    }
+
+    /// The remaining blocks and the index, which go last in the file.
+    #[wasm_bindgen(getter)]
+    pub fn trailer(&self) -> Vec<u8> {
+        self.trailer.clone()
+    }
+}
+
+impl From<CarV2Output<Vec<u8>>> for CarV2Parts {
+    fn from(output: CarV2Output<Vec<u8>>) -> Self {
+        let mut trailer = output.out;
+        trailer.extend_from_slice(&output.index);
+        Self {
+            root_cid: output.root.to_string(),
+            header: output.header,
+            trailer,
+        }
+    }
+}
+
+/// Generates a CARv2 file while the file is being read, with bounded memory.
+///
+/// ```js
+/// const stream = new CarV2Stream();
+/// const chunks = [];
+/// for await (const data of file.stream()) chunks.push(stream.write(data));
+/// const parts = stream.finish();
+/// const car = new Blob([parts.header, ...chunks, parts.trailer]);
+/// ```
+#[wasm_bindgen]
+pub struct CarV2Stream {
+    builder: CarV2Builder<Vec<u8>>,
+}
+
+#[wasm_bindgen]
+impl CarV2Stream {
+    #[wasm_bindgen(constructor)]
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> CarV2Stream {
+        Self {
+            builder: CarV2Builder::new(vec![]),
+        }
+    }
+
+    /// Creates a stream with the DAG options taken by `generateCarV2WithOptions`.
+    #[wasm_bindgen(js_name = "withOptions")]
+    pub fn with_options(options: JsValue) -> Result<CarV2Stream, JsValue> {
+        let builder = CarV2Builder::with_options(vec![], dag_options(options)?)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(Self { builder })
+    }
+
+    /// Creates a stream keeping the name and metadata of the file.
+    ///
+    /// # Arguments
+    /// * `options` - The DAG options taken by `generateCarV2WithOptions`, or `undefined`.
+    /// * `info` - `{ name, mode, mtime: { seconds, nanos } }`, every field being optional.
+    ///   With a `name`, the file is wrapped in a directory holding it under that name.
+    #[wasm_bindgen(js_name = "withFileInfo")]
+    pub fn with_file_info(options: JsValue, info: JsValue) -> Result<CarV2Stream, JsValue> {
+        let builder = CarV2Builder::with_info(vec![], dag_options(options)?, file_info(info)?)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(Self { builder })
+    }
+
+    /// Appends `data` to the file, returning the CAR bytes completed so far.
+    pub fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, JsValue> {
+        self.builder
+            .write(data)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(std::mem::take(self.builder.get_mut()))
+    }
+
+    /// Completes the file.
+    pub fn finish(self) -> Result<CarV2Parts, JsValue> {
+        let output = self
+            .builder
+            .finish()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+        info!("Root CID: {}", output.root);
+
+        Ok(output.into())
+    }
+}
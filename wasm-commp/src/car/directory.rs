@@ -0,0 +1,377 @@
+//! Streaming generation of CARv2 files from many files, as a UnixFS directory DAG.
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+use cid::Version;
+use thiserror::Error;
+use tracing::info;
+use wasm_bindgen::prelude::*;
+
+use super::{
+    builder::{dag_options, CarV2Parts, FileDag},
+    CarV2Output, CarV2Writer,
+};
+use crate::unixfs::{
+    directory::{directory, HashCollision},
+    pb::PbLink,
+    DagOptions, DagOptionsError, FileLink, Metadata,
+};
+
+#[derive(Debug, Error)]
+pub enum DirectoryError {
+    #[error("invalid path {0:?}")]
+    InvalidPath(String),
+    #[error("{0} already exists")]
+    AlreadyExists(String),
+    #[error("{0} is a file")]
+    NotADirectory(String),
+    #[error("no file is being written")]
+    NoFile,
+    #[error(transparent)]
+    HashCollision(#[from] HashCollision),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+enum Entry {
+    File(FileLink),
+    Directory(BTreeMap<String, Entry>),
+}
+
+/// Splits a `/` separated path, rejecting empty, `.` and `..` components.
+fn components(path: &str) -> Result<Vec<String>, DirectoryError> {
+    let components = path.split('/').map(str::to_string).collect::<Vec<_>>();
+    if components
+        .iter()
+        .any(|c| c.is_empty() || c == "." || c == "..")
+    {
+        return Err(DirectoryError::InvalidPath(path.to_string()));
+    }
+    Ok(components)
+}
+
+/// The directory at `path` in `root`, created along with its parents if missing.
+fn directory_mut<'a>(
+    mut dir: &'a mut BTreeMap<String, Entry>,
+    path: &[String],
+) -> Result<&'a mut BTreeMap<String, Entry>, DirectoryError> {
+    for (depth, name) in path.iter().enumerate() {
+        let entry = dir
+            .entry(name.clone())
+            .or_insert_with(|| Entry::Directory(BTreeMap::new()));
+        dir = match entry {
+            Entry::Directory(dir) => dir,
+            Entry::File(_) => return Err(DirectoryError::NotADirectory(path[..=depth].join("/"))),
+        };
+    }
+    Ok(dir)
+}
+
+/// Writes the nodes of `dir` and its subdirectories, children first.
+fn write_directory<W: Write>(
+    writer: &mut CarV2Writer<W>,
+    version: Version,
+    dir: BTreeMap<String, Entry>,
+) -> Result<FileLink, DirectoryError> {
+    let mut links = vec![];
+    for (name, entry) in dir {
+        let link = match entry {
+            Entry::File(link) => link,
+            Entry::Directory(dir) => write_directory(writer, version, dir)?,
+        };
+        links.push(PbLink {
+            cid: link.cid,
+            name,
+            tsize: link.tsize,
+        });
+    }
+
+    let (link, blocks) = directory(version, links)?;
+    for block in &blocks {
+        writer.write_block(block)?;
+    }
+    Ok(link)
+}
+
+/// Builds the CARv2 file of a UnixFS directory as files are written to it, one at a time.
+///
+/// The blocks of each file are written out as the file is, only the directory entries are
+/// kept in memory. The directory nodes are written on finish, the root directory last.
+pub struct DirectoryCarBuilder<W: Write> {
+    writer: CarV2Writer<W>,
+    options: DagOptions,
+    root: BTreeMap<String, Entry>,
+    /// The path of the file being written, and its DAG.
+    file: Option<(Vec<String>, FileDag)>,
+}
+
+impl<W: Write> DirectoryCarBuilder<W> {
+    /// Creates a builder writing the block sections to `out`, with the default DAG options.
+    pub fn new(out: W) -> Self {
+        Self::with_options(out, DagOptions::default()).expect("default options are valid")
+    }
+
+    /// Creates a builder writing the block sections to `out`.
+    pub fn with_options(out: W, options: DagOptions) -> Result<Self, DagOptionsError> {
+        options.validate()?;
+        Ok(Self {
+            writer: CarV2Writer::new(out),
+            options,
+            root: BTreeMap::new(),
+            file: None,
+        })
+    }
+
+    /// The output the block sections are written to.
+    pub fn get_mut(&mut self) -> &mut W {
+        self.writer.get_mut()
+    }
+
+    /// Completes the file being written, if any.
+    fn finish_file(&mut self) -> Result<(), DirectoryError> {
+        let Some((path, file)) = self.file.take() else {
+            return Ok(());
+        };
+        let link = file.finish(&mut self.writer)?;
+        let (name, parent) = path.split_last().expect("paths aren't empty");
+        directory_mut(&mut self.root, parent)?.insert(name.clone(), Entry::File(link));
+        Ok(())
+    }
+
+    /// Adds an empty directory at `path`, along with its missing parents.
+    pub fn add_directory(&mut self, path: &str) -> Result<(), DirectoryError> {
+        self.finish_file()?;
+        directory_mut(&mut self.root, &components(path)?)?;
+        Ok(())
+    }
+
+    /// Starts a file at `path`, a `/` separated path relative to the root directory, completing
+    /// the previous file. Missing parent directories are created.
+    pub fn add_file(&mut self, path: &str) -> Result<(), DirectoryError> {
+        self.add_file_with_metadata(path, Metadata::default())
+    }
+
+    /// Starts a file as [`Self::add_file`] does, storing its UnixFS 1.5 metadata.
+    pub fn add_file_with_metadata(
+        &mut self,
+        path: &str,
+        metadata: Metadata,
+    ) -> Result<(), DirectoryError> {
+        self.finish_file()?;
+        let path = components(path)?;
+        let (name, parent) = path.split_last().expect("paths aren't empty");
+        if directory_mut(&mut self.root, parent)?.contains_key(name) {
+            return Err(DirectoryError::AlreadyExists(path.join("/")));
+        }
+        self.file = Some((path, FileDag::new(self.options, metadata)));
+        Ok(())
+    }
+
+    /// Appends `data` to the file started last.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), DirectoryError> {
+        let (_, file) = self.file.as_mut().ok_or(DirectoryError::NoFile)?;
+        file.write(&mut self.writer, data)?;
+        Ok(())
+    }
+
+    /// Completes the last file and writes the directory nodes.
+    pub fn finish(mut self) -> Result<CarV2Output<W>, DirectoryError> {
+        self.finish_file()?;
+        let root = write_directory(&mut self.writer, self.options.version(), self.root)?;
+        Ok(self.writer.finish(root.cid)?)
+    }
+}
+
+/// Generates a CARv2 file holding a UnixFS directory while its files are being read.
+///
+/// ```js
+/// const stream = new DirectoryCarStream();
+/// const chunks = [];
+/// for (const file of files) {
+///   stream.addFile(file.webkitRelativePath || file.name);
+///   for await (const data of file.stream()) chunks.push(stream.write(data));
+/// }
+/// const parts = stream.finish();
+/// const car = new Blob([parts.header, ...chunks, parts.trailer]);
+/// ```
+#[wasm_bindgen]
+pub struct DirectoryCarStream {
+    builder: DirectoryCarBuilder<Vec<u8>>,
+}
+
+#[wasm_bindgen]
+impl DirectoryCarStream {
+    #[wasm_bindgen(constructor)]
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> DirectoryCarStream {
+        Self {
+            builder: DirectoryCarBuilder::new(vec![]),
+        }
+    }
+
+    /// Creates a stream with the DAG options taken by `generateCarV2WithOptions`.
+    #[wasm_bindgen(js_name = "withOptions")]
+    pub fn with_options(options: JsValue) -> Result<DirectoryCarStream, JsValue> {
+        let builder = DirectoryCarBuilder::with_options(vec![], dag_options(options)?)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(Self { builder })
+    }
+
+    /// Adds an empty directory, `path` being `/` separated.
+    #[wasm_bindgen(js_name = "addDirectory")]
+    pub fn add_directory(&mut self, path: &str) -> Result<Vec<u8>, JsValue> {
+        self.builder
+            .add_directory(path)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(std::mem::take(self.builder.get_mut()))
+    }
+
+    /// Starts a file, `path` being `/` separated, returning the CAR bytes completed by the
+    /// previous file. `metadata` is either `undefined` or `{ mode, mtime: { seconds, nanos } }`.
+    #[wasm_bindgen(js_name = "addFile")]
+    pub fn add_file(&mut self, path: &str, metadata: JsValue) -> Result<Vec<u8>, JsValue> {
+        let metadata = if metadata.is_undefined() || metadata.is_null() {
+            Metadata::default()
+        } else {
+            serde_wasm_bindgen::from_value(metadata)?
+        };
+        self.builder
+            .add_file_with_metadata(path, metadata)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(std::mem::take(self.builder.get_mut()))
+    }
+
+    /// Appends `data` to the current file, returning the CAR bytes completed so far.
+    pub fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, JsValue> {
+        self.builder
+            .write(data)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(std::mem::take(self.builder.get_mut()))
+    }
+
+    /// Completes the directory.
+    pub fn finish(self) -> Result<CarV2Parts, JsValue> {
+        let output = self
+            .builder
+            .finish()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+        info!("Root CID: {}", output.root);
+
+        Ok(output.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        car::{builder::generate_car_v2, reader::validate_car},
+        unixfs::pb::decode_node,
+    };
+
+    fn build(files: &[(&str, &[u8])]) -> (cid::Cid, Vec<u8>) {
+        let mut builder = DirectoryCarBuilder::new(vec![]);
+        for (path, data) in files {
+            builder.add_file(path).unwrap();
+            builder.write(data).unwrap();
+        }
+        let output = builder.finish().unwrap();
+        let car = [output.header, output.out, output.index].concat();
+        (output.root, car)
+    }
+
+    #[test]
+    fn nested_directories() {
+        let data = vec![3; 300 * 1024];
+        let (root, car) = build(&[("b.txt", b"b"), ("a/big.bin", &data), ("a/sub/c.txt", b"c")]);
+        let report = validate_car(&car);
+        assert!(report.valid, "{report:?}");
+        // b, 2 big leaves and its stem, c, sub, a and the root
+        assert_eq!(report.blocks, 8);
+        assert_eq!(report.roots, [root.to_string()]);
+
+        // Files have the same root as on their own
+        let (file_root, _) = generate_car_v2(&data).unwrap();
+        let mut reader = crate::car::reader::CarReader::new(&car[..]).unwrap();
+        let mut blocks = std::collections::HashMap::new();
+        while let Some((_, block)) = reader.next_block().unwrap() {
+            blocks.insert(block.cid, block.data);
+        }
+        let root_node = decode_node(&blocks[&root]).unwrap();
+        let names = root_node.links.iter().map(|l| &l.name).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b.txt"]);
+        let a = decode_node(&blocks[&root_node.links[0].cid]).unwrap();
+        assert_eq!(a.links[0].name, "big.bin");
+        assert_eq!(a.links[0].cid, file_root);
+    }
+
+    #[test]
+    fn empty_directories() {
+        let options = DagOptions {
+            raw_leaves: false,
+            cid_version: 0,
+            ..Default::default()
+        };
+        let builder = DirectoryCarBuilder::with_options(vec![], options).unwrap();
+        let output = builder.finish().unwrap();
+        assert_eq!(
+            output.root.to_string(),
+            "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn"
+        );
+
+        let mut builder = DirectoryCarBuilder::new(vec![]);
+        builder.add_directory("x/y").unwrap();
+        builder.add_file("x/z").unwrap();
+        // The empty leaf of z, y, x and the root
+        assert_eq!(builder.finish().unwrap().blocks.len(), 4);
+    }
+
+    #[test]
+    fn sharded_directories() {
+        let names = (0..6000).map(|i| format!("{i:040}")).collect::<Vec<_>>();
+        let files = names
+            .iter()
+            .map(|name| (name.as_str(), name.as_bytes()))
+            .collect::<Vec<_>>();
+        let (root, car) = build(&files);
+        let report = validate_car(&car);
+        assert!(report.valid, "{report:?}");
+        // Every file is a single leaf, the rest are HAMT nodes
+        assert!(report.blocks > 6000 + 1);
+
+        let mut reader = crate::car::reader::CarReader::new(&car[..]).unwrap();
+        let mut last = None;
+        while let Some((_, block)) = reader.next_block().unwrap() {
+            last = Some(block);
+        }
+        let last = last.unwrap();
+        assert_eq!(last.cid, root);
+        assert_eq!(decode_node(&last.data).unwrap().links.len(), 256);
+    }
+
+    #[test]
+    fn rejects_invalid_paths() {
+        let mut builder = DirectoryCarBuilder::new(vec![]);
+        assert!(matches!(builder.write(b"x"), Err(DirectoryError::NoFile)));
+        for path in ["", "/a", "a//b", "a/../b", "./a", "a/"] {
+            assert!(matches!(
+                builder.add_file(path),
+                Err(DirectoryError::InvalidPath(_))
+            ));
+        }
+
+        builder.add_file("a/b").unwrap();
+        assert!(matches!(
+            builder.add_file("a/b"),
+            Err(DirectoryError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            builder.add_file("a/b/c"),
+            Err(DirectoryError::NotADirectory(_))
+        ));
+        builder.add_directory("a").unwrap();
+    }
+}
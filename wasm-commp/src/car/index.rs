@@ -0,0 +1,568 @@
+//! CARv2 indexes.
+//!
+//! Both index formats of go-car are supported, `IndexSorted` and `MultihashIndexSorted`.
+//! An index is either stored at the end of a CARv2 file or on its own, as an `.idx` sidecar
+//! file holding the same bytes.
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Cursor,
+};
+
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use super::{
+    encode_v2_header,
+    reader::{verify_data, CarHeader, CarReader},
+    CarError, DATA_OFFSET,
+};
+use crate::ipld::{decode_varint, write_varint};
+
+/// Multicodec of the `IndexSorted` index format.
+pub const INDEX_SORTED: u64 = 0x0400;
+/// Multicodec of the `MultihashIndexSorted` index format.
+pub const MULTIHASH_INDEX_SORTED: u64 = 0x0401;
+
+/// The format of an index.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum IndexCodec {
+    /// Digests grouped by length, without their multihash code.
+    IndexSorted,
+    /// Digests grouped by multihash code, then by length.
+    #[default]
+    MultihashIndexSorted,
+}
+
+impl IndexCodec {
+    pub fn code(&self) -> u64 {
+        match self {
+            Self::IndexSorted => INDEX_SORTED,
+            Self::MultihashIndexSorted => MULTIHASH_INDEX_SORTED,
+        }
+    }
+}
+
+impl TryFrom<u64> for IndexCodec {
+    type Error = CarError;
+
+    fn try_from(code: u64) -> Result<Self, Self::Error> {
+        match code {
+            INDEX_SORTED => Ok(Self::IndexSorted),
+            MULTIHASH_INDEX_SORTED => Ok(Self::MultihashIndexSorted),
+            code => Err(CarError::UnsupportedIndex(code)),
+        }
+    }
+}
+
+/// The location of a block in the CARv1 payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// Multihash code of the block's CID, `None` for entries read from an `IndexSorted`
+    /// index, which only stores digests.
+    pub code: Option<u64>,
+    /// The multihash digest, without its code and length.
+    pub digest: Vec<u8>,
+    /// Offset of the block's section, from the start of the CARv1 payload.
+    pub offset: u64,
+}
+
+impl IndexEntry {
+    pub fn new(cid: &Cid, offset: u64) -> Self {
+        Self {
+            code: Some(cid.hash().code()),
+            digest: cid.hash().digest().to_vec(),
+            offset,
+        }
+    }
+
+    /// Whether the entry is the one of `cid`, ignoring the offset.
+    pub fn matches(&self, cid: &Cid) -> bool {
+        self.code.is_none_or(|code| code == cid.hash().code()) && self.digest == cid.hash().digest()
+    }
+}
+
+/// Writes the buckets of entries of the same digest length, in ascending length order, each
+/// bucket being sorted by digest.
+fn encode_buckets(out: &mut Vec<u8>, entries: Vec<&IndexEntry>) {
+    let mut widths: BTreeMap<usize, Vec<&IndexEntry>> = BTreeMap::new();
+    for entry in entries {
+        widths.entry(entry.digest.len()).or_default().push(entry);
+    }
+
+    out.extend_from_slice(&(widths.len() as u32).to_le_bytes());
+    for (digest_len, mut bucket) in widths {
+        bucket.sort_by(|a, b| a.digest.cmp(&b.digest));
+
+        let entry_size = digest_len as u32 + 8;
+        out.extend_from_slice(&entry_size.to_le_bytes());
+        out.extend_from_slice(&(entry_size as u64 * bucket.len() as u64).to_le_bytes());
+        for entry in bucket {
+            out.extend_from_slice(&entry.digest);
+            out.extend_from_slice(&entry.offset.to_le_bytes());
+        }
+    }
+}
+
+/// Encodes `entries` as an index, prefixed by its multicodec.
+///
+/// `MultihashIndexSorted` groups the entries by multihash code, in ascending order, before
+/// grouping them by digest length.
+///
+/// # Panics
+/// If an entry without a multihash code is written to a `MultihashIndexSorted` index.
+pub fn encode_index(codec: IndexCodec, entries: &[IndexEntry]) -> Vec<u8> {
+    let mut out = vec![];
+    write_varint(&mut out, codec.code());
+    match codec {
+        IndexCodec::IndexSorted => encode_buckets(&mut out, entries.iter().collect()),
+        IndexCodec::MultihashIndexSorted => {
+            let mut codes: BTreeMap<u64, Vec<&IndexEntry>> = BTreeMap::new();
+            for entry in entries {
+                let code = entry
+                    .code
+                    .expect("MultihashIndexSorted entries have a multihash code");
+                codes.entry(code).or_default().push(entry);
+            }
+
+            out.extend_from_slice(&(codes.len() as u32).to_le_bytes());
+            for (code, entries) in codes {
+                out.extend_from_slice(&code.to_le_bytes());
+                encode_buckets(&mut out, entries);
+            }
+        }
+    }
+    out
+}
+
+/// Reads the little endian integers of an index.
+struct IndexBytes<'a>(&'a [u8]);
+
+impl<'a> IndexBytes<'a> {
+    fn take(&mut self, len: u64) -> Result<&'a [u8], CarError> {
+        let len = usize::try_from(len).map_err(|_| CarError::Truncated)?;
+        if self.0.len() < len {
+            return Err(CarError::Truncated);
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, CarError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, CarError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Reads buckets written by [`encode_buckets`].
+    fn buckets(
+        &mut self,
+        code: Option<u64>,
+        entries: &mut Vec<IndexEntry>,
+    ) -> Result<(), CarError> {
+        for _ in 0..self.u32()? {
+            let entry_size = self.u32()?;
+            let total = self.u64()?;
+            if entry_size < 8 || total % u64::from(entry_size) != 0 {
+                return Err(CarError::InvalidIndex(format!(
+                    "{total} bytes of {entry_size} bytes entries"
+                )));
+            }
+            for entry in self.take(total)?.chunks(entry_size as usize) {
+                let (digest, offset) = entry.split_at(entry.len() - 8);
+                entries.push(IndexEntry {
+                    code,
+                    digest: digest.to_vec(),
+                    offset: u64::from_le_bytes(offset.try_into().unwrap()),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes an index, returning its codec and entries.
+pub fn decode_index(bytes: &[u8]) -> Result<(IndexCodec, Vec<IndexEntry>), CarError> {
+    let (codec, entries, len) = decode_index_prefix(bytes)?;
+    if len != bytes.len() {
+        return Err(CarError::InvalidIndex("trailing bytes".into()));
+    }
+    Ok((codec, entries))
+}
+
+/// The length of the index `bytes` start with, for indexes followed by other data.
+pub fn index_len(bytes: &[u8]) -> Result<usize, CarError> {
+    Ok(decode_index_prefix(bytes)?.2)
+}
+
+/// Decodes the index `bytes` start with, returning its codec, entries and length.
+fn decode_index_prefix(bytes: &[u8]) -> Result<(IndexCodec, Vec<IndexEntry>, usize), CarError> {
+    let (code, read) = decode_varint(bytes).ok_or(CarError::Truncated)?;
+    let codec = IndexCodec::try_from(code)?;
+    let total_len = bytes.len();
+    let mut bytes = IndexBytes(&bytes[read..]);
+
+    let mut entries = vec![];
+    match codec {
+        IndexCodec::IndexSorted => bytes.buckets(None, &mut entries)?,
+        IndexCodec::MultihashIndexSorted => {
+            for _ in 0..bytes.u32()? {
+                let code = bytes.u64()?;
+                bytes.buckets(Some(code), &mut entries)?;
+            }
+        }
+    }
+    Ok((codec, entries, total_len - bytes.0.len()))
+}
+
+/// The CARv1 payload of a CARv1 or CARv2 file.
+fn payload(car: &[u8]) -> Result<&[u8], CarError> {
+    let reader = CarReader::new(car)?;
+    let Some(v2) = &reader.header().v2 else {
+        return Ok(car);
+    };
+    let end = v2.data_offset.checked_add(v2.data_size).ok_or_else(|| {
+        CarError::InvalidV2Header(format!(
+            "data size {} overflows the file offsets",
+            v2.data_size
+        ))
+    })?;
+    usize::try_from(end)
+        .ok()
+        .and_then(|end| car.get(v2.data_offset as usize..end))
+        .ok_or(CarError::Truncated)
+}
+
+/// Reads the section at `offset` of a CARv1 payload, returning the block's CID and data along
+/// with the section length.
+fn read_section(payload: &[u8], offset: u64) -> Result<(Cid, &[u8], u64), CarError> {
+    let bytes = usize::try_from(offset)
+        .ok()
+        .and_then(|offset| payload.get(offset..))
+        .ok_or(CarError::Truncated)?;
+    let (len, read) = decode_varint(bytes).ok_or(CarError::Truncated)?;
+    let section = usize::try_from(len)
+        .ok()
+        .and_then(|len| bytes.get(read..read.checked_add(len)?))
+        .ok_or(CarError::Truncated)?;
+    let mut cursor = Cursor::new(section);
+    let cid = Cid::read_bytes(&mut cursor)
+        .map_err(|e| CarError::InvalidSection(format!("invalid CID: {e}")))?;
+    Ok((
+        cid,
+        &section[cursor.position() as usize..],
+        read as u64 + len,
+    ))
+}
+
+/// Random access to the blocks of a CAR file held in memory.
+///
+/// The blocks of indexed CARv2 files are located through the index. Other files are indexed
+/// when opened by going over the section headers, skipping the blocks. Blocks are only
+/// verified against their CIDs when requested.
+///
+/// Files retrieved as pieces may be followed by the zero padding of the piece, which is
+/// ignored after the index of CARv2 files and after the last section of CARv1 files.
+pub struct IndexedCar<'a> {
+    header: CarHeader,
+    payload: &'a [u8],
+    /// The offsets of the sections holding each digest, from the start of the payload.
+    offsets: HashMap<Vec<u8>, Vec<u64>>,
+}
+
+impl<'a> IndexedCar<'a> {
+    pub fn new(car: &'a [u8]) -> Result<Self, CarError> {
+        let reader = CarReader::new(car)?;
+        let header = reader.header().clone();
+        let payload = payload(car)?;
+
+        let mut offsets: HashMap<Vec<u8>, Vec<u64>> = HashMap::new();
+        match &header.v2 {
+            Some(v2) if v2.index_offset != 0 => {
+                let index = usize::try_from(v2.index_offset)
+                    .ok()
+                    .and_then(|offset| car.get(offset..))
+                    .ok_or(CarError::Truncated)?;
+                // The padding after the index is left out, as `PieceVerifier::finish` does
+                let (_, entries, len) = decode_index_prefix(index)?;
+                if index[len..].iter().any(|&byte| byte != 0) {
+                    return Err(CarError::InvalidIndex("trailing bytes".into()));
+                }
+                for entry in entries {
+                    offsets.entry(entry.digest).or_default().push(entry.offset);
+                }
+            }
+            _ => {
+                let mut offset = reader.offset();
+                while offset < payload.len() as u64 {
+                    if payload[offset as usize..].iter().all(|&byte| byte == 0) {
+                        break;
+                    }
+                    let (cid, _, len) = read_section(payload, offset)?;
+                    offsets
+                        .entry(cid.hash().digest().to_vec())
+                        .or_default()
+                        .push(offset);
+                    offset += len;
+                }
+            }
+        }
+        Ok(Self {
+            header,
+            payload,
+            offsets,
+        })
+    }
+
+    pub fn header(&self) -> &CarHeader {
+        &self.header
+    }
+
+    /// The data of block `cid`, `None` if the file doesn't hold it.
+    pub fn get(&self, cid: &Cid) -> Result<Option<&'a [u8]>, CarError> {
+        let Some(offsets) = self.offsets.get(cid.hash().digest()) else {
+            return Ok(None);
+        };
+        for &offset in offsets {
+            let (section_cid, data, _) = read_section(self.payload, offset)?;
+            if section_cid.hash() == cid.hash() {
+                verify_data(cid, data)?;
+                return Ok(Some(data));
+            }
+        }
+        Err(CarError::IndexMismatch {
+            digest: hex::encode(cid.hash().digest()),
+        })
+    }
+}
+
+/// Generates the index of a CARv1 or CARv2 file, ignoring the index it may already have.
+///
+/// As [`super::CarV2Writer`] does, each block is indexed once, at its first section. Blocks
+/// aren't verified against their CIDs.
+pub fn generate_index(car: &[u8], codec: IndexCodec) -> Result<Vec<u8>, CarError> {
+    let mut reader = CarReader::new(payload(car)?)?;
+    let mut seen = HashSet::new();
+    let mut entries = vec![];
+    while let Some((offset, block)) = reader.next_block()? {
+        if seen.insert(block.cid) {
+            entries.push(IndexEntry::new(&block.cid, offset));
+        }
+    }
+    Ok(encode_index(codec, &entries))
+}
+
+/// Builds a CARv2 file out of the CARv1 payload of `car` followed by `index`, an encoded
+/// index such as a sidecar file.
+///
+/// Every entry of the index has to point at a section of the payload holding its block, and
+/// every block of the payload has to be indexed. Blocks aren't verified against their CIDs.
+pub fn attach_index(car: &[u8], index: &[u8]) -> Result<Vec<u8>, CarError> {
+    let (_, entries) = decode_index(index)?;
+    let payload = payload(car)?;
+
+    let mut reader = CarReader::new(payload)?;
+    let mut sections = HashMap::new();
+    while let Some((offset, block)) = reader.next_block()? {
+        sections.insert(offset, block.cid);
+    }
+    let mut indexed = HashSet::new();
+    for entry in entries {
+        match sections.get(&entry.offset) {
+            Some(cid) if entry.matches(cid) => indexed.insert(*cid),
+            _ => {
+                return Err(CarError::IndexMismatch {
+                    digest: hex::encode(&entry.digest),
+                })
+            }
+        };
+    }
+    if let Some(cid) = sections.values().find(|cid| !indexed.contains(*cid)) {
+        return Err(CarError::NotIndexed(*cid));
+    }
+
+    let data_size = payload.len() as u64;
+    Ok([
+        &encode_v2_header(data_size, DATA_OFFSET + data_size)[..],
+        payload,
+        index,
+    ]
+    .concat())
+}
+
+fn index_codec(codec: JsValue) -> Result<IndexCodec, JsValue> {
+    if codec.is_undefined() || codec.is_null() {
+        return Ok(IndexCodec::default());
+    }
+    Ok(serde_wasm_bindgen::from_value(codec)?)
+}
+
+/// Generates the index of a CAR file, as stored in an `.idx` sidecar file.
+///
+/// # Arguments
+/// * `car` - A CARv1 or CARv2 file, any existing index is ignored.
+/// * `codec` - `"indexSorted"`, `"multihashIndexSorted"` or `undefined` for the latter.
+///
+/// # Returns
+/// The encoded index, prefixed by its multicodec.
+#[wasm_bindgen(js_name = "generateCarIndex")]
+pub fn generate_index_js(car: &[u8], codec: JsValue) -> Result<Vec<u8>, JsValue> {
+    generate_index(car, index_codec(codec)?).map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// Rebuilds a CAR file as a CARv2 file with a freshly generated index.
+///
+/// # Arguments
+/// * `car` - A CARv1 or CARv2 file, any existing index is replaced.
+/// * `codec` - As taken by `generateCarIndex`.
+#[wasm_bindgen(js_name = "indexCar")]
+pub fn index_car_js(car: &[u8], codec: JsValue) -> Result<Vec<u8>, JsValue> {
+    let codec = index_codec(codec)?;
+    generate_index(car, codec)
+        .and_then(|index| attach_index(car, &index))
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// Builds a CARv2 file out of a CAR file and an `.idx` sidecar file.
+///
+/// # Arguments
+/// * `car` - A CARv1 or CARv2 file, any existing index is replaced.
+/// * `index` - The sidecar file contents.
+#[wasm_bindgen(js_name = "attachCarIndex")]
+pub fn attach_index_js(car: &[u8], index: &[u8]) -> Result<Vec<u8>, JsValue> {
+    attach_index(car, index).map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use primitives::commitment::piece::PaddedPieceSize;
+
+    use crate::{
+        car::{
+            builder::generate_car_v2,
+            reader::{validate_car, validate_car_with_index},
+        },
+        testing::root_first_car,
+    };
+
+    fn car() -> (Cid, Vec<u8>) {
+        generate_car_v2(&(0..600 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn index_roundtrip() {
+        let (_, car) = car();
+        let mut reader = CarReader::new(&car[..]).unwrap();
+        let mut entries = vec![];
+        while let Some((offset, block)) = reader.next_block().unwrap() {
+            entries.push(IndexEntry::new(&block.cid, offset));
+        }
+        entries.sort_by(|a, b| a.digest.cmp(&b.digest));
+
+        for codec in [IndexCodec::IndexSorted, IndexCodec::MultihashIndexSorted] {
+            let (decoded_codec, mut decoded) =
+                decode_index(&encode_index(codec, &entries)).unwrap();
+            assert_eq!(decoded_codec, codec);
+            if codec == IndexCodec::IndexSorted {
+                assert!(decoded.iter().all(|entry| entry.code.is_none()));
+                decoded.iter_mut().for_each(|entry| entry.code = Some(0x12));
+            }
+            assert_eq!(decoded, entries);
+        }
+
+        let index = encode_index(IndexCodec::IndexSorted, &entries);
+        assert!(decode_index(&index[..index.len() - 1]).is_err());
+        assert!(decode_index(&[0x81, 0x08]).is_err());
+    }
+
+    #[test]
+    fn regenerates_indexes() {
+        let (_, car) = car();
+        let index_offset = u64::from_le_bytes(car[43..51].try_into().unwrap()) as usize;
+        assert_eq!(
+            generate_index(&car, IndexCodec::MultihashIndexSorted).unwrap(),
+            car[index_offset..]
+        );
+        // Replacing the index with the same one gives the same file
+        assert_eq!(attach_index(&car, &car[index_offset..]).unwrap(), car);
+
+        let index = generate_index(&car, IndexCodec::IndexSorted).unwrap();
+        let report = validate_car(&attach_index(&car, &index).unwrap());
+        assert!(report.valid, "{report:?}");
+        assert_eq!(report.index_codec, Some(INDEX_SORTED));
+    }
+
+    #[test]
+    fn indexes_car_v1_files() {
+        let data = vec![7; 300 * 1024];
+        let (_, v1) = root_first_car(&data);
+        let sidecar = generate_index(&v1, IndexCodec::MultihashIndexSorted).unwrap();
+        assert!(validate_car_with_index(&v1, &sidecar).valid);
+
+        let v2 = attach_index(&v1, &sidecar).unwrap();
+        assert_eq!(v2[DATA_OFFSET as usize..][..v1.len()], v1);
+        let report = validate_car(&v2);
+        assert!(report.valid, "{report:?}");
+        assert_eq!(report.indexed_blocks, 3);
+
+        // A sidecar of another file doesn't match
+        let (_, other) = car();
+        let other = generate_index(&other, IndexCodec::IndexSorted).unwrap();
+        assert!(!validate_car_with_index(&v1, &other).valid);
+        assert!(matches!(
+            attach_index(&v1, &other),
+            Err(CarError::IndexMismatch { .. })
+        ));
+        assert!(attach_index(&v1, b"not an index").is_err());
+
+        // Nor does one missing a block, or pointing a block at another section
+        let mut reader = CarReader::new(&v1[..]).unwrap();
+        let mut cids = vec![];
+        let mut entries = vec![];
+        while let Some((offset, block)) = reader.next_block().unwrap() {
+            cids.push(block.cid);
+            entries.push(IndexEntry::new(&block.cid, offset));
+        }
+        let missing = encode_index(IndexCodec::MultihashIndexSorted, &entries[1..]);
+        assert!(matches!(
+            attach_index(&v1, &missing),
+            Err(CarError::NotIndexed(cid)) if cid == cids[0]
+        ));
+        let offset = entries[0].offset;
+        entries[0].offset = entries[1].offset;
+        entries[1].offset = offset;
+        let moved = encode_index(IndexCodec::MultihashIndexSorted, &entries);
+        assert!(matches!(
+            attach_index(&v1, &moved),
+            Err(CarError::IndexMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn ignores_piece_padding() {
+        let (root, v2) = car();
+        let (v1_root, v1) = root_first_car(&[7; 300 * 1024]);
+        for (root, car) in [(root, v2), (v1_root, v1)] {
+            let size = PaddedPieceSize::from_arbitrary_size(car.len() as u64);
+            let mut padded = car.clone();
+            padded.resize(*size.unpadded() as usize, 0);
+            assert!(padded.len() > car.len());
+
+            let blocks = IndexedCar::new(&padded).unwrap();
+            assert_eq!(
+                blocks.get(&root).unwrap(),
+                IndexedCar::new(&car).unwrap().get(&root).unwrap()
+            );
+        }
+
+        // Only zeros are padding
+        let (_, mut car) = car();
+        car.extend_from_slice(&[0, 0, 1]);
+        assert!(IndexedCar::new(&car).is_err());
+    }
+}
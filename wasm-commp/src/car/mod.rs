@@ -0,0 +1,92 @@
+//! CAR (Content Addressable aRchive) files.
+//!
+//! CARv2 files are written as the pragma, the CARv2 header, the CARv1 payload and a
+//! `MultihashIndexSorted` index. Files are stored on their own or, with
+//! [`directory::DirectoryCarBuilder`], as the entries of a UnixFS directory.
+//!
+//! CARv1 and CARv2 files are read back by [`reader::CarReader`], and checked block by block
+//! while downloaded by [`verifier::CarVerifier`].
+use cid::Cid;
+
+pub mod builder;
+pub mod directory;
+pub mod index;
+pub mod reader;
+pub mod verifier;
+mod writer;
+
+pub use builder::CarV2Builder;
+pub use reader::CarError;
+pub use writer::{CarV2Output, CarV2Writer};
+
+use crate::ipld::{varint_len, write_varint};
+
+/// The CARv2 pragma, the DAG-CBOR encoding of `{ "version": 2 }` prefixed by its length.
+pub const PRAGMA: [u8; 11] = [
+    0x0a, 0xa1, 0x67, b'v', b'e', b'r', b's', b'i', b'o', b'n', 0x02,
+];
+/// Size of the CARv2 header: characteristics, data offset, data size and index offset.
+pub const CARV2_HEADER_SIZE: usize = 40;
+/// Offset of the CARv1 payload in a CARv2 file.
+pub const DATA_OFFSET: u64 = (PRAGMA.len() + CARV2_HEADER_SIZE) as u64;
+
+/// Encodes the CARv1 header, `{ "roots": [root], "version": 1 }` as DAG-CBOR, prefixed by
+/// its length.
+pub fn encode_v1_header(root: &Cid) -> Vec<u8> {
+    // The CID is a tag 42 byte string, prefixed by the multibase identity byte
+    let cid = root.to_bytes();
+    let mut header = vec![0xa2, 0x65];
+    header.extend_from_slice(b"roots");
+    header.extend_from_slice(&[0x81, 0xd8, 0x2a]);
+    write_cbor_bytes_len(&mut header, cid.len() as u64 + 1);
+    header.push(0x00);
+    header.extend_from_slice(&cid);
+    header.push(0x67);
+    header.extend_from_slice(b"version");
+    header.push(0x01);
+
+    let mut out = vec![];
+    write_varint(&mut out, header.len() as u64);
+    out.extend_from_slice(&header);
+    out
+}
+
+/// Size of the pragma, CARv2 header and CARv1 header of a file with a root of `root_len` bytes.
+pub fn header_len(root_len: usize) -> usize {
+    // The CID, its multibase prefix and the byte string head
+    let cid = root_len + 1;
+    let cid_head = match cid {
+        0..=23 => 1,
+        24..=0xff => 2,
+        _ => 3,
+    };
+    // Map, "roots" key, array and tag heads, then "version" key and value
+    let v1_header = 1 + 6 + 1 + 2 + cid_head + cid + 8 + 1;
+    DATA_OFFSET as usize + varint_len(v1_header as u64) + v1_header
+}
+
+/// Writes the CBOR major type 2 (byte string) head for `len` bytes.
+fn write_cbor_bytes_len(out: &mut Vec<u8>, len: u64) {
+    match len {
+        0..=23 => out.push(0x40 | len as u8),
+        24..=0xff => out.extend_from_slice(&[0x58, len as u8]),
+        _ => {
+            out.push(0x59);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+    }
+}
+
+/// Encodes the pragma and the CARv2 header.
+pub fn encode_v2_header(data_size: u64, index_offset: u64) -> Vec<u8> {
+    let mut header = PRAGMA.to_vec();
+    // Characteristics, currently unused
+    header.extend_from_slice(&[0; 16]);
+    header.extend_from_slice(&DATA_OFFSET.to_le_bytes());
+    header.extend_from_slice(&data_size.to_le_bytes());
+    header.extend_from_slice(&index_offset.to_le_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests;
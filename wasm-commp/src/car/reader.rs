@@ -0,0 +1,963 @@
+//! Reading and validating CARv1 and CARv2 files.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::{self, Cursor, Read},
+};
+
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use wasm_bindgen::prelude::*;
+
+use super::{index::decode_index, CARV2_HEADER_SIZE, DATA_OFFSET, PRAGMA};
+use crate::ipld::{decode_varint, read_varint, varint_len, Block, SHA2_256};
+
+/// Multihash code of the identity hash, whose digest is the data itself.
+pub const IDENTITY: u64 = 0x00;
+/// Largest header or section accepted, matching go-car's default.
+pub const MAX_SECTION_SIZE: u64 = 32 << 20;
+/// Largest gap accepted between the CARv2 header and the CARv1 payload.
+pub const MAX_DATA_PADDING: u64 = MAX_SECTION_SIZE;
+
+#[derive(Debug, Error)]
+pub enum CarError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("unexpected end of file")]
+    Truncated,
+    #[error("invalid header: {0}")]
+    InvalidHeader(String),
+    #[error("unsupported CAR version {0}")]
+    UnsupportedVersion(u64),
+    #[error("invalid CARv2 header: {0}")]
+    InvalidV2Header(String),
+    #[error("invalid block section: {0}")]
+    InvalidSection(String),
+    #[error("block {0} doesn't match its CID")]
+    HashMismatch(Cid),
+    #[error("unsupported multihash code {0:#x}")]
+    UnsupportedHash(u64),
+    #[error("unsupported index codec {0:#x}")]
+    UnsupportedIndex(u64),
+    #[error("invalid index: {0}")]
+    InvalidIndex(String),
+    #[error("index entry for digest {digest} points at a section without that block")]
+    IndexMismatch { digest: String },
+    #[error("block {0} is missing from the index")]
+    NotIndexed(Cid),
+}
+
+/// Turns an unexpected end of file into [`CarError::Truncated`].
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), CarError> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => CarError::Truncated,
+        _ => e.into(),
+    })
+}
+
+/// The fields of a CARv2 header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarV2Header {
+    pub characteristics: [u8; 16],
+    /// Offset of the CARv1 payload.
+    pub data_offset: u64,
+    /// Size of the CARv1 payload.
+    pub data_size: u64,
+    /// Offset of the index, 0 when there is none.
+    pub index_offset: u64,
+}
+
+impl CarV2Header {
+    pub fn decode(bytes: &[u8; CARV2_HEADER_SIZE]) -> Self {
+        let u64_at = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap());
+        Self {
+            characteristics: bytes[..16].try_into().unwrap(),
+            data_offset: u64_at(16),
+            data_size: u64_at(24),
+            index_offset: u64_at(32),
+        }
+    }
+
+    /// Checks that the payload follows the header, within [`MAX_DATA_PADDING`] bytes, and
+    /// that the index follows the payload.
+    pub(crate) fn check(&self) -> Result<(), CarError> {
+        if self.data_offset < DATA_OFFSET {
+            return Err(CarError::InvalidV2Header(format!(
+                "data offset {} overlaps the header",
+                self.data_offset
+            )));
+        }
+        if self.data_offset - DATA_OFFSET > MAX_DATA_PADDING {
+            return Err(CarError::InvalidV2Header(format!(
+                "data offset {} is more than {MAX_DATA_PADDING} bytes past the header",
+                self.data_offset
+            )));
+        }
+        let data_end = self
+            .data_offset
+            .checked_add(self.data_size)
+            .ok_or_else(|| {
+                CarError::InvalidV2Header(format!(
+                    "data size {} overflows the file offsets",
+                    self.data_size
+                ))
+            })?;
+        if self.index_offset != 0 && self.index_offset < data_end {
+            return Err(CarError::InvalidV2Header(format!(
+                "index offset {} overlaps the data",
+                self.index_offset
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The headers of a CAR file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarHeader {
+    pub roots: Vec<Cid>,
+    /// The CARv2 header, `None` for CARv1 files.
+    pub v2: Option<CarV2Header>,
+}
+
+impl CarHeader {
+    pub fn version(&self) -> u8 {
+        if self.v2.is_some() {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+/// The subset of CBOR used by CAR headers.
+#[derive(Debug, PartialEq)]
+enum Cbor {
+    Uint(u64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Cbor>),
+    Map(Vec<(Cbor, Cbor)>),
+    Tag(u64, Box<Cbor>),
+}
+
+fn decode_cbor(bytes: &mut &[u8], depth: usize) -> Result<Cbor, String> {
+    if depth > 8 {
+        return Err("nested too deeply".into());
+    }
+    let (&head, rest) = bytes.split_first().ok_or("truncated")?;
+    *bytes = rest;
+
+    let argument = match head & 0x1f {
+        info @ 0..=23 => u64::from(info),
+        info @ 24..=27 => {
+            let len = 1 << (info - 24);
+            if bytes.len() < len {
+                return Err("truncated".into());
+            }
+            let (value, rest) = bytes.split_at(len);
+            *bytes = rest;
+            value.iter().fold(0, |acc, &b| acc << 8 | u64::from(b))
+        }
+        _ => return Err("indefinite lengths are not allowed".into()),
+    };
+    let mut take = |len: u64| -> Result<Vec<u8>, String> {
+        let len = usize::try_from(len).map_err(|_| "too long")?;
+        if bytes.len() < len {
+            return Err("truncated".into());
+        }
+        let (value, rest) = bytes.split_at(len);
+        *bytes = rest;
+        Ok(value.to_vec())
+    };
+
+    Ok(match head >> 5 {
+        0 => Cbor::Uint(argument),
+        2 => Cbor::Bytes(take(argument)?),
+        3 => Cbor::Text(String::from_utf8(take(argument)?).map_err(|_| "invalid UTF-8")?),
+        4 => Cbor::Array(
+            (0..argument)
+                .map(|_| decode_cbor(bytes, depth + 1))
+                .collect::<Result<_, _>>()?,
+        ),
+        5 => Cbor::Map(
+            (0..argument)
+                .map(|_| {
+                    Ok((
+                        decode_cbor(bytes, depth + 1)?,
+                        decode_cbor(bytes, depth + 1)?,
+                    ))
+                })
+                .collect::<Result<_, String>>()?,
+        ),
+        6 => Cbor::Tag(argument, Box::new(decode_cbor(bytes, depth + 1)?)),
+        major => return Err(format!("unexpected CBOR major type {major}")),
+    })
+}
+
+/// Decodes a `{ "roots": [...], "version": n }` header, returning the version and roots.
+fn decode_header(mut bytes: &[u8]) -> Result<(u64, Vec<Cid>), CarError> {
+    let invalid = CarError::InvalidHeader;
+    let Cbor::Map(entries) = decode_cbor(&mut bytes, 0).map_err(invalid)? else {
+        return Err(invalid("expected a map".into()));
+    };
+    if !bytes.is_empty() {
+        return Err(invalid("trailing bytes".into()));
+    }
+
+    let mut version = None;
+    let mut roots = None;
+    for (key, value) in entries {
+        match (key, value) {
+            (Cbor::Text(key), Cbor::Uint(value)) if key == "version" => version = Some(value),
+            (Cbor::Text(key), Cbor::Array(values)) if key == "roots" => {
+                let cids = values
+                    .into_iter()
+                    .map(|value| match value {
+                        // Tag 42 byte strings prefixed by the identity multibase
+                        Cbor::Tag(42, bytes) => match *bytes {
+                            Cbor::Bytes(bytes) if bytes.first() == Some(&0) => {
+                                Cid::try_from(&bytes[1..]).map_err(|e| invalid(e.to_string()))
+                            }
+                            _ => Err(invalid("invalid root CID".into())),
+                        },
+                        _ => Err(invalid("roots must be CIDs".into())),
+                    })
+                    .collect::<Result<_, _>>()?;
+                roots = Some(cids);
+            }
+            (Cbor::Text(key), _) => return Err(invalid(format!("unexpected field {key}"))),
+            _ => return Err(invalid("keys must be strings".into())),
+        }
+    }
+
+    let version = version.ok_or_else(|| invalid("missing version".into()))?;
+    Ok((version, roots.unwrap_or_default()))
+}
+
+/// Checks the length of a header or section.
+fn check_frame_len(len: u64) -> Result<(), CarError> {
+    if len == 0 {
+        return Err(CarError::InvalidSection("empty section".into()));
+    }
+    if len > MAX_SECTION_SIZE {
+        return Err(CarError::InvalidSection(format!(
+            "{len} bytes exceed the {MAX_SECTION_SIZE} bytes limit"
+        )));
+    }
+    Ok(())
+}
+
+/// The length prefixed header or section `bytes` start with, along with the number of bytes
+/// it takes, `None` until it's held in full.
+fn frame_prefix(bytes: &[u8]) -> Result<Option<(&[u8], usize)>, CarError> {
+    let Some((len, read)) = decode_varint(bytes) else {
+        if bytes.len() >= 10 {
+            return Err(CarError::InvalidSection("invalid section length".into()));
+        }
+        return Ok(None);
+    };
+    check_frame_len(len)?;
+    let end = read + len as usize;
+    Ok(bytes.get(read..end).map(|frame| (frame, end)))
+}
+
+/// Reads a length prefixed header or section.
+fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, CarError> {
+    let Some(len) = read_varint(reader).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => CarError::Truncated,
+        _ => e.into(),
+    })?
+    else {
+        return Ok(None);
+    };
+    check_frame_len(len)?;
+    let mut frame = vec![0; len as usize];
+    read_exact(reader, &mut frame)?;
+    Ok(Some(frame))
+}
+
+/// Reads the blocks of a CAR file one by one.
+pub struct CarReader<R> {
+    reader: R,
+    header: CarHeader,
+    /// Offset of the next section, from the start of the CARv1 payload.
+    offset: u64,
+    /// CARv1 payload bytes left to read, for CARv2 files.
+    remaining: Option<u64>,
+}
+
+impl<R> CarReader<R> {
+    /// A reader of the sections following the CARv1 header `frame`, the header of the
+    /// payload of `v2` for CARv2 files.
+    fn from_header(reader: R, frame: &[u8], v2: Option<CarV2Header>) -> Result<Self, CarError> {
+        let (version, roots) = decode_header(frame)?;
+        if version != 1 {
+            return Err(CarError::UnsupportedVersion(version));
+        }
+        let offset = (varint_len(frame.len() as u64) + frame.len()) as u64;
+        let remaining = match &v2 {
+            Some(v2) => Some(v2.data_size.checked_sub(offset).ok_or_else(|| {
+                CarError::InvalidV2Header("data size is smaller than the header".into())
+            })?),
+            None => None,
+        };
+        Ok(Self {
+            reader,
+            header: CarHeader { roots, v2 },
+            offset,
+            remaining,
+        })
+    }
+}
+
+impl<R: Read> CarReader<R> {
+    /// Reads the headers of a CARv1 or CARv2 file.
+    pub fn new(mut reader: R) -> Result<Self, CarError> {
+        let first = read_frame(&mut reader)?.ok_or(CarError::Truncated)?;
+        match decode_header(&first)?.0 {
+            1 => Self::from_header(reader, &first, None),
+            2 => {
+                if first != PRAGMA[1..] {
+                    return Err(CarError::InvalidHeader("invalid CARv2 pragma".into()));
+                }
+                let mut bytes = [0; CARV2_HEADER_SIZE];
+                read_exact(&mut reader, &mut bytes)?;
+                let v2 = CarV2Header::decode(&bytes);
+                v2.check()?;
+                io::copy(
+                    &mut (&mut reader).take(v2.data_offset - DATA_OFFSET),
+                    &mut io::sink(),
+                )?;
+
+                let inner = read_frame(&mut reader)?.ok_or(CarError::Truncated)?;
+                Self::from_header(reader, &inner, Some(v2))
+            }
+            version => Err(CarError::UnsupportedVersion(version)),
+        }
+    }
+
+    pub fn header(&self) -> &CarHeader {
+        &self.header
+    }
+
+    /// Offset of the next section, from the start of the CARv1 payload.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Reads the next block along with the offset of its section, from the start of the
+    /// CARv1 payload. The block isn't verified against its CID, see [`verify_block`].
+    pub fn next_block(&mut self) -> Result<Option<(u64, Block)>, CarError> {
+        if self.remaining == Some(0) {
+            return Ok(None);
+        }
+        let Some(section) = read_frame(&mut self.reader)? else {
+            return match self.remaining {
+                Some(_) => Err(CarError::Truncated),
+                None => Ok(None),
+            };
+        };
+
+        let len = (varint_len(section.len() as u64) + section.len()) as u64;
+        if let Some(remaining) = &mut self.remaining {
+            *remaining = remaining
+                .checked_sub(len)
+                .ok_or_else(|| CarError::InvalidSection("section overruns the data size".into()))?;
+        }
+
+        let mut cursor = Cursor::new(&section);
+        let cid = Cid::read_bytes(&mut cursor)
+            .map_err(|e| CarError::InvalidSection(format!("invalid CID: {e}")))?;
+        let data = section[cursor.position() as usize..].to_vec();
+
+        let offset = self.offset;
+        self.offset += len;
+        Ok(Some((offset, Block { cid, data })))
+    }
+}
+
+/// Where a [`CarDecoder`] is in the headers of the file.
+#[derive(Debug, Default)]
+enum Head {
+    /// Receiving the CARv1 header, or the pragma of CARv2 files.
+    #[default]
+    First,
+    /// Receiving the CARv2 header.
+    V2,
+    /// Skipping the bytes between the CARv2 header and the payload, `left` of them.
+    Padding { v2: CarV2Header, left: u64 },
+    /// Receiving the CARv1 header of the payload.
+    Inner(CarV2Header),
+}
+
+/// Decodes a CAR file pushed chunk by chunk, as it is received.
+///
+/// Only the header being received and then the section being received are buffered, each
+/// header being decoded once held in full. The padding before the payload and the index of
+/// CARv2 files are skipped.
+#[derive(Default)]
+pub struct CarDecoder {
+    /// The bytes of the headers received and not decoded yet.
+    head: Vec<u8>,
+    state: Head,
+    reader: Option<CarReader<VecDeque<u8>>>,
+}
+
+impl CarDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The headers, once received.
+    pub fn header(&self) -> Option<&CarHeader> {
+        self.reader.as_ref().map(CarReader::header)
+    }
+
+    /// Number of bytes pushed and not decoded yet, those of the header or of the section
+    /// being received.
+    pub fn buffered(&self) -> u64 {
+        let sections = self.reader.as_ref().map_or(0, |reader| reader.reader.len());
+        (self.head.len() + sections) as u64
+    }
+
+    /// Appends received bytes.
+    pub fn push(&mut self, mut data: &[u8]) {
+        match &mut self.reader {
+            // The index isn't needed, and may be large
+            Some(reader) if reader.remaining == Some(0) => {}
+            Some(reader) => reader.reader.extend(data),
+            None => {
+                if let Head::Padding { left, .. } = &mut self.state {
+                    let skipped = data.len().min(usize::try_from(*left).unwrap_or(usize::MAX));
+                    *left -= skipped as u64;
+                    data = &data[skipped..];
+                }
+                self.head.extend_from_slice(data);
+            }
+        }
+    }
+
+    /// Decodes the headers held by `head`, returning `false` until they're complete.
+    fn decode_head(&mut self) -> Result<bool, CarError> {
+        loop {
+            match &mut self.state {
+                Head::First => {
+                    let Some((frame, len)) = frame_prefix(&self.head)? else {
+                        return Ok(false);
+                    };
+                    match decode_header(frame)?.0 {
+                        1 => {
+                            let reader = CarReader::from_header(VecDeque::new(), frame, None)?;
+                            self.start(reader, len);
+                            return Ok(true);
+                        }
+                        2 if frame == &PRAGMA[1..] => {}
+                        2 => return Err(CarError::InvalidHeader("invalid CARv2 pragma".into())),
+                        version => return Err(CarError::UnsupportedVersion(version)),
+                    }
+                    self.head.drain(..len);
+                    self.state = Head::V2;
+                }
+                Head::V2 => {
+                    let Some(bytes) = self.head.get(..CARV2_HEADER_SIZE) else {
+                        return Ok(false);
+                    };
+                    let v2 = CarV2Header::decode(bytes.try_into().unwrap());
+                    v2.check()?;
+                    self.head.drain(..CARV2_HEADER_SIZE);
+                    let left = v2.data_offset - DATA_OFFSET;
+                    let skipped = self.head.len().min(left as usize);
+                    self.head.drain(..skipped);
+                    self.state = Head::Padding {
+                        v2,
+                        left: left - skipped as u64,
+                    };
+                }
+                Head::Padding { left: 0, v2 } => self.state = Head::Inner(v2.clone()),
+                Head::Padding { .. } => return Ok(false),
+                Head::Inner(v2) => {
+                    let Some((frame, len)) = frame_prefix(&self.head)? else {
+                        return Ok(false);
+                    };
+                    let reader = CarReader::from_header(VecDeque::new(), frame, Some(v2.clone()))?;
+                    self.start(reader, len);
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    /// Starts reading the sections, which follow the first `header_len` bytes of `head`.
+    fn start(&mut self, mut reader: CarReader<VecDeque<u8>>, header_len: usize) {
+        let head = std::mem::take(&mut self.head);
+        reader.reader.extend(&head[header_len..]);
+        self.reader = Some(reader);
+    }
+
+    /// Returns the next block once its whole section is received, along with the offset of
+    /// the section from the start of the CARv1 payload. Blocks aren't verified.
+    pub fn next_block(&mut self) -> Result<Option<(u64, Block)>, CarError> {
+        if self.reader.is_none() && !self.decode_head()? {
+            return Ok(None);
+        }
+        let Some(reader) = &mut self.reader else {
+            return Ok(None);
+        };
+        if reader.remaining == Some(0) {
+            reader.reader.clear();
+            return Ok(None);
+        }
+
+        // Partial sections are left alone, the reader would fail on them
+        let buffered = reader.reader.len();
+        let prefix = reader.reader.iter().take(10).copied().collect::<Vec<_>>();
+        match decode_varint(&prefix) {
+            Some((len, read)) if (buffered - read) as u64 >= len => reader.next_block(),
+            Some((len, _)) if len > MAX_SECTION_SIZE => reader.next_block(),
+            None if buffered >= 10 => {
+                Err(CarError::InvalidSection("invalid section length".into()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Checks that the file was received in full, once every block is read.
+    pub fn finish(&self) -> Result<(), CarError> {
+        let Some(reader) = &self.reader else {
+            return Err(CarError::Truncated);
+        };
+        match reader.remaining {
+            Some(0) => Ok(()),
+            None if reader.reader.is_empty() => Ok(()),
+            _ => Err(CarError::Truncated),
+        }
+    }
+}
+
+/// Whether `bytes`, the start of a file, look like a CARv1 or CARv2 file.
+///
+/// Only the first header is decoded, a CARv1 header or the CARv2 pragma, it must be held in
+/// full by `bytes`.
+pub fn is_car(mut bytes: &[u8]) -> bool {
+    match read_frame(&mut bytes) {
+        Ok(Some(frame)) => matches!(decode_header(&frame), Ok((1 | 2, _))),
+        _ => false,
+    }
+}
+
+/// Checks that `block`'s data hashes to its CID.
+pub fn verify_block(block: &Block) -> Result<(), CarError> {
+    verify_data(&block.cid, &block.data)
+}
+
+/// Checks that `data` hashes to `cid`.
+pub fn verify_data(cid: &Cid, data: &[u8]) -> Result<(), CarError> {
+    let hash = cid.hash();
+    let matches = match hash.code() {
+        SHA2_256 => Sha256::digest(data).as_slice() == hash.digest(),
+        IDENTITY => data == hash.digest(),
+        code => return Err(CarError::UnsupportedHash(code)),
+    };
+    if !matches {
+        return Err(CarError::HashMismatch(*cid));
+    }
+    Ok(())
+}
+
+/// An error found in a CAR file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CarIssue {
+    /// Offset in the file where the error was found.
+    pub offset: u64,
+    pub message: String,
+}
+
+/// The result of [`validate_car`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CarReport {
+    pub valid: bool,
+    /// 1 or 2, `None` if the headers couldn't be read.
+    pub version: Option<u8>,
+    pub roots: Vec<String>,
+    pub data_offset: Option<u64>,
+    pub data_size: Option<u64>,
+    pub index_offset: Option<u64>,
+    pub index_codec: Option<u64>,
+    /// Number of blocks read.
+    pub blocks: u64,
+    /// Number of index entries.
+    pub indexed_blocks: u64,
+    pub errors: Vec<CarIssue>,
+}
+
+impl CarReport {
+    fn error(&mut self, offset: u64, error: CarError) {
+        self.errors.push(CarIssue {
+            offset,
+            message: error.to_string(),
+        });
+    }
+}
+
+/// Validates a CAR file, reporting every error found along with its offset.
+///
+/// The headers are checked first, then every block is read and verified against its CID.
+/// For CARv2 files, every index entry must point at a section holding its block, and every
+/// block must be indexed. Reading stops at the first malformed section.
+pub fn validate_car(bytes: &[u8]) -> CarReport {
+    validate(bytes, None)
+}
+
+/// Validates a CAR file against an `.idx` sidecar file, in place of the index of the file.
+///
+/// Errors in the sidecar itself are reported at offset 0.
+pub fn validate_car_with_index(bytes: &[u8], index: &[u8]) -> CarReport {
+    validate(bytes, Some(index))
+}
+
+fn validate(bytes: &[u8], sidecar: Option<&[u8]>) -> CarReport {
+    let mut report = CarReport::default();
+    let mut reader = match CarReader::new(bytes) {
+        Ok(reader) => reader,
+        Err(error) => {
+            report.error(0, error);
+            return report;
+        }
+    };
+
+    let header = reader.header().clone();
+    report.version = Some(header.version());
+    report.roots = header.roots.iter().map(Cid::to_string).collect();
+    let data_offset = header.v2.as_ref().map_or(0, |v2| v2.data_offset);
+    if let Some(v2) = &header.v2 {
+        report.data_offset = Some(v2.data_offset);
+        report.data_size = Some(v2.data_size);
+        report.index_offset = Some(v2.index_offset);
+        let data_end = v2.data_offset.checked_add(v2.data_size);
+        if data_end.is_none_or(|end| end > bytes.len() as u64) {
+            report.error(
+                PRAGMA.len() as u64,
+                CarError::InvalidV2Header("the data runs past the end of the file".into()),
+            );
+        }
+    }
+
+    // The blocks and the offsets of their sections, by digest
+    let mut sections: HashMap<Vec<u8>, Vec<(Cid, u64)>> = HashMap::new();
+    loop {
+        match reader.next_block() {
+            Ok(Some((offset, block))) => {
+                report.blocks += 1;
+                if let Err(error) = verify_block(&block) {
+                    report.error(data_offset + offset, error);
+                }
+                sections
+                    .entry(block.cid.hash().digest().to_vec())
+                    .or_default()
+                    .push((block.cid, offset));
+            }
+            Ok(None) => break,
+            Err(error) => {
+                report.error(data_offset + reader.offset(), error);
+                break;
+            }
+        }
+    }
+
+    let (index_offset, index) = match (sidecar, &header.v2) {
+        (Some(sidecar), _) => (0, sidecar),
+        (None, Some(v2)) if v2.index_offset != 0 => {
+            let Some(index) = bytes.get(v2.index_offset as usize..) else {
+                report.error(v2.index_offset, CarError::Truncated);
+                return report;
+            };
+            (v2.index_offset, index)
+        }
+        _ => {
+            report.valid = report.errors.is_empty();
+            return report;
+        }
+    };
+    match decode_index(index) {
+        Ok((codec, entries)) => {
+            report.index_codec = Some(codec.code());
+            report.indexed_blocks = entries.len() as u64;
+
+            let mut indexed = HashSet::new();
+            for entry in entries {
+                let found = sections.get(&entry.digest).and_then(|blocks| {
+                    blocks
+                        .iter()
+                        .find(|(cid, offset)| *offset == entry.offset && entry.matches(cid))
+                });
+                match found {
+                    Some((cid, _)) => {
+                        indexed.insert(*cid);
+                    }
+                    None => {
+                        let digest = hex::encode(&entry.digest);
+                        let offset = data_offset + entry.offset;
+                        report.error(offset, CarError::IndexMismatch { digest });
+                    }
+                }
+            }
+
+            let mut missing = sections
+                .values()
+                .flatten()
+                .filter(|(cid, _)| indexed.insert(*cid))
+                .map(|(cid, offset)| (*offset, *cid))
+                .collect::<Vec<_>>();
+            missing.sort();
+            for (offset, cid) in missing {
+                report.error(data_offset + offset, CarError::NotIndexed(cid));
+            }
+        }
+        Err(error) => report.error(index_offset, error),
+    }
+
+    report.valid = report.errors.is_empty();
+    report
+}
+
+/// Validates a CARv1 or CARv2 file.
+///
+/// Checks the pragma and headers, verifies every block against its CID and checks that
+/// the index entries point at the blocks they claim.
+///
+/// # Arguments
+/// * `bytes` - The CAR file.
+///
+/// # Returns
+/// `{ valid, version, roots, dataOffset, dataSize, indexOffset, indexCodec, blocks,
+/// indexedBlocks, errors: [{ offset, message }] }`.
+#[wasm_bindgen(js_name = "validateCar")]
+pub fn validate_car_js(bytes: &[u8]) -> Result<JsValue, JsValue> {
+    Ok(serde_wasm_bindgen::to_value(&validate_car(bytes))?)
+}
+
+/// Validates a CAR file against an `.idx` sidecar file.
+///
+/// # Arguments
+/// * `bytes` - The CAR file, its own index, if any, is ignored.
+/// * `index` - The sidecar file contents.
+///
+/// # Returns
+/// The same report as `validateCar`.
+#[wasm_bindgen(js_name = "validateCarWithIndex")]
+pub fn validate_car_with_index_js(bytes: &[u8], index: &[u8]) -> Result<JsValue, JsValue> {
+    Ok(serde_wasm_bindgen::to_value(&validate_car_with_index(
+        bytes, index,
+    ))?)
+}
+
+/// Tells whether a file is a CAR file from its first bytes, 64 KiB being enough for headers
+/// holding a few hundred roots.
+#[wasm_bindgen(js_name = "isCar")]
+pub fn is_car_js(bytes: &[u8]) -> bool {
+    is_car(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        car::{builder::generate_car_v2, encode_v2_header},
+        testing::root_first_car,
+    };
+
+    fn car(len: usize) -> (Cid, Vec<u8>) {
+        generate_car_v2(&(0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn reads_blocks() {
+        let (root, car) = car(3 * 256 * 1024);
+        let mut reader = CarReader::new(&car[..]).unwrap();
+        assert_eq!(reader.header().roots, [root]);
+        assert_eq!(reader.header().version(), 2);
+
+        let mut blocks = vec![];
+        while let Some((_, block)) = reader.next_block().unwrap() {
+            verify_block(&block).unwrap();
+            blocks.push(block.cid);
+        }
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks.last(), Some(&root));
+    }
+
+    #[test]
+    fn valid_files() {
+        let (root, car) = car(1000);
+        let report = validate_car(&car);
+        assert!(report.valid, "{report:?}");
+        assert_eq!(report.version, Some(2));
+        assert_eq!(report.roots, [root.to_string()]);
+        assert_eq!(report.index_codec, Some(0x0401));
+        assert_eq!((report.blocks, report.indexed_blocks), (1, 1));
+
+        let report = validate_car(include_bytes!("fixtures/hello.car"));
+        assert!(report.valid, "{report:?}");
+
+        let (_, v1) = root_first_car(b"hello");
+        let report = validate_car(&v1);
+        assert!(report.valid, "{report:?}");
+        assert_eq!(report.version, Some(1));
+        assert_eq!(report.data_offset, None);
+    }
+
+    #[test]
+    fn reports_corrupted_blocks() {
+        let (_, mut car) = car(600 * 1024);
+        // The first section starts right after the CARv1 header
+        let first_section = CarReader::new(&car[..]).unwrap().offset() + DATA_OFFSET;
+        car[first_section as usize + 100] ^= 1;
+
+        let report = validate_car(&car);
+        assert!(!report.valid);
+        assert_eq!(report.blocks, 4);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].offset, first_section);
+        assert!(report.errors[0].message.contains("doesn't match"));
+    }
+
+    #[test]
+    fn reports_wrong_index_offsets() {
+        let (_, mut car) = car(600 * 1024);
+        // The last 8 bytes are the offset of the last entry
+        let len = car.len();
+        car[len - 8..].copy_from_slice(&12345u64.to_le_bytes());
+
+        let report = validate_car(&car);
+        assert_eq!(report.errors.len(), 2, "{report:?}");
+        assert_eq!(report.errors[0].offset, DATA_OFFSET + 12345);
+        assert!(report.errors[1].message.contains("missing from the index"));
+    }
+
+    #[test]
+    fn decodes_pushed_chunks() {
+        let (_, car) = car(600 * 1024);
+        let mut expected = vec![];
+        let mut reader = CarReader::new(&car[..]).unwrap();
+        while let Some(block) = reader.next_block().unwrap() {
+            expected.push(block);
+        }
+
+        for chunk_size in [1, 7, 1000, 100_000] {
+            let mut decoder = CarDecoder::new();
+            let mut blocks = vec![];
+            for chunk in car.chunks(chunk_size) {
+                decoder.push(chunk);
+                while let Some(block) = decoder.next_block().unwrap() {
+                    blocks.push(block);
+                }
+            }
+            assert_eq!(blocks, expected);
+            decoder.finish().unwrap();
+        }
+
+        let mut decoder = CarDecoder::new();
+        decoder.push(&car[..car.len() / 2]);
+        while decoder.next_block().unwrap().is_some() {}
+        assert!(decoder.finish().is_err());
+    }
+
+    #[test]
+    fn skips_padding_before_the_payload() {
+        let (_, car) = car(600 * 1024);
+        let v2 = CarReader::new(&car[..])
+            .unwrap()
+            .header()
+            .v2
+            .clone()
+            .unwrap();
+        let padded = |padding: u64| {
+            let mut padded = car[..PRAGMA.len() + 16].to_vec();
+            padded.extend_from_slice(&(DATA_OFFSET + padding).to_le_bytes());
+            padded.extend_from_slice(&v2.data_size.to_le_bytes());
+            padded.extend_from_slice(&(v2.index_offset + padding).to_le_bytes());
+            padded.resize(padded.len() + padding as usize, 0);
+            padded.extend_from_slice(&car[DATA_OFFSET as usize..]);
+            padded
+        };
+
+        let mut expected = vec![];
+        let mut reader = CarReader::new(&car[..]).unwrap();
+        while let Some(block) = reader.next_block().unwrap() {
+            expected.push(block);
+        }
+        let car = padded(1000);
+        for chunk_size in [1, 7, 1000, 100_000] {
+            let mut decoder = CarDecoder::new();
+            let mut blocks = vec![];
+            for chunk in car.chunks(chunk_size) {
+                decoder.push(chunk);
+                while let Some(block) = decoder.next_block().unwrap() {
+                    blocks.push(block);
+                }
+            }
+            assert_eq!(blocks, expected);
+            decoder.finish().unwrap();
+        }
+
+        // Only the headers are needed to reject larger gaps
+        let mut car = car;
+        car[PRAGMA.len() + 16..PRAGMA.len() + 24]
+            .copy_from_slice(&(DATA_OFFSET + MAX_DATA_PADDING + 1).to_le_bytes());
+        let mut decoder = CarDecoder::new();
+        decoder.push(&car[..DATA_OFFSET as usize]);
+        assert!(matches!(
+            decoder.next_block(),
+            Err(CarError::InvalidV2Header(_))
+        ));
+    }
+
+    #[test]
+    fn reports_malformed_files() {
+        let (_, car) = car(1000);
+
+        let report = validate_car(&car[..car.len() - 600]);
+        assert!(!report.valid);
+        assert!(report
+            .errors
+            .iter()
+            .any(|issue| issue.message == "unexpected end of file"));
+
+        let report = validate_car(b"not a car file");
+        assert_eq!(report.version, None);
+        assert_eq!(report.errors[0].offset, 0);
+
+        let mut bad_pragma = car.clone();
+        bad_pragma[1] = 0xa2;
+        assert!(!validate_car(&bad_pragma).valid);
+
+        // A data size overflowing the offsets
+        let mut overflowing = car.clone();
+        overflowing[PRAGMA.len()..DATA_OFFSET as usize]
+            .copy_from_slice(&encode_v2_header(u64::MAX, 0)[PRAGMA.len()..]);
+        let report = validate_car(&overflowing);
+        assert!(!report.valid);
+        assert!(report.errors[0].message.contains("overflows"), "{report:?}");
+        assert!(matches!(
+            CarReader::new(&overflowing[..]),
+            Err(CarError::InvalidV2Header(_))
+        ));
+    }
+
+    #[test]
+    fn detects_car_files() {
+        let (_, v2) = car(1000);
+        let (_, v1) = root_first_car(&[1; 1000]);
+        assert!(is_car(&v2[..PRAGMA.len()]));
+        assert!(is_car(&v1[..64]));
+        // The whole header is needed
+        assert!(!is_car(&v1[..20]));
+        assert!(!is_car(b"not a car file"));
+        assert!(!is_car(&[0xa2; 16]));
+        assert!(!is_car(&[]));
+    }
+}
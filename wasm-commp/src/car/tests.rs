@@ -0,0 +1,356 @@
+//! Golden tests for the CARv2 encoder.
+//!
+//! The values pin this encoder's own output, they don't prove it matches `generateCar` of
+//! `src/lib/car/v2.ts`; only the root CIDs of the single leaf files are known to be what
+//! `ipfs add --raw-leaves` gives. `pnpm car-fixtures` writes what the TypeScript builder
+//! returns for the same inputs to `fixtures/ts/`, those fixtures still have to be generated and
+//! asserted on before the two builders can be called byte-compatible.
+//!
+//! The small files are stored in full under `fixtures/`, the larger ones are pinned by their
+//! root CID and the SHA2-256 of the whole CARv2 file.
+use sha2::{Digest, Sha256};
+
+use super::{
+    builder::{generate_car_v2, generate_car_v2_with_options, FileInfo},
+    header_len,
+    reader::{validate_car, CarReader},
+    CarV2Builder, CarV2Output,
+};
+use crate::{
+    ipld::{decode_varint, Block, DAG_PB},
+    unixfs::{
+        pb::{decode_node, UnixFsData, UnixTime, DATA_TYPE_FILE},
+        Chunker, DagOptions, Layout, CHUNK_SIZE, MAX_LINKS,
+    },
+};
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+fn assert_golden(data: &[u8], root_cid: &str, car_len: usize, car_sha256: &str) {
+    let (root, car) = generate_car_v2(data).unwrap();
+    assert_eq!(root.to_string(), root_cid);
+    assert_eq!(car.len(), car_len);
+    assert_eq!(hex::encode(Sha256::digest(&car)), car_sha256);
+}
+
+#[test]
+fn empty_file() {
+    let (root, car) = generate_car_v2(&[]).unwrap();
+    assert_eq!(
+        root.to_string(),
+        "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+    );
+    assert_eq!(car, include_bytes!("fixtures/empty.car"));
+}
+
+#[test]
+fn single_small_leaf() {
+    let (root, car) = generate_car_v2(b"hello world\n").unwrap();
+    assert_eq!(
+        root.to_string(),
+        "bafkreifjjcie6lypi6ny7amxnfftagclbuxndqonfipmb64f2km2devei4"
+    );
+    assert_eq!(car, include_bytes!("fixtures/hello.car"));
+}
+
+#[test]
+fn single_full_leaf() {
+    assert_golden(
+        &pattern(CHUNK_SIZE),
+        "bafkreibruh455iawsviqslif5c7uurdcfdemh22mtnytyzvnzn75kpejxy",
+        262363,
+        "efa77c1d23b625ec736579335879d146ea86db4153092d7e499c92c334418d37",
+    );
+}
+
+#[test]
+fn two_leaves() {
+    assert_golden(
+        &pattern(CHUNK_SIZE + 1),
+        "bafybeiexg2oqkfnj56l7fcmawswqbijt5shq4b5rg6a546uwpkqqzwjioi",
+        262623,
+        "dc7fba9d4e8ef46df9ab06afafb0f8e6609b4f903ac5da191877aa0a7ca7ba95",
+    );
+}
+
+#[test]
+fn ten_leaves() {
+    assert_golden(
+        &pattern(10 * CHUNK_SIZE - 7),
+        "bafybeieoi3s5f3o4bvbbuu7cmvm5rj34opddh2xng7zmocqogrf7pp7mku",
+        2622950,
+        "b9f5ef5c38281e15ab7b932e275cec7f32dbe05d5954c927319e6abf70bbe334",
+    );
+}
+
+#[test]
+fn deduplicated_leaves_over_two_levels() {
+    // 174 identical leaves and a 1 byte one, the zero leaf and its stem are written once
+    assert_golden(
+        &vec![0; MAX_LINKS * CHUNK_SIZE + 1],
+        "bafybeihqwzd3o6q6v3pmwhzjy22vokhr767burokmqemg63hptx2nqd7ym",
+        271543,
+        "ecaa86192406330e40c65a9d7fbf26e073ae73517a402a4477d642985d84827e",
+    );
+}
+
+#[test]
+fn streamed_output_matches() {
+    let data = pattern(3 * CHUNK_SIZE + 1000);
+    let (root, car) = generate_car_v2(&data).unwrap();
+
+    let mut builder = CarV2Builder::new(vec![]);
+    let mut body = vec![];
+    for chunk in data.chunks(100_000) {
+        builder.write(chunk).unwrap();
+        // Complete leaves are written out right away
+        body.append(builder.get_mut());
+    }
+    let output = builder.finish().unwrap();
+    body.extend_from_slice(&output.out);
+
+    assert_eq!(output.root, root);
+    assert_eq!(output.header.len(), header_len(root.encoded_len()));
+    assert_eq!(
+        [output.header, body, output.index].concat(),
+        car,
+        "streamed file differs"
+    );
+}
+
+#[test]
+fn default_options_match() {
+    let data = pattern(2 * CHUNK_SIZE + 5);
+    assert_eq!(
+        generate_car_v2_with_options(&data, DagOptions::default()).unwrap(),
+        generate_car_v2(&data).unwrap()
+    );
+}
+
+#[test]
+fn cid_v0_root() {
+    // `ipfs add` of "hello world\n"
+    let options = DagOptions {
+        raw_leaves: false,
+        cid_version: 0,
+        ..Default::default()
+    };
+    let mut builder = CarV2Builder::with_options(vec![], options).unwrap();
+    builder.write(b"hello world\n").unwrap();
+    let header_len = builder.header_len();
+    let output = builder.finish().unwrap();
+
+    assert_eq!(
+        output.root.to_string(),
+        "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o"
+    );
+    assert_eq!(output.header.len(), header_len);
+}
+
+#[test]
+fn custom_dag_shape() {
+    // 10 leaves of 4 bytes, 3 links per stem: 4 stems -> 2 stems -> 1 root
+    let options = DagOptions {
+        chunk_size: 4,
+        max_links: 3,
+        raw_leaves: false,
+        ..Default::default()
+    };
+    let mut builder = CarV2Builder::with_options(vec![], options).unwrap();
+    builder.write(&pattern(40)).unwrap();
+    let output = builder.finish().unwrap();
+    assert_eq!(output.root.codec(), DAG_PB);
+
+    let mut sections = 0;
+    let mut rest = &output.out[..];
+    while let Some((len, read)) = decode_varint(rest) {
+        rest = &rest[read + len as usize..];
+        sections += 1;
+    }
+    assert!(rest.is_empty());
+    assert_eq!(sections, 10 + 7);
+}
+
+#[test]
+fn trickle_layout() {
+    // `ipfs add --trickle` of an empty file, with and without `--cid-version=1`, the only roots
+    // pinned to boxo's output, larger DAGs being compared with a port of boxo in `trickle.rs`
+    let trickle = DagOptions {
+        layout: Layout::Trickle,
+        ..Default::default()
+    };
+    let (root, _) = generate_car_v2_with_options(&[], trickle).unwrap();
+    assert_eq!(
+        root.to_string(),
+        "bafybeif7ztnhq65lumvvtr4ekcwd2ifwgm3awq4zfr3srh462rwyinlb4y"
+    );
+    let v0 = DagOptions {
+        raw_leaves: false,
+        cid_version: 0,
+        ..trickle
+    };
+    let (root, _) = generate_car_v2_with_options(&[], v0).unwrap();
+    assert_eq!(
+        root.to_string(),
+        "QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH"
+    );
+
+    // Even a single leaf sits below a root node
+    let (root, _) = generate_car_v2_with_options(b"hello world\n", trickle).unwrap();
+    assert_eq!(root.codec(), DAG_PB);
+
+    // Both layouts only differ once the root can't link to every leaf
+    let small = DagOptions {
+        chunk_size: 1024,
+        max_links: 3,
+        ..trickle
+    };
+    let balanced = DagOptions {
+        layout: Layout::Balanced,
+        ..small
+    };
+    let data = pattern(3 * 1024);
+    assert_eq!(
+        generate_car_v2_with_options(&data, small).unwrap().0,
+        generate_car_v2_with_options(&data, balanced).unwrap().0
+    );
+    let data = pattern(4 * 1024);
+    assert_ne!(
+        generate_car_v2_with_options(&data, small).unwrap().0,
+        generate_car_v2_with_options(&data, balanced).unwrap().0
+    );
+}
+
+#[test]
+fn content_defined_chunks_are_streamed() {
+    let data = pattern(3 * CHUNK_SIZE + 1000);
+    for chunker in [
+        Chunker::FastCdc {
+            min_size: 4096,
+            avg_size: 16384,
+            max_size: 65536,
+        },
+        Chunker::LocalRabin {
+            min_size: 4096,
+            avg_size: 16384,
+            max_size: 65536,
+        },
+    ] {
+        let options = DagOptions {
+            chunker,
+            ..Default::default()
+        };
+        let (root, car) = generate_car_v2_with_options(&data, options).unwrap();
+
+        let mut builder = CarV2Builder::with_options(vec![], options).unwrap();
+        for chunk in data.chunks(10_007) {
+            builder.write(chunk).unwrap();
+        }
+        let output = builder.finish().unwrap();
+        assert_eq!(output.root, root);
+        assert_eq!([output.header, output.out, output.index].concat(), car);
+        assert!(output.blocks.len() > data.len() / 65536);
+    }
+}
+
+#[test]
+fn rejects_invalid_options() {
+    let options = DagOptions {
+        cid_version: 0,
+        ..Default::default()
+    };
+    assert!(CarV2Builder::with_options(vec![], options).is_err());
+    assert!(generate_car_v2_with_options(b"", options).is_err());
+}
+
+fn generate_with_info(data: &[u8], info: FileInfo) -> (CarV2Output<Vec<u8>>, Vec<u8>) {
+    let mut builder = CarV2Builder::with_info(vec![], DagOptions::default(), info).unwrap();
+    builder.write(data).unwrap();
+    let output = builder.finish().unwrap();
+    let car = [&output.header[..], &output.out, &output.index].concat();
+    let report = validate_car(&car);
+    assert!(report.valid, "{report:?}");
+    (output, car)
+}
+
+/// The last block of a CAR file, its root.
+fn last_block(car: &[u8]) -> Block {
+    let mut reader = CarReader::new(car).unwrap();
+    let mut last = None;
+    while let Some((_, block)) = reader.next_block().unwrap() {
+        last = Some(block);
+    }
+    last.unwrap()
+}
+
+#[test]
+fn wraps_named_files() {
+    let data = pattern(CHUNK_SIZE + 1);
+    let (file_root, _) = generate_car_v2(&data).unwrap();
+    let info = FileInfo {
+        name: Some("photo.jpg".to_string()),
+        ..Default::default()
+    };
+    let (output, car) = generate_with_info(&data, info);
+    assert_eq!(output.header.len(), header_len(36));
+    assert_eq!(output.blocks.len(), 4);
+
+    let directory = last_block(&car);
+    assert_eq!(directory.cid, output.root);
+    let node = decode_node(&directory.data).unwrap();
+    assert_eq!(node.links.len(), 1);
+    assert_eq!(node.links[0].name, "photo.jpg");
+    assert_eq!(node.links[0].cid, file_root);
+
+    for name in ["", "a/b", ".."] {
+        let info = FileInfo {
+            name: Some(name.to_string()),
+            ..Default::default()
+        };
+        assert!(CarV2Builder::with_info(vec![], DagOptions::default(), info).is_err());
+    }
+}
+
+#[test]
+fn keeps_unixfs_metadata() {
+    let mtime = UnixTime {
+        seconds: 1_700_000_000,
+        nanos: 500,
+    };
+    let info = FileInfo {
+        mode: Some(0o644),
+        mtime: Some(mtime),
+        ..Default::default()
+    };
+
+    // A single raw leaf becomes a DAG-PB node, the raw leaf isn't stored
+    let (output, car) = generate_with_info(b"hello", info.clone());
+    assert_eq!(output.blocks.len(), 1);
+    assert_eq!(output.root.codec(), DAG_PB);
+    let expected = UnixFsData {
+        data_type: DATA_TYPE_FILE,
+        data: b"hello".to_vec(),
+        filesize: Some(5),
+        mode: Some(0o644),
+        mtime: Some(mtime),
+        ..Default::default()
+    };
+    let node = decode_node(&last_block(&car).data).unwrap();
+    assert!(node.links.is_empty());
+    assert_eq!(node.data, Some(expected.encode()));
+
+    // Only the root of larger files changes
+    let data = pattern(3 * CHUNK_SIZE);
+    let (plain, _) = generate_with_info(&data, FileInfo::default());
+    let (output, car) = generate_with_info(&data, info);
+    assert_ne!(output.root, plain.root);
+    assert_eq!(output.blocks[..3], plain.blocks[..3]);
+    assert_eq!(output.blocks.len(), 4);
+    let node = decode_node(&last_block(&car).data).unwrap();
+    let mut expected = UnixFsData::file(vec![CHUNK_SIZE as u64; 3]);
+    expected.mode = Some(0o644);
+    expected.mtime = Some(mtime);
+    assert_eq!(node.data, Some(expected.encode()));
+}
@@ -0,0 +1,465 @@
+//! Block level verification of a CAR file while it is being downloaded.
+//!
+//! Each block is checked against its CID as soon as its section is received, and its links
+//! are followed to check that every block belongs to the DAG of the expected root. Blocks
+//! are only reachable once a block linking to them is. When the root comes first, as in
+//! `ipfs dag export` files, every later block must be linked from one received before it, so
+//! a stray block is rejected as it arrives. When the root comes last, as in the files of
+//! `src/lib/car`, blocks wait for a block linking to them and stray blocks are rejected once
+//! the DAG is complete, at most [`MAX_PENDING_BLOCKS`] blocks waiting at once. Files of
+//! several roots hold the DAGs of all of them.
+//!
+//! Only DAG-PB and raw blocks are understood. Once a block of another codec is received, its
+//! links are unknown, so the blocks are only checked against their CIDs from then on.
+use std::collections::{HashMap, HashSet};
+
+use cid::Cid;
+use serde::Serialize;
+use thiserror::Error;
+use wasm_bindgen::prelude::*;
+
+use super::{
+    reader::{verify_block, CarDecoder, IDENTITY},
+    CarError,
+};
+use crate::{
+    ipld::{Block, DAG_PB, RAW},
+    unixfs::pb::decode_node,
+};
+
+/// Maximum number of blocks received before any block linking to them, four times the leaves
+/// of a 64 GiB piece of 256 KiB leaves.
+pub const MAX_PENDING_BLOCKS: usize = 1 << 20;
+
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error(transparent)]
+    Car(#[from] CarError),
+    #[error("invalid root CID {0}")]
+    InvalidRoot(String),
+    #[error("expected the root {expected}, the CAR file has {actual:?}")]
+    UnexpectedRoots {
+        expected: String,
+        actual: Vec<String>,
+    },
+    #[error("block {cid} at offset {offset} doesn't match its CID")]
+    HashMismatch { cid: Cid, offset: u64 },
+    #[error("invalid block at offset {offset}: {reason}")]
+    InvalidBlock { offset: u64, reason: String },
+    #[error("block {cid} at offset {offset} isn't reachable from the root")]
+    Unreachable { cid: Cid, offset: u64 },
+    #[error("block {0} is missing")]
+    MissingBlock(Cid),
+    #[error("more than {MAX_PENDING_BLOCKS} blocks received before any block linking to them")]
+    TooManyPending,
+}
+
+/// The outcome of a successful verification.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifiedCar {
+    /// The expected root, or the first root of the file.
+    pub root: String,
+    /// Whether the blocks were only checked against their CIDs, as the DAG holds blocks of
+    /// codecs whose links are unknown.
+    pub blocks_only: bool,
+    /// Number of block sections, duplicates included.
+    pub blocks: u64,
+}
+
+/// Verifies the blocks of a CAR file as it is received.
+pub struct CarVerifier {
+    decoder: CarDecoder,
+    expected: Option<Cid>,
+    /// The roots, once the headers are received.
+    roots: Vec<Cid>,
+    /// Whether a block of a codec whose links are unknown was received.
+    blocks_only: bool,
+    /// Blocks reachable from the root and received.
+    reached: HashSet<Cid>,
+    /// Blocks reachable from the root and not received yet.
+    missing: HashSet<Cid>,
+    /// Blocks received before any block linking to them, with their offsets and links.
+    pending: HashMap<Cid, (u64, Vec<Cid>)>,
+    blocks: u64,
+}
+
+impl CarVerifier {
+    /// Creates a verifier of a CAR file whose DAG is rooted at `expected`, or at the roots of
+    /// the file if `None`.
+    pub fn new(expected: Option<Cid>) -> Self {
+        Self {
+            decoder: CarDecoder::new(),
+            expected,
+            roots: vec![],
+            blocks_only: false,
+            reached: HashSet::new(),
+            missing: HashSet::new(),
+            pending: HashMap::new(),
+            blocks: 0,
+        }
+    }
+
+    /// Whether every root was received.
+    fn has_roots(&self) -> bool {
+        !self.roots.is_empty() && self.roots.iter().all(|root| self.reached.contains(root))
+    }
+
+    /// Whether every block of the DAG was received.
+    fn is_complete(&self) -> bool {
+        self.has_roots() && self.missing.is_empty()
+    }
+
+    /// Whether the roots were reached with no block waiting, the file then being written root
+    /// first and every block following a block linking to it.
+    fn is_root_first(&self) -> bool {
+        self.has_roots() && self.pending.is_empty()
+    }
+
+    /// Appends received bytes, checking the blocks completed by them.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), VerifyError> {
+        self.decoder.push(data);
+        while let Some((offset, block)) = self.decoder.next_block()? {
+            if self.roots.is_empty() {
+                self.roots = self.check_roots()?;
+            }
+            let header = self.decoder.header().expect("blocks follow the headers");
+            let data_offset = header.v2.as_ref().map_or(0, |v2| v2.data_offset);
+            self.check_block(data_offset + offset, block)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the expected root is one of the file's, returning the roots of the DAG.
+    fn check_roots(&self) -> Result<Vec<Cid>, VerifyError> {
+        let roots = &self.decoder.header().expect("headers are received").roots;
+        match (self.expected, &roots[..]) {
+            (Some(expected), roots) if roots.contains(&expected) => Ok(vec![expected]),
+            (None, roots) if !roots.is_empty() => Ok(roots.to_vec()),
+            (expected, roots) => Err(VerifyError::UnexpectedRoots {
+                expected: expected.map_or("a root".to_string(), |cid| cid.to_string()),
+                actual: roots.iter().map(Cid::to_string).collect(),
+            }),
+        }
+    }
+
+    /// The CIDs `block` links to, identity CIDs aside, `None` for codecs whose links are
+    /// unknown.
+    fn links(block: &Block, offset: u64) -> Result<Option<Vec<Cid>>, VerifyError> {
+        let links = match block.cid.codec() {
+            RAW => vec![],
+            DAG_PB => decode_node(&block.data)
+                .map_err(|e| VerifyError::InvalidBlock {
+                    offset,
+                    reason: format!("{}: {e}", block.cid),
+                })?
+                .links
+                .into_iter()
+                .map(|link| link.cid)
+                .collect(),
+            _ => return Ok(None),
+        };
+        Ok(Some(
+            links
+                .into_iter()
+                .filter(|cid| cid.hash().code() != IDENTITY)
+                .collect(),
+        ))
+    }
+
+    fn check_block(&mut self, offset: u64, block: Block) -> Result<(), VerifyError> {
+        self.blocks += 1;
+        verify_block(&block).map_err(|e| match e {
+            CarError::HashMismatch(cid) => VerifyError::HashMismatch { cid, offset },
+            e => VerifyError::InvalidBlock {
+                offset,
+                reason: format!("{}: {e}", block.cid),
+            },
+        })?;
+        // Blocks may be written more than once
+        if self.blocks_only
+            || self.reached.contains(&block.cid)
+            || self.pending.contains_key(&block.cid)
+        {
+            return Ok(());
+        }
+        if self.is_complete() || (self.is_root_first() && !self.missing.contains(&block.cid)) {
+            return Err(VerifyError::Unreachable {
+                cid: block.cid,
+                offset,
+            });
+        }
+
+        let Some(links) = Self::links(&block, offset)? else {
+            self.blocks_only = true;
+            self.pending.clear();
+            self.missing.clear();
+            return Ok(());
+        };
+        if self.missing.remove(&block.cid) || self.roots.contains(&block.cid) {
+            self.reach(block.cid, links);
+        } else if self.pending.len() < MAX_PENDING_BLOCKS {
+            self.pending.insert(block.cid, (offset, links));
+        } else {
+            return Err(VerifyError::TooManyPending);
+        }
+
+        if self.is_complete() {
+            if let Some((&cid, &(offset, _))) =
+                self.pending.iter().min_by_key(|(_, (offset, _))| *offset)
+            {
+                return Err(VerifyError::Unreachable { cid, offset });
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks `cid` as reached along with the pending blocks it links to, directly or not.
+    fn reach(&mut self, cid: Cid, links: Vec<Cid>) {
+        self.reached.insert(cid);
+        let mut stack = vec![links];
+        while let Some(links) = stack.pop() {
+            for link in links {
+                if self.reached.contains(&link) {
+                    continue;
+                }
+                match self.pending.remove(&link) {
+                    Some((_, links)) => {
+                        self.reached.insert(link);
+                        stack.push(links);
+                    }
+                    None => {
+                        self.missing.insert(link);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks that the file was received in full and that the DAG is complete.
+    pub fn finish(self) -> Result<VerifiedCar, VerifyError> {
+        self.decoder.finish()?;
+        let root = *self.roots.first().ok_or(CarError::Truncated)?;
+        if !self.blocks_only {
+            if let Some(&root) = self.roots.iter().find(|root| !self.reached.contains(root)) {
+                return Err(VerifyError::MissingBlock(root));
+            }
+            if let Some(&cid) = self.missing.iter().next() {
+                return Err(VerifyError::MissingBlock(cid));
+            }
+        }
+        Ok(VerifiedCar {
+            root: root.to_string(),
+            blocks_only: self.blocks_only,
+            blocks: self.blocks,
+        })
+    }
+}
+
+/// Verifies a CAR file block by block while it's being downloaded, so a bad download can be
+/// aborted at its first bad block.
+///
+/// ```js
+/// const verifier = new CarStreamVerifier(undefined);
+/// for await (const data of response.body) verifier.write(data); // throws on a bad block
+/// const { root, blocks } = verifier.finish();
+/// ```
+#[wasm_bindgen]
+pub struct CarStreamVerifier {
+    verifier: CarVerifier,
+}
+
+#[wasm_bindgen]
+impl CarStreamVerifier {
+    /// Creates a verifier of a CAR file rooted at `root`, or at its roots when
+    /// `undefined`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(root: Option<String>) -> Result<CarStreamVerifier, JsValue> {
+        let expected = root
+            .map(|root| Cid::try_from(root.as_str()).map_err(|_| VerifyError::InvalidRoot(root)))
+            .transpose()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(Self {
+            verifier: CarVerifier::new(expected),
+        })
+    }
+
+    /// Appends received bytes, throwing at the first bad block, with its offset in the file.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        self.verifier
+            .write(data)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Checks that the whole DAG was received, returning `{ root, blocksOnly, blocks }`.
+    pub fn finish(self) -> Result<JsValue, JsValue> {
+        let verified = self
+            .verifier
+            .finish()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(serde_wasm_bindgen::to_value(&verified)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        car::{builder::generate_car_v2, reader::CarReader, DATA_OFFSET},
+        testing::root_first_car,
+    };
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn verify(car: &[u8], expected: Option<Cid>) -> Result<VerifiedCar, VerifyError> {
+        let mut verifier = CarVerifier::new(expected);
+        for chunk in car.chunks(1000) {
+            verifier.write(chunk)?;
+        }
+        verifier.finish()
+    }
+
+    /// Appends a section holding `block` to a CARv1 file.
+    fn append_block(car: &mut Vec<u8>, block: &Block) {
+        let cid = block.cid.to_bytes();
+        crate::ipld::write_varint(car, (cid.len() + block.data.len()) as u64);
+        car.extend_from_slice(&cid);
+        car.extend_from_slice(&block.data);
+    }
+
+    #[test]
+    fn verifies_blocks() {
+        let (root, car) = generate_car_v2(&data(600 * 1024)).unwrap();
+        let verified = verify(&car, Some(root)).unwrap();
+        assert_eq!(verified.root, root.to_string());
+        assert_eq!(verified.blocks, 4);
+        assert!(verify(&car, None).is_ok());
+
+        // Repeated leaves are written once but linked to more than once
+        let (_, car) = generate_car_v2(&vec![0; 3 << 20]).unwrap();
+        assert!(verify(&car, None).is_ok());
+
+        let (root, v1) = root_first_car(&data(600 * 1024));
+        assert_eq!(verify(&v1, Some(root)).unwrap().blocks, 4);
+
+        let (other, _) = generate_car_v2(&data(10)).unwrap();
+        assert!(matches!(
+            verify(&v1, Some(other)),
+            Err(VerifyError::UnexpectedRoots { .. })
+        ));
+    }
+
+    /// A CARv1 file of `roots` holding `blocks`.
+    fn car_of(roots: &[Cid], blocks: &[&Block]) -> Vec<u8> {
+        let mut header = vec![0xa2, 0x65];
+        header.extend_from_slice(b"roots");
+        header.push(0x80 + roots.len() as u8);
+        for root in roots {
+            let cid = root.to_bytes();
+            header.extend_from_slice(&[0xd8, 0x2a, 0x58, cid.len() as u8 + 1, 0x00]);
+            header.extend_from_slice(&cid);
+        }
+        header.push(0x67);
+        header.extend_from_slice(b"version");
+        header.push(0x01);
+
+        let mut car = vec![];
+        crate::ipld::write_varint(&mut car, header.len() as u64);
+        car.extend_from_slice(&header);
+        for block in blocks {
+            append_block(&mut car, block);
+        }
+        car
+    }
+
+    #[test]
+    fn verifies_every_root() {
+        let a = Block::new(RAW, b"a".to_vec());
+        let b = Block::new(RAW, b"b".to_vec());
+        let verified = verify(&car_of(&[a.cid, b.cid], &[&a, &b]), None).unwrap();
+        assert_eq!(verified.root, a.cid.to_string());
+        assert_eq!(verified.blocks, 2);
+        assert!(verify(&car_of(&[a.cid, b.cid], &[&a]), Some(a.cid)).is_ok());
+        assert!(matches!(
+            verify(&car_of(&[a.cid, b.cid], &[&a]), None),
+            Err(VerifyError::MissingBlock(cid)) if cid == b.cid
+        ));
+
+        // The links of DAG-CBOR blocks aren't known, the blocks are only checked on their own
+        let node = Block::new(0x71, vec![0xa1, 0x61, b'x', 0x01]);
+        let verified = verify(&car_of(&[node.cid], &[&node, &a]), None).unwrap();
+        assert!(verified.blocks_only);
+        let mut corrupted = car_of(&[node.cid], &[&node, &a]);
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            verify(&corrupted, None),
+            Err(VerifyError::HashMismatch { cid, .. }) if cid == a.cid
+        ));
+    }
+
+    #[test]
+    fn stops_at_bad_blocks() {
+        let (_, car) = generate_car_v2(&data(600 * 1024)).unwrap();
+        let mut reader = CarReader::new(&car[..]).unwrap();
+        reader.next_block().unwrap();
+        let (second, _) = reader.next_block().unwrap().unwrap();
+        let second_end = DATA_OFFSET + reader.offset();
+
+        // The error is raised by the chunk completing the second block
+        let mut corrupted = car.clone();
+        let at = (DATA_OFFSET + second) as usize + 100;
+        corrupted[at] ^= 1;
+        let mut verifier = CarVerifier::new(None);
+        let mut received = 0;
+        let error = corrupted
+            .chunks(1000)
+            .find_map(|chunk| {
+                received += chunk.len();
+                verifier.write(chunk).err()
+            })
+            .unwrap();
+        assert!(matches!(
+            error,
+            VerifyError::HashMismatch { offset, .. } if offset == DATA_OFFSET + second
+        ));
+        assert!((received as u64) < second_end + 1000);
+
+        assert!(matches!(
+            verify(&car[..car.len() / 2], None),
+            Err(VerifyError::Car(CarError::Truncated))
+        ));
+    }
+
+    #[test]
+    fn rejects_unreachable_blocks() {
+        let stray = Block::new(RAW, b"stray".to_vec());
+
+        // Root first, the stray block is rejected as soon as it's received, before the DAG is
+        // complete
+        let (root, v1) = root_first_car(&data(600 * 1024));
+        let mut reader = CarReader::new(&v1[..]).unwrap();
+        reader.next_block().unwrap();
+        let offset = reader.offset();
+        let mut strayed = v1[..offset as usize].to_vec();
+        append_block(&mut strayed, &stray);
+        let mut verifier = CarVerifier::new(Some(root));
+        assert!(matches!(
+            verifier.write(&strayed),
+            Err(VerifyError::Unreachable { cid, offset: at }) if cid == stray.cid && at == offset
+        ));
+
+        // Root last, once the root completes the DAG
+        let mut writer = crate::car::CarV2Writer::new(vec![]);
+        let leaf = Block::new(RAW, b"leaf".to_vec());
+        writer.write_block(&stray).unwrap();
+        writer.write_block(&leaf).unwrap();
+        let output = writer.finish(leaf.cid).unwrap();
+        let car = [output.header, output.out, output.index].concat();
+        assert!(matches!(
+            verify(&car, None),
+            Err(VerifyError::Unreachable { cid, .. }) if cid == stray.cid
+        ));
+    }
+}
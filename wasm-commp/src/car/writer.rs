@@ -0,0 +1,115 @@
+//! Streaming CARv2 writer.
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+};
+
+use cid::Cid;
+
+use super::{
+    encode_v1_header, encode_v2_header,
+    index::{encode_index, IndexCodec, IndexEntry},
+    DATA_OFFSET,
+};
+use crate::{
+    dedup::BlockRef,
+    ipld::{write_varint, Block},
+};
+
+/// Writes the block sections of a CARv1 payload as they come.
+///
+/// The root, and as such the CARv1 header, is usually only known once every block is written,
+/// the headers and the index are returned by [`CarV2Writer::finish`] and the complete file is
+/// `header`, followed by everything written to the output, followed by `index`.
+///
+/// Only the index entries are kept in memory, 40 bytes per block.
+pub struct CarV2Writer<W: Write> {
+    out: W,
+    /// Offset of the next section, from the first section.
+    offset: u64,
+    seen: HashSet<Cid>,
+    /// Entries with offsets from the first section, shifted by the CARv1 header on finish.
+    index: Vec<IndexEntry>,
+    blocks: Vec<BlockRef>,
+}
+
+/// The parts of a CARv2 file completed by [`CarV2Writer::finish`].
+pub struct CarV2Output<W> {
+    pub root: Cid,
+    /// The pragma, CARv2 header and CARv1 header.
+    pub header: Vec<u8>,
+    /// The output the block sections were written to.
+    pub out: W,
+    /// The encoded index.
+    pub index: Vec<u8>,
+    /// The blocks written, in order.
+    pub blocks: Vec<BlockRef>,
+}
+
+impl<W: Write> CarV2Writer<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            offset: 0,
+            seen: HashSet::new(),
+            index: vec![],
+            blocks: vec![],
+        }
+    }
+
+    /// The output the block sections are written to.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.out
+    }
+
+    /// Number of section bytes written so far.
+    pub fn written(&self) -> u64 {
+        self.offset
+    }
+
+    /// Writes the section of `block`, unless a block with the same CID was already written.
+    ///
+    /// Returns whether the block was written.
+    pub fn write_block(&mut self, block: &Block) -> io::Result<bool> {
+        if !self.seen.insert(block.cid) {
+            return Ok(false);
+        }
+
+        let mut section = vec![];
+        write_varint(
+            &mut section,
+            (block.cid.encoded_len() + block.data.len()) as u64,
+        );
+        section.extend_from_slice(&block.cid.to_bytes());
+        self.out.write_all(&section)?;
+        self.out.write_all(&block.data)?;
+
+        self.index.push(IndexEntry::new(&block.cid, self.offset));
+        self.blocks
+            .push(BlockRef::new(&block.cid, block.data.len() as u64));
+        self.offset += (section.len() + block.data.len()) as u64;
+        Ok(true)
+    }
+
+    /// Completes the file with `root` as its only root.
+    pub fn finish(mut self, root: Cid) -> io::Result<CarV2Output<W>> {
+        self.out.flush()?;
+
+        let v1_header = encode_v1_header(&root);
+        for entry in &mut self.index {
+            entry.offset += v1_header.len() as u64;
+        }
+        let data_size = v1_header.len() as u64 + self.offset;
+
+        let mut header = encode_v2_header(data_size, DATA_OFFSET + data_size);
+        header.extend_from_slice(&v1_header);
+
+        Ok(CarV2Output {
+            root,
+            header,
+            out: self.out,
+            index: encode_index(IndexCodec::MultihashIndexSorted, &self.index),
+            blocks: self.blocks,
+        })
+    }
+}
@@ -0,0 +1,165 @@
+//! Commitments over groups of pieces.
+//!
+//! A sector's data commitment (CommD) is the root of the Merkle tree over the whole unsealed
+//! sector, it can be computed from the commitments of the pieces it holds by filling the
+//! gaps between them with zero pieces, whose commitments are known in advance.
+use cid::{multihash::Multihash, Cid};
+use primitives::{commitment::piece::PaddedPieceSize, NODE_SIZE};
+use rs_merkle::Hasher;
+use thiserror::Error;
+
+use crate::hasher::Sha256;
+
+/// Multicodec of an unsealed commitment CID (`fil-commitment-unsealed`).
+const FIL_COMMITMENT_UNSEALED: u64 = 0xf101;
+/// Multihash of a commitment CID (`sha2-256-trunc254-padded`).
+const SHA2_256_TRUNC254_PADDED: u64 = 0x1012;
+
+#[derive(Debug, Error)]
+pub enum CommitmentError {
+    #[error("pieces take {required} bytes, which does not fit in a sector of {sector_size} bytes")]
+    SectorOverflow { required: u64, sector_size: u64 },
+    #[error("sector size {0} is not a power of two")]
+    InvalidSectorSize(u64),
+}
+
+/// Hashes two sibling nodes into their parent.
+pub fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut buffer = [0; 2 * NODE_SIZE];
+    buffer[..NODE_SIZE].copy_from_slice(left);
+    buffer[NODE_SIZE..].copy_from_slice(right);
+    Sha256::hash(&buffer)
+}
+
+/// The commitment of a piece of `size` padded bytes holding only zeros.
+pub fn zero_commitment(size: u64) -> [u8; 32] {
+    let mut commitment = [0; 32];
+    let mut level_size = NODE_SIZE as u64;
+    while level_size < size {
+        commitment = hash_pair(&commitment, &commitment);
+        level_size <<= 1;
+    }
+    commitment
+}
+
+/// Returns the sizes of the zero pieces needed to move from `offset` to the next multiple
+/// of `alignment`, both being padded byte offsets and `alignment` a power of two.
+///
+/// Each zero piece is itself aligned to its size, as every piece in a sector must be.
+pub fn padding_pieces(mut offset: u64, alignment: u64) -> Vec<u64> {
+    let mut sizes = vec![];
+    while offset % alignment != 0 {
+        let size = 1 << offset.trailing_zeros();
+        sizes.push(size);
+        offset += size;
+    }
+    sizes
+}
+
+/// Computes the data commitment (CommD) of a sector holding `pieces`, in order.
+///
+/// Every piece is placed at the next offset aligned to its size, the gaps and the end of the
+/// sector are filled with zero pieces.
+pub fn compute_comm_d(
+    sector_size: u64,
+    pieces: &[(PaddedPieceSize, [u8; 32])],
+) -> Result<[u8; 32], CommitmentError> {
+    if !sector_size.is_power_of_two() || sector_size < NODE_SIZE as u64 {
+        return Err(CommitmentError::InvalidSectorSize(sector_size));
+    }
+
+    // The stack always holds subtrees of decreasing size, equal neighbours are merged
+    let mut stack = vec![];
+    let mut offset = 0;
+
+    for (size, commitment) in pieces {
+        let size = **size;
+        for padding in padding_pieces(offset, size) {
+            push(&mut stack, padding, zero_commitment(padding));
+            offset += padding;
+        }
+        push(&mut stack, size, *commitment);
+        offset += size;
+    }
+
+    if offset > sector_size {
+        return Err(CommitmentError::SectorOverflow {
+            required: offset,
+            sector_size,
+        });
+    }
+    for padding in padding_pieces(offset, sector_size) {
+        push(&mut stack, padding, zero_commitment(padding));
+    }
+
+    Ok(match stack.as_slice() {
+        [(_, root)] => *root,
+        // No pieces at all
+        _ => zero_commitment(sector_size),
+    })
+}
+
+/// Pushes a subtree on the stack, merging it with its left siblings.
+fn push(stack: &mut Vec<(u64, [u8; 32])>, size: u64, commitment: [u8; 32]) {
+    stack.push((size, commitment));
+    while let [.., (left_size, left), (right_size, right)] = stack.as_slice() {
+        if left_size != right_size {
+            break;
+        }
+        let parent = (left_size * 2, hash_pair(left, right));
+        stack.truncate(stack.len() - 2);
+        stack.push(parent);
+    }
+}
+
+/// Formats a data commitment (CommD) as a CID.
+pub fn comm_d_cid(comm_d: [u8; 32]) -> Cid {
+    let multihash = Multihash::<64>::wrap(SHA2_256_TRUNC254_PADDED, &comm_d)
+        .expect("32 bytes always fit in a multihash");
+    Cid::new_v1(FIL_COMMITMENT_UNSEALED, multihash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CommD of an empty 2KiB sector, from rust-fil-proofs `filecoin-proofs/tests/pieces.rs`.
+    const EMPTY_2KIB_COMM_D: [u8; 32] = [
+        252, 126, 146, 130, 150, 229, 22, 250, 173, 233, 134, 178, 143, 146, 212, 74, 79, 36, 185,
+        53, 72, 82, 35, 55, 106, 121, 144, 39, 188, 24, 248, 51,
+    ];
+
+    #[test]
+    fn zero_commitment_matches_reference() {
+        assert_eq!(zero_commitment(32), [0; 32]);
+        assert_eq!(zero_commitment(2048), EMPTY_2KIB_COMM_D);
+        assert_eq!(compute_comm_d(2048, &[]).unwrap(), EMPTY_2KIB_COMM_D);
+    }
+
+    #[test]
+    fn padding_aligns_offsets() {
+        assert_eq!(padding_pieces(0, 512), Vec::<u64>::new());
+        assert_eq!(padding_pieces(128, 512), vec![128, 256]);
+        assert_eq!(padding_pieces(384, 2048), vec![128, 512, 1024]);
+    }
+
+    #[test]
+    fn comm_d_of_zero_pieces_is_zero_commitment() {
+        let piece = |size| (PaddedPieceSize::new(size).unwrap(), zero_commitment(size));
+        let pieces = [piece(128), piece(512), piece(256)];
+        assert_eq!(compute_comm_d(2048, &pieces).unwrap(), EMPTY_2KIB_COMM_D);
+    }
+
+    #[test]
+    fn comm_d_rejects_overflow() {
+        let piece = |size| (PaddedPieceSize::new(size).unwrap(), zero_commitment(size));
+        assert!(matches!(
+            compute_comm_d(2048, &[piece(128), piece(2048)]),
+            Err(CommitmentError::SectorOverflow { required: 4096, .. })
+        ));
+        assert!(matches!(
+            compute_comm_d(3000, &[]),
+            Err(CommitmentError::InvalidSectorSize(3000))
+        ));
+    }
+}
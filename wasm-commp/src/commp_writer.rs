@@ -0,0 +1,277 @@
+//! Incremental piece commitment calculation.
+//!
+//! [`calculate_piece_commitment`](crate::calculate_piece_commitment) needs the piece size
+//! upfront and keeps every leaf in memory. When the data is produced as a stream, such as a
+//! CAR file being generated, its size is only known at the end. [`CommPWriter`] instead
+//! keeps one pending subtree per tree level and pads the tree with zero subtrees once the
+//! data ends.
+use std::io::{self, Cursor, Read, Write};
+
+use primitives::{
+    commitment::{piece::PaddedPieceSize, CommP, Commitment},
+    NODE_SIZE,
+};
+
+use crate::{
+    commitment::{hash_pair, padding_pieces, zero_commitment},
+    fr32_reader::Fr32Reader,
+    zero_reader::ZeroPaddingReader,
+};
+
+/// Unpadded bytes in an Fr32 block.
+const BLOCK_SIZE: usize = 127;
+/// Padded bytes in an Fr32 block.
+const PADDED_BLOCK_SIZE: u64 = 128;
+/// Number of blocks padded at once.
+const BLOCKS_PER_BATCH: usize = 1024;
+
+/// Calculates the piece commitment of the bytes written to it.
+///
+/// The first bytes of the data can be deferred, i.e. written as placeholders and provided
+/// on [`CommPWriter::finish`]. This allows writing a file whose header depends on its
+/// contents, such as a CAR file's root, in a single pass.
+pub struct CommPWriter {
+    /// Bytes not yet making up a whole block.
+    pending: Vec<u8>,
+    /// Number of unpadded bytes written, deferred ones included.
+    written: u64,
+    /// Number of padded bytes committed to the stack.
+    committed: u64,
+    /// Subtree roots of decreasing size, `None` stands for the subtree holding the deferred
+    /// bytes, which is always the leftmost one.
+    stack: Vec<(u64, Option<[u8; 32]>)>,
+    /// Number of deferred bytes at the start of the data.
+    deferred: usize,
+    /// The first block, with its deferred bytes zeroed, once it has been committed.
+    first_block: Option<Vec<u8>>,
+    /// Right siblings of the deferred subtree, from the bottom up.
+    spine: Vec<[u8; 32]>,
+}
+
+impl Default for CommPWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommPWriter {
+    pub fn new() -> Self {
+        Self::with_deferred_prefix(0)
+    }
+
+    /// Creates a writer whose first `len` bytes, at most a block, are provided on finish.
+    pub fn with_deferred_prefix(len: usize) -> Self {
+        assert!(len <= BLOCK_SIZE, "at most a block can be deferred");
+        Self {
+            pending: vec![0; len],
+            written: len as u64,
+            committed: 0,
+            stack: vec![],
+            deferred: len,
+            first_block: None,
+            spine: vec![],
+        }
+    }
+
+    /// Number of unpadded bytes written so far, deferred ones included.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Commits every whole block of `pending`.
+    fn commit_blocks(&mut self) -> io::Result<()> {
+        let blocks = self.pending.len() / BLOCK_SIZE;
+        if blocks == 0 {
+            return Ok(());
+        }
+
+        let mut start = 0;
+        for batch in (0..blocks).step_by(BLOCKS_PER_BATCH) {
+            let count = BLOCKS_PER_BATCH.min(blocks - batch);
+            let end = start + count * BLOCK_SIZE;
+
+            let mut padded = Vec::with_capacity(count * PADDED_BLOCK_SIZE as usize);
+            Fr32Reader::new(Cursor::new(&self.pending[start..end])).read_to_end(&mut padded)?;
+            for block in padded.chunks(PADDED_BLOCK_SIZE as usize) {
+                if self.deferred > 0 && self.committed == 0 {
+                    self.first_block = Some(self.pending[start..start + BLOCK_SIZE].to_vec());
+                    self.push(PADDED_BLOCK_SIZE, None);
+                } else {
+                    self.push(PADDED_BLOCK_SIZE, Some(block_root(block)));
+                }
+            }
+            start = end;
+        }
+
+        self.pending.drain(..start);
+        Ok(())
+    }
+
+    /// Pushes a subtree, merging it with its left siblings of the same size.
+    fn push(&mut self, size: u64, root: Option<[u8; 32]>) {
+        self.committed += size;
+        self.stack.push((size, root));
+        while let [.., (left_size, left), (right_size, right)] = self.stack[..] {
+            if left_size != right_size {
+                break;
+            }
+            let right = right.expect("only the leftmost subtree is deferred");
+            let parent = match left {
+                Some(left) => Some(hash_pair(&left, &right)),
+                None => {
+                    self.spine.push(right);
+                    None
+                }
+            };
+            self.stack.truncate(self.stack.len() - 2);
+            self.stack.push((left_size * 2, parent));
+        }
+    }
+
+    /// Completes the data, returning its piece commitment and the smallest piece size
+    /// holding it.
+    ///
+    /// `prefix` are the deferred bytes, it must be as long as announced on creation.
+    pub fn finish(mut self, prefix: &[u8]) -> io::Result<(Commitment<CommP>, PaddedPieceSize)> {
+        if prefix.len() != self.deferred {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "expected {} deferred bytes, got {}",
+                    self.deferred,
+                    prefix.len()
+                ),
+            ));
+        }
+        if self.written == 0 {
+            // The smallest piece, all zeros
+            let piece_size = PaddedPieceSize::new(PADDED_BLOCK_SIZE).expect("a block is a piece");
+            return Ok((Commitment::from(zero_commitment(*piece_size)), piece_size));
+        }
+        if self.first_block.is_none() {
+            // The first block was never committed, the prefix can be written in place
+            self.pending[..prefix.len()].copy_from_slice(prefix);
+            self.deferred = 0;
+        }
+
+        let piece_size = PaddedPieceSize::from_arbitrary_size(self.written);
+
+        // Zero pad the last block
+        self.commit_blocks()?;
+        if !self.pending.is_empty() {
+            let last = std::mem::take(&mut self.pending);
+            let mut block = vec![];
+            ZeroPaddingReader::new(Cursor::new(last), BLOCK_SIZE as u64).read_to_end(&mut block)?;
+            self.pending = block;
+            self.commit_blocks()?;
+        }
+        // Then the rest of the piece with zero subtrees
+        for size in padding_pieces(self.committed, *piece_size) {
+            self.push(size, Some(zero_commitment(size)));
+        }
+
+        let root = match self.stack.as_slice() {
+            [(_, Some(root))] => *root,
+            [(_, None)] => {
+                let mut first_block = self.first_block.take().expect("deferred block is kept");
+                first_block[..prefix.len()].copy_from_slice(prefix);
+
+                let mut padded = vec![];
+                Fr32Reader::new(Cursor::new(first_block)).read_to_end(&mut padded)?;
+                self.spine
+                    .iter()
+                    .fold(block_root(&padded), |node, sibling| {
+                        hash_pair(&node, sibling)
+                    })
+            }
+            _ => unreachable!("the stack is merged up to the piece size"),
+        };
+
+        Ok((Commitment::from(root), piece_size))
+    }
+}
+
+impl Write for CommPWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        self.written += buf.len() as u64;
+        if self.pending.len() >= BLOCK_SIZE * BLOCKS_PER_BATCH {
+            self.commit_blocks()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.commit_blocks()
+    }
+}
+
+/// The root of the 4 leaves of a padded block.
+fn block_root(block: &[u8]) -> [u8; 32] {
+    let leaf = |i: usize| -> [u8; 32] {
+        block[i * NODE_SIZE..(i + 1) * NODE_SIZE]
+            .try_into()
+            .expect("leaves are 32 bytes")
+    };
+    hash_pair(
+        &hash_pair(&leaf(0), &leaf(1)),
+        &hash_pair(&leaf(2), &leaf(3)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::piece_commitment;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 256) as u8).collect()
+    }
+
+    fn expected(data: &[u8]) -> (Commitment<CommP>, PaddedPieceSize) {
+        let piece_size = PaddedPieceSize::from_arbitrary_size(data.len() as u64);
+        (piece_commitment(data, piece_size).unwrap(), piece_size)
+    }
+
+    #[test]
+    fn matches_piece_commitment() {
+        let (commitment, piece_size) = CommPWriter::new().finish(&[]).unwrap();
+        assert_eq!(*piece_size, 128);
+        assert_eq!(commitment.raw(), zero_commitment(128));
+
+        for len in [1, 126, 127, 128, 1000, 2032, 2033, 130_048, 200_000] {
+            let data = data(len);
+            let mut writer = CommPWriter::new();
+            for chunk in data.chunks(1000) {
+                writer.write_all(chunk).unwrap();
+            }
+            let (commitment, piece_size) = writer.finish(&[]).unwrap();
+
+            let (expected, expected_size) = expected(&data);
+            assert_eq!(*piece_size, *expected_size, "{} bytes", len);
+            assert_eq!(commitment.cid(), expected.cid(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn deferred_prefix() {
+        for (len, deferred) in [(50, 50), (100, 40), (127, 110), (500, 110), (300_000, 127)] {
+            let data = data(len);
+            let mut writer = CommPWriter::with_deferred_prefix(deferred);
+            for chunk in data[deferred..].chunks(333) {
+                writer.write_all(chunk).unwrap();
+            }
+            let (commitment, piece_size) = writer.finish(&data[..deferred]).unwrap();
+
+            let (expected, expected_size) = expected(&data);
+            assert_eq!(*piece_size, *expected_size, "{} bytes", len);
+            assert_eq!(commitment.cid(), expected.cid(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_prefix() {
+        let writer = CommPWriter::with_deferred_prefix(10);
+        assert!(writer.finish(&[0; 9]).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+//! Deduplication statistics between uploads.
+//!
+//! Every upload reports the list of blocks of its CAR file, comparing it with the list of a
+//! previous upload tells how many blocks (and bytes) a provider already holding the previous
+//! upload wouldn't need to store again.
+use std::collections::HashSet;
+
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use wasm_bindgen::prelude::*;
+
+#[derive(Debug, Error)]
+pub enum DedupError {
+    #[error("invalid CID {0}: {1}")]
+    InvalidCid(String, cid::Error),
+}
+
+/// A block of an upload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockRef {
+    pub cid: String,
+    /// Size of the block data.
+    pub size: u64,
+}
+
+impl BlockRef {
+    pub fn new(cid: &Cid, size: u64) -> Self {
+        Self {
+            cid: cid.to_string(),
+            size,
+        }
+    }
+}
+
+/// How much of an upload is already stored by a previous one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DedupStats {
+    pub blocks: u64,
+    pub bytes: u64,
+    /// Blocks found in the previous upload.
+    pub shared_blocks: u64,
+    pub shared_bytes: u64,
+    /// Blocks missing from the previous upload.
+    pub new_blocks: u64,
+    pub new_bytes: u64,
+}
+
+fn parse(block: &BlockRef) -> Result<Cid, DedupError> {
+    Cid::try_from(block.cid.as_str()).map_err(|e| DedupError::InvalidCid(block.cid.clone(), e))
+}
+
+impl DedupStats {
+    /// Compares the blocks of an upload with the ones of a previous upload.
+    ///
+    /// Blocks are compared by multihash, as blockstores do, so a block shared under a CIDv0
+    /// and a CIDv1 counts as shared.
+    pub fn new(blocks: &[BlockRef], previous: &[BlockRef]) -> Result<Self, DedupError> {
+        let previous = previous
+            .iter()
+            .map(|block| Ok(*parse(block)?.hash()))
+            .collect::<Result<HashSet<_>, DedupError>>()?;
+
+        let mut stats = Self::default();
+        for block in blocks {
+            stats.blocks += 1;
+            stats.bytes += block.size;
+            if previous.contains(parse(block)?.hash()) {
+                stats.shared_blocks += 1;
+                stats.shared_bytes += block.size;
+            } else {
+                stats.new_blocks += 1;
+                stats.new_bytes += block.size;
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// Compares the block list of an upload with the one of a previous upload.
+///
+/// # Arguments
+/// * `blocks` - The `blocks` of a `PiecePipeline` result, `[{ cid, size }]`.
+/// * `previous` - The block list of the previous upload, in the same format.
+///
+/// # Returns
+/// `{ blocks, bytes, sharedBlocks, sharedBytes, newBlocks, newBytes }`.
+#[wasm_bindgen(js_name = "dedupStats")]
+pub fn dedup_stats_js(blocks: JsValue, previous: JsValue) -> Result<JsValue, JsValue> {
+    let blocks: Vec<BlockRef> = serde_wasm_bindgen::from_value(blocks)?;
+    let previous: Vec<BlockRef> = serde_wasm_bindgen::from_value(previous)?;
+    let stats =
+        DedupStats::new(&blocks, &previous).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    Ok(serde_wasm_bindgen::to_value(&stats)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+    use super::*;
+    use crate::{
+        car::CarV2Builder,
+        ipld::{Block, RAW},
+        unixfs::{Chunker, DagOptions},
+    };
+
+    fn block_list(data: &[u8], chunker: Chunker) -> Vec<BlockRef> {
+        let options = DagOptions {
+            chunker,
+            ..Default::default()
+        };
+        let mut builder = CarV2Builder::with_options(vec![], options).unwrap();
+        builder.write(data).unwrap();
+        builder.finish().unwrap().blocks
+    }
+
+    #[test]
+    fn counts_shared_blocks() {
+        let a = Block::new(RAW, b"a".to_vec());
+        let b = Block::new(RAW, b"bb".to_vec());
+        let blocks = [BlockRef::new(&a.cid, 1), BlockRef::new(&b.cid, 2)];
+        let stats = DedupStats::new(&blocks, &blocks[..1]).unwrap();
+        assert_eq!(
+            stats,
+            DedupStats {
+                blocks: 2,
+                bytes: 3,
+                shared_blocks: 1,
+                shared_bytes: 1,
+                new_blocks: 1,
+                new_bytes: 2,
+            }
+        );
+
+        let invalid = [BlockRef {
+            cid: "not a cid".to_string(),
+            size: 1,
+        }];
+        assert!(DedupStats::new(&blocks, &invalid).is_err());
+    }
+
+    #[test]
+    fn content_defined_chunking_shares_blocks() {
+        let mut data = vec![0; 4 << 20];
+        StdRng::seed_from_u64(1).fill_bytes(&mut data);
+        let mut edited = data.clone();
+        edited.insert(1000, 0);
+
+        let fixed = DedupStats::new(
+            &block_list(&edited, Chunker::Fixed),
+            &block_list(&data, Chunker::Fixed),
+        )
+        .unwrap();
+        assert_eq!(fixed.shared_blocks, 0);
+
+        let fast_cdc = Chunker::FastCdc {
+            min_size: 16 * 1024,
+            avg_size: 64 * 1024,
+            max_size: 256 * 1024,
+        };
+        let stats =
+            DedupStats::new(&block_list(&edited, fast_cdc), &block_list(&data, fast_cdc)).unwrap();
+        assert!(stats.shared_bytes * 10 > stats.bytes * 9, "{stats:?}");
+    }
+}
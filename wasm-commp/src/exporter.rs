@@ -0,0 +1,1195 @@
+//! Reconstruction of the original file from a retrieved CAR file.
+//!
+//! The payload root is either the root of a UnixFS file or a directory holding a single
+//! file, as written when the file name is kept. The file DAG is walked depth first from the
+//! root, through DAG-PB stems and raw or DAG-PB leaves, every block being verified against
+//! its CID, and the file bytes are emitted in order as soon as the blocks they're in arrive.
+//!
+//! A file can also be picked out of a directory by its path, with [`resolve_path`], and a
+//! byte range of it read on its own, with [`extract_range`].
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Range,
+};
+
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    car::{
+        index::IndexedCar,
+        reader::{verify_block, CarDecoder, CarHeader, IDENTITY},
+        CarError,
+    },
+    gateway::Recorder,
+    ipld::{DAG_PB, RAW},
+    unixfs::{
+        directory::{murmur3_x64_64, HAMT_FANOUT, MURMUR3_X64_64},
+        pb::{
+            decode_node, PbNode, UnixFsData, UnixTime, DATA_TYPE_DIRECTORY, DATA_TYPE_FILE,
+            DATA_TYPE_HAMT_SHARD, DATA_TYPE_RAW,
+        },
+    },
+};
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error(transparent)]
+    Car(#[from] CarError),
+    #[error("expected a single root, found {0}")]
+    InvalidRoots(usize),
+    #[error("invalid DAG-PB node {0}: {1}")]
+    InvalidNode(Cid, String),
+    #[error("unsupported codec {0:#x} of block {1}")]
+    UnsupportedCodec(u64, Cid),
+    #[error("block {0} is neither a file nor a directory of a single file, UnixFS type {1}")]
+    NotAFile(Cid, u64),
+    #[error("block {0} is missing")]
+    MissingBlock(Cid),
+    #[error("no such file or directory: {0}")]
+    NotFound(String),
+    #[error("not a directory: {0}")]
+    NotADirectory(String),
+    #[error("invalid range {start}..{end}")]
+    InvalidRange { start: u64, end: u64 },
+    #[error("unsupported HAMT shard {0}, only murmur3 hashes and 256 buckets are")]
+    UnsupportedShard(Cid),
+    #[error("the file should hold {expected} bytes, found {actual}")]
+    SizeMismatch { expected: u64, actual: u64 },
+    #[error(
+        "block {0} is linked again after it was read and dropped, too many blocks were read since"
+    )]
+    DroppedBlock(Cid),
+}
+
+/// Bytes of read blocks [`FileExporter`] keeps in case they're linked again.
+pub const MAX_KEPT_SIZE: usize = 64 << 20;
+
+/// What is known of an exported file besides its contents.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportInfo {
+    /// The file name, for files wrapped in a directory.
+    pub name: Option<String>,
+    /// The CID of the file root.
+    pub cid: String,
+    pub size: u64,
+    pub mode: Option<u32>,
+    pub mtime: Option<UnixTime>,
+}
+
+/// The blocks a DAG is read from.
+pub(crate) trait Blocks {
+    /// The data of block `cid`, verified against it, `None` if it isn't available.
+    fn block(&self, cid: &Cid) -> Result<Option<&[u8]>, ExportError>;
+}
+
+impl Blocks for HashMap<Cid, Vec<u8>> {
+    fn block(&self, cid: &Cid) -> Result<Option<&[u8]>, ExportError> {
+        Ok(self.get(cid).map(Vec::as_slice))
+    }
+}
+
+impl Blocks for IndexedCar<'_> {
+    fn block(&self, cid: &Cid) -> Result<Option<&[u8]>, ExportError> {
+        Ok(self.get(cid)?)
+    }
+}
+
+/// The data of block `cid`, including identity blocks which are stored in their CIDs.
+pub(crate) fn load<'a>(
+    blocks: &'a impl Blocks,
+    cid: &'a Cid,
+) -> Result<Option<&'a [u8]>, ExportError> {
+    if cid.hash().code() == IDENTITY {
+        return Ok(Some(cid.hash().digest()));
+    }
+    blocks.block(cid)
+}
+
+/// Decodes a DAG-PB node along with its UnixFS data.
+pub(crate) fn decode_unixfs(cid: Cid, data: &[u8]) -> Result<(PbNode, UnixFsData), ExportError> {
+    if cid.codec() != DAG_PB {
+        return Err(ExportError::UnsupportedCodec(cid.codec(), cid));
+    }
+    let invalid = |e: std::io::Error| ExportError::InvalidNode(cid, e.to_string());
+    let node = decode_node(data).map_err(invalid)?;
+    let unixfs = UnixFsData::decode(node.data.as_deref().unwrap_or_default()).map_err(invalid)?;
+    Ok((node, unixfs))
+}
+
+/// The single root of a CAR file.
+fn root(header: &CarHeader) -> Result<Cid, ExportError> {
+    match header.roots[..] {
+        [root] => Ok(root),
+        _ => Err(ExportError::InvalidRoots(header.roots.len())),
+    }
+}
+
+/// Where the walk is in the DAG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Position {
+    /// At the payload root, a file or a directory.
+    PayloadRoot,
+    /// At the root of the file wrapped by the payload root.
+    FileRoot,
+    /// Below the file root.
+    Inner,
+}
+
+/// Walks a file DAG depth first, emitting its bytes in order.
+pub(crate) struct Walker {
+    /// The blocks left to visit along with the offset of their bytes in the file, the next
+    /// one last.
+    stack: Vec<(Cid, u64)>,
+    position: Position,
+    info: ExportInfo,
+    /// The file size recorded in the file root.
+    filesize: Option<u64>,
+    /// The file size, once the file root is visited.
+    size: Option<u64>,
+    /// The bytes emitted, all of them if `None`. Only the blocks holding them are visited.
+    range: Option<Range<u64>>,
+}
+
+impl Walker {
+    /// Starts a walk at the payload root.
+    fn new(root: Cid) -> Self {
+        Self {
+            stack: vec![(root, 0)],
+            position: Position::PayloadRoot,
+            info: ExportInfo::default(),
+            filesize: None,
+            size: None,
+            range: None,
+        }
+    }
+
+    /// Starts a walk at the root of file `name`, found in a directory.
+    pub(crate) fn file(root: Cid, name: String) -> Self {
+        Self {
+            position: Position::FileRoot,
+            info: ExportInfo {
+                name: Some(name),
+                ..Default::default()
+            },
+            ..Self::new(root)
+        }
+    }
+
+    /// Only emits the bytes of `range`, ranges ending at `u64::MAX` being open ended.
+    pub(crate) fn range(mut self, range: Range<u64>) -> Self {
+        self.range = Some(range);
+        self
+    }
+
+    /// Whether the bytes of `range` are emitted.
+    fn wants(&self, range: Range<u64>) -> bool {
+        self.range
+            .as_ref()
+            .is_none_or(|wanted| range.start < wanted.end && wanted.start < range.end)
+    }
+
+    /// Emits `data`, the bytes at `offset` of the file, or the part of them in range.
+    fn emit(&self, offset: u64, data: &[u8], out: &mut Vec<u8>) {
+        let Some(range) = &self.range else {
+            out.extend_from_slice(data);
+            return;
+        };
+        let len = data.len() as u64;
+        let start = range.start.saturating_sub(offset).min(len);
+        let end = range.end.saturating_sub(offset).min(len);
+        if start < end {
+            out.extend_from_slice(&data[start as usize..end as usize]);
+        }
+    }
+
+    /// Visits the blocks available, stopping at the first missing one.
+    pub(crate) fn advance(
+        &mut self,
+        blocks: &impl Blocks,
+        out: &mut Vec<u8>,
+    ) -> Result<(), ExportError> {
+        while let Some(&(cid, offset)) = self.stack.last() {
+            let Some(data) = load(blocks, &cid)? else {
+                return Ok(());
+            };
+            self.stack.pop();
+            self.visit(cid, offset, data, out)?;
+        }
+        Ok(())
+    }
+
+    fn visit(
+        &mut self,
+        cid: Cid,
+        offset: u64,
+        data: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), ExportError> {
+        let position = self.position;
+        if position != Position::Inner {
+            self.info.cid = cid.to_string();
+            self.position = Position::Inner;
+        }
+
+        if cid.codec() == RAW {
+            if position != Position::Inner {
+                self.filesize = Some(data.len() as u64);
+                self.size = self.filesize;
+            }
+            self.emit(offset, data, out);
+            self.info.size += data.len() as u64;
+            return Ok(());
+        }
+
+        let (node, unixfs) = decode_unixfs(cid, data)?;
+        match unixfs.data_type {
+            DATA_TYPE_DIRECTORY if position == Position::PayloadRoot && node.links.len() == 1 => {
+                let link = &node.links[0];
+                self.info.name = Some(link.name.clone());
+                self.position = Position::FileRoot;
+                self.stack.push((link.cid, 0));
+                return Ok(());
+            }
+            DATA_TYPE_FILE | DATA_TYPE_RAW => {}
+            data_type => return Err(ExportError::NotAFile(cid, data_type)),
+        }
+        if unixfs.blocksizes.len() != node.links.len() {
+            return Err(ExportError::InvalidNode(
+                cid,
+                "the block sizes don't match the links".to_string(),
+            ));
+        }
+        if position != Position::Inner {
+            self.filesize = unixfs.filesize;
+            self.size = Some(unixfs.filesize.unwrap_or_else(|| {
+                unixfs.data.len() as u64 + unixfs.blocksizes.iter().sum::<u64>()
+            }));
+            self.info.mode = unixfs.mode;
+            self.info.mtime = unixfs.mtime;
+        }
+
+        self.emit(offset, &unixfs.data, out);
+        self.info.size += unixfs.data.len() as u64;
+        let mut start = offset + unixfs.data.len() as u64;
+        let mut children = vec![];
+        for (link, &size) in node.links.iter().zip(&unixfs.blocksizes) {
+            let end = start.saturating_add(size);
+            if self.wants(start..end) {
+                children.push((link.cid, start));
+            }
+            start = end;
+        }
+        self.stack.extend(children.into_iter().rev());
+        Ok(())
+    }
+
+    /// The file size, once the file root is visited.
+    pub(crate) fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// The blocks left to visit.
+    fn pending(&self) -> HashSet<Cid> {
+        self.stack.iter().map(|&(cid, _)| cid).collect()
+    }
+
+    /// Whether every block of the file was visited.
+    pub(crate) fn is_done(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    pub(crate) fn finish(mut self) -> Result<ExportInfo, ExportError> {
+        if let Some(&(cid, _)) = self.stack.last() {
+            return Err(ExportError::MissingBlock(cid));
+        }
+        if self.range.is_some() {
+            // Only the blocks in range were visited
+            self.info.size = self.size.unwrap_or_default();
+            return Ok(self.info);
+        }
+        match self.filesize {
+            Some(expected) if expected != self.info.size => Err(ExportError::SizeMismatch {
+                expected,
+                actual: self.info.size,
+            }),
+            _ => Ok(self.info),
+        }
+    }
+}
+
+/// Reconstructs a file from its CAR file as the CAR file is received.
+///
+/// When the root comes first, as in `ipfs dag export` files, the file bytes are emitted as
+/// they're received. When it comes last, as in the files of `src/lib/car`, they're only
+/// emitted once the root is received.
+///
+/// Blocks are kept until they're read, and then as long as the walk still has to visit them
+/// again or a block received but not read yet links to them. A root first file links again
+/// to blocks read long before whenever the same leaf is in two subtrees, as the zero leaves
+/// of zero runs, so read blocks are kept as well, up to [`MAX_KEPT_SIZE`] bytes of them, the
+/// blocks read the longest ago being dropped first. Only a block linked again after that
+/// fails the export, with [`ExportError::DroppedBlock`].
+pub struct FileExporter {
+    decoder: CarDecoder,
+    blocks: Recorder<HashMap<Cid, Vec<u8>>>,
+    /// The number of links to each block from the blocks received and not read yet.
+    links: HashMap<Cid, usize>,
+    /// The blocks read at least once.
+    read: HashSet<Cid>,
+    walker: Option<Walker>,
+    /// The read blocks kept only in case they're linked again, by when they were last read.
+    kept: BTreeMap<u64, Cid>,
+    /// When each kept block was last read.
+    last_read: HashMap<Cid, u64>,
+    kept_size: usize,
+    max_kept_size: usize,
+    reads: u64,
+}
+
+impl Default for FileExporter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FileExporter {
+    pub fn new() -> Self {
+        Self::with_max_kept_size(MAX_KEPT_SIZE)
+    }
+
+    /// Creates an exporter keeping up to `size` bytes of read blocks.
+    pub fn with_max_kept_size(size: usize) -> Self {
+        Self {
+            decoder: CarDecoder::new(),
+            blocks: Recorder::default(),
+            links: HashMap::new(),
+            read: HashSet::new(),
+            walker: None,
+            kept: BTreeMap::new(),
+            last_read: HashMap::new(),
+            kept_size: 0,
+            max_kept_size: size,
+            reads: 0,
+        }
+    }
+
+    /// Feeds received CAR bytes, returning the file bytes that follow the ones already
+    /// returned, if any.
+    pub fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, ExportError> {
+        self.decoder.push(data);
+        while let Some((_, block)) = self.decoder.next_block()? {
+            verify_block(&block)?;
+            self.insert(block.cid, block.data);
+        }
+
+        if self.walker.is_none() {
+            if let Some(header) = self.decoder.header() {
+                self.walker = Some(Walker::new(root(header)?));
+            }
+        }
+        let mut out = vec![];
+        if let Some(walker) = &mut self.walker {
+            walker.advance(&self.blocks, &mut out)?;
+            let read = self.blocks.take_read();
+            for cid in &read {
+                if self.read.insert(*cid) {
+                    // Its links are now on the walker's stack
+                    for link in links(cid, &self.blocks.get_mut()[cid]) {
+                        if let Some(count) = self.links.get_mut(&link) {
+                            *count -= 1;
+                        }
+                    }
+                }
+            }
+            let pending = walker.pending();
+            for cid in read {
+                if !pending.contains(&cid) && self.links.get(&cid).is_none_or(|&n| n == 0) {
+                    self.links.remove(&cid);
+                    self.keep(cid);
+                }
+            }
+            self.drop_kept(&pending);
+        }
+        Ok(out)
+    }
+
+    /// Keeps read block `cid` in case it's linked again.
+    fn keep(&mut self, cid: Cid) {
+        match self.last_read.remove(&cid) {
+            Some(reads) => {
+                self.kept.remove(&reads);
+            }
+            None => self.kept_size += self.blocks.get_mut()[&cid].len(),
+        }
+        self.reads += 1;
+        self.kept.insert(self.reads, cid);
+        self.last_read.insert(cid, self.reads);
+    }
+
+    /// Drops the blocks read the longest ago until at most `max_kept_size` bytes are kept,
+    /// the ones the walk is waiting for aside.
+    fn drop_kept(&mut self, pending: &HashSet<Cid>) {
+        while self.kept_size > self.max_kept_size {
+            let Some((_, cid)) = self.kept.pop_first() else {
+                break;
+            };
+            self.last_read.remove(&cid);
+            let blocks = self.blocks.get_mut();
+            self.kept_size -= blocks[&cid].len();
+            if !pending.contains(&cid) {
+                blocks.remove(&cid);
+            }
+        }
+    }
+
+    fn insert(&mut self, cid: Cid, data: Vec<u8>) {
+        let blocks = self.blocks.get_mut();
+        if blocks.contains_key(&cid) {
+            return;
+        }
+        if self.read.contains(&cid) {
+            // Received again, only needed if the walk goes back to it
+            if self
+                .walker
+                .as_ref()
+                .is_some_and(|walker| walker.pending().contains(&cid))
+            {
+                blocks.insert(cid, data);
+            }
+            return;
+        }
+        for link in links(&cid, &data) {
+            *self.links.entry(link).or_default() += 1;
+        }
+        blocks.insert(cid, data);
+    }
+
+    /// Checks that the CAR file and the file were received in full.
+    pub fn finish(self) -> Result<ExportInfo, ExportError> {
+        self.decoder.finish()?;
+        let walker = self.walker.ok_or(CarError::Truncated)?;
+        walker.finish().map_err(|e| match e {
+            ExportError::MissingBlock(cid) if self.read.contains(&cid) => {
+                ExportError::DroppedBlock(cid)
+            }
+            e => e,
+        })
+    }
+}
+
+/// The blocks linked from DAG-PB block `cid`, none if it doesn't decode, which the walk
+/// reports when reading it.
+fn links(cid: &Cid, data: &[u8]) -> Vec<Cid> {
+    if cid.codec() != DAG_PB {
+        return vec![];
+    }
+    decode_node(data)
+        .map(|node| node.links.into_iter().map(|link| link.cid).collect())
+        .unwrap_or_default()
+}
+
+/// Reconstructs a file from its CAR file.
+pub fn export_file(car: &[u8]) -> Result<(Vec<u8>, ExportInfo), ExportError> {
+    let mut exporter = FileExporter::new();
+    let data = exporter.write(car)?;
+    Ok((data, exporter.finish()?))
+}
+
+/// Finds entry `name` of directory `cid`, following HAMT shards down to the entry's bucket.
+///
+/// `path` is the path of the directory, for errors, empty for the root.
+pub(crate) fn find_entry(
+    blocks: &impl Blocks,
+    cid: Cid,
+    name: &str,
+    path: &str,
+) -> Result<Option<Cid>, ExportError> {
+    let read = |cid: Cid| {
+        let data = load(blocks, &cid)?.ok_or(ExportError::MissingBlock(cid))?;
+        decode_unixfs(cid, data)
+    };
+    let (mut node, unixfs) = match cid.codec() {
+        DAG_PB => read(cid)?,
+        _ => return Err(ExportError::NotADirectory(format!("{path}/"))),
+    };
+
+    match unixfs.data_type {
+        DATA_TYPE_DIRECTORY => {
+            let link = node.links.iter().find(|link| link.name == name);
+            Ok(link.map(|link| link.cid))
+        }
+        DATA_TYPE_HAMT_SHARD => {
+            if unixfs.hash_type != Some(MURMUR3_X64_64) || unixfs.fanout != Some(HAMT_FANOUT as u64)
+            {
+                return Err(ExportError::UnsupportedShard(cid));
+            }
+            // Each level is indexed by the next byte of the hash, entries being named after
+            // their bucket and sub-shards by their bucket alone
+            for index in murmur3_x64_64(name.as_bytes()).to_be_bytes() {
+                let prefix = format!("{index:02X}");
+                let Some(link) = node
+                    .links
+                    .iter()
+                    .find(|link| link.name.starts_with(&prefix))
+                else {
+                    return Ok(None);
+                };
+                if link.name.len() > prefix.len() {
+                    return Ok((link.name[prefix.len()..] == *name).then_some(link.cid));
+                }
+                let (child, data) = read(link.cid)?;
+                if data.data_type != DATA_TYPE_HAMT_SHARD {
+                    return Err(ExportError::InvalidNode(
+                        link.cid,
+                        "expected a HAMT shard".to_string(),
+                    ));
+                }
+                node = child;
+            }
+            Ok(None)
+        }
+        _ => Err(ExportError::NotADirectory(format!("{path}/"))),
+    }
+}
+
+/// Extracts the file at `path` of the directory held in a CAR file.
+///
+/// `path` is `/` separated, leading and repeated separators being ignored. Only the
+/// directory nodes along the path and the blocks of the file are read and verified, the
+/// blocks being located through the index of CARv2 files. An empty path designates the
+/// payload root, as [`export_file`] does.
+pub fn resolve_path(car: &[u8], path: &str) -> Result<(Vec<u8>, ExportInfo), ExportError> {
+    let blocks = IndexedCar::new(car)?;
+    let mut cid = root(blocks.header())?;
+
+    let mut walker = Walker::new(cid);
+    let mut resolved = String::new();
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        let entry = find_entry(&blocks, cid, name, &resolved)?;
+        resolved = format!("{resolved}/{name}");
+        cid = entry.ok_or_else(|| ExportError::NotFound(resolved.clone()))?;
+        walker = Walker::file(cid, name.to_string());
+    }
+
+    let mut out = vec![];
+    walker.advance(&blocks, &mut out)?;
+    Ok((out, walker.finish()?))
+}
+
+/// Appends the bytes of `range` found in `data`, which starts at `offset` in the file.
+fn append_range(data: &[u8], offset: u64, range: &Range<u64>, out: &mut Vec<u8>) {
+    let start = range.start.saturating_sub(offset).min(data.len() as u64) as usize;
+    let end = range.end.saturating_sub(offset).min(data.len() as u64) as usize;
+    out.extend_from_slice(&data[start..end.max(start)]);
+}
+
+/// Appends the bytes of `range` held by the file node `cid`, starting at `offset` in the file
+/// and `size` bytes long according to its parent. Only the children overlapping the range are
+/// read.
+pub(crate) fn read_range(
+    blocks: &impl Blocks,
+    cid: Cid,
+    offset: u64,
+    size: u64,
+    range: &Range<u64>,
+    out: &mut Vec<u8>,
+) -> Result<(), ExportError> {
+    let data = load(blocks, &cid)?.ok_or(ExportError::MissingBlock(cid))?;
+    if cid.codec() == RAW {
+        if data.len() as u64 != size {
+            return Err(ExportError::SizeMismatch {
+                expected: size,
+                actual: data.len() as u64,
+            });
+        }
+        append_range(data, offset, range, out);
+        return Ok(());
+    }
+
+    let (node, unixfs) = decode_unixfs(cid, data)?;
+    if !matches!(unixfs.data_type, DATA_TYPE_FILE | DATA_TYPE_RAW) {
+        return Err(ExportError::NotAFile(cid, unixfs.data_type));
+    }
+    if unixfs.blocksizes.len() != node.links.len() {
+        return Err(ExportError::InvalidNode(
+            cid,
+            "the block sizes don't match the links".to_string(),
+        ));
+    }
+    let actual = unixfs.data.len() as u64 + unixfs.blocksizes.iter().sum::<u64>();
+    if actual != size {
+        return Err(ExportError::SizeMismatch {
+            expected: size,
+            actual,
+        });
+    }
+
+    append_range(&unixfs.data, offset, range, out);
+    let mut offset = offset + unixfs.data.len() as u64;
+    for (link, &size) in node.links.iter().zip(&unixfs.blocksizes) {
+        if offset < range.end && offset + size > range.start {
+            read_range(blocks, link.cid, offset, size, range, out)?;
+        }
+        offset += size;
+    }
+    Ok(())
+}
+
+/// Reads `range` of the file held in a CAR file, as exported by [`export_file`].
+///
+/// The leaves holding the range are found from the block sizes of the nodes above them,
+/// and located through the index of CARv2 files. Only these blocks are read and verified.
+/// The range is truncated to the end of the file.
+pub fn extract_range(car: &[u8], range: Range<u64>) -> Result<(Vec<u8>, ExportInfo), ExportError> {
+    if range.start > range.end {
+        return Err(ExportError::InvalidRange {
+            start: range.start,
+            end: range.end,
+        });
+    }
+    let blocks = IndexedCar::new(car)?;
+    let mut cid = root(blocks.header())?;
+    let mut info = ExportInfo::default();
+
+    // Unwraps the file from its directory, as the walker does
+    let data = load(&blocks, &cid)?.ok_or(ExportError::MissingBlock(cid))?;
+    let mut root_data = data;
+    if cid.codec() == DAG_PB {
+        let (node, unixfs) = decode_unixfs(cid, data)?;
+        if let ([link], DATA_TYPE_DIRECTORY) = (&node.links[..], unixfs.data_type) {
+            info.name = Some(link.name.clone());
+            cid = link.cid;
+            root_data = load(&blocks, &cid)?.ok_or(ExportError::MissingBlock(cid))?;
+        }
+    }
+
+    info.cid = cid.to_string();
+    info.size = match cid.codec() {
+        RAW => root_data.len() as u64,
+        _ => {
+            let (_, unixfs) = decode_unixfs(cid, root_data)?;
+            info.mode = unixfs.mode;
+            info.mtime = unixfs.mtime;
+            // Checked against the block sizes when reading the root
+            unixfs
+                .filesize
+                .unwrap_or_else(|| unixfs.data.len() as u64 + unixfs.blocksizes.iter().sum::<u64>())
+        }
+    };
+
+    let range = range.start.min(info.size)..range.end.min(info.size);
+    let mut out = vec![];
+    if !range.is_empty() {
+        read_range(&blocks, cid, 0, info.size, &range, &mut out)?;
+    }
+    Ok((out, info))
+}
+
+/// A file reconstructed by `exportFile` or `resolvePath`, or part of it read by
+/// `extractRange`.
+#[wasm_bindgen]
+pub struct ExportedFile {
+    bytes: Vec<u8>,
+    info: ExportInfo,
+}
+
+#[wasm_bindgen]
+impl ExportedFile {
+    /// The file contents.
+    #[wasm_bindgen(getter)]
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    /// `{ name, cid, size, mode, mtime: { seconds, nanos } }`, `name`, `mode` and `mtime`
+    /// being only set when stored in the payload.
+    #[wasm_bindgen(getter)]
+    pub fn info(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.info)?)
+    }
+}
+
+/// Reconstructs the original file from a CARv1 or CARv2 file, verifying every block.
+///
+/// # Arguments
+/// * `car` - The CAR file, as retrieved.
+///
+/// # Returns
+/// The file contents and what is known of the file.
+#[wasm_bindgen(js_name = "exportFile")]
+pub fn export_file_js(car: &[u8]) -> Result<ExportedFile, JsValue> {
+    let (bytes, info) = export_file(car).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    Ok(ExportedFile { bytes, info })
+}
+
+/// Extracts a file out of the UnixFS directory held in a CARv1 or CARv2 file.
+///
+/// # Arguments
+/// * `car` - The CAR file, as retrieved.
+/// * `path` - The path of the file in the directory, such as `/photos/2024/a.jpg`.
+///
+/// # Returns
+/// The file contents and what is known of the file, `name` being the last path component.
+#[wasm_bindgen(js_name = "resolvePath")]
+pub fn resolve_path_js(car: &[u8], path: &str) -> Result<ExportedFile, JsValue> {
+    let (bytes, info) = resolve_path(car, path).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    Ok(ExportedFile { bytes, info })
+}
+
+/// Reads a byte range of the file held in a CARv1 or CARv2 file, verifying only the blocks
+/// holding it.
+///
+/// # Arguments
+/// * `car` - The CAR file, as retrieved.
+/// * `start` - The offset of the first byte in the file.
+/// * `end` - The offset following the last byte, truncated to the file size.
+///
+/// # Returns
+/// The bytes of the range and what is known of the whole file.
+#[wasm_bindgen(js_name = "extractRange")]
+pub fn extract_range_js(car: &[u8], start: u64, end: u64) -> Result<ExportedFile, JsValue> {
+    let (bytes, info) =
+        extract_range(car, start..end).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    Ok(ExportedFile { bytes, info })
+}
+
+/// Reconstructs the original file while its CAR file is being received.
+///
+/// ```js
+/// const exporter = new FileExportStream();
+/// const parts = [];
+/// for await (const data of response.body) parts.push(exporter.write(data));
+/// const info = exporter.finish();
+/// const file = new File(parts, info.name ?? info.cid);
+/// ```
+#[wasm_bindgen]
+pub struct FileExportStream {
+    exporter: FileExporter,
+}
+
+#[wasm_bindgen]
+impl FileExportStream {
+    #[wasm_bindgen(constructor)]
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> FileExportStream {
+        Self {
+            exporter: FileExporter::new(),
+        }
+    }
+
+    /// Feeds received CAR bytes, returning the next file bytes, possibly none.
+    pub fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, JsValue> {
+        self.exporter
+            .write(data)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Checks that the file was received in full, returning the same info as
+    /// `ExportedFile.info`.
+    pub fn finish(self) -> Result<JsValue, JsValue> {
+        let info = self
+            .exporter
+            .finish()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(serde_wasm_bindgen::to_value(&info)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        car::{
+            builder::{generate_car_v2, generate_car_v2_with_options, FileInfo},
+            directory::DirectoryCarBuilder,
+            CarV2Builder, DATA_OFFSET,
+        },
+        testing::{root_first_car, root_first_car_with_options},
+        unixfs::{Chunker, DagOptions, Layout, Metadata},
+    };
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn exports_files() {
+        let kubo = DagOptions {
+            raw_leaves: false,
+            cid_version: 0,
+            ..Default::default()
+        };
+        let layouts = [
+            DagOptions::default(),
+            kubo,
+            DagOptions {
+                chunk_size: 1024,
+                max_links: 3,
+                layout: Layout::Trickle,
+                ..kubo
+            },
+            DagOptions {
+                chunker: Chunker::FastCdc {
+                    min_size: 1024,
+                    avg_size: 4096,
+                    max_size: 16384,
+                },
+                max_links: 4,
+                ..Default::default()
+            },
+        ];
+        for options in layouts {
+            for len in [0, 1, 1024, 5000, 600 * 1024] {
+                let data = pattern(len);
+                let (root, car) = generate_car_v2_with_options(&data, options).unwrap();
+                let (exported, info) = export_file(&car).unwrap();
+                assert!(exported == data, "{options:?} {len}");
+                assert_eq!(info.cid, root.to_string());
+                assert_eq!(info.size, len as u64);
+                assert_eq!(info.name, None);
+            }
+        }
+
+        // Repeated leaves are only stored once
+        let zeros = vec![0; 3 << 20];
+        let (_, car) = generate_car_v2(&zeros).unwrap();
+        assert!(car.len() < 300 * 1024);
+        assert!(export_file(&car).unwrap().0 == zeros);
+    }
+
+    #[test]
+    fn exports_named_files() {
+        let info = FileInfo {
+            name: Some("notes.txt".to_string()),
+            mode: Some(0o600),
+            mtime: Some(UnixTime {
+                seconds: 1_700_000_000,
+                nanos: 0,
+            }),
+        };
+        for len in [5, 600 * 1024] {
+            let data = pattern(len);
+            let mut builder =
+                CarV2Builder::with_info(vec![], DagOptions::default(), info.clone()).unwrap();
+            builder.write(&data).unwrap();
+            let output = builder.finish().unwrap();
+            let car = [output.header, output.out, output.index].concat();
+
+            let (exported, exported_info) = export_file(&car).unwrap();
+            assert!(exported == data);
+            assert_eq!(exported_info.name.as_deref(), Some("notes.txt"));
+            assert_eq!(exported_info.mode, Some(0o600));
+            assert_eq!(exported_info.mtime, info.mtime);
+            assert_ne!(exported_info.cid, output.root.to_string());
+        }
+    }
+
+    #[test]
+    fn streams_root_first_files() {
+        let data = pattern(3 << 20);
+        let (_, car) = root_first_car(&data);
+
+        let mut exporter = FileExporter::new();
+        let mut exported = vec![];
+        let mut received = 0;
+        for chunk in car.chunks(64 * 1024) {
+            exported.extend(exporter.write(chunk).unwrap());
+            received += chunk.len();
+            // The root comes first, then the leaves in order, each emitted once received
+            assert!(exported.len() + 2 * 256 * 1024 > received);
+        }
+        exporter.finish().unwrap();
+        assert!(exported == data);
+
+        // Read blocks are only kept up to the limit
+        let mut exporter = FileExporter::with_max_kept_size(512 * 1024);
+        for chunk in car.chunks(64 * 1024) {
+            exporter.write(chunk).unwrap();
+            assert!(
+                exporter
+                    .blocks
+                    .get_mut()
+                    .values()
+                    .map(Vec::len)
+                    .sum::<usize>()
+                    < 1 << 20
+            );
+        }
+        exporter.finish().unwrap();
+    }
+
+    #[test]
+    fn keeps_repeated_blocks() {
+        let options = DagOptions {
+            chunk_size: 1024,
+            max_links: 3,
+            ..Default::default()
+        };
+        // Stems [a, b, a] and [c, a], the first stem links twice to the same leaf
+        let chunk = |byte| vec![byte; 1024];
+        let data = [chunk(1), chunk(2), chunk(1), chunk(3), chunk(1)].concat();
+        let (_, car) = root_first_car_with_options(&data, options);
+
+        // Read twice through the first stem before the second stem is received, the leaf is
+        // kept for it
+        let mut exporter = FileExporter::new();
+        let mut exported = vec![];
+        for chunk in car.chunks(100) {
+            exported.extend(exporter.write(chunk).unwrap());
+        }
+        exporter.finish().unwrap();
+        assert!(exported == data);
+
+        // Unless no read block is kept
+        let mut exporter = FileExporter::with_max_kept_size(0);
+        let mut exported = vec![];
+        for chunk in car.chunks(100) {
+            exported.extend(exporter.write(chunk).unwrap());
+        }
+        assert!(exported.len() < data.len());
+        assert!(matches!(
+            exporter.finish(),
+            Err(ExportError::DroppedBlock(cid)) if cid == options.leaf(chunk(1)).0.cid
+        ));
+
+        // Received along with the second stem, it's kept either way
+        let (exported, _) = export_file(&car).unwrap();
+        assert!(exported == data);
+
+        // Children first, every block is read after its last parent is received
+        let (_, car) = generate_car_v2_with_options(&data, options).unwrap();
+        let mut exporter = FileExporter::with_max_kept_size(0);
+        let mut exported = vec![];
+        for chunk in car.chunks(100) {
+            exported.extend(exporter.write(chunk).unwrap());
+        }
+        assert_eq!(exporter.blocks.get_mut().len(), 0);
+        exporter.finish().unwrap();
+        assert!(exported == data);
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let data = pattern(600 * 1024);
+        let (_, car) = generate_car_v2(&data).unwrap();
+
+        let mut corrupted = car.clone();
+        corrupted[200] ^= 1;
+        assert!(matches!(
+            export_file(&corrupted),
+            Err(ExportError::Car(CarError::HashMismatch(_)))
+        ));
+
+        assert!(matches!(
+            export_file(&car[..car.len() / 2]),
+            Err(ExportError::Car(CarError::Truncated))
+        ));
+
+        let car = directory_car(&[("a", vec![]), ("b", vec![])]);
+        assert!(matches!(
+            export_file(&car),
+            Err(ExportError::NotAFile(_, DATA_TYPE_DIRECTORY))
+        ));
+    }
+
+    fn directory_car(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut builder = DirectoryCarBuilder::new(vec![]);
+        for (path, data) in files {
+            builder.add_file(path).unwrap();
+            builder.write(data).unwrap();
+        }
+        let output = builder.finish().unwrap();
+        [output.header, output.out, output.index].concat()
+    }
+
+    #[test]
+    fn resolves_paths() {
+        let photo = pattern(600 * 1024);
+        let mut builder = DirectoryCarBuilder::new(vec![]);
+        builder.add_file("photos/2024/a.jpg").unwrap();
+        builder.write(&photo).unwrap();
+        let mtime = UnixTime {
+            seconds: 1_700_000_000,
+            nanos: 5,
+        };
+        let metadata = Metadata {
+            mode: Some(0o644),
+            mtime: Some(mtime),
+        };
+        builder
+            .add_file_with_metadata("photos/b.txt", metadata)
+            .unwrap();
+        builder.write(b"b").unwrap();
+        builder.add_file("readme").unwrap();
+        builder.write(b"read me").unwrap();
+        builder.add_directory("empty").unwrap();
+        let output = builder.finish().unwrap();
+        let car = [output.header, output.out, output.index].concat();
+
+        let (data, info) = resolve_path(&car, "/photos/2024/a.jpg").unwrap();
+        assert!(data == photo);
+        assert_eq!(info.name.as_deref(), Some("a.jpg"));
+        assert_eq!(info.size, photo.len() as u64);
+        let (expected, _) = generate_car_v2(&photo).unwrap();
+        assert_eq!(info.cid, expected.to_string());
+
+        let (data, info) = resolve_path(&car, "photos//b.txt").unwrap();
+        assert_eq!(data, b"b");
+        assert_eq!((info.mode, info.mtime), (Some(0o644), Some(mtime)));
+
+        // CARv1 files are indexed when opened
+        let data_size = u64::from_le_bytes(car[35..43].try_into().unwrap()) as usize;
+        let v1 = &car[DATA_OFFSET as usize..][..data_size];
+        assert_eq!(resolve_path(v1, "/readme").unwrap().0, b"read me");
+
+        // Retrieved pieces end with zero padding
+        let mut padded = car.clone();
+        padded.resize(car.len().next_power_of_two(), 0);
+        assert_eq!(resolve_path(&padded, "/photos//b.txt").unwrap().0, b"b");
+        let (_, file) = generate_car_v2(&photo).unwrap();
+        let mut padded = file.clone();
+        padded.resize(file.len().next_power_of_two(), 0);
+        let (data, _) = extract_range(&padded, 1000..2000).unwrap();
+        assert!(data == photo[1000..2000]);
+
+        for (path, error) in [
+            ("/photos/c.txt", "no such file or directory: /photos/c.txt"),
+            ("/readme/a", "not a directory: /readme/"),
+        ] {
+            assert_eq!(resolve_path(&car, path).unwrap_err().to_string(), error);
+        }
+        assert!(matches!(
+            resolve_path(&car, "/empty"),
+            Err(ExportError::NotAFile(_, DATA_TYPE_DIRECTORY))
+        ));
+    }
+
+    #[test]
+    fn resolves_sharded_paths() {
+        // 5141 entries make a sharded directory, see `unixfs::directory`
+        let files = (0..5141)
+            .map(|i| {
+                (
+                    format!("file-{i:06}.bin"),
+                    format!("contents of {i}").into_bytes(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let files = files
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.clone()))
+            .collect::<Vec<_>>();
+        let car = directory_car(&files);
+
+        for i in [0, 1, 2024, 5140] {
+            let (data, info) = resolve_path(&car, &format!("/file-{i:06}.bin")).unwrap();
+            assert_eq!(data, files[i].1);
+            assert_eq!(info.name.as_deref(), Some(files[i].0));
+        }
+        assert!(matches!(
+            resolve_path(&car, "/file-005141.bin"),
+            Err(ExportError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn only_reads_resolved_blocks() {
+        let other = vec![0xaa; 1024];
+        let mut car = directory_car(&[("a", b"a file".to_vec()), ("b", other.clone())]);
+        let at = car.windows(other.len()).position(|w| w == other).unwrap();
+        car[at] ^= 1;
+
+        assert_eq!(resolve_path(&car, "/a").unwrap().0, b"a file");
+        assert!(matches!(
+            resolve_path(&car, "/b"),
+            Err(ExportError::Car(CarError::HashMismatch(_)))
+        ));
+    }
+
+    #[test]
+    fn extracts_ranges() {
+        let data = pattern(1536 * 1024);
+        let len = data.len() as u64;
+        let layouts = [
+            DagOptions::default(),
+            DagOptions {
+                layout: Layout::Trickle,
+                max_links: 3,
+                raw_leaves: false,
+                cid_version: 0,
+                ..Default::default()
+            },
+            DagOptions {
+                chunker: Chunker::FastCdc {
+                    min_size: 16384,
+                    avg_size: 65536,
+                    max_size: 262144,
+                },
+                max_links: 4,
+                ..Default::default()
+            },
+        ];
+        for options in layouts {
+            let (root, car) = generate_car_v2_with_options(&data, options).unwrap();
+            for range in [
+                0..1 << 20,
+                len - 100..len,
+                262143..262145,
+                1000..1000,
+                len - 10..len + 100,
+                len + 5..len + 10,
+            ] {
+                let (extracted, info) = extract_range(&car, range.clone()).unwrap();
+                let start = range.start.min(len) as usize;
+                let end = range.end.min(len) as usize;
+                assert!(extracted == data[start..end], "{options:?} {range:?}");
+                assert_eq!(info.cid, root.to_string());
+                assert_eq!(info.size, len);
+            }
+        }
+
+        let (_, car) = generate_car_v2(&data).unwrap();
+        assert!(matches!(
+            extract_range(&car, Range { start: 10, end: 5 }),
+            Err(ExportError::InvalidRange { start: 10, end: 5 })
+        ));
+    }
+
+    #[test]
+    fn extracts_ranges_of_named_files() {
+        let data = pattern(600 * 1024);
+        let info = FileInfo {
+            name: Some("video.mp4".to_string()),
+            ..Default::default()
+        };
+        let mut builder = CarV2Builder::with_info(vec![], DagOptions::default(), info).unwrap();
+        builder.write(&data).unwrap();
+        let output = builder.finish().unwrap();
+        let car = [output.header, output.out, output.index].concat();
+
+        let (extracted, info) = extract_range(&car, 300_000..300_010).unwrap();
+        assert!(extracted == data[300_000..300_010]);
+        assert_eq!(info.name.as_deref(), Some("video.mp4"));
+        assert_eq!(info.size, data.len() as u64);
+    }
+
+    #[test]
+    fn only_reads_range_blocks() {
+        let mut data = pattern(1536 * 1024);
+        data[5 * 256 * 1024..].fill(0xee);
+        let (_, mut car) = generate_car_v2(&data).unwrap();
+        // Corrupts the last leaf
+        let at = car.windows(64).position(|w| w == [0xee; 64]).unwrap();
+        car[at] ^= 1;
+
+        assert!(extract_range(&car, 0..1 << 20).unwrap().0 == data[..1 << 20]);
+        assert!(matches!(
+            extract_range(&car, data.len() as u64 - 1..data.len() as u64),
+            Err(ExportError::Car(CarError::HashMismatch(_)))
+        ));
+    }
+}
@@ -0,0 +1,232 @@
+//! Minimal HTTP abstraction used to talk to storage providers.
+//!
+//! The retrieval code is generic over [`Fetch`] so that it runs on top of the browser's
+//! `fetch` in WASM ([`BrowserFetch`]) and on top of a mock server in tests.
+use std::{future::Future, ops::Range};
+
+use js_sys::Uint8Array;
+use thiserror::Error;
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+
+/// HTTP status code for a successful request.
+pub const STATUS_OK: u16 = 200;
+/// HTTP status code for a successful range request.
+pub const STATUS_PARTIAL_CONTENT: u16 = 206;
+/// HTTP status code for a range request past the end of the resource.
+pub const STATUS_RANGE_NOT_SATISFIABLE: u16 = 416;
+
+#[derive(Debug, Error)]
+pub enum FetchError {
+    #[error("request to {url} failed: {reason}")]
+    Network { url: String, reason: String },
+    #[error("{url} responded with status {status}")]
+    Status { url: String, status: u16 },
+    #[error("{url} responded with {actual:?} to a request for bytes {expected:?}")]
+    ContentRange {
+        url: String,
+        expected: Range<u64>,
+        actual: Option<String>,
+    },
+}
+
+/// A `GET` request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpRequest {
+    pub fn get(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            headers: vec![],
+        }
+    }
+
+    /// Adds a header to the request.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Requests only the given (exclusive) byte range.
+    pub fn range(self, range: Range<u64>) -> Self {
+        let value = format!("bytes={}-{}", range.start, range.end.saturating_sub(1));
+        self.header("Range", value)
+    }
+}
+
+/// The response to an [`HttpRequest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Returns the value of the first header named `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the status is in the 2xx range.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Sends HTTP requests.
+pub trait Fetch {
+    fn fetch(&self, request: HttpRequest)
+        -> impl Future<Output = Result<HttpResponse, FetchError>>;
+}
+
+impl<F: Fetch> Fetch for &F {
+    fn fetch(
+        &self,
+        request: HttpRequest,
+    ) -> impl Future<Output = Result<HttpResponse, FetchError>> {
+        (**self).fetch(request)
+    }
+}
+
+/// Parses a `Content-Range: bytes start-end/total` header into the exclusive range sent and
+/// the total length, `None` when unknown.
+fn parse_content_range(value: &str) -> Option<(Range<u64>, Option<u64>)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.parse::<u64>().ok()?, end.parse::<u64>().ok()?);
+    if end < start {
+        return None;
+    }
+    let total = match total {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    Some((start..end + 1, total))
+}
+
+/// Fetches a byte range of the resource at `url`.
+///
+/// Servers ignoring the `Range` header are handled by slicing the full response. Partial
+/// responses must carry the requested range in their `Content-Range`, only cut short where
+/// the resource ends. The returned bytes are shorter than the range if the resource ends
+/// before it.
+///
+/// Cross-origin, `Content-Range` is hidden unless the server sends
+/// `Access-Control-Expose-Headers: Content-Range`. A partial response without it is taken as
+/// the requested range as long as it isn't longer, the callers checking the bytes received
+/// against the piece commitment anyway.
+pub async fn fetch_range<F: Fetch>(
+    fetcher: &F,
+    url: &str,
+    range: Range<u64>,
+) -> Result<Vec<u8>, FetchError> {
+    let response = fetcher
+        .fetch(HttpRequest::get(url).range(range.clone()))
+        .await?;
+
+    match response.status {
+        STATUS_PARTIAL_CONTENT => {
+            let content_range = response.header("Content-Range");
+            let valid = match content_range.and_then(parse_content_range) {
+                Some((sent, total)) => {
+                    sent.start == range.start
+                        && sent.end <= range.end
+                        && (sent.end == range.end || total == Some(sent.end))
+                        && sent.end - sent.start == response.body.len() as u64
+                }
+                None if content_range.is_none() => {
+                    response.body.len() as u64 <= range.end - range.start
+                }
+                None => false,
+            };
+            if !valid {
+                return Err(FetchError::ContentRange {
+                    url: url.to_string(),
+                    expected: range,
+                    actual: content_range.map(str::to_string),
+                });
+            }
+            Ok(response.body)
+        }
+        STATUS_OK => {
+            let len = response.body.len() as u64;
+            let start = range.start.min(len) as usize;
+            let end = range.end.min(len) as usize;
+            Ok(response.body[start..end].to_vec())
+        }
+        STATUS_RANGE_NOT_SATISFIABLE => Ok(vec![]),
+        status => Err(FetchError::Status {
+            url: url.to_string(),
+            status,
+        }),
+    }
+}
+
+#[wasm_bindgen]
+extern "C" {
+    /// The global `fetch`, available both in windows and in workers.
+    #[wasm_bindgen(js_name = fetch)]
+    fn global_fetch(request: &web_sys::Request) -> js_sys::Promise;
+}
+
+/// [`Fetch`] implementation on top of the browser's `fetch`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BrowserFetch;
+
+impl Fetch for BrowserFetch {
+    async fn fetch(&self, request: HttpRequest) -> Result<HttpResponse, FetchError> {
+        let url = request.url.clone();
+        let network_error = |e: JsValue| FetchError::Network {
+            url: url.clone(),
+            reason: format!("{:?}", e),
+        };
+
+        let headers = web_sys::Headers::new().map_err(network_error)?;
+        for (name, value) in &request.headers {
+            headers.append(name, value).map_err(network_error)?;
+        }
+        let init = web_sys::RequestInit::new();
+        init.set_method("GET");
+        init.set_headers(&headers);
+        let js_request =
+            web_sys::Request::new_with_str_and_init(&request.url, &init).map_err(network_error)?;
+
+        let response: web_sys::Response = JsFuture::from(global_fetch(&js_request))
+            .await
+            .map_err(network_error)?
+            .dyn_into()
+            .map_err(network_error)?;
+
+        let mut headers = vec![];
+        if let Some(entries) = js_sys::try_iter(&response.headers()).map_err(network_error)? {
+            for entry in entries {
+                let entry: js_sys::Array = entry
+                    .map_err(network_error)?
+                    .dyn_into()
+                    .map_err(network_error)?;
+                headers.push((
+                    entry.get(0).as_string().unwrap_or_default(),
+                    entry.get(1).as_string().unwrap_or_default(),
+                ));
+            }
+        }
+
+        let body = JsFuture::from(response.array_buffer().map_err(network_error)?)
+            .await
+            .map_err(network_error)?;
+
+        Ok(HttpResponse {
+            status: response.status(),
+            headers,
+            body: Uint8Array::new(&body).to_vec(),
+        })
+    }
+}
@@ -0,0 +1,817 @@
+//! Retrieval from IPFS gateways over the trustless gateway protocol.
+//!
+//! Content is requested as a CAR file, `application/vnd.ipld.car`, for a path below a root
+//! CID. The `dag-scope` parameter picks the blocks sent for the entity the path ends at, and
+//! `entity-bytes` a byte range of a file. Nothing the gateway sends is trusted: every block is
+//! checked against its CID, the path is resolved from the root through the blocks received,
+//! and the response must hold every block the request asks for. Gateways may send more, as
+//! the specification allows, but only blocks linked from the root. Any compliant gateway or
+//! provider can then serve the content.
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    ops::Range,
+};
+
+use cid::Cid;
+use thiserror::Error;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    car::{
+        reader::{verify_block, CarDecoder},
+        CarError,
+    },
+    exporter::{decode_unixfs, find_entry, load, read_range, Blocks, ExportError},
+    fetch::{BrowserFetch, Fetch, FetchError, HttpRequest},
+    ipld::{DAG_PB, RAW},
+    unixfs::pb::{
+        decode_node, PbLink, DATA_TYPE_DIRECTORY, DATA_TYPE_FILE, DATA_TYPE_HAMT_SHARD,
+        DATA_TYPE_RAW,
+    },
+};
+
+/// Media type of CAR responses.
+pub const CAR_MEDIA_TYPE: &str = "application/vnd.ipld.car";
+/// The `Accept` header of requests: CARv1 files, blocks in depth first order, duplicates
+/// allowed.
+pub const CAR_ACCEPT: &str = "application/vnd.ipld.car; version=1; order=dfs; dups=y";
+
+#[derive(Debug, Error)]
+pub enum GatewayError {
+    #[error(transparent)]
+    Fetch(#[from] FetchError),
+    #[error("expected a CAR response, got {0}")]
+    ContentType(String),
+    #[error(transparent)]
+    Car(#[from] CarError),
+    #[error(transparent)]
+    Export(#[from] ExportError),
+    #[error("expected the root {expected}, the response has {actual:?}")]
+    UnexpectedRoots {
+        expected: String,
+        actual: Vec<String>,
+    },
+    #[error("block {0} isn't linked from the requested root")]
+    UnexpectedBlock(Cid),
+    #[error("invalid IPFS path: {0}")]
+    InvalidPath(String),
+}
+
+/// The blocks sent for the entity a path ends at.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DagScope {
+    /// Only the block the path ends at.
+    Block,
+    /// A whole file, or a directory without its entries.
+    Entity,
+    /// The whole DAG below the path.
+    #[default]
+    All,
+}
+
+impl DagScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DagScope::Block => "block",
+            DagScope::Entity => "entity",
+            DagScope::All => "all",
+        }
+    }
+}
+
+/// A request for the blocks of a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GatewayRequest {
+    pub root: Cid,
+    /// `/` separated, leading and repeated separators being ignored, empty for the root.
+    pub path: String,
+    pub scope: DagScope,
+    /// The (exclusive) byte range of the file the path ends at, the whole file if `None`.
+    /// Ranges ending at `u64::MAX` are open ended.
+    pub entity_bytes: Option<Range<u64>>,
+}
+
+impl GatewayRequest {
+    pub fn new(root: Cid) -> Self {
+        Self {
+            root,
+            path: String::new(),
+            scope: DagScope::default(),
+            entity_bytes: None,
+        }
+    }
+
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    pub fn scope(mut self, scope: DagScope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// Requests only `range` of the file, which implies the `entity` scope.
+    pub fn entity_bytes(mut self, range: Range<u64>) -> Self {
+        self.scope = DagScope::Entity;
+        self.entity_bytes = Some(range);
+        self
+    }
+
+    /// A request for the entity at `path`, a URL path such as `/ipfs/<cid>/photos/a.jpg`.
+    ///
+    /// Anything before `/ipfs/` is ignored, so that the path can be below a base path, and
+    /// the names along the path are percent-decoded.
+    pub fn from_path(path: &str) -> Result<Self, GatewayError> {
+        let invalid = || GatewayError::InvalidPath(path.to_string());
+        let (_, rest) = path.split_once("/ipfs/").ok_or_else(invalid)?;
+        let mut names = rest.split('/');
+        let root = names
+            .next()
+            .and_then(|root| Cid::try_from(root).ok())
+            .ok_or_else(invalid)?;
+        let mut decoded = String::new();
+        for name in names.filter(|name| !name.is_empty()) {
+            decoded.push('/');
+            decoded.push_str(&percent_decode(name).ok_or_else(invalid)?);
+        }
+        Ok(Self::new(root).path(decoded).scope(DagScope::Entity))
+    }
+
+    /// The names along the path.
+    pub(crate) fn names(&self) -> impl Iterator<Item = &str> {
+        self.path.split('/').filter(|name| !name.is_empty())
+    }
+
+    /// The URL of the request on the gateway at `base`, e.g. `https://ipfs.io`.
+    pub fn url(&self, base: &str) -> String {
+        let mut url = format!("{}/ipfs/{}", base.trim_end_matches('/'), self.root);
+        for name in self.names() {
+            url.push('/');
+            url.push_str(&percent_encode(name));
+        }
+        url.push_str("?dag-scope=");
+        url.push_str(self.scope.as_str());
+        if let Some(range) = &self.entity_bytes {
+            // Both ends are inclusive
+            let end = match range.end {
+                u64::MAX => "*".to_string(),
+                end => (end - 1).to_string(),
+            };
+            url.push_str(&format!("&entity-bytes={}:{}", range.start, end));
+        }
+        url
+    }
+}
+
+/// Percent-encodes a path segment, leaving only unreserved characters as they are.
+fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::new();
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Decodes a percent-encoded path segment, `None` if it isn't valid UTF-8 once decoded.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut decoded = vec![];
+    let mut bytes = segment.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let hex = [bytes.next()?, bytes.next()?];
+        decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// A verified response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GatewayResponse {
+    /// The CID the path resolves to.
+    pub cid: Cid,
+    /// The file the path ends at, or its requested range. `None` for directories and for the
+    /// `block` scope.
+    pub data: Option<Vec<u8>>,
+    /// The CAR file received.
+    pub car: Vec<u8>,
+}
+
+/// Blocks recording which of them are read, in the order they're first read.
+#[derive(Default)]
+pub(crate) struct Recorder<B> {
+    blocks: B,
+    read: RefCell<(HashSet<Cid>, Vec<Cid>)>,
+}
+
+impl<B: Blocks> Recorder<B> {
+    pub(crate) fn new(blocks: B) -> Self {
+        Self {
+            blocks,
+            read: RefCell::default(),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut B {
+        &mut self.blocks
+    }
+
+    /// The blocks read since the last call, in order.
+    pub(crate) fn take_read(&self) -> Vec<Cid> {
+        self.read.take().1
+    }
+}
+
+impl<B: Blocks> Blocks for Recorder<B> {
+    fn block(&self, cid: &Cid) -> Result<Option<&[u8]>, ExportError> {
+        let data = self.blocks.block(cid)?;
+        let mut read = self.read.borrow_mut();
+        if data.is_some() && read.0.insert(*cid) {
+            read.1.push(*cid);
+        }
+        Ok(data)
+    }
+}
+
+/// The blocks of a response, checking that each of them is linked from the requested root
+/// through the others.
+///
+/// Only the CIDs are kept, as the links of a block are read as it's received. Since blocks
+/// can't link to themselves through their descendants, a block linked from any block received
+/// is reached from the root, unless its ancestors lead to one that's linked from none.
+#[derive(Debug, Clone)]
+pub(crate) struct LinkedBlocks {
+    received: Vec<Cid>,
+    linked: HashSet<Cid>,
+}
+
+impl LinkedBlocks {
+    pub(crate) fn new(root: Cid) -> Self {
+        Self {
+            received: vec![],
+            linked: HashSet::from([root]),
+        }
+    }
+
+    /// Records block `cid`, whose data was checked against it.
+    pub(crate) fn insert(&mut self, cid: Cid, data: &[u8]) -> Result<(), ExportError> {
+        if cid.codec() == DAG_PB {
+            let node =
+                decode_node(data).map_err(|e| ExportError::InvalidNode(cid, e.to_string()))?;
+            self.linked.extend(node.links.iter().map(|link| link.cid));
+        }
+        self.received.push(cid);
+        Ok(())
+    }
+
+    /// The first block received that no block links to, the root aside.
+    pub(crate) fn unlinked(&self) -> Option<Cid> {
+        self.received
+            .iter()
+            .find(|cid| !self.linked.contains(cid))
+            .copied()
+    }
+}
+
+/// Reads the blocks `request` asks for, returning the CID the path resolves to along with
+/// the file it ends at, as [`GatewayResponse`] holds them.
+pub(crate) fn traverse(
+    blocks: &impl Blocks,
+    request: &GatewayRequest,
+) -> Result<(Cid, Option<Vec<u8>>), ExportError> {
+    let mut cid = request.root;
+    let mut resolved = String::new();
+    for name in request.names() {
+        let entry = find_entry(blocks, cid, name, &resolved)?;
+        resolved = format!("{resolved}/{name}");
+        cid = entry.ok_or_else(|| ExportError::NotFound(resolved.clone()))?;
+    }
+
+    let data = load(blocks, &cid)?.ok_or(ExportError::MissingBlock(cid))?;
+    if request.scope == DagScope::Block {
+        return Ok((cid, None));
+    }
+    if request.scope == DagScope::All {
+        traverse_dag(blocks, cid)?;
+    }
+
+    let size = match cid.codec() {
+        RAW => data.len() as u64,
+        _ => {
+            let (node, unixfs) = decode_unixfs(cid, data)?;
+            match unixfs.data_type {
+                DATA_TYPE_FILE | DATA_TYPE_RAW => unixfs.filesize.unwrap_or_else(|| {
+                    unixfs.data.len() as u64 + unixfs.blocksizes.iter().sum::<u64>()
+                }),
+                DATA_TYPE_DIRECTORY => return Ok((cid, None)),
+                DATA_TYPE_HAMT_SHARD => {
+                    traverse_shard(blocks, &node.links)?;
+                    return Ok((cid, None));
+                }
+                data_type => return Err(ExportError::NotAFile(cid, data_type)),
+            }
+        }
+    };
+
+    let range = request.entity_bytes.clone().unwrap_or(0..size);
+    let range = range.start.min(size)..range.end.min(size);
+    let mut out = vec![];
+    if !range.is_empty() {
+        read_range(blocks, cid, 0, size, &range, &mut out)?;
+    }
+    Ok((cid, Some(out)))
+}
+
+/// Reads every block of the DAG below `cid`.
+fn traverse_dag(blocks: &impl Blocks, cid: Cid) -> Result<(), ExportError> {
+    let mut stack = vec![cid];
+    let mut seen = HashSet::new();
+    while let Some(cid) = stack.pop() {
+        if !seen.insert(cid) {
+            continue;
+        }
+        let data = load(blocks, &cid)?.ok_or(ExportError::MissingBlock(cid))?;
+        match cid.codec() {
+            RAW => {}
+            DAG_PB => {
+                let node =
+                    decode_node(data).map_err(|e| ExportError::InvalidNode(cid, e.to_string()))?;
+                stack.extend(node.links.iter().rev().map(|link| link.cid));
+            }
+            codec => return Err(ExportError::UnsupportedCodec(codec, cid)),
+        }
+    }
+    Ok(())
+}
+
+/// Reads the inner shards of a HAMT directory, sub-shards being named after their bucket
+/// alone.
+fn traverse_shard(blocks: &impl Blocks, links: &[PbLink]) -> Result<(), ExportError> {
+    for link in links.iter().filter(|link| link.name.len() == 2) {
+        let data = load(blocks, &link.cid)?.ok_or(ExportError::MissingBlock(link.cid))?;
+        let (node, _) = decode_unixfs(link.cid, data)?;
+        traverse_shard(blocks, &node.links)?;
+    }
+    Ok(())
+}
+
+/// Verifies a response to `request`.
+///
+/// Every block is checked against its CID, the CAR roots must include the requested root,
+/// none of the blocks [`traverse`] reads may be missing, and every other block must be linked
+/// from the root, as [`LinkedBlocks`] checks.
+pub fn verify_response(
+    request: &GatewayRequest,
+    car: Vec<u8>,
+) -> Result<GatewayResponse, GatewayError> {
+    let mut decoder = CarDecoder::new();
+    decoder.push(&car);
+    let mut blocks = HashMap::new();
+    let mut linked = LinkedBlocks::new(request.root);
+    while let Some((_, block)) = decoder.next_block()? {
+        verify_block(&block)?;
+        linked.insert(block.cid, &block.data)?;
+        blocks.insert(block.cid, block.data);
+    }
+    decoder.finish()?;
+
+    let roots = &decoder.header().ok_or(CarError::Truncated)?.roots;
+    if !roots.contains(&request.root) {
+        return Err(GatewayError::UnexpectedRoots {
+            expected: request.root.to_string(),
+            actual: roots.iter().map(Cid::to_string).collect(),
+        });
+    }
+
+    let (cid, data) = traverse(&blocks, request)?;
+    if let Some(unexpected) = linked.unlinked() {
+        return Err(GatewayError::UnexpectedBlock(unexpected));
+    }
+    Ok(GatewayResponse { cid, data, car })
+}
+
+/// A client of a trustless gateway.
+#[derive(Debug, Clone)]
+pub struct GatewayClient<F> {
+    fetcher: F,
+    url: String,
+}
+
+impl<F: Fetch> GatewayClient<F> {
+    /// A client of the gateway at `url`, e.g. `https://ipfs.io`.
+    pub fn new(fetcher: F, url: impl Into<String>) -> Self {
+        Self {
+            fetcher,
+            url: url.into(),
+        }
+    }
+
+    /// Sends `request` and verifies the response.
+    pub async fn fetch(&self, request: &GatewayRequest) -> Result<GatewayResponse, GatewayError> {
+        if let Some(range) = &request.entity_bytes {
+            if range.start >= range.end {
+                return Err(ExportError::InvalidRange {
+                    start: range.start,
+                    end: range.end,
+                }
+                .into());
+            }
+        }
+
+        let url = request.url(&self.url);
+        let response = self
+            .fetcher
+            .fetch(HttpRequest::get(&url).header("Accept", CAR_ACCEPT))
+            .await?;
+        if !response.is_success() {
+            return Err(FetchError::Status {
+                url,
+                status: response.status,
+            }
+            .into());
+        }
+        if let Some(content_type) = response.header("Content-Type") {
+            if !content_type.starts_with(CAR_MEDIA_TYPE) {
+                return Err(GatewayError::ContentType(content_type.to_string()));
+            }
+        }
+        verify_response(request, response.body)
+    }
+}
+
+/// Fetches a file from a trustless gateway, verifying every block received.
+///
+/// # Arguments
+/// * `gateway_url` - The gateway's base URL, e.g. `https://ipfs.io`.
+/// * `root` - The root CID of the content.
+/// * `path` - The path of the file below the root, empty for the root itself.
+/// * `start`, `end` - The (exclusive) byte range to fetch, the whole file if unset.
+///
+/// # Returns
+/// The file bytes, or those of the range.
+#[wasm_bindgen(js_name = "fetchFromGateway")]
+pub async fn fetch_from_gateway_js(
+    gateway_url: String,
+    root: String,
+    path: String,
+    start: Option<u64>,
+    end: Option<u64>,
+) -> Result<Vec<u8>, JsValue> {
+    let root = Cid::try_from(root.as_str()).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let mut request = GatewayRequest::new(root).path(path).scope(DagScope::Entity);
+    if start.is_some() || end.is_some() {
+        request = request.entity_bytes(start.unwrap_or(0)..end.unwrap_or(u64::MAX));
+    }
+
+    let response = GatewayClient::new(BrowserFetch, gateway_url)
+        .fetch(&request)
+        .await
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    response
+        .data
+        .ok_or_else(|| JsValue::from_str(&format!("{} is a directory", response.cid)))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+    use crate::{
+        car::{directory::DirectoryCarBuilder, index::IndexedCar},
+        fetch::HttpResponse,
+        ipld::Block,
+        testing::{encode_car, status, MockFetch, MockRequest, MockServer},
+    };
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// A directory holding `photos/a.jpg`, `photos/b.txt` and `readme`, and its root.
+    fn directory() -> (Cid, Vec<u8>) {
+        let mut builder = DirectoryCarBuilder::new(vec![]);
+        builder.add_file("photos/a.jpg").unwrap();
+        builder.write(&pattern(600 * 1024)).unwrap();
+        builder.add_file("photos/b.txt").unwrap();
+        builder.write(b"b").unwrap();
+        builder.add_file("read me").unwrap();
+        builder.write(b"read me").unwrap();
+        let output = builder.finish().unwrap();
+        let car = [output.header, output.out, output.index].concat();
+        (output.root, car)
+    }
+
+    /// Parses a request URL path the way a gateway does.
+    fn parse(path: &str) -> GatewayRequest {
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        let mut request = GatewayRequest::from_path(path).unwrap();
+        for (key, value) in query.split('&').filter_map(|param| param.split_once('=')) {
+            match key {
+                "dag-scope" => {
+                    request.scope = [DagScope::Block, DagScope::Entity, DagScope::All]
+                        .into_iter()
+                        .find(|scope| scope.as_str() == value)
+                        .unwrap();
+                }
+                "entity-bytes" => {
+                    let (start, end) = value.split_once(':').unwrap();
+                    let end = match end {
+                        "*" => u64::MAX,
+                        end => end.parse::<u64>().unwrap() + 1,
+                    };
+                    request.entity_bytes = Some(start.parse().unwrap()..end);
+                }
+                _ => {}
+            }
+        }
+        request
+    }
+
+    /// The links of block `cid` of `car`.
+    fn links(car: &[u8], cid: Cid) -> Vec<Cid> {
+        let data = IndexedCar::new(car).unwrap().get(&cid).unwrap().unwrap();
+        let node = decode_node(data).unwrap();
+        node.links.iter().map(|link| link.cid).collect()
+    }
+
+    /// The blocks of [`directory`], named.
+    struct Dag {
+        car: Vec<u8>,
+        root: Cid,
+        photos: Cid,
+        photo: Cid,
+        /// Of 256 KiB, but the last.
+        leaves: Vec<Cid>,
+        text: Cid,
+        readme: Cid,
+    }
+
+    impl Dag {
+        fn new() -> Self {
+            let (root, car) = directory();
+            let [photos, readme] = links(&car, root)[..] else {
+                panic!("expected photos and read me");
+            };
+            let [photo, text] = links(&car, photos)[..] else {
+                panic!("expected a.jpg and b.txt");
+            };
+            let leaves = links(&car, photo);
+            assert_eq!(leaves.len(), 3);
+            Self {
+                car,
+                root,
+                photos,
+                photo,
+                leaves,
+                text,
+                readme,
+            }
+        }
+
+        /// The blocks of `cids`, in order.
+        fn blocks(&self, cids: &[Cid]) -> Vec<(Cid, Vec<u8>)> {
+            let car = IndexedCar::new(&self.car).unwrap();
+            cids.iter()
+                .map(|cid| (*cid, car.get(cid).unwrap().unwrap().to_vec()))
+                .collect()
+        }
+
+        /// The blocks a gateway sends for each request URL path, as the trustless gateway
+        /// specification describes them.
+        fn responses(&self) -> Vec<(String, Vec<Cid>)> {
+            let &Dag {
+                root,
+                photos,
+                photo,
+                text,
+                readme,
+                ..
+            } = self;
+            let [l0, l1, l2] = self.leaves[..] else {
+                unreachable!()
+            };
+            let photo_path = format!("/ipfs/{root}/photos/a.jpg?dag-scope=entity");
+            vec![
+                (photo_path.clone(), vec![root, photos, photo, l0, l1, l2]),
+                (
+                    format!("{photo_path}&entity-bytes=270000:279999"),
+                    vec![root, photos, photo, l1],
+                ),
+                (
+                    format!("{photo_path}&entity-bytes=614000:*"),
+                    vec![root, photos, photo, l2],
+                ),
+                (
+                    format!("{photo_path}&entity-bytes=0:999"),
+                    vec![root, photos, photo, l0],
+                ),
+                (
+                    format!("/ipfs/{root}/read%20me?dag-scope=all"),
+                    vec![root, readme],
+                ),
+                (
+                    format!("/ipfs/{root}/photos?dag-scope=block"),
+                    vec![root, photos],
+                ),
+                (
+                    format!("/ipfs/{root}?dag-scope=all"),
+                    vec![root, photos, photo, l0, l1, l2, text, readme],
+                ),
+            ]
+        }
+    }
+
+    /// A gateway serving the responses of `dag`, their blocks being passed through `tamper`.
+    fn gateway(
+        dag: &Dag,
+        tamper: impl Fn(&mut Vec<(Cid, Vec<u8>)>) + Send + Sync + 'static,
+    ) -> MockServer {
+        let root = dag.root;
+        let responses = dag
+            .responses()
+            .into_iter()
+            .map(|(path, cids)| (path, dag.blocks(&cids)))
+            .collect::<HashMap<_, _>>();
+        MockServer::new(move |request: &MockRequest| {
+            let Some(blocks) = responses.get(&request.path) else {
+                return status(404);
+            };
+            let mut blocks = blocks.clone();
+            tamper(&mut blocks);
+            HttpResponse {
+                status: 200,
+                headers: vec![("Content-Type".to_string(), CAR_ACCEPT.to_string())],
+                body: encode_car(&root, &blocks),
+            }
+        })
+    }
+
+    #[wasm_bindgen_test]
+    async fn fetches_from_gateways() {
+        let dag = Dag::new();
+        let root = dag.root;
+        let server = gateway(&dag, |_| {});
+        let client = GatewayClient::new(MockFetch, server.url());
+        let photo = pattern(600 * 1024);
+
+        let request = GatewayRequest::new(root)
+            .path("/photos/a.jpg")
+            .scope(DagScope::Entity);
+        let response = client.fetch(&request).await.unwrap();
+        assert!(response.data.unwrap() == photo);
+        assert_eq!(response.cid, dag.photo);
+
+        let response = client
+            .fetch(&request.clone().entity_bytes(270_000..280_000))
+            .await
+            .unwrap();
+        assert!(response.data.unwrap() == photo[270_000..280_000]);
+
+        let response = client
+            .fetch(&request.clone().entity_bytes(614_000..u64::MAX))
+            .await
+            .unwrap();
+        assert!(response.data.unwrap() == photo[614_000..]);
+
+        let response = client
+            .fetch(&GatewayRequest::new(root).path("read me"))
+            .await
+            .unwrap();
+        assert_eq!(response.data.unwrap(), b"read me");
+
+        let request = GatewayRequest::new(root)
+            .path("photos")
+            .scope(DagScope::Block);
+        let response = client.fetch(&request).await.unwrap();
+        assert_eq!(response.cid, dag.photos);
+        assert_eq!(response.data, None);
+
+        let response = client.fetch(&GatewayRequest::new(root)).await.unwrap();
+        assert_eq!(response.data, None);
+
+        let requests = server.requests();
+        assert_eq!(
+            requests[1].path,
+            format!("/ipfs/{root}/photos/a.jpg?dag-scope=entity&entity-bytes=270000:279999")
+        );
+        assert_eq!(requests[0].header("Accept"), Some(CAR_ACCEPT));
+    }
+
+    #[wasm_bindgen_test]
+    async fn accepts_extra_linked_blocks() {
+        let dag = Dag::new();
+        let request = GatewayRequest::new(dag.root)
+            .path("/photos/a.jpg")
+            .entity_bytes(0..1000);
+        let (dag, request) = (&dag, &request);
+        let fetch = |extra: Vec<(Cid, Vec<u8>)>| async move {
+            let server = gateway(dag, move |blocks| blocks.extend(extra.iter().cloned()));
+            GatewayClient::new(MockFetch, server.url())
+                .fetch(request)
+                .await
+        };
+
+        // The whole file rather than the leaf holding the range
+        let response = fetch(dag.blocks(&dag.leaves[1..])).await.unwrap();
+        assert!(response.data.unwrap() == pattern(1000));
+        // A sibling of the file, and duplicates
+        let response = fetch(dag.blocks(&[dag.text, dag.photos, dag.leaves[0]]))
+            .await
+            .unwrap();
+        assert!(response.data.unwrap() == pattern(1000));
+        // Another entry of the root directory
+        assert!(fetch(dag.blocks(&[dag.readme])).await.is_ok());
+    }
+
+    #[wasm_bindgen_test]
+    async fn rejects_untrusted_responses() {
+        let dag = Dag::new();
+        let request = GatewayRequest::new(dag.root)
+            .path("/photos/a.jpg")
+            .entity_bytes(0..1000);
+        let (dag, request) = (&dag, &request);
+        let fetch = |tamper: fn(&mut Vec<(Cid, Vec<u8>)>)| async move {
+            let server = gateway(dag, tamper);
+            GatewayClient::new(MockFetch, server.url())
+                .fetch(request)
+                .await
+        };
+
+        assert!(fetch(|_| {}).await.is_ok());
+        assert!(matches!(
+            fetch(|blocks| blocks.last_mut().unwrap().1[0] ^= 1).await,
+            Err(GatewayError::Car(CarError::HashMismatch(_)))
+        ));
+        assert!(matches!(
+            fetch(|blocks| {
+                blocks.pop();
+            })
+            .await,
+            Err(GatewayError::Export(ExportError::MissingBlock(_)))
+        ));
+        assert!(matches!(
+            fetch(|blocks| {
+                let stray = Block::new(RAW, b"stray".to_vec());
+                blocks.push((stray.cid, stray.data));
+            })
+            .await,
+            Err(GatewayError::UnexpectedBlock(_))
+        ));
+        // A leaf of the file, without the blocks linking to it
+        assert!(matches!(
+            fetch(|blocks| {
+                let leaf = blocks.pop().unwrap();
+                blocks.truncate(1);
+                blocks.push(leaf);
+            })
+            .await,
+            Err(GatewayError::Export(ExportError::MissingBlock(_)))
+        ));
+
+        let server = MockServer::new(|_| HttpResponse {
+            status: 200,
+            headers: vec![("Content-Type".to_string(), "text/html".to_string())],
+            body: b"<html>".to_vec(),
+        });
+        let client = GatewayClient::new(MockFetch, server.url());
+        assert!(matches!(
+            client.fetch(request).await,
+            Err(GatewayError::ContentType(_))
+        ));
+
+        // Valid blocks of another DAG
+        let server = MockServer::new(|_| {
+            let other = Block::new(RAW, b"other".to_vec());
+            HttpResponse {
+                status: 200,
+                headers: vec![],
+                body: encode_car(&other.cid, &[(other.cid, other.data)]),
+            }
+        });
+        assert!(matches!(
+            GatewayClient::new(MockFetch, server.url())
+                .fetch(request)
+                .await,
+            Err(GatewayError::UnexpectedRoots { .. })
+        ));
+
+        let server = MockServer::new(|_| status(404));
+        assert!(matches!(
+            GatewayClient::new(MockFetch, server.url())
+                .fetch(request)
+                .await,
+            Err(GatewayError::Fetch(FetchError::Status { status: 404, .. }))
+        ));
+    }
+}
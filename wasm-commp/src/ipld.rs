@@ -0,0 +1,123 @@
+//! IPLD blocks and the varint encoding shared by the CAR and UnixFS code.
+use std::io::{self, Read};
+
+use cid::{multihash::Multihash, Cid, Version};
+use sha2::{Digest, Sha256};
+
+/// Multicodec of raw binary blocks.
+pub const RAW: u64 = 0x55;
+/// Multicodec of DAG-PB blocks.
+pub const DAG_PB: u64 = 0x70;
+/// Multihash code of SHA2-256.
+pub const SHA2_256: u64 = 0x12;
+
+/// Length of a binary CIDv0, a bare SHA2-256 multihash.
+pub const CIDV0_LEN: usize = 34;
+/// Length of a binary CIDv1 with a SHA2-256 multihash and a single byte codec.
+pub const CIDV1_SHA256_LEN: usize = 36;
+
+/// A block of data along with its CID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    pub cid: Cid,
+    pub data: Vec<u8>,
+}
+
+impl Block {
+    /// Creates a block with a CIDv1 of the given codec and a SHA2-256 hash.
+    pub fn new(codec: u64, data: Vec<u8>) -> Self {
+        Self::with_version(Version::V1, codec, data)
+    }
+
+    /// Creates a block with a CID of the given version and codec and a SHA2-256 hash.
+    ///
+    /// # Panics
+    /// If `version` is [`Version::V0`] and `codec` isn't [`DAG_PB`].
+    pub fn with_version(version: Version, codec: u64, data: Vec<u8>) -> Self {
+        let cid =
+            Cid::new(version, codec, sha256_multihash(&data)).expect("CIDv0 blocks must be DAG-PB");
+        Self { cid, data }
+    }
+}
+
+/// Hashes `data` into a SHA2-256 multihash.
+pub fn sha256_multihash(data: &[u8]) -> Multihash<64> {
+    Multihash::wrap(SHA2_256, &Sha256::digest(data)).expect("SHA2-256 digests fit in a multihash")
+}
+
+/// Appends the unsigned LEB128 encoding of `value` to `out`.
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Number of bytes taken by the varint encoding of `value`.
+pub fn varint_len(value: u64) -> usize {
+    (64 - (value | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+/// Decodes a varint from the start of `bytes`, returning it along with its length.
+pub fn decode_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Reads a varint from `reader`, returning `None` if the reader is already at its end.
+pub fn read_varint<R: Read>(reader: &mut R) -> io::Result<Option<u64>> {
+    let mut value = 0u64;
+    for i in 0..10 {
+        let mut byte = [0];
+        if reader.read(&mut byte)? == 0 {
+            if i == 0 {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        value |= u64::from(byte[0] & 0x7f) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "varint is longer than 10 bytes",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_roundtrip() {
+        for value in [0, 1, 127, 128, 300, 0x0401, u32::MAX as u64, u64::MAX] {
+            let mut encoded = vec![];
+            write_varint(&mut encoded, value);
+            assert_eq!(encoded.len(), varint_len(value));
+            assert_eq!(decode_varint(&encoded), Some((value, encoded.len())));
+            assert_eq!(read_varint(&mut &encoded[..]).unwrap(), Some(value));
+        }
+        assert_eq!(read_varint(&mut &[][..]).unwrap(), None);
+        assert_eq!(decode_varint(&[0x80]), None);
+    }
+
+    #[test]
+    fn raw_block_cid() {
+        // `ipfs add --raw-leaves --cid-version=1` of "hello world\n"
+        let block = Block::new(RAW, b"hello world\n".to_vec());
+        assert_eq!(
+            block.cid.to_string(),
+            "bafkreifjjcie6lypi6ny7amxnfftagclbuxndqonfipmb64f2km2devei4"
+        );
+        assert_eq!(block.cid.encoded_len(), CIDV1_SHA256_LEN);
+    }
+}
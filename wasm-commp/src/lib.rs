@@ -1,4 +1,4 @@
-use std::io::{Cursor, Read};
+use std::io::{self, Cursor, Read};
 
 use primitives::{
     commitment::{piece::PaddedPieceSize, CommP, Commitment},
@@ -13,8 +13,29 @@ use wasm_bindgen::prelude::*;
 
 use crate::{fr32_reader::Fr32Reader, hasher::Sha256, zero_reader::ZeroPaddingReader};
 
+mod car;
+mod commitment;
+mod commp_writer;
+mod dedup;
+mod exporter;
+mod fetch;
 mod fr32_reader;
+mod gateway;
 mod hasher;
+mod ipld;
+mod multi_retrieval;
+mod pipeline;
+mod proofs;
+mod resumable;
+mod retrieval;
+mod self_test;
+mod service_worker;
+mod split;
+mod spot_check;
+#[cfg(test)]
+mod testing;
+mod unixfs;
+mod unsealed;
 mod zero_reader;
 
 #[wasm_bindgen]
@@ -65,12 +86,8 @@ pub fn commp_from_bytes(data: &[u8]) -> Result<JsValue, JsValue> {
     let file_size = data.len() as u64;
     let padded_piece_size = PaddedPieceSize::from_arbitrary_size(file_size);
 
-    // Compute unpadded size, apply zero-padding accordingly
-    let padded_with_zeroes = *padded_piece_size.unpadded();
-    let buffered = Cursor::new(data);
-    let mut zero_padding_reader = ZeroPaddingReader::new(buffered, padded_with_zeroes);
-
-    let commitment = calculate_piece_commitment(&mut zero_padding_reader, padded_piece_size)?;
+    let commitment =
+        piece_commitment(data, padded_piece_size).map_err(|e| JsValue::from_str(&e.to_string()))?;
 
     info!("CID from Rust: {}", commitment.cid());
 
@@ -94,12 +111,41 @@ pub fn padded_piece_size(data: &[u8]) -> Result<JsValue, JsValue> {
     Ok(JsValue::from_str(&padded_piece_size.to_string()))
 }
 
+/// Calculates the piece commitment (CommP) of `data` stored in a piece of `piece_size`.
+///
+/// The data is zero-padded up to the unpadded size of the piece before the commitment
+/// is calculated, as such, `data` must fit in the piece.
+///
+/// # Arguments
+/// * `data` - The original unpadded bytes.
+/// * `piece_size` - The padded size of the piece holding `data`.
+///
+/// # Returns
+/// A `Commitment<CommP>` containing the Merkle root.
+pub fn piece_commitment(data: &[u8], piece_size: PaddedPieceSize) -> io::Result<Commitment<CommP>> {
+    // Compute unpadded size, apply zero-padding accordingly
+    let padded_with_zeroes = *piece_size.unpadded();
+    if data.len() as u64 > padded_with_zeroes {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} bytes do not fit in a piece of {}",
+                data.len(),
+                piece_size
+            ),
+        ));
+    }
+
+    let buffered = Cursor::new(data);
+    let zero_padding_reader = ZeroPaddingReader::new(buffered, padded_with_zeroes);
+
+    calculate_piece_commitment(zero_padding_reader, piece_size)
+}
+
 /// Calculates the piece commitment (CommP) for a data stream with a given padded piece size.
 ///
 /// This function:
-/// - Wraps the input in an `Fr32Reader` to apply Fr32 bit-padding.
-/// - Splits data into `NODE_SIZE` chunks to generate Merkle tree leaves.
-/// - Constructs a Merkle tree using `Sha256` (masked).
+/// - Builds the piece Merkle tree with [`build_piece_tree`].
 /// - Returns the root hash as a `Commitment<CommP>`.
 ///
 /// # Arguments
@@ -111,7 +157,32 @@ pub fn padded_piece_size(data: &[u8]) -> Result<JsValue, JsValue> {
 pub fn calculate_piece_commitment<R: Read>(
     source: R,
     piece_size: PaddedPieceSize,
-) -> Result<Commitment<CommP>, JsValue> {
+) -> io::Result<Commitment<CommP>> {
+    let tree = build_piece_tree(source, piece_size)?;
+    let raw = tree
+        .root()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Merkle tree is empty"))?;
+
+    Ok(raw.into())
+}
+
+/// Builds the piece Merkle tree for a data stream with a given padded piece size.
+///
+/// This function:
+/// - Wraps the input in an `Fr32Reader` to apply Fr32 bit-padding.
+/// - Splits data into `NODE_SIZE` chunks to generate Merkle tree leaves.
+/// - Constructs a Merkle tree using `Sha256` (masked).
+///
+/// # Arguments
+/// * `source` - A reader over the padded input data.
+/// * `piece_size` - The padded piece size in bytes.
+///
+/// # Returns
+/// The whole tree, whose root is the piece commitment.
+pub fn build_piece_tree<R: Read>(
+    source: R,
+    piece_size: PaddedPieceSize,
+) -> io::Result<MerkleTree<Sha256>> {
     let mut fr32_reader = Fr32Reader::new(source);
     let mut buffer = [0; NODE_SIZE];
     let num_leafs = piece_size.div_ceil(NODE_SIZE as u64) as usize;
@@ -120,17 +191,12 @@ pub fn calculate_piece_commitment<R: Read>(
         .map(|_| {
             fr32_reader
                 .read_exact(&mut buffer)
-                .map_err(|e| JsValue::from_str(&format!("Read error: {}", e)))?;
+                .map_err(|e| io::Error::new(e.kind(), format!("Read error: {}", e)))?;
             Ok(buffer)
         })
-        .collect::<Result<Vec<_>, JsValue>>()?;
+        .collect::<io::Result<Vec<_>>>()?;
 
-    let tree = MerkleTree::<Sha256>::from_leaves(&leaves);
-    let raw = tree
-        .root()
-        .ok_or_else(|| JsValue::from_str("Merkle tree is empty"))?;
-
-    Ok(raw.into())
+    Ok(MerkleTree::<Sha256>::from_leaves(&leaves))
 }
 
 #[cfg(test)]
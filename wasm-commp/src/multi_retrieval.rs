@@ -0,0 +1,638 @@
+//! Retrieval of a piece from several providers at once.
+//!
+//! Deals are usually replicated to several providers, so the piece is split into ranges
+//! fetched in parallel from all the providers holding it, a range a provider fails to serve
+//! being retried on another one. Each range covers an aligned subtree of the piece Merkle
+//! tree, the piece commitment being the root of the tree over the subtree roots computed from
+//! the ranges received.
+//!
+//! A wrong commitment doesn't tell which range is wrong, so the ranges not yet confirmed by two
+//! providers are then fetched again from another provider, and the bytes of each range are
+//! picked by vote, the bytes sent by the most providers winning. Should the commitment still
+//! be wrong, each provider is left out in turn, which gets past a single corrupt provider
+//! outvoting the others on a range. Every round fetches each range at most once more and
+//! tries one pick per provider, so retrievals from `n` providers end after at most `n` rounds.
+//! The providers whose bytes weren't picked are reported as corrupt.
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    iter,
+    ops::Range,
+};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use primitives::commitment::piece::PaddedPieceSize;
+use serde::Serialize;
+use thiserror::Error;
+use tracing::info;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    commitment::{hash_pair, zero_commitment},
+    commp_writer::CommPWriter,
+    fetch::{fetch_range, BrowserFetch, Fetch},
+    spot_check::piece_cid_root,
+};
+
+/// Default padded size of the ranges a piece is split into.
+pub const DEFAULT_RANGE_SIZE: u64 = 8 << 20;
+/// Default number of ranges fetched at once.
+pub const DEFAULT_PARALLEL_REQUESTS: usize = 4;
+
+#[derive(Debug, Error)]
+pub enum MultiRetrievalError {
+    #[error("invalid piece CID: {0}")]
+    InvalidPieceCid(String),
+    #[error("invalid piece size {0}: {1}")]
+    InvalidPieceSize(u64, String),
+    #[error("range size must be a power of two of at least 512 bytes, got {0}")]
+    InvalidRangeSize(u64),
+    #[error("no providers to retrieve the piece from")]
+    NoProviders,
+    #[error("no provider served bytes {start}..{end}: {reason}")]
+    Unavailable {
+        start: u64,
+        end: u64,
+        reason: String,
+    },
+    #[error("the ranges received don't match the piece CID {0}, whichever provider is left out")]
+    Unverified(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// How a provider took part in a retrieval.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderReport {
+    /// The provider's base URL.
+    pub url: String,
+    /// Number of ranges of the result it served.
+    pub ranges: u64,
+    /// Number of requests it failed to answer.
+    pub failures: u64,
+    /// Whether it served data not matching the piece CID.
+    pub corrupt: bool,
+}
+
+/// A piece retrieved and verified by [`MultiRetrieval`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetrievedPiece {
+    /// The unpadded piece bytes, without the trailing zeros the providers left out.
+    pub data: Vec<u8>,
+    pub providers: Vec<ProviderReport>,
+}
+
+/// Bytes received for a range, along with the providers that sent them.
+struct Candidate {
+    root: [u8; 32],
+    bytes: Vec<u8>,
+    providers: Vec<usize>,
+}
+
+/// What has been received for a range.
+#[derive(Default)]
+struct RangeState {
+    /// The providers it was requested from.
+    tried: Vec<usize>,
+    candidates: Vec<Candidate>,
+}
+
+/// Retrieves a piece from several providers holding it.
+#[derive(Debug, Clone)]
+pub struct MultiRetrieval<F> {
+    fetcher: F,
+    piece_cid: String,
+    expected: [u8; 32],
+    piece_size: PaddedPieceSize,
+    providers: Vec<String>,
+    range_size: u64,
+    parallel_requests: usize,
+}
+
+impl<F: Fetch> MultiRetrieval<F> {
+    /// Retrieves piece `piece_cid` of `piece_size` padded bytes, as stored on-chain, from the
+    /// `providers` given by their base URLs, e.g. `http://127.0.0.1:8001`.
+    pub fn new(
+        fetcher: F,
+        piece_cid: &str,
+        piece_size: u64,
+        providers: Vec<String>,
+    ) -> Result<Self, MultiRetrievalError> {
+        let expected = piece_cid_root(piece_cid)
+            .map_err(|e| MultiRetrievalError::InvalidPieceCid(e.to_string()))?;
+        let piece_size = PaddedPieceSize::new(piece_size)
+            .map_err(|e| MultiRetrievalError::InvalidPieceSize(piece_size, e.to_string()))?;
+        if providers.is_empty() {
+            return Err(MultiRetrievalError::NoProviders);
+        }
+        Ok(Self {
+            fetcher,
+            piece_cid: piece_cid.to_string(),
+            expected,
+            piece_size,
+            providers,
+            range_size: DEFAULT_RANGE_SIZE,
+            parallel_requests: DEFAULT_PARALLEL_REQUESTS,
+        })
+    }
+
+    /// Sets the padded size of the ranges, a power of two, ranges larger than the piece
+    /// standing for the whole piece.
+    pub fn range_size(mut self, range_size: u64) -> Result<Self, MultiRetrievalError> {
+        if !range_size.is_power_of_two() || range_size < 512 {
+            return Err(MultiRetrievalError::InvalidRangeSize(range_size));
+        }
+        self.range_size = range_size;
+        Ok(self)
+    }
+
+    /// Sets the number of ranges fetched at once, at least one.
+    pub fn parallel_requests(mut self, parallel_requests: usize) -> Self {
+        self.parallel_requests = parallel_requests.max(1);
+        self
+    }
+
+    /// The unpadded byte ranges of the subtrees the piece is split into.
+    fn ranges(&self) -> Vec<Range<u64>> {
+        let range_size = self.range_size.min(*self.piece_size);
+        let unpadded = range_size / 128 * 127;
+        (0..*self.piece_size / range_size)
+            .map(|i| i * unpadded..(i + 1) * unpadded)
+            .collect()
+    }
+
+    fn piece_url(&self, provider: usize) -> String {
+        format!(
+            "{}/api/v0/download/{}",
+            self.providers[provider].trim_end_matches('/'),
+            self.piece_cid
+        )
+    }
+
+    /// Fetches range `index` from `provider` and computes its subtree root.
+    async fn request_range(
+        &self,
+        index: usize,
+        range: Range<u64>,
+        provider: usize,
+    ) -> (usize, usize, Result<(Vec<u8>, [u8; 32]), String>) {
+        let result = match fetch_range(&self.fetcher, &self.piece_url(provider), range).await {
+            Ok(bytes) => subtree_root(&bytes, self.range_size.min(*self.piece_size))
+                .map(|root| (bytes, root))
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        (index, provider, result)
+    }
+
+    /// The next provider to request range `index` from, skipping the ones it was already
+    /// requested from and, while possible, the ones that failed before.
+    fn next_provider(&self, index: usize, state: &RangeState, failed: &[u64]) -> Option<usize> {
+        let count = self.providers.len();
+        let untried = (0..count)
+            .map(|i| (index + state.tried.len() + i) % count)
+            .filter(|provider| !state.tried.contains(provider));
+        let mut fallback = None;
+        for provider in untried {
+            if failed[provider] == 0 {
+                return Some(provider);
+            }
+            fallback = fallback.or(Some(provider));
+        }
+        fallback
+    }
+
+    /// Requests each of the ranges `wanted` from one more provider, in parallel, moving on to
+    /// the next provider when one fails. Ranges received from no provider at all fail the
+    /// retrieval.
+    async fn fetch_round(
+        &self,
+        ranges: &[Range<u64>],
+        states: &mut [RangeState],
+        wanted: impl IntoIterator<Item = usize>,
+        failures: &mut [u64],
+    ) -> Result<(), MultiRetrievalError> {
+        let mut queue = wanted.into_iter().collect::<VecDeque<_>>();
+        let mut pending = FuturesUnordered::new();
+        let mut errors = vec![String::new(); ranges.len()];
+
+        loop {
+            while pending.len() < self.parallel_requests {
+                let Some(index) = queue.pop_front() else {
+                    break;
+                };
+                let Some(provider) = self.next_provider(index, &states[index], failures) else {
+                    if states[index].candidates.is_empty() {
+                        return Err(MultiRetrievalError::Unavailable {
+                            start: ranges[index].start,
+                            end: ranges[index].end,
+                            reason: errors[index].clone(),
+                        });
+                    }
+                    continue;
+                };
+                states[index].tried.push(provider);
+                pending.push(self.request_range(index, ranges[index].clone(), provider));
+            }
+
+            let Some((index, provider, result)) = pending.next().await else {
+                return Ok(());
+            };
+            match result {
+                Ok((bytes, root)) => {
+                    let candidates = &mut states[index].candidates;
+                    match candidates
+                        .iter_mut()
+                        .find(|candidate| candidate.root == root)
+                    {
+                        Some(candidate) => candidate.providers.push(provider),
+                        None => candidates.push(Candidate {
+                            root,
+                            bytes,
+                            providers: vec![provider],
+                        }),
+                    }
+                }
+                Err(reason) => {
+                    failures[provider] += 1;
+                    errors[index] = format!("{}: {}", self.providers[provider], reason);
+                    queue.push_back(index);
+                }
+            }
+        }
+    }
+
+    /// Picks the candidate of each range sent by the most providers, not counting `excluded`,
+    /// the first one received winning a tie. `None` if a range then has no candidate.
+    fn pick<'a>(
+        &self,
+        states: &'a [RangeState],
+        excluded: Option<usize>,
+    ) -> Option<Vec<&'a Candidate>> {
+        let votes = |candidate: &Candidate| {
+            candidate
+                .providers
+                .iter()
+                .filter(|&&provider| Some(provider) != excluded)
+                .count()
+        };
+        states
+            .iter()
+            .map(|state| {
+                state
+                    .candidates
+                    .iter()
+                    .filter(|candidate| votes(candidate) > 0)
+                    .rev()
+                    .max_by_key(|candidate| votes(candidate))
+            })
+            .collect()
+    }
+
+    /// Retrieves and verifies the piece.
+    pub async fn retrieve(&self) -> Result<RetrievedPiece, MultiRetrievalError> {
+        let ranges = self.ranges();
+        let mut states = ranges
+            .iter()
+            .map(|_| RangeState::default())
+            .collect::<Vec<_>>();
+        let mut failures = vec![0; self.providers.len()];
+
+        let mut wanted = (0..ranges.len()).collect::<Vec<_>>();
+        loop {
+            self.fetch_round(&ranges, &mut states, wanted, &mut failures)
+                .await?;
+
+            // The vote of every provider first, then of all of them but one
+            let exclusions = iter::once(None).chain((0..self.providers.len()).map(Some));
+            for excluded in exclusions {
+                let Some(picked) = self.pick(&states, excluded) else {
+                    continue;
+                };
+                let roots = picked.iter().map(|candidate| candidate.root).collect();
+                if root_of(roots) != self.expected {
+                    continue;
+                }
+
+                info!(
+                    "Retrieved piece {} from {} providers",
+                    self.piece_cid,
+                    self.providers.len()
+                );
+                return Ok(self.assemble(&ranges, &states, &picked, excluded, &failures));
+            }
+
+            // Ranges two providers agree on are settled, unless they're the only ones sent
+            wanted = (0..ranges.len())
+                .filter(|&index| {
+                    let state = &states[index];
+                    let settled = matches!(
+                        &state.candidates[..],
+                        [candidate] if candidate.providers.len() > 1
+                    );
+                    !settled && state.tried.len() < self.providers.len()
+                })
+                .collect();
+            if wanted.is_empty() {
+                return Err(MultiRetrievalError::Unverified(self.piece_cid.clone()));
+            }
+        }
+    }
+
+    fn assemble(
+        &self,
+        ranges: &[Range<u64>],
+        states: &[RangeState],
+        picked: &[&Candidate],
+        excluded: Option<usize>,
+        failures: &[u64],
+    ) -> RetrievedPiece {
+        let mut providers = self
+            .providers
+            .iter()
+            .zip(failures)
+            .map(|(url, &failures)| ProviderReport {
+                url: url.clone(),
+                ranges: 0,
+                failures,
+                corrupt: false,
+            })
+            .collect::<Vec<_>>();
+
+        let mut data = vec![];
+        for ((range, state), candidate) in ranges.iter().zip(states).zip(picked) {
+            let kept = candidate
+                .providers
+                .iter()
+                .find(|&&provider| Some(provider) != excluded)
+                .expect("picked candidates have a provider left");
+            providers[*kept].ranges += 1;
+            for rejected in state
+                .candidates
+                .iter()
+                .filter(|other| other.root != candidate.root)
+            {
+                for &provider in &rejected.providers {
+                    providers[provider].corrupt = true;
+                }
+            }
+
+            if !candidate.bytes.is_empty() {
+                data.resize(range.start as usize, 0);
+                data.extend_from_slice(&candidate.bytes);
+            }
+        }
+        RetrievedPiece { data, providers }
+    }
+}
+
+/// The root of the subtree of `size` padded bytes holding `bytes` followed by zeros.
+pub(crate) fn subtree_root(bytes: &[u8], size: u64) -> io::Result<[u8; 32]> {
+    if bytes.len() as u64 > size / 128 * 127 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} bytes do not fit in the range", bytes.len()),
+        ));
+    }
+    let mut commp = CommPWriter::new();
+    commp.write_all(bytes)?;
+    let (commitment, piece_size) = commp.finish(&[])?;
+    let mut root = commitment.raw();
+    let mut level = *piece_size;
+    while level < size {
+        root = hash_pair(&root, &zero_commitment(level));
+        level *= 2;
+    }
+    Ok(root)
+}
+
+/// The root of the tree over `roots`, a power of two of sibling subtree roots.
+pub(crate) fn root_of(mut roots: Vec<[u8; 32]>) -> [u8; 32] {
+    while roots.len() > 1 {
+        roots = roots
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    roots[0]
+}
+
+/// A piece retrieved by `retrieveFromProviders`.
+#[wasm_bindgen(js_name = "RetrievedPiece")]
+pub struct RetrievedPieceJs {
+    piece: RetrievedPiece,
+}
+
+#[wasm_bindgen(js_class = "RetrievedPiece")]
+impl RetrievedPieceJs {
+    /// The unpadded piece bytes, the CAR file possibly followed by zeros.
+    #[wasm_bindgen(getter)]
+    pub fn data(&self) -> Vec<u8> {
+        self.piece.data.clone()
+    }
+
+    /// `[{ url, ranges, failures, corrupt }]`, one entry per provider.
+    #[wasm_bindgen(getter)]
+    pub fn providers(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.piece.providers)?)
+    }
+}
+
+/// Retrieves a piece from several providers holding it in parallel, verifying it against its
+/// on-chain piece CID.
+///
+/// # Arguments
+/// * `piece_cid` - The piece CID as stored on-chain.
+/// * `piece_size` - The padded piece size as stored on-chain.
+/// * `providers` - The base URLs of the providers, e.g. `http://127.0.0.1:8001`.
+/// * `range_size` - The padded size of the ranges requested, 8 MiB if unset.
+/// * `parallel_requests` - The number of ranges fetched at once, 4 if unset.
+///
+/// # Returns
+/// The piece and how each provider took part.
+#[wasm_bindgen(js_name = "retrieveFromProviders")]
+pub async fn retrieve_from_providers_js(
+    piece_cid: String,
+    piece_size: u64,
+    providers: Vec<String>,
+    range_size: Option<u64>,
+    parallel_requests: Option<usize>,
+) -> Result<RetrievedPieceJs, JsValue> {
+    let to_js = |e: MultiRetrievalError| JsValue::from_str(&e.to_string());
+    let piece = MultiRetrieval::new(BrowserFetch, &piece_cid, piece_size, providers)
+        .and_then(|retrieval| retrieval.range_size(range_size.unwrap_or(DEFAULT_RANGE_SIZE)))
+        .map_err(to_js)?
+        .parallel_requests(parallel_requests.unwrap_or(DEFAULT_PARALLEL_REQUESTS))
+        .retrieve()
+        .await
+        .map_err(to_js)?;
+    Ok(RetrievedPieceJs { piece })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use primitives::commitment::{CommP, Commitment};
+
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+    use crate::{
+        piece_commitment,
+        testing::{range_response, status, MockFetch, MockServer},
+    };
+
+    /// 100 KiB of data in a piece of 128 KiB, split in 8 ranges.
+    const RANGE_SIZE: u64 = 16 * 1024;
+
+    fn piece() -> (Vec<u8>, String, u64) {
+        let data = (0..100 * 1024)
+            .map(|i| (i * 13 % 251) as u8)
+            .collect::<Vec<_>>();
+        let piece_size = PaddedPieceSize::new(128 * 1024).unwrap();
+        let piece_cid = piece_commitment(&data, piece_size)
+            .unwrap()
+            .cid()
+            .to_string();
+        (data, piece_cid, *piece_size)
+    }
+
+    fn provider(piece_cid: &str, data: Vec<u8>) -> MockServer {
+        let path = format!("/api/v0/download/{piece_cid}");
+        MockServer::with_files(HashMap::from([(path, data)]))
+    }
+
+    /// A provider flipping the `flipped` bits of the first byte of every range it serves but
+    /// the first one.
+    fn corrupt_provider(data: Vec<u8>, flipped: u8) -> MockServer {
+        MockServer::new(move |request| {
+            let mut response = range_response(request, &data);
+            if request.range().is_some_and(|(start, _)| start > 0) && !response.body.is_empty() {
+                response.body[0] ^= flipped;
+            }
+            response
+        })
+    }
+
+    async fn retrieve(
+        piece_cid: &str,
+        piece_size: u64,
+        providers: &[&str],
+    ) -> Result<RetrievedPiece, MultiRetrievalError> {
+        let providers = providers.iter().map(|url| url.to_string()).collect();
+        let retrieval = MultiRetrieval::new(MockFetch, piece_cid, piece_size, providers)?
+            .range_size(RANGE_SIZE)?
+            .parallel_requests(3);
+        retrieval.retrieve().await
+    }
+
+    #[wasm_bindgen_test]
+    async fn retrieves_from_several_providers() {
+        let (data, piece_cid, piece_size) = piece();
+        let servers = (0..3)
+            .map(|_| provider(&piece_cid, data.clone()))
+            .collect::<Vec<_>>();
+        let urls = servers.iter().map(MockServer::url).collect::<Vec<_>>();
+
+        let piece = retrieve(&piece_cid, piece_size, &urls).await.unwrap();
+        assert!(piece.data == data);
+        // The ranges are spread over the providers
+        for (server, report) in servers.iter().zip(&piece.providers) {
+            assert!(!server.requests().is_empty());
+            assert!(report.ranges > 0 && !report.corrupt);
+        }
+        assert_eq!(piece.providers.iter().map(|p| p.ranges).sum::<u64>(), 8);
+    }
+
+    #[wasm_bindgen_test]
+    async fn fails_over_to_other_providers() {
+        let (data, piece_cid, piece_size) = piece();
+        let honest = provider(&piece_cid, data.clone());
+        let failing = MockServer::new(|_| status(503));
+        // No server runs there
+        let down = "http://unreachable";
+
+        let piece = retrieve(&piece_cid, piece_size, &[failing.url(), down, honest.url()])
+            .await
+            .unwrap();
+        assert!(piece.data == data);
+        assert_eq!(piece.providers[2].ranges, 8);
+        assert!(piece.providers[0].failures > 0 && piece.providers[1].failures > 0);
+        // Failing providers are only tried again when no other provider is left
+        assert!(failing.requests().len() < 8);
+
+        let error = retrieve(&piece_cid, piece_size, &[failing.url(), down])
+            .await
+            .unwrap_err();
+        assert!(matches!(error, MultiRetrievalError::Unavailable { .. }));
+    }
+
+    #[wasm_bindgen_test]
+    async fn detects_corrupt_providers() {
+        let (data, piece_cid, piece_size) = piece();
+        let corrupt = corrupt_provider(data.clone(), 1);
+        let honest = provider(&piece_cid, data.clone());
+        let other = provider(&piece_cid, data.clone());
+
+        let piece = retrieve(
+            &piece_cid,
+            piece_size,
+            &[corrupt.url(), honest.url(), other.url()],
+        )
+        .await
+        .unwrap();
+        assert!(piece.data == data);
+        assert!(piece.providers[0].corrupt);
+        assert!(!piece.providers[1].corrupt && !piece.providers[2].corrupt);
+        // Only the first range it served is kept
+        assert!(piece.providers[0].ranges <= 1);
+
+        // Without another provider to compare with, nothing can be trusted
+        let error = retrieve(&piece_cid, piece_size, &[corrupt.url()])
+            .await
+            .unwrap_err();
+        assert!(matches!(error, MultiRetrievalError::Unverified(_)));
+    }
+
+    #[wasm_bindgen_test]
+    async fn outvotes_corrupt_providers() {
+        let (data, piece_cid, piece_size) = piece();
+        // Two corrupt providers sending different bytes for the same ranges
+        let servers = [
+            provider(&piece_cid, data.clone()),
+            corrupt_provider(data.clone(), 1),
+            corrupt_provider(data.clone(), 2),
+            provider(&piece_cid, data.clone()),
+            provider(&piece_cid, data.clone()),
+        ];
+        let urls = servers.iter().map(MockServer::url).collect::<Vec<_>>();
+
+        let piece = retrieve(&piece_cid, piece_size, &urls).await.unwrap();
+        assert!(piece.data == data);
+        for (i, report) in piece.providers.iter().enumerate() {
+            assert_eq!(report.corrupt, i == 1 || i == 2);
+        }
+        // No range is requested twice from the same provider
+        for server in &servers {
+            assert!(server.requests().len() <= 8);
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn computes_subtree_roots() {
+        let (data, piece_cid, piece_size) = piece();
+        let unpadded = RANGE_SIZE / 128 * 127;
+        let roots = data
+            .chunks(unpadded as usize)
+            .map(|chunk| subtree_root(chunk, RANGE_SIZE).unwrap())
+            .chain(std::iter::repeat(zero_commitment(RANGE_SIZE)))
+            .take((piece_size / RANGE_SIZE) as usize)
+            .collect();
+        let root = Commitment::<CommP>::from(root_of(roots));
+        assert_eq!(root.cid().to_string(), piece_cid);
+
+        assert!(subtree_root(&vec![0; unpadded as usize + 1], RANGE_SIZE).is_err());
+    }
+}
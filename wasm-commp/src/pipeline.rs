@@ -0,0 +1,542 @@
+//! Single pass file to CAR to piece commitment pipeline.
+//!
+//! The CAR bytes are fed to a [`CommPWriter`] as they are produced, the CAR headers (which
+//! depend on the root CID) being deferred until the end, so the file is only read once and
+//! neither it nor the CAR file have to be held in memory.
+//!
+//! CAR files built elsewhere are stored as they are instead, see [`prepare_car`].
+use std::io::{self, Write};
+
+use cid::Cid;
+use primitives::commitment::{piece::PaddedPieceSize, CommP, Commitment};
+use tracing::info;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    car::{
+        builder::{dag_options, file_info, FileInfo},
+        index::{attach_index, generate_index, IndexCodec},
+        reader::{validate_car, verify_block, CarReader},
+        CarError, CarV2Builder,
+    },
+    commp_writer::CommPWriter,
+    dedup::BlockRef,
+    unixfs::{DagOptions, DagOptionsError},
+};
+
+/// Forwards the CAR bytes to the piece commitment and, optionally, to an output.
+struct Tee<W> {
+    commp: CommPWriter,
+    out: Option<W>,
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.commp.write_all(buf)?;
+        if let Some(out) = &mut self.out {
+            out.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(out) = &mut self.out {
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of a [`FilePipeline`].
+pub struct PipelineOutput<W> {
+    /// The payload root CID.
+    pub root: Cid,
+    pub commitment: Commitment<CommP>,
+    pub piece_size: PaddedPieceSize,
+    /// Size of the CARv2 file.
+    pub car_size: u64,
+    /// The CAR headers, which go first in the file.
+    pub header: Vec<u8>,
+    /// The output the CAR blocks were written to.
+    pub out: Option<W>,
+    /// The CAR index, which goes last in the file.
+    pub index: Vec<u8>,
+    /// The blocks of the CAR file.
+    pub blocks: Vec<BlockRef>,
+}
+
+/// Turns a file into a CARv2 file and calculates its piece commitment in a single pass.
+pub struct FilePipeline<W: Write> {
+    builder: CarV2Builder<Tee<W>>,
+}
+
+impl<W: Write> FilePipeline<W> {
+    /// Creates a pipeline, writing the CAR blocks to `out` if given.
+    pub fn new(out: Option<W>) -> Self {
+        Self::with_options(out, DagOptions::default()).expect("default options are valid")
+    }
+
+    /// Creates a pipeline with the given DAG options.
+    pub fn with_options(out: Option<W>, options: DagOptions) -> Result<Self, DagOptionsError> {
+        Self::with_info(out, options, FileInfo::default())
+    }
+
+    /// Creates a pipeline with the given DAG options, keeping the name and metadata of the file.
+    pub fn with_info(
+        out: Option<W>,
+        options: DagOptions,
+        info: FileInfo,
+    ) -> Result<Self, DagOptionsError> {
+        let mut builder = CarV2Builder::with_info(
+            Tee {
+                commp: CommPWriter::new(),
+                out,
+            },
+            options,
+            info,
+        )?;
+        // The headers are only known once the root is, they're provided on finish
+        builder.get_mut().commp = CommPWriter::with_deferred_prefix(builder.header_len());
+        Ok(Self { builder })
+    }
+
+    /// The output the CAR blocks are written to.
+    pub fn get_mut(&mut self) -> Option<&mut W> {
+        self.builder.get_mut().out.as_mut()
+    }
+
+    /// Appends `data` to the file.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.builder.write(data)
+    }
+
+    /// Completes the CAR file and its piece commitment.
+    pub fn finish(self) -> io::Result<PipelineOutput<W>> {
+        let car = self.builder.finish()?;
+        let Tee { mut commp, out } = car.out;
+        commp.write_all(&car.index)?;
+
+        let car_size = commp.written();
+        let (commitment, piece_size) = commp.finish(&car.header)?;
+
+        Ok(PipelineOutput {
+            root: car.root,
+            commitment,
+            piece_size,
+            car_size,
+            header: car.header,
+            out,
+            index: car.index,
+            blocks: car.blocks,
+        })
+    }
+}
+
+/// The result of a `PiecePipeline`.
+#[wasm_bindgen]
+pub struct PipelineResult {
+    root_cid: String,
+    piece_cid: String,
+    piece_size: u64,
+    car_size: u64,
+    header: Vec<u8>,
+    trailer: Vec<u8>,
+    blocks: Vec<BlockRef>,
+}
+
+#[wasm_bindgen]
+impl PipelineResult {
+    /// The payload root CID.
+    #[wasm_bindgen(getter, js_name = "rootCid")]
+    pub fn root_cid(&self) -> String {
+        self.root_cid.clone()
+    }
+
+    /// The piece CID (CommP) of the CARv2 file.
+    #[wasm_bindgen(getter, js_name = "pieceCid")]
+    pub fn piece_cid(&self) -> String {
+        self.piece_cid.clone()
+    }
+
+    /// The padded piece size in bytes, as a string.
+    #[wasm_bindgen(getter, js_name = "pieceSize")]
+    pub fn piece_size(&self) -> String {
+        self.piece_size.to_string()
+    }
+
+    /// The CARv2 file size in bytes, as a string.
+    #[wasm_bindgen(getter, js_name = "carSize")]
+    pub fn car_size(&self) -> String {
+        self.car_size.to_string()
+    }
+
+    /// The CAR headers, which go first in the file. Only set when the CAR is emitted.
+    #[wasm_bindgen(getter)]
+    pub fn header(&self) -> Vec<u8> {
+        self.header.clone()
+    }
+
+    /// The remaining blocks and the index, which go last in the file. Only set when the CAR
+    /// is emitted.
+    #[wasm_bindgen(getter)]
+    pub fn trailer(&self) -> Vec<u8> {
+        self.trailer.clone()
+    }
+
+    /// The blocks of the CAR file, `[{ cid, size }]`, to be kept for `dedupStats`.
+    #[wasm_bindgen(getter)]
+    pub fn blocks(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(&self.blocks)?)
+    }
+}
+
+/// Calculates the payload CID, piece CID and piece size of a file in a single pass,
+/// optionally streaming out its CARv2 file.
+///
+/// ```js
+/// const pipeline = new PiecePipeline(true);
+/// const chunks = [];
+/// for await (const data of file.stream()) chunks.push(pipeline.write(data));
+/// const result = pipeline.finish();
+/// const car = new Blob([result.header, ...chunks, result.trailer]);
+/// ```
+#[wasm_bindgen]
+pub struct PiecePipeline {
+    pipeline: FilePipeline<Vec<u8>>,
+}
+
+#[wasm_bindgen]
+impl PiecePipeline {
+    /// Creates a pipeline, `emit_car` tells whether the CAR bytes are returned.
+    #[wasm_bindgen(constructor)]
+    pub fn new(emit_car: bool) -> PiecePipeline {
+        Self {
+            pipeline: FilePipeline::new(emit_car.then(Vec::new)),
+        }
+    }
+
+    /// Creates a pipeline with the DAG options taken by `generateCarV2WithOptions`.
+    #[wasm_bindgen(js_name = "withOptions")]
+    pub fn with_options(emit_car: bool, options: JsValue) -> Result<PiecePipeline, JsValue> {
+        let pipeline = FilePipeline::with_options(emit_car.then(Vec::new), dag_options(options)?)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(Self { pipeline })
+    }
+
+    /// Creates a pipeline with DAG options and the file info taken by
+    /// `CarV2Stream.withFileInfo`.
+    #[wasm_bindgen(js_name = "withFileInfo")]
+    pub fn with_file_info(
+        emit_car: bool,
+        options: JsValue,
+        info: JsValue,
+    ) -> Result<PiecePipeline, JsValue> {
+        let pipeline = FilePipeline::with_info(
+            emit_car.then(Vec::new),
+            dag_options(options)?,
+            file_info(info)?,
+        )
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(Self { pipeline })
+    }
+
+    /// Appends `data` to the file, returning the CAR bytes completed so far, if emitted.
+    pub fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, JsValue> {
+        self.pipeline
+            .write(data)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(self
+            .pipeline
+            .get_mut()
+            .map(std::mem::take)
+            .unwrap_or_default())
+    }
+
+    /// Completes the file.
+    pub fn finish(self) -> Result<PipelineResult, JsValue> {
+        let output = self
+            .pipeline
+            .finish()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+        info!(
+            "Root CID: {}, piece CID: {}, piece size: {}",
+            output.root,
+            output.commitment.cid(),
+            output.piece_size
+        );
+
+        let (header, trailer) = match output.out {
+            Some(mut trailer) => {
+                trailer.extend_from_slice(&output.index);
+                (output.header, trailer)
+            }
+            None => (vec![], vec![]),
+        };
+        Ok(PipelineResult {
+            root_cid: output.root.to_string(),
+            piece_cid: output.commitment.cid().to_string(),
+            piece_size: *output.piece_size,
+            car_size: output.car_size,
+            header,
+            trailer,
+            blocks: output.blocks,
+        })
+    }
+}
+
+/// A CAR file supplied by the user, ready to be stored as is.
+#[wasm_bindgen]
+pub struct PreparedCar {
+    roots: Vec<Cid>,
+    commitment: Commitment<CommP>,
+    piece_size: PaddedPieceSize,
+    car: Vec<u8>,
+    reindexed: bool,
+}
+
+/// Validates a CARv1 or CARv2 file built elsewhere and calculates its piece commitment,
+/// keeping its roots as the payload CIDs.
+///
+/// Every block is verified. CARv1 files, and CARv2 files without an index or with an index
+/// that doesn't match their blocks, are given a `MultihashIndexSorted` index, the piece
+/// commitment being the one of the resulting CARv2 file.
+pub fn prepare_car(car: &[u8]) -> Result<PreparedCar, CarError> {
+    let report = validate_car(car);
+    // CARv1 files have no index offset, CARv2 files without an index have an offset of 0
+    let reindexed = !report.valid || report.index_offset.unwrap_or(0) == 0;
+    if !report.valid {
+        // Only the index may be repaired, the errors of the blocks and headers are fatal
+        let mut reader = CarReader::new(car)?;
+        while let Some((_, block)) = reader.next_block()? {
+            verify_block(&block)?;
+        }
+    }
+
+    let roots = CarReader::new(car)?.header().roots.clone();
+    if roots.is_empty() {
+        return Err(CarError::InvalidHeader("no roots".into()));
+    }
+    let car = if reindexed {
+        let index = generate_index(car, IndexCodec::MultihashIndexSorted)?;
+        attach_index(car, &index)?
+    } else {
+        car.to_vec()
+    };
+
+    let mut commp = CommPWriter::new();
+    commp.write_all(&car)?;
+    let (commitment, piece_size) = commp.finish(&[])?;
+    Ok(PreparedCar {
+        roots,
+        commitment,
+        piece_size,
+        car,
+        reindexed,
+    })
+}
+
+#[wasm_bindgen]
+impl PreparedCar {
+    /// The first root CID, the payload CID of the deal.
+    #[wasm_bindgen(getter, js_name = "rootCid")]
+    pub fn root_cid(&self) -> String {
+        self.roots[0].to_string()
+    }
+
+    /// All the root CIDs, in header order.
+    #[wasm_bindgen(getter)]
+    pub fn roots(&self) -> Result<JsValue, JsValue> {
+        let roots = self.roots.iter().map(Cid::to_string).collect::<Vec<_>>();
+        Ok(serde_wasm_bindgen::to_value(&roots)?)
+    }
+
+    /// The piece CID (CommP) of the CARv2 file.
+    #[wasm_bindgen(getter, js_name = "pieceCid")]
+    pub fn piece_cid(&self) -> String {
+        self.commitment.cid().to_string()
+    }
+
+    /// The padded piece size in bytes, as a string.
+    #[wasm_bindgen(getter, js_name = "pieceSize")]
+    pub fn piece_size(&self) -> String {
+        self.piece_size.to_string()
+    }
+
+    /// The CARv2 file size in bytes, as a string.
+    #[wasm_bindgen(getter, js_name = "carSize")]
+    pub fn car_size(&self) -> String {
+        self.car.len().to_string()
+    }
+
+    /// The CARv2 file to upload, the supplied file itself unless it was reindexed.
+    #[wasm_bindgen(getter)]
+    pub fn car(&self) -> Vec<u8> {
+        self.car.clone()
+    }
+
+    /// Whether a new index was attached to the file.
+    #[wasm_bindgen(getter)]
+    pub fn reindexed(&self) -> bool {
+        self.reindexed
+    }
+}
+
+/// Prepares a CARv1 or CARv2 file built elsewhere to be stored without wrapping it in
+/// another DAG, see `isCar`.
+///
+/// # Arguments
+/// * `car` - The CAR file.
+///
+/// # Returns
+/// Its roots, the piece CID and size of the CARv2 file to store and the file itself.
+#[wasm_bindgen(js_name = "prepareCar")]
+pub fn prepare_car_js(car: &[u8]) -> Result<PreparedCar, JsValue> {
+    let prepared = prepare_car(car).map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    info!(
+        "Root CID: {}, piece CID: {}, piece size: {}",
+        prepared.roots[0],
+        prepared.commitment.cid(),
+        prepared.piece_size
+    );
+
+    Ok(prepared)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        car::{
+            builder::{generate_car_v2, generate_car_v2_with_options},
+            encode_v2_header, DATA_OFFSET,
+        },
+        piece_commitment,
+        testing::root_first_car,
+        unixfs::CHUNK_SIZE,
+    };
+
+    fn pipeline(data: &[u8], emit: bool) -> PipelineOutput<Vec<u8>> {
+        let mut pipeline = FilePipeline::new(emit.then(Vec::new));
+        let mut out = vec![];
+        for chunk in data.chunks(64 * 1024 + 3) {
+            pipeline.write(chunk).unwrap();
+            if let Some(written) = pipeline.get_mut() {
+                out.append(written);
+            }
+        }
+        let mut output = pipeline.finish().unwrap();
+        if let Some(written) = &mut output.out {
+            out.append(written);
+            *written = out;
+        }
+        output
+    }
+
+    #[test]
+    fn matches_two_pass_calculation() {
+        for len in [0, 12, 1000, CHUNK_SIZE + 1, 3 * CHUNK_SIZE + 12345] {
+            let data = (0..len).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+            let (root, car) = generate_car_v2(&data).unwrap();
+            let piece_size = PaddedPieceSize::from_arbitrary_size(car.len() as u64);
+            let commitment = piece_commitment(&car, piece_size).unwrap();
+
+            let output = pipeline(&data, true);
+            assert_eq!(output.root, root);
+            assert_eq!(output.commitment.cid(), commitment.cid(), "{} bytes", len);
+            assert_eq!(*output.piece_size, *piece_size);
+            assert_eq!(output.car_size, car.len() as u64);
+            assert_eq!(
+                [output.header, output.out.unwrap(), output.index].concat(),
+                car
+            );
+
+            let output = pipeline(&data, false);
+            assert!(output.out.is_none());
+            assert_eq!(output.commitment.cid(), commitment.cid());
+        }
+    }
+
+    #[test]
+    fn cid_v0_headers() {
+        // The CARv1 header of a CIDv0 root is shorter, the deferred prefix must follow
+        let options = DagOptions {
+            raw_leaves: false,
+            cid_version: 0,
+            ..Default::default()
+        };
+        let data = vec![7; CHUNK_SIZE + 1];
+        let (root, car) = generate_car_v2_with_options(&data, options).unwrap();
+
+        let mut pipeline = FilePipeline::with_options(Some(vec![]), options).unwrap();
+        pipeline.write(&data).unwrap();
+        let output = pipeline.finish().unwrap();
+
+        let piece_size = PaddedPieceSize::from_arbitrary_size(car.len() as u64);
+        assert_eq!(output.root, root);
+        assert_eq!(
+            output.commitment.cid(),
+            piece_commitment(&car, piece_size).unwrap().cid()
+        );
+    }
+
+    #[test]
+    fn prepares_car_files() {
+        let data = (0..3 * CHUNK_SIZE)
+            .map(|i| (i % 253) as u8)
+            .collect::<Vec<_>>();
+        let (root, car) = generate_car_v2(&data).unwrap();
+
+        let prepared = prepare_car(&car).unwrap();
+        assert!(!prepared.reindexed);
+        assert_eq!(prepared.roots, [root]);
+        assert_eq!(prepared.car, car);
+        let output = pipeline(&data, false);
+        assert_eq!(prepared.commitment.cid(), output.commitment.cid());
+        assert_eq!(*prepared.piece_size, *output.piece_size);
+
+        // The index of another file is replaced, giving back the original file
+        let data_size = u64::from_le_bytes(car[35..43].try_into().unwrap());
+        let payload = &car[DATA_OFFSET as usize..][..data_size as usize];
+        let (_, other) = generate_car_v2(&data[1..]).unwrap();
+        let other_index = generate_index(&other, IndexCodec::IndexSorted).unwrap();
+        let mismatched = [
+            &encode_v2_header(data_size, DATA_OFFSET + data_size)[..],
+            payload,
+            &other_index,
+        ]
+        .concat();
+        let prepared = prepare_car(&mismatched).unwrap();
+        assert!(prepared.reindexed);
+        assert_eq!(prepared.car, car);
+
+        // CARv2 files without an index are indexed
+        let unindexed = [&encode_v2_header(data_size, 0)[..], payload].concat();
+        assert!(validate_car(&unindexed).valid);
+        let prepared = prepare_car(&unindexed).unwrap();
+        assert!(prepared.reindexed);
+        assert_eq!(prepared.car, car);
+
+        // CARv1 files are indexed
+        let (root, v1) = root_first_car(&data);
+        let prepared = prepare_car(&v1).unwrap();
+        assert!(prepared.reindexed);
+        assert_eq!(prepared.roots, [root]);
+        let report = validate_car(&prepared.car);
+        assert!(report.valid && report.index_offset.is_some(), "{report:?}");
+        let piece_size = PaddedPieceSize::from_arbitrary_size(prepared.car.len() as u64);
+        assert_eq!(
+            prepared.commitment.cid(),
+            piece_commitment(&prepared.car, piece_size).unwrap().cid()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_car_files() {
+        let (_, mut car) = generate_car_v2(&[1; 1000]).unwrap();
+        car[200] ^= 1;
+        assert!(matches!(prepare_car(&car), Err(CarError::HashMismatch(_))));
+        assert!(prepare_car(b"not a car file").is_err());
+    }
+}
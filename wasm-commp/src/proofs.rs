@@ -0,0 +1,379 @@
+//! Registry of the seal and PoSt proof types supported by the chain.
+//!
+//! Provider information read from the chain carries the sector size and the window PoSt
+//! proof type as the names of their enum variants (e.g. `_2KiB` and
+//! `StackedDRGWindow2KiBV1P1`). This module maps those names to sector sizes so that
+//! pieces can be checked against a provider before a deal is proposed.
+use std::{fmt, str::FromStr};
+
+use primitives::commitment::piece::PaddedPieceSize;
+use serde::Serialize;
+use thiserror::Error;
+use wasm_bindgen::prelude::*;
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const GIB: u64 = 1024 * MIB;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ProofError {
+    #[error("unknown sector size: {0}")]
+    UnknownSectorSize(String),
+    #[error("unknown seal proof: {0}")]
+    UnknownSealProof(String),
+    #[error("unknown PoSt proof: {0}")]
+    UnknownPoStProof(String),
+    #[error("PoSt proof {post_proof} does not support sectors of {sector_size}")]
+    SectorSizeMismatch {
+        sector_size: SectorSize,
+        post_proof: RegisteredPoStProof,
+    },
+}
+
+/// Sector sizes, named after the on-chain `SectorSize` variants.
+///
+/// The chain also names `_32GiB` and `_64GiB` sectors, but has no seal proof for them, so no
+/// provider can use them.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum SectorSize {
+    _2KiB,
+    _8MiB,
+    _512MiB,
+    _1GiB,
+}
+
+impl SectorSize {
+    /// All the sector sizes, from the smallest to the largest.
+    pub const ALL: [SectorSize; 4] = [
+        SectorSize::_2KiB,
+        SectorSize::_8MiB,
+        SectorSize::_512MiB,
+        SectorSize::_1GiB,
+    ];
+
+    /// Returns the sector size in bytes.
+    pub fn bytes(self) -> u64 {
+        match self {
+            SectorSize::_2KiB => 2 * KIB,
+            SectorSize::_8MiB => 8 * MIB,
+            SectorSize::_512MiB => 512 * MIB,
+            SectorSize::_1GiB => GIB,
+        }
+    }
+
+    /// The largest padded piece that fits in a sector, i.e. a piece filling the whole sector.
+    pub fn max_piece_size(self) -> PaddedPieceSize {
+        PaddedPieceSize::new(self.bytes()).expect("sector sizes are valid piece sizes")
+    }
+
+    /// The largest unpadded payload that fits in a sector.
+    pub fn max_payload_size(self) -> u64 {
+        *self.max_piece_size().unpadded()
+    }
+}
+
+impl FromStr for SectorSize {
+    type Err = ProofError;
+
+    /// Parses the on-chain variant name, the leading underscore is optional.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.strip_prefix('_').unwrap_or(s);
+        SectorSize::ALL
+            .into_iter()
+            .find(|size| size.to_string() == name)
+            .ok_or_else(|| ProofError::UnknownSectorSize(s.to_string()))
+    }
+}
+
+impl fmt::Display for SectorSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = format!("{:?}", self);
+        f.write_str(name.trim_start_matches('_'))
+    }
+}
+
+/// Seal proof types supported by the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RegisteredSealProof {
+    StackedDRG2KiBV1P1,
+    StackedDRG8MiBV1,
+    StackedDRG512MiBV1,
+    StackedDRG1GiBV1,
+}
+
+impl RegisteredSealProof {
+    /// All the supported seal proofs, from the smallest sector size to the largest.
+    pub const ALL: [RegisteredSealProof; 4] = [
+        RegisteredSealProof::StackedDRG2KiBV1P1,
+        RegisteredSealProof::StackedDRG8MiBV1,
+        RegisteredSealProof::StackedDRG512MiBV1,
+        RegisteredSealProof::StackedDRG1GiBV1,
+    ];
+
+    /// Returns the size of the sectors sealed with this proof.
+    pub fn sector_size(self) -> SectorSize {
+        match self {
+            RegisteredSealProof::StackedDRG2KiBV1P1 => SectorSize::_2KiB,
+            RegisteredSealProof::StackedDRG8MiBV1 => SectorSize::_8MiB,
+            RegisteredSealProof::StackedDRG512MiBV1 => SectorSize::_512MiB,
+            RegisteredSealProof::StackedDRG1GiBV1 => SectorSize::_1GiB,
+        }
+    }
+
+    /// Returns the window PoSt proof used for sectors sealed with this proof.
+    pub fn window_post_proof(self) -> RegisteredPoStProof {
+        match self {
+            RegisteredSealProof::StackedDRG2KiBV1P1 => {
+                RegisteredPoStProof::StackedDRGWindow2KiBV1P1
+            }
+            RegisteredSealProof::StackedDRG8MiBV1 => RegisteredPoStProof::StackedDRGWindow8MiBV1,
+            RegisteredSealProof::StackedDRG512MiBV1 => {
+                RegisteredPoStProof::StackedDRGWindow512MiBV1
+            }
+            RegisteredSealProof::StackedDRG1GiBV1 => RegisteredPoStProof::StackedDRGWindow1GiBV1,
+        }
+    }
+}
+
+impl FromStr for RegisteredSealProof {
+    type Err = ProofError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RegisteredSealProof::ALL
+            .into_iter()
+            .find(|proof| format!("{:?}", proof) == s)
+            .ok_or_else(|| ProofError::UnknownSealProof(s.to_string()))
+    }
+}
+
+/// Window PoSt proof types supported by the chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RegisteredPoStProof {
+    StackedDRGWindow2KiBV1P1,
+    StackedDRGWindow8MiBV1,
+    StackedDRGWindow512MiBV1,
+    StackedDRGWindow1GiBV1,
+}
+
+impl RegisteredPoStProof {
+    /// Returns the size of the sectors proven with this proof.
+    pub fn sector_size(self) -> SectorSize {
+        match self {
+            RegisteredPoStProof::StackedDRGWindow2KiBV1P1 => SectorSize::_2KiB,
+            RegisteredPoStProof::StackedDRGWindow8MiBV1 => SectorSize::_8MiB,
+            RegisteredPoStProof::StackedDRGWindow512MiBV1 => SectorSize::_512MiB,
+            RegisteredPoStProof::StackedDRGWindow1GiBV1 => SectorSize::_1GiB,
+        }
+    }
+}
+
+impl FromStr for RegisteredPoStProof {
+    type Err = ProofError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RegisteredSealProof::ALL
+            .into_iter()
+            .map(RegisteredSealProof::window_post_proof)
+            .find(|proof| format!("{:?}", proof) == s)
+            .ok_or_else(|| ProofError::UnknownPoStProof(s.to_string()))
+    }
+}
+
+impl fmt::Display for RegisteredPoStProof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// An entry of the proof registry.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProofInfo {
+    pub seal_proof: RegisteredSealProof,
+    pub window_post_proof: RegisteredPoStProof,
+    pub sector_size: SectorSize,
+    /// Size of the sector in bytes.
+    pub sector_size_bytes: u64,
+    /// The largest padded piece accepted in a sector.
+    pub max_piece_size: u64,
+    /// The largest unpadded payload accepted in a sector.
+    pub max_payload_size: u64,
+}
+
+impl From<RegisteredSealProof> for ProofInfo {
+    fn from(seal_proof: RegisteredSealProof) -> Self {
+        let sector_size = seal_proof.sector_size();
+        Self {
+            seal_proof,
+            window_post_proof: seal_proof.window_post_proof(),
+            sector_size,
+            sector_size_bytes: sector_size.bytes(),
+            max_piece_size: *sector_size.max_piece_size(),
+            max_payload_size: sector_size.max_payload_size(),
+        }
+    }
+}
+
+/// The sealing parameters of a storage provider, as published on-chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProviderSector {
+    pub sector_size: SectorSize,
+    pub window_post_proof: RegisteredPoStProof,
+}
+
+impl ProviderSector {
+    /// Parses the provider's `sectorSize` and `windowPostProofType`,
+    /// checking that the proof type supports the sector size.
+    pub fn parse(sector_size: &str, window_post_proof: &str) -> Result<Self, ProofError> {
+        let sector_size = sector_size.parse::<SectorSize>()?;
+        let window_post_proof = window_post_proof.parse::<RegisteredPoStProof>()?;
+
+        if window_post_proof.sector_size() != sector_size {
+            return Err(ProofError::SectorSizeMismatch {
+                sector_size,
+                post_proof: window_post_proof,
+            });
+        }
+
+        Ok(Self {
+            sector_size,
+            window_post_proof,
+        })
+    }
+
+    /// Checks whether a piece of the given padded size fits in this provider's sectors.
+    pub fn fits(&self, padded_piece_size: PaddedPieceSize) -> bool {
+        *padded_piece_size <= *self.sector_size.max_piece_size()
+    }
+}
+
+/// Lists the seal proofs supported by the chain, along with their sector and piece sizes.
+#[wasm_bindgen(js_name = "supportedProofs")]
+pub fn supported_proofs() -> Result<JsValue, JsValue> {
+    let registry = RegisteredSealProof::ALL
+        .into_iter()
+        .map(ProofInfo::from)
+        .collect::<Vec<_>>();
+
+    Ok(serde_wasm_bindgen::to_value(&registry)?)
+}
+
+/// Checks whether a padded piece fits in the sectors of a storage provider.
+///
+/// # Arguments
+/// * `padded_piece_size` - The padded piece size, as returned by `paddedPieceSize`.
+/// * `sector_size` - The provider's `sectorSize` (e.g. `_2KiB`).
+/// * `window_post_proof` - The provider's `windowPostProofType` (e.g. `StackedDRGWindow2KiBV1P1`).
+///
+/// # Returns
+/// Whether the piece fits, errors if the provider information is invalid.
+#[wasm_bindgen(js_name = "pieceFitsProvider")]
+pub fn piece_fits_provider(
+    padded_piece_size: u64,
+    sector_size: &str,
+    window_post_proof: &str,
+) -> Result<bool, JsValue> {
+    let padded_piece_size = PaddedPieceSize::new(padded_piece_size)
+        .map_err(|e| JsValue::from_str(&format!("Invalid padded piece size: {}", e)))?;
+    let provider = ProviderSector::parse(sector_size, window_post_proof)
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    Ok(provider.fits(padded_piece_size))
+}
+
+/// Computes the largest unpadded payload (e.g. a CAR file) a storage provider accepts.
+///
+/// # Arguments
+/// * `sector_size` - The provider's `sectorSize` (e.g. `_2KiB`).
+/// * `window_post_proof` - The provider's `windowPostProofType` (e.g. `StackedDRGWindow2KiBV1P1`).
+///
+/// # Returns
+/// A JS string representing the payload size in bytes.
+#[wasm_bindgen(js_name = "maxProviderPayloadSize")]
+pub fn max_provider_payload_size(
+    sector_size: &str,
+    window_post_proof: &str,
+) -> Result<JsValue, JsValue> {
+    let provider = ProviderSector::parse(sector_size, window_post_proof)
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    Ok(JsValue::from_str(
+        &provider.sector_size.max_payload_size().to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_on_chain_names() {
+        assert_eq!("_2KiB".parse(), Ok(SectorSize::_2KiB));
+        assert_eq!("1GiB".parse(), Ok(SectorSize::_1GiB));
+        assert_eq!(
+            "StackedDRG8MiBV1".parse(),
+            Ok(RegisteredSealProof::StackedDRG8MiBV1)
+        );
+        assert_eq!(
+            "StackedDRGWindow512MiBV1".parse(),
+            Ok(RegisteredPoStProof::StackedDRGWindow512MiBV1)
+        );
+        assert!("4KiB".parse::<SectorSize>().is_err());
+        assert!("_32GiB".parse::<SectorSize>().is_err());
+        assert!("StackedDRGWindow32GiBV1"
+            .parse::<RegisteredPoStProof>()
+            .is_err());
+    }
+
+    #[test]
+    fn registry_is_consistent() {
+        for seal_proof in RegisteredSealProof::ALL {
+            let info = ProofInfo::from(seal_proof);
+            assert_eq!(info.window_post_proof.sector_size(), info.sector_size);
+            assert_eq!(info.max_piece_size, info.sector_size_bytes);
+            assert_eq!(info.max_payload_size, info.max_piece_size / 128 * 127);
+        }
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        for size in SectorSize::ALL {
+            assert_eq!(size.to_string().parse(), Ok(size));
+            assert_eq!(format!("{:?}", size).parse(), Ok(size));
+            assert!(RegisteredSealProof::ALL
+                .into_iter()
+                .any(|proof| proof.sector_size() == size));
+        }
+        for seal_proof in RegisteredSealProof::ALL {
+            let post_proof = seal_proof.window_post_proof();
+            assert_eq!(format!("{:?}", seal_proof).parse(), Ok(seal_proof));
+            assert_eq!(post_proof.to_string().parse(), Ok(post_proof));
+
+            let sector_size = format!("{:?}", seal_proof.sector_size());
+            let provider = ProviderSector::parse(&sector_size, &post_proof.to_string()).unwrap();
+            assert!(provider.fits(seal_proof.sector_size().max_piece_size()));
+        }
+    }
+
+    #[test]
+    fn provider_rejects_mismatched_proof() {
+        assert_eq!(
+            ProviderSector::parse("_8MiB", "StackedDRGWindow2KiBV1P1"),
+            Err(ProofError::SectorSizeMismatch {
+                sector_size: SectorSize::_8MiB,
+                post_proof: RegisteredPoStProof::StackedDRGWindow2KiBV1P1,
+            })
+        );
+    }
+
+    #[test]
+    fn piece_fits_in_sector() {
+        let provider = ProviderSector::parse("_2KiB", "StackedDRGWindow2KiBV1P1").unwrap();
+
+        assert!(provider.fits(PaddedPieceSize::new(128).unwrap()));
+        assert!(provider.fits(PaddedPieceSize::new(2048).unwrap()));
+        assert!(!provider.fits(PaddedPieceSize::new(4096).unwrap()));
+        assert_eq!(provider.sector_size.max_payload_size(), 2032);
+    }
+}
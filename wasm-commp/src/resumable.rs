@@ -0,0 +1,726 @@
+//! Resumable retrieval of a piece from its provider.
+//!
+//! The piece is fetched with HTTP range requests, one aligned subtree of the piece Merkle tree
+//! at a time, like in [`crate::multi_retrieval`]. The piece being a CAR file, the blocks are
+//! checked against their CIDs as they're received, and once every block a range holds is
+//! checked, the range is stored along with its subtree root in a [`CheckpointStore`]. A block
+//! running over into the next range holds its range back until that one is received. A
+//! retrieval interrupted by a closed tab or a dropped connection then picks up where it
+//! stopped: the stored ranges and their roots are loaded as they are, and only the missing
+//! ranges are fetched, hashed and checked. The piece commitment is then the root of the tree
+//! over all the subtree roots, which catches a stored range altered since.
+//!
+//! Only the bytes around the blocks, the headers and the index, are stored without being
+//! checked, as nothing but the piece commitment covers them. A commitment not matching the
+//! piece CID doesn't tell which range is wrong, so the whole checkpoint is dropped and the
+//! next attempt starts over. It's dropped as well once the piece is verified.
+//!
+//! The ranges can also be passed on one at a time as they're loaded or fetched, with
+//! [`ResumableRetrieval::retrieve_with`], so that their blocks are checked as they arrive
+//! rather than once the whole piece is in memory.
+use std::{collections::VecDeque, future::Future, io, ops::Range};
+
+use js_sys::{Promise, Uint8Array};
+use primitives::commitment::{piece::PaddedPieceSize, CommP, Commitment};
+use thiserror::Error;
+use tracing::info;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+
+use crate::{
+    car::{
+        reader::{verify_block, CarDecoder},
+        CarError,
+    },
+    commitment::zero_commitment,
+    fetch::{fetch_range, BrowserFetch, Fetch, FetchError},
+    multi_retrieval::{root_of, subtree_root, DEFAULT_RANGE_SIZE},
+    spot_check::piece_cid_root,
+};
+
+/// Size of the subtree root stored in front of the bytes of a range.
+const ROOT_SIZE: usize = 32;
+
+#[derive(Debug, Error)]
+#[error("checkpoint store failed: {0}")]
+pub struct StoreError(pub String);
+
+#[derive(Debug, Error)]
+pub enum ResumableError {
+    #[error("invalid piece CID: {0}")]
+    InvalidPieceCid(String),
+    #[error("invalid piece size {0}: {1}")]
+    InvalidPieceSize(u64, String),
+    #[error("range size must be a power of two of at least 512 bytes, got {0}")]
+    InvalidRangeSize(u64),
+    #[error(transparent)]
+    Fetch(#[from] FetchError),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("the data has piece CID {actual} instead of {expected}, it was altered or isn't the deal's piece")]
+    Mismatch { expected: String, actual: String },
+    #[error("range {index} was rejected: {reason}")]
+    Rejected { index: u64, reason: String },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Persists the ranges retrieved so far, as opaque values.
+pub trait CheckpointStore {
+    /// Returns the value stored under `key`, if any.
+    fn load(&self, key: &str) -> impl Future<Output = Result<Option<Vec<u8>>, StoreError>>;
+
+    fn save(&self, key: &str, value: Vec<u8>) -> impl Future<Output = Result<(), StoreError>>;
+
+    /// Removes the value stored under `key`, if any.
+    fn remove(&self, key: &str) -> impl Future<Output = Result<(), StoreError>>;
+}
+
+impl<S: CheckpointStore> CheckpointStore for &S {
+    fn load(&self, key: &str) -> impl Future<Output = Result<Option<Vec<u8>>, StoreError>> {
+        (**self).load(key)
+    }
+
+    fn save(&self, key: &str, value: Vec<u8>) -> impl Future<Output = Result<(), StoreError>> {
+        (**self).save(key, value)
+    }
+
+    fn remove(&self, key: &str) -> impl Future<Output = Result<(), StoreError>> {
+        (**self).remove(key)
+    }
+}
+
+/// A piece retrieved by [`ResumableRetrieval`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointedPiece {
+    /// The unpadded piece bytes, without the trailing zeros the provider left out.
+    pub data: Vec<u8>,
+    /// Number of ranges loaded from the checkpoint.
+    pub resumed: u64,
+    /// Number of ranges fetched from the provider.
+    pub fetched: u64,
+}
+
+/// Retrieves a piece from a provider, checkpointing the ranges received.
+#[derive(Debug, Clone)]
+pub struct ResumableRetrieval<F, S> {
+    fetcher: F,
+    store: S,
+    url: String,
+    piece_cid: String,
+    expected: [u8; 32],
+    piece_size: PaddedPieceSize,
+    range_size: u64,
+}
+
+impl<F: Fetch, S: CheckpointStore> ResumableRetrieval<F, S> {
+    /// Retrieves piece `piece_cid` of `piece_size` padded bytes, as stored on-chain, from the
+    /// provider at `provider`, e.g. `http://127.0.0.1:8001`, checkpointing it to `store`.
+    pub fn new(
+        fetcher: F,
+        store: S,
+        provider: &str,
+        piece_cid: &str,
+        piece_size: u64,
+    ) -> Result<Self, ResumableError> {
+        let expected = piece_cid_root(piece_cid)
+            .map_err(|e| ResumableError::InvalidPieceCid(e.to_string()))?;
+        let piece_size = PaddedPieceSize::new(piece_size)
+            .map_err(|e| ResumableError::InvalidPieceSize(piece_size, e.to_string()))?;
+        Ok(Self {
+            fetcher,
+            store,
+            url: format!(
+                "{}/api/v0/download/{}",
+                provider.trim_end_matches('/'),
+                piece_cid
+            ),
+            piece_cid: piece_cid.to_string(),
+            expected,
+            piece_size,
+            range_size: DEFAULT_RANGE_SIZE,
+        })
+    }
+
+    /// Sets the padded size of the ranges, a power of two, ranges larger than the piece
+    /// standing for the whole piece.
+    ///
+    /// Checkpoints are kept per range size, a retrieval only resumes from the ranges stored
+    /// with the same size.
+    pub fn range_size(mut self, range_size: u64) -> Result<Self, ResumableError> {
+        if !range_size.is_power_of_two() || range_size < 512 {
+            return Err(ResumableError::InvalidRangeSize(range_size));
+        }
+        self.range_size = range_size;
+        Ok(self)
+    }
+
+    /// The key range `index` is stored under.
+    fn range_key(&self, index: u64) -> String {
+        format!("{}/{}/{}", self.piece_cid, self.range_size, index)
+    }
+
+    /// Loads a stored range of `range_size` padded bytes along with its subtree root,
+    /// dropping malformed entries.
+    async fn load_range(
+        &self,
+        key: &str,
+        range_size: u64,
+    ) -> Result<Option<([u8; 32], Vec<u8>)>, ResumableError> {
+        let Some(mut record) = self.store.load(key).await? else {
+            return Ok(None);
+        };
+        let unpadded = range_size / 128 * 127;
+        if record.len() < ROOT_SIZE || (record.len() - ROOT_SIZE) as u64 > unpadded {
+            self.store.remove(key).await?;
+            return Ok(None);
+        }
+        let bytes = record.split_off(ROOT_SIZE);
+        let root = record.try_into().expect("the length is checked above");
+        Ok(Some((root, bytes)))
+    }
+
+    /// Retrieves the piece, resuming from the checkpoint, and verifies it.
+    ///
+    /// The checkpoint is kept when the retrieval fails on a request or on the store, so that
+    /// calling this again resumes from the last range stored.
+    pub async fn retrieve(&self) -> Result<CheckpointedPiece, ResumableError> {
+        let mut data = vec![];
+        let (resumed, fetched) = self
+            .retrieve_with(|bytes| {
+                data.extend_from_slice(bytes);
+                Ok(())
+            })
+            .await?;
+        Ok(CheckpointedPiece {
+            data,
+            resumed,
+            fetched,
+        })
+    }
+
+    /// Retrieves the piece like [`Self::retrieve`], passing the unpadded bytes of each range
+    /// to `on_range` in order rather than keeping them, and returns the number of ranges
+    /// resumed and fetched.
+    ///
+    /// The piece is only verified once every range is passed on, a range only being stored
+    /// once `on_range` accepted it. An error of `on_range` aborts the retrieval and drops the
+    /// checkpoint, as the next attempt would get the same bytes. A bad block aborts it as
+    /// well, the ranges stored before it being kept.
+    pub async fn retrieve_with(
+        &self,
+        mut on_range: impl FnMut(&[u8]) -> Result<(), String>,
+    ) -> Result<(u64, u64), ResumableError> {
+        let range_size = self.range_size.min(*self.piece_size);
+        let unpadded = range_size / 128 * 127;
+        let count = *self.piece_size / range_size;
+
+        let mut roots = Vec::with_capacity(count as usize);
+        let mut keys = vec![];
+        let (mut resumed, mut fetched) = (0, 0);
+        let mut decoder = CarDecoder::new();
+        // The fetched ranges whose blocks aren't all checked yet, with their end
+        let mut unchecked = VecDeque::new();
+        // The fetched bytes, the only ones whose blocks are checked
+        let mut untrusted = vec![];
+        for index in 0..count {
+            let key = self.range_key(index);
+            let start = index * unpadded;
+            let (root, bytes) = match self.load_range(&key, range_size).await? {
+                Some(range) => {
+                    resumed += 1;
+                    range
+                }
+                None => {
+                    let bytes =
+                        fetch_range(&self.fetcher, &self.url, start..start + unpadded).await?;
+                    let root = subtree_root(&bytes, range_size)?;
+                    let mut record = root.to_vec();
+                    record.extend_from_slice(&bytes);
+                    unchecked.push_back((start + bytes.len() as u64, key.clone(), record));
+                    untrusted.push(start..start + bytes.len() as u64);
+                    fetched += 1;
+                    (root, bytes)
+                }
+            };
+            keys.push(key);
+            roots.push(root);
+
+            decoder.push(&bytes);
+            let received = start + bytes.len() as u64;
+            if let Err(e) = check_blocks(&mut decoder, received, &untrusted) {
+                return Err(ResumableError::Rejected {
+                    index,
+                    reason: e.to_string(),
+                });
+            }
+            if let Err(reason) = on_range(&bytes) {
+                for key in &keys {
+                    self.store.remove(key).await?;
+                }
+                return Err(ResumableError::Rejected { index, reason });
+            }
+            let checked = received - decoder.buffered();
+            while unchecked.front().is_some_and(|(end, _, _)| *end <= checked) {
+                let (_, key, record) = unchecked.pop_front().expect("checked above");
+                self.store.save(&key, record).await?;
+            }
+
+            // The provider's data ended, the rest of the piece is zeros
+            if (bytes.len() as u64) < unpadded {
+                break;
+            }
+        }
+        roots.resize(count as usize, zero_commitment(range_size));
+        let root = root_of(roots);
+
+        for key in &keys {
+            self.store.remove(key).await?;
+        }
+        if root != self.expected {
+            return Err(ResumableError::Mismatch {
+                expected: self.piece_cid.clone(),
+                actual: Commitment::<CommP>::from(root).cid().to_string(),
+            });
+        }
+
+        info!(
+            "Retrieved piece {}, {} ranges resumed and {} fetched",
+            self.piece_cid, resumed, fetched
+        );
+        Ok((resumed, fetched))
+    }
+}
+
+/// Checks the blocks held in full by `decoder` against their CIDs, `received` bytes being
+/// pushed to it, skipping those lying entirely in stored ranges.
+fn check_blocks(
+    decoder: &mut CarDecoder,
+    received: u64,
+    untrusted: &[Range<u64>],
+) -> Result<(), CarError> {
+    let mut start = received - decoder.buffered();
+    while let Some((_, block)) = decoder.next_block()? {
+        let end = received - decoder.buffered();
+        if untrusted
+            .iter()
+            .any(|range| range.start < end && start < range.end)
+        {
+            verify_block(&block)?;
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+#[wasm_bindgen]
+extern "C" {
+    /// A JS object with `get(key)`, `set(key, value)` and `delete(key)` methods returning
+    /// values or promises of them, e.g. a `Map` or a wrapper around IndexedDB.
+    pub type JsCheckpointStore;
+
+    #[wasm_bindgen(method, catch, js_name = get)]
+    fn get_value(this: &JsCheckpointStore, key: &str) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(method, catch, js_name = set)]
+    fn set_value(
+        this: &JsCheckpointStore,
+        key: &str,
+        value: Uint8Array,
+    ) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(method, catch, js_name = delete)]
+    fn delete_value(this: &JsCheckpointStore, key: &str) -> Result<JsValue, JsValue>;
+}
+
+/// Waits for the value returned by a store method, whether it's a promise or not.
+async fn settle(value: Result<JsValue, JsValue>) -> Result<JsValue, StoreError> {
+    let store_error = |e: JsValue| StoreError(format!("{:?}", e));
+    let value = value.map_err(store_error)?;
+    JsFuture::from(Promise::resolve(&value))
+        .await
+        .map_err(store_error)
+}
+
+impl CheckpointStore for JsCheckpointStore {
+    async fn load(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        let value = settle(self.get_value(key)).await?;
+        if value.is_undefined() || value.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(Uint8Array::new(&value).to_vec()))
+    }
+
+    async fn save(&self, key: &str, value: Vec<u8>) -> Result<(), StoreError> {
+        settle(self.set_value(key, Uint8Array::from(value.as_slice()))).await?;
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), StoreError> {
+        settle(self.delete_value(key)).await?;
+        Ok(())
+    }
+}
+
+/// Retrieves a piece from its provider, resuming from the ranges checkpointed to `store` by a
+/// previous attempt, and verifies it against its on-chain piece CID.
+///
+/// ```js
+/// const store = new Map(); // or an IndexedDB wrapper to survive reloads
+/// const piece = await retrieveResumable(provider, pieceCid, BigInt(deal.piece_size), store);
+/// // or range by range, an error thrown by `verifier.write` aborting the retrieval
+/// const onRange = (range) => verifier.write(range);
+/// await retrieveResumable(provider, pieceCid, BigInt(deal.piece_size), store, undefined, onRange);
+/// ```
+///
+/// # Arguments
+/// * `provider` - The base URL of the provider, e.g. `http://127.0.0.1:8001`.
+/// * `piece_cid` - The piece CID as stored on-chain.
+/// * `piece_size` - The padded piece size as stored on-chain.
+/// * `store` - Where the ranges received are checkpointed.
+/// * `range_size` - The padded size of the ranges requested, 8 MiB if unset.
+/// * `on_range` - Called with the unpadded bytes of each range, in order, as they're loaded
+///   or fetched, a range only being checkpointed once it returns. The piece is verified once
+///   they're all passed on.
+///
+/// # Returns
+/// The unpadded piece bytes, the CAR file possibly followed by zeros, or nothing if they're
+/// passed to `on_range`.
+#[wasm_bindgen(js_name = "retrieveResumable")]
+pub async fn retrieve_resumable_js(
+    provider: String,
+    piece_cid: String,
+    piece_size: u64,
+    store: JsCheckpointStore,
+    range_size: Option<u64>,
+    on_range: Option<js_sys::Function>,
+) -> Result<Vec<u8>, JsValue> {
+    let to_js = |e: ResumableError| JsValue::from_str(&e.to_string());
+    let retrieval = ResumableRetrieval::new(BrowserFetch, store, &provider, &piece_cid, piece_size)
+        .and_then(|retrieval| retrieval.range_size(range_size.unwrap_or(DEFAULT_RANGE_SIZE)))
+        .map_err(to_js)?;
+    let Some(on_range) = on_range else {
+        return Ok(retrieval.retrieve().await.map_err(to_js)?.data);
+    };
+    retrieval
+        .retrieve_with(|bytes| {
+            on_range
+                .call1(&JsValue::NULL, &Uint8Array::from(bytes))
+                .map(|_| ())
+                .map_err(|e| match e.dyn_ref::<js_sys::Error>() {
+                    Some(error) => String::from(error.message()),
+                    None => format!("{:?}", e),
+                })
+        })
+        .await
+        .map_err(to_js)?;
+    Ok(vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+    use crate::{
+        car::{builder::generate_car_v2_with_options, reader::CarReader},
+        fetch::HttpResponse,
+        piece_commitment,
+        testing::{range_response, status, MemoryStore, MockFetch, MockServer},
+        unixfs::DagOptions,
+    };
+
+    /// The CAR file of 95 KiB of data in a piece of 128 KiB, split in 8 ranges, the last one
+    /// past the data.
+    const RANGE_SIZE: u64 = 16 * 1024;
+    const UNPADDED_RANGE: u64 = RANGE_SIZE / 128 * 127;
+
+    fn piece() -> (Vec<u8>, String, u64) {
+        let file = (0..95 * 1024)
+            .map(|i| (i * 7 % 253) as u8)
+            .collect::<Vec<_>>();
+        // Blocks much smaller than the ranges, most ranges being checked once the next one
+        // is received
+        let options = DagOptions {
+            chunk_size: 4096,
+            ..Default::default()
+        };
+        let (_, data) = generate_car_v2_with_options(&file, options).unwrap();
+        assert_eq!(data.len().div_ceil(UNPADDED_RANGE as usize), 7);
+        let piece_size = PaddedPieceSize::new(128 * 1024).unwrap();
+        let piece_cid = piece_commitment(&data, piece_size)
+            .unwrap()
+            .cid()
+            .to_string();
+        (data, piece_cid, *piece_size)
+    }
+
+    /// A provider whose connection drops after serving `served` ranges.
+    fn flaky_provider(data: Vec<u8>, served: usize) -> MockServer {
+        let count = Arc::new(AtomicUsize::new(0));
+        MockServer::new(move |request| {
+            if count.fetch_add(1, Ordering::SeqCst) < served {
+                range_response(request, &data)
+            } else {
+                status(503)
+            }
+        })
+    }
+
+    async fn retrieve(
+        store: &MemoryStore,
+        provider: &str,
+        piece_cid: &str,
+        piece_size: u64,
+    ) -> Result<CheckpointedPiece, ResumableError> {
+        let retrieval = ResumableRetrieval::new(MockFetch, store, provider, piece_cid, piece_size)?
+            .range_size(RANGE_SIZE)?;
+        retrieval.retrieve().await
+    }
+
+    #[wasm_bindgen_test]
+    async fn resumes_interrupted_retrievals() {
+        let (data, piece_cid, piece_size) = piece();
+        let store = MemoryStore::default();
+
+        let flaky = flaky_provider(data.clone(), 3);
+        let error = retrieve(&store, flaky.url(), &piece_cid, piece_size)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ResumableError::Fetch(_)));
+        // The last range received waits for the block running over into the next one
+        assert_eq!(store.len(), 2);
+
+        // Only the missing ranges are requested
+        let path = format!("/api/v0/download/{piece_cid}");
+        let provider = MockServer::with_files(HashMap::from([(path, data.clone())]));
+        let piece = retrieve(&store, provider.url(), &piece_cid, piece_size)
+            .await
+            .unwrap();
+        assert!(piece.data == data);
+        assert_eq!((piece.resumed, piece.fetched), (2, 5));
+        let starts = provider
+            .requests()
+            .iter()
+            .map(|request| request.range().unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            starts,
+            (2..7).map(|i| i * UNPADDED_RANGE).collect::<Vec<_>>()
+        );
+        // The checkpoint is dropped once the piece is verified
+        assert_eq!(store.len(), 0);
+    }
+
+    #[wasm_bindgen_test]
+    async fn checkpoints_only_checked_blocks() {
+        let (data, piece_cid, piece_size) = piece();
+        let store = MemoryStore::default();
+
+        // A bad block in the middle of range 3
+        let mut altered = data.clone();
+        altered[UNPADDED_RANGE as usize * 7 / 2] ^= 1;
+        let path = format!("/api/v0/download/{piece_cid}");
+        let bad = MockServer::with_files(HashMap::from([(path.clone(), altered)]));
+        let error = retrieve(&store, bad.url(), &piece_cid, piece_size)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ResumableError::Rejected { index: 3, .. }));
+        // Range 2 waited for a block of range 3
+        assert_eq!(store.len(), 2);
+
+        let provider = MockServer::with_files(HashMap::from([(path, data.clone())]));
+        let piece = retrieve(&store, provider.url(), &piece_cid, piece_size)
+            .await
+            .unwrap();
+        assert!(piece.data == data);
+        assert_eq!((piece.resumed, piece.fetched), (2, 5));
+    }
+
+    #[wasm_bindgen_test]
+    async fn drops_checkpoints_not_matching_the_piece() {
+        let (data, piece_cid, piece_size) = piece();
+        let store = MemoryStore::default();
+
+        // Another root in the header, which the blocks don't tell
+        let root = CarReader::new(&data[..]).unwrap().header().roots[0].to_bytes();
+        let at = data.windows(root.len()).position(|w| w == root).unwrap() + root.len();
+        let mut altered = data.clone();
+        altered[at - 1] ^= 1;
+        let flaky = flaky_provider(altered, 2);
+        retrieve(&store, flaky.url(), &piece_cid, piece_size)
+            .await
+            .unwrap_err();
+        assert_eq!(store.len(), 1);
+
+        let path = format!("/api/v0/download/{piece_cid}");
+        let provider = MockServer::with_files(HashMap::from([(path, data.clone())]));
+        let error = retrieve(&store, provider.url(), &piece_cid, piece_size)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ResumableError::Mismatch { .. }));
+        assert_eq!(provider.requests().len(), 6);
+
+        // The next attempt starts over
+        assert_eq!(store.len(), 0);
+        let piece = retrieve(&store, provider.url(), &piece_cid, piece_size)
+            .await
+            .unwrap();
+        assert!(piece.data == data);
+        assert_eq!((piece.resumed, piece.fetched), (0, 7));
+    }
+
+    #[wasm_bindgen_test]
+    async fn drops_altered_checkpoints() {
+        let (data, piece_cid, piece_size) = piece();
+        let store = MemoryStore::default();
+        let flaky = flaky_provider(data.clone(), 4);
+        retrieve(&store, flaky.url(), &piece_cid, piece_size)
+            .await
+            .unwrap_err();
+
+        // A stored root is trusted, and a record cut short is fetched again
+        let key = |index: u64| format!("{piece_cid}/{RANGE_SIZE}/{index}");
+        let mut record = store.load(&key(1)).await.unwrap().unwrap();
+        record[0] ^= 1;
+        store.save(&key(1), record).await.unwrap();
+        store.save(&key(2), vec![0; ROOT_SIZE - 1]).await.unwrap();
+
+        // The piece commitment catches the altered root and drops the checkpoint
+        let path = format!("/api/v0/download/{piece_cid}");
+        let provider = MockServer::with_files(HashMap::from([(path, data.clone())]));
+        let error = retrieve(&store, provider.url(), &piece_cid, piece_size)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ResumableError::Mismatch { .. }));
+        let starts = provider
+            .requests()
+            .iter()
+            .map(|request| request.range().unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            starts,
+            (2..7).map(|i| i * UNPADDED_RANGE).collect::<Vec<_>>()
+        );
+        for index in 0..7 {
+            assert!(store.load(&key(index)).await.unwrap().is_none());
+        }
+
+        let piece = retrieve(&store, provider.url(), &piece_cid, piece_size)
+            .await
+            .unwrap();
+        assert!(piece.data == data);
+        assert_eq!((piece.resumed, piece.fetched), (0, 7));
+    }
+
+    #[wasm_bindgen_test]
+    async fn passes_ranges_on_in_order() {
+        let (data, piece_cid, piece_size) = piece();
+        let store = MemoryStore::default();
+        let flaky = flaky_provider(data.clone(), 2);
+        retrieve(&store, flaky.url(), &piece_cid, piece_size)
+            .await
+            .unwrap_err();
+
+        let path = format!("/api/v0/download/{piece_cid}");
+        let provider = MockServer::with_files(HashMap::from([(path, data.clone())]));
+        let retrieval =
+            ResumableRetrieval::new(MockFetch, &store, provider.url(), &piece_cid, piece_size)
+                .unwrap()
+                .range_size(RANGE_SIZE)
+                .unwrap();
+        let mut ranges = vec![];
+        let counts = retrieval
+            .retrieve_with(|bytes| {
+                ranges.push(bytes.to_vec());
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(counts, (1, 6));
+        assert!(ranges.concat() == data);
+        assert!(ranges[..6]
+            .iter()
+            .all(|range| range.len() == UNPADDED_RANGE as usize));
+
+        // A rejected range aborts the retrieval and drops the checkpoint
+        let flaky = flaky_provider(data.clone(), 2);
+        retrieve(&store, flaky.url(), &piece_cid, piece_size)
+            .await
+            .unwrap_err();
+        let mut passed = 0;
+        let error = retrieval
+            .retrieve_with(|_| {
+                passed += 1;
+                match passed {
+                    3 => Err("bad block".to_string()),
+                    _ => Ok(()),
+                }
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(error, ResumableError::Rejected { index: 2, .. }));
+        assert_eq!(store.len(), 0);
+    }
+
+    #[wasm_bindgen_test]
+    async fn reads_ranges_without_their_content_range() {
+        let (data, piece_cid, piece_size) = piece();
+        let store = MemoryStore::default();
+        // A provider not exposing `Content-Range` to cross-origin requests
+        let body = data.clone();
+        let provider = MockServer::new(move |request| {
+            let mut response = range_response(request, &body);
+            response.headers.clear();
+            response
+        });
+        let piece = retrieve(&store, provider.url(), &piece_cid, piece_size)
+            .await
+            .unwrap();
+        assert!(piece.data == data);
+        assert_eq!((piece.resumed, piece.fetched), (0, 7));
+
+        // Bodies longer than the range are still rejected
+        let long = MockServer::new(|_| HttpResponse {
+            status: 206,
+            headers: vec![],
+            body: vec![0; UNPADDED_RANGE as usize + 1],
+        });
+        let error = retrieve(&store, long.url(), &piece_cid, piece_size)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            ResumableError::Fetch(FetchError::ContentRange { .. })
+        ));
+    }
+
+    #[wasm_bindgen_test]
+    async fn stops_at_the_end_of_the_data() {
+        let (data, piece_cid, piece_size) = piece();
+        let store = MemoryStore::default();
+        // Half a range, the rest of the piece being zeros
+        let short = data[..UNPADDED_RANGE as usize / 2].to_vec();
+        let short_cid = piece_commitment(&short, PaddedPieceSize::new(piece_size).unwrap())
+            .unwrap()
+            .cid()
+            .to_string();
+        assert_ne!(short_cid, piece_cid);
+
+        let path = format!("/api/v0/download/{short_cid}");
+        let provider = MockServer::with_files(HashMap::from([(path, short.clone())]));
+        let piece = retrieve(&store, provider.url(), &short_cid, piece_size)
+            .await
+            .unwrap();
+        assert!(piece.data == short);
+        assert_eq!(provider.requests().len(), 1);
+    }
+}
@@ -0,0 +1,366 @@
+//! Verification of retrieved pieces against their on-chain piece CID.
+//!
+//! The download endpoint serves the unpadded piece, the CARv2 file possibly followed by zero
+//! padding up to the piece size. Zeros at the end of the data don't change its piece
+//! commitment, so every byte is committed to as received and the commitment is then grown
+//! to the deal's piece size with zero subtrees. The CARv2 header gives the end of the payload
+//! and the offset of the index, the file ends with the index, or with the payload when there
+//! is none; anything but zeros following it is rejected.
+use std::io::Write;
+
+use primitives::commitment::{piece::PaddedPieceSize, CommP, Commitment};
+use serde::Serialize;
+use thiserror::Error;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    car::{
+        index::index_len,
+        reader::{CarError, CarV2Header},
+        CARV2_HEADER_SIZE, DATA_OFFSET, PRAGMA,
+    },
+    commitment::{hash_pair, zero_commitment},
+    commp_writer::CommPWriter,
+    spot_check::piece_cid_root,
+};
+
+#[derive(Debug, Error)]
+pub enum RetrievalError {
+    #[error("invalid piece CID: {0}")]
+    InvalidPieceCid(String),
+    #[error("invalid piece size {0}: {1}")]
+    InvalidPieceSize(u64, String),
+    #[error("received more than the {max} bytes a piece of {piece_size} bytes holds")]
+    TooLarge { max: u64, piece_size: u64 },
+    #[error("the download ended after {0} bytes, before the end of the CAR file")]
+    Truncated(u64),
+    #[error("data other than zero padding follows the CAR file")]
+    TrailingData,
+    #[error(transparent)]
+    Car(#[from] CarError),
+    #[error("the data has piece CID {actual} instead of {expected}, it was altered or isn't the deal's piece")]
+    Mismatch { expected: String, actual: String },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// What is known of the data from its first bytes.
+enum Layout {
+    /// Less than a CARv2 header was received.
+    Unknown,
+    /// A CARv2 file.
+    CarV2(CarV2Header),
+    /// A CARv1 file or other data, whose end isn't known.
+    Other,
+}
+
+/// A verified piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifiedPiece {
+    /// Size of the data once the padding is trimmed, everything received for data other
+    /// than CARv2 files.
+    pub data_size: u64,
+    /// Number of padding bytes received after the data.
+    pub padding: u64,
+}
+
+/// Recomputes the piece commitment of retrieved data as it is received.
+pub struct PieceVerifier {
+    expected: [u8; 32],
+    piece_cid: String,
+    piece_size: PaddedPieceSize,
+    commp: CommPWriter,
+    received: u64,
+    /// The first bytes, until the CARv2 header is received.
+    head: Vec<u8>,
+    layout: Layout,
+    /// The start of the index of a CARv2 file, until its length is known.
+    index: Vec<u8>,
+    /// The end of a CARv2 file, once its index, if any, is received.
+    end: Option<u64>,
+}
+
+impl PieceVerifier {
+    /// Creates a verifier of the piece `piece_cid`, of `piece_size` padded bytes, as stored
+    /// on-chain.
+    pub fn new(piece_cid: &str, piece_size: u64) -> Result<Self, RetrievalError> {
+        let expected = piece_cid_root(piece_cid)
+            .map_err(|e| RetrievalError::InvalidPieceCid(e.to_string()))?;
+        let piece_size = PaddedPieceSize::new(piece_size)
+            .map_err(|e| RetrievalError::InvalidPieceSize(piece_size, e.to_string()))?;
+        Ok(Self {
+            expected,
+            piece_cid: piece_cid.to_string(),
+            piece_size,
+            commp: CommPWriter::new(),
+            received: 0,
+            head: vec![],
+            layout: Layout::Unknown,
+            index: vec![],
+            end: None,
+        })
+    }
+
+    /// Appends received bytes.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), RetrievalError> {
+        let max = *self.piece_size.unpadded();
+        if self.received + data.len() as u64 > max {
+            return Err(RetrievalError::TooLarge {
+                max,
+                piece_size: *self.piece_size,
+            });
+        }
+        self.commp.write_all(data)?;
+
+        if let Layout::Unknown = self.layout {
+            let header_size = DATA_OFFSET as usize;
+            let missing = header_size - self.head.len();
+            self.head
+                .extend_from_slice(&data[..missing.min(data.len())]);
+            if self.head.len() == header_size {
+                self.layout = if self.head[..PRAGMA.len()] == PRAGMA {
+                    let header: &[u8; CARV2_HEADER_SIZE] = self.head[PRAGMA.len()..]
+                        .try_into()
+                        .expect("header is complete");
+                    let header = CarV2Header::decode(header);
+                    header.check()?;
+                    if header.index_offset == 0 {
+                        self.end = Some(header.data_offset + header.data_size);
+                    }
+                    Layout::CarV2(header)
+                } else {
+                    Layout::Other
+                };
+                self.head = vec![];
+            }
+        }
+
+        if let Layout::CarV2(header) = &self.layout {
+            let start = self.received;
+            if self.end.is_none() {
+                if let Some(index) = suffix(data, start, header.index_offset) {
+                    self.index.extend_from_slice(index);
+                    match index_len(&self.index) {
+                        Ok(len) => {
+                            self.end = Some(header.index_offset + len as u64);
+                            self.index = vec![];
+                        }
+                        Err(CarError::Truncated) => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+            let padding = self.end.and_then(|end| suffix(data, start, end));
+            if padding.is_some_and(|padding| padding.iter().any(|&byte| byte != 0)) {
+                return Err(RetrievalError::TrailingData);
+            }
+        }
+        self.received += data.len() as u64;
+        Ok(())
+    }
+
+    /// Checks that the whole piece was received and that it matches the piece CID.
+    pub fn finish(self) -> Result<VerifiedPiece, RetrievalError> {
+        let data_size = match (&self.layout, self.end) {
+            (Layout::CarV2(_), Some(end)) if end <= self.received => end,
+            (Layout::CarV2(_), _) => return Err(RetrievalError::Truncated(self.received)),
+            (Layout::Unknown | Layout::Other, _) => self.received,
+        };
+
+        let (commitment, size) = self.commp.finish(&[])?;
+        let mut root = commitment.raw();
+        let mut size = *size;
+        while size < *self.piece_size {
+            root = hash_pair(&root, &zero_commitment(size));
+            size *= 2;
+        }
+        if root != self.expected {
+            return Err(RetrievalError::Mismatch {
+                expected: self.piece_cid,
+                actual: Commitment::<CommP>::from(root).cid().to_string(),
+            });
+        }
+
+        Ok(VerifiedPiece {
+            data_size,
+            padding: self.received - data_size,
+        })
+    }
+}
+
+/// The bytes of `data`, received at offset `start`, from offset `from` on.
+fn suffix(data: &[u8], start: u64, from: u64) -> Option<&[u8]> {
+    let skip = usize::try_from(from.saturating_sub(start)).ok()?;
+    data.get(skip..)
+}
+
+/// Verifies a retrieved piece against its on-chain piece CID while it's being downloaded.
+///
+/// ```js
+/// const verifier = new RetrievalVerifier(pieceCid, BigInt(deal.piece_size));
+/// for await (const data of response.body) verifier.write(data);
+/// // Throws unless the data matches the piece CID
+/// const { dataSize, padding } = verifier.finish();
+/// ```
+#[wasm_bindgen]
+pub struct RetrievalVerifier {
+    verifier: PieceVerifier,
+}
+
+#[wasm_bindgen]
+impl RetrievalVerifier {
+    /// Creates a verifier of the piece `piece_cid` of `piece_size` padded bytes, as stored
+    /// on-chain.
+    #[wasm_bindgen(constructor)]
+    pub fn new(piece_cid: &str, piece_size: u64) -> Result<RetrievalVerifier, JsValue> {
+        let verifier = PieceVerifier::new(piece_cid, piece_size)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(Self { verifier })
+    }
+
+    /// Appends received bytes.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        self.verifier
+            .write(data)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Checks the piece, returning `{ dataSize, padding }`, the size of the data without
+    /// its trailing zero padding and the size of the padding. Throws, explaining why, when
+    /// the data doesn't match the piece CID.
+    pub fn finish(self) -> Result<JsValue, JsValue> {
+        let verified = self
+            .verifier
+            .finish()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(serde_wasm_bindgen::to_value(&verified)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{car::builder::generate_car_v2, piece_commitment, testing::root_first_car};
+
+    fn piece(len: usize) -> (Vec<u8>, String, PaddedPieceSize) {
+        let data = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let (_, car) = generate_car_v2(&data).unwrap();
+        // A piece twice as large as needed, as providers may round sizes up
+        let piece_size = PaddedPieceSize::from_arbitrary_size(car.len() as u64 * 2);
+        let piece_cid = piece_commitment(&car, piece_size)
+            .unwrap()
+            .cid()
+            .to_string();
+        (car, piece_cid, piece_size)
+    }
+
+    fn verify(
+        data: &[u8],
+        piece_cid: &str,
+        piece_size: PaddedPieceSize,
+    ) -> Result<VerifiedPiece, RetrievalError> {
+        let mut verifier = PieceVerifier::new(piece_cid, *piece_size)?;
+        for chunk in data.chunks(1000) {
+            verifier.write(chunk)?;
+        }
+        verifier.finish()
+    }
+
+    #[test]
+    fn verifies_pieces() {
+        for len in [10, 300 * 1024] {
+            let (car, piece_cid, piece_size) = piece(len);
+            let verified = verify(&car, &piece_cid, piece_size).unwrap();
+            assert_eq!(verified.data_size, car.len() as u64);
+            assert_eq!(verified.padding, 0);
+
+            // With the padding up to the piece size
+            let mut padded = car.clone();
+            padded.resize(*piece_size.unpadded() as usize, 0);
+            let verified = verify(&padded, &piece_cid, piece_size).unwrap();
+            assert_eq!(verified.data_size, car.len() as u64);
+            assert_eq!(verified.padding, padded.len() as u64 - car.len() as u64);
+        }
+
+        // CARv1 files aren't trimmed
+        let (_, v1) = root_first_car(&[3; 1000]);
+        let piece_size = PaddedPieceSize::from_arbitrary_size(v1.len() as u64);
+        let piece_cid = piece_commitment(&v1, piece_size).unwrap().cid().to_string();
+        let verified = verify(&v1, &piece_cid, piece_size).unwrap();
+        assert_eq!(verified.data_size, v1.len() as u64);
+    }
+
+    #[test]
+    fn trims_after_the_index() {
+        // Long zero runs before the index, and in it, are part of the file
+        let (car, _, _) = piece(300 * 1024);
+        let mut header =
+            CarV2Header::decode(car[PRAGMA.len()..DATA_OFFSET as usize].try_into().unwrap());
+        let gap = 100 * 1024;
+        let index = &car[header.index_offset as usize..];
+        header.index_offset += gap;
+
+        let mut spaced = car[..header.index_offset as usize - gap as usize].to_vec();
+        spaced[PRAGMA.len() + 32..DATA_OFFSET as usize]
+            .copy_from_slice(&header.index_offset.to_le_bytes());
+        spaced.resize(spaced.len() + gap as usize, 0);
+        spaced.extend_from_slice(index);
+        let end = spaced.len() as u64;
+
+        let piece_size = PaddedPieceSize::from_arbitrary_size(end * 2);
+        spaced.resize(*piece_size.unpadded() as usize, 0);
+        let piece_cid = piece_commitment(&spaced, piece_size)
+            .unwrap()
+            .cid()
+            .to_string();
+        let verified = verify(&spaced, &piece_cid, piece_size).unwrap();
+        assert_eq!(verified.data_size, end);
+        assert_eq!(verified.padding, spaced.len() as u64 - end);
+
+        // Until the whole index is received the end isn't known
+        assert!(matches!(
+            verify(&spaced[..end as usize - 1], &piece_cid, piece_size),
+            Err(RetrievalError::Truncated(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_altered_pieces() {
+        let (car, piece_cid, piece_size) = piece(300 * 1024);
+
+        let mut altered = car.clone();
+        altered[1000] ^= 1;
+        assert!(matches!(
+            verify(&altered, &piece_cid, piece_size),
+            Err(RetrievalError::Mismatch { .. })
+        ));
+
+        assert!(matches!(
+            verify(&car[..car.len() - 10], &piece_cid, piece_size),
+            Err(RetrievalError::Truncated(_))
+        ));
+
+        let mut trailing = car.clone();
+        trailing.extend_from_slice(&[0, 0, 1]);
+        assert!(matches!(
+            verify(&trailing, &piece_cid, piece_size),
+            Err(RetrievalError::TrailingData)
+        ));
+
+        let too_large = vec![0; *piece_size.unpadded() as usize + 1];
+        assert!(matches!(
+            verify(&too_large, &piece_cid, piece_size),
+            Err(RetrievalError::TooLarge { .. })
+        ));
+
+        // The piece size is part of the commitment
+        let smaller = PaddedPieceSize::new(*piece_size / 2).unwrap();
+        assert!(matches!(
+            verify(&car, &piece_cid, smaller),
+            Err(RetrievalError::Mismatch { .. })
+        ));
+        assert!(PieceVerifier::new("not a CID", *piece_size).is_err());
+        assert!(PieceVerifier::new(&piece_cid, 1000).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! Self-test of the CommP implementation against reference vectors.
+//!
+//! Every vector is a run of zeros, padded with zeros up to the unpadded size of its piece, as
+//! [`piece_commitment`] does. Running them catches a miscompiled or mismatched WASM build before
+//! it produces piece CIDs that no provider will accept.
+//!
+//! The expected commitments are the zero piece commitments of their piece sizes, as listed by
+//! go-commp-utils' `zerocomm` package. The 2 KiB one is the piece CID
+//! `baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy`. Vectors of other data
+//! are only added once their commitments come from rust-fil-proofs or go-fil-commp-hashhash.
+use primitives::commitment::{piece::PaddedPieceSize, CommP, Commitment};
+use serde::Serialize;
+use tracing::{error, info};
+use wasm_bindgen::prelude::*;
+
+use crate::piece_commitment;
+
+/// A known-good piece commitment.
+#[derive(Debug, Clone, Copy)]
+pub struct ReferenceVector {
+    /// Number of data bytes, all zero.
+    pub size: usize,
+    /// The expected padded piece size.
+    pub piece_size: u64,
+    /// The expected commitment, hex encoded.
+    pub commitment: &'static str,
+}
+
+const fn vector(size: usize, piece_size: u64, commitment: &'static str) -> ReferenceVector {
+    ReferenceVector {
+        size,
+        piece_size,
+        commitment,
+    }
+}
+
+/// Sizes at, just below and just above the piece size boundaries.
+#[rustfmt::skip]
+pub const REFERENCE_VECTORS: [ReferenceVector; 7] = [
+    vector(127, 128, "3731bb99ac689f66eef5973e4a94da188f4ddcae580724fc6f3fd60dfd488333"),
+    vector(128, 256, "642a607ef886b004bf2c1978463ae1d4693ac0f410eb2d1b7a47fe205e5e750f"),
+    vector(1017, 2048, "fc7e928296e516faade986b28f92d44a4f24b935485223376a799027bc18f833"),
+    vector(2032, 2048, "fc7e928296e516faade986b28f92d44a4f24b935485223376a799027bc18f833"),
+    vector(2033, 4096, "08c47b38ee13bc43f41b915c0eed9911a26086b3ed62401bf9d58b8d19dff624"),
+    vector(65536, 131072, "8e9e2403fa884cf6237f60df25f83ee40dca9ed879eb6f6352d15084f5ad0d3f"),
+    vector(1048576, 2097152, "d0b530dbb0b4f25c5d2f2a28dfee808b53412a02931f18c499f5a254086b1326"),
+];
+
+/// The outcome of a single reference vector.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VectorResult {
+    pub size: usize,
+    pub expected_piece_size: String,
+    pub actual_piece_size: String,
+    pub expected_piece_cid: String,
+    /// The calculated piece CID, or the error raised while calculating it.
+    pub actual_piece_cid: String,
+    pub passed: bool,
+}
+
+/// The outcome of the whole self-test.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelfTestReport {
+    /// Whether every vector passed.
+    pub passed: bool,
+    pub results: Vec<VectorResult>,
+}
+
+impl ReferenceVector {
+    /// Calculates the commitment of the vector's data and compares it with the expected one.
+    pub fn check(&self) -> VectorResult {
+        let mut expected = [0; 32];
+        hex::decode_to_slice(self.commitment, &mut expected)
+            .expect("reference commitments are valid hex");
+        let expected_piece_cid = Commitment::<CommP>::from(expected).cid().to_string();
+
+        let piece_size = PaddedPieceSize::from_arbitrary_size(self.size as u64);
+        let actual_piece_cid = match piece_commitment(&vec![0; self.size], piece_size) {
+            Ok(commitment) => commitment.cid().to_string(),
+            Err(e) => e.to_string(),
+        };
+
+        VectorResult {
+            size: self.size,
+            expected_piece_size: self.piece_size.to_string(),
+            actual_piece_size: piece_size.to_string(),
+            passed: *piece_size == self.piece_size && actual_piece_cid == expected_piece_cid,
+            expected_piece_cid,
+            actual_piece_cid,
+        }
+    }
+}
+
+/// Checks every reference vector.
+pub fn run_self_test() -> SelfTestReport {
+    let results = REFERENCE_VECTORS
+        .iter()
+        .map(ReferenceVector::check)
+        .collect::<Vec<_>>();
+
+    SelfTestReport {
+        passed: results.iter().all(|result| result.passed),
+        results,
+    }
+}
+
+/// Checks the CommP implementation against the reference vectors.
+///
+/// # Returns
+/// A report with the outcome of each vector, `passed` is false if any of them failed.
+#[wasm_bindgen(js_name = "selfTest")]
+pub fn self_test() -> Result<JsValue, JsValue> {
+    let report = run_self_test();
+
+    if report.passed {
+        info!("CommP self-test passed {} vectors", report.results.len());
+    } else {
+        for result in report.results.iter().filter(|result| !result.passed) {
+            error!("CommP self-test failed: {:?}", result);
+        }
+    }
+
+    Ok(serde_wasm_bindgen::to_value(&report)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reference_vectors_pass() {
+        let report = run_self_test();
+        for result in &report.results {
+            assert!(result.passed, "{:?}", result);
+        }
+        assert!(report.passed);
+    }
+
+    /// Data of byte `i` being `i % 251`, the size, padded piece size and commitment printed by
+    /// `python3 wasm-commp/scripts/reference_vectors.py`, an implementation of the piece
+    /// commitment sharing no code with this crate. They aren't reference vectors, nothing
+    /// outside this repository having checked them.
+    #[rustfmt::skip]
+    const PATTERN_VECTORS: [(usize, u64, &str); 18] = [
+        (96, 128, "a824e15015869b666ecad4d73ba67f459b6b6ff5b09b7240ba49d955e601c237"),
+        (127, 128, "b817099547b8c59060fede17e8fef01cd2e5871fcc83cb2cf492000001a97216"),
+        (128, 256, "04fe0a0f7e0292ba93eec28cfa96c116cc6aa7b04221f6a1d92af1ed29824b33"),
+        (254, 256, "f31e0cd7efce2226e300b71f1e86c6140eb4a88b065c489122bf59343abe7e29"),
+        (255, 512, "b273e130e372619aa78372fd7bbe459035131172f1c2d8de62de73179d04ec1f"),
+        (1016, 1024, "23b71f6dd110fc8fb2e5e5940a2c946032bd77e79ce137d8aa887e3a2937a226"),
+        (1017, 2048, "ab8f6969ca6798e3be152678733d5844c657f2e0ab534168ae58be3175999806"),
+        (1023, 2048, "e0a65137038cb6c43dda995f2de18ff6a3bbdbcc492b3130202c28a5c208a01d"),
+        (1024, 2048, "c816836dc0c88b314df12ab87ff2fcb1ac9917c0bba5490a980bc1d6fda5c22d"),
+        (1025, 2048, "01c1558aaf997092456b31870ac8449b564f99af50a16334a765325f5fe43f1d"),
+        (2032, 2048, "6eadd63463ef3576a34dcb6ff742f8448eb22f09d59b9795a4f55d257be3da35"),
+        (2033, 4096, "5dd5e971075d468f6afdec963f393fc553ba6ae0751fe52de307248c3d6bc700"),
+        (4095, 8192, "c3fa02d73aacc8a86109c3d36748ef887b74ba9b9cb9c44dce59c26e68d1cf33"),
+        (4096, 8192, "21d98db8620635e347e9c60a20072413f9d77906d42bdc9f739ca8e6b666c40d"),
+        (4097, 8192, "7f330d88100b04fa28dc83a58329169e245d29a5cd4ee038b6e6126398121235"),
+        (65535, 131072, "68f1065adfbd18161ef2cc160154b7193e41016940ca023e3a8eee724ec2850c"),
+        (65536, 131072, "fd06cf96fb4db24663583d910b7fdd1326999d120ca2c826f12d2f1d0dffad11"),
+        (65537, 131072, "32404780ebc1f9fca9f1b5212214a9eeff126c3f573dae0b6b3efe621b815708"),
+    ];
+
+    #[test]
+    fn matches_the_python_implementation() {
+        for (size, piece_size, commitment) in PATTERN_VECTORS {
+            let data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let padded = PaddedPieceSize::from_arbitrary_size(size as u64);
+            assert_eq!(*padded, piece_size, "{size} bytes");
+            let actual = piece_commitment(&data, padded).unwrap();
+            assert_eq!(hex::encode(actual.raw()), commitment, "{size} bytes");
+        }
+    }
+
+    #[test]
+    fn mismatch_is_reported() {
+        let mut vector = REFERENCE_VECTORS[0];
+        vector.commitment = REFERENCE_VECTORS[1].commitment;
+        assert!(!vector.check().passed);
+
+        let mut vector = REFERENCE_VECTORS[0];
+        vector.piece_size = 256;
+        assert!(!vector.check().passed);
+    }
+}
@@ -0,0 +1,640 @@
+//! Verified content for the in-browser gateway of `src/sw.ts`.
+//!
+//! The service worker answers `/ipfs/<cid>/<path>` requests by requesting the entity at the
+//! path from trustless gateways and passing the CAR data to a [`PathExportStream`] as it's
+//! received. Every block is checked against its CID, the path is resolved through the blocks
+//! received, and the file bytes are streamed back to the page as soon as the blocks holding
+//! them are verified. The content type is guessed from the file name, or sniffed from the
+//! first bytes of the file. `Range` requests are answered from the blocks holding the range,
+//! requested with `entity-bytes`.
+use std::collections::HashMap;
+
+use cid::Cid;
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    car::{
+        reader::{verify_block, CarDecoder},
+        CarError,
+    },
+    exporter::{decode_unixfs, find_entry, load, Blocks, ExportError, ExportInfo, Walker},
+    gateway::{GatewayError, GatewayRequest, LinkedBlocks, Recorder, CAR_ACCEPT},
+    ipld::RAW,
+    unixfs::pb::{DATA_TYPE_DIRECTORY, DATA_TYPE_FILE, DATA_TYPE_HAMT_SHARD, DATA_TYPE_RAW},
+};
+
+/// Number of bytes the content type is sniffed from.
+pub const SNIFF_LEN: usize = 512;
+/// Content type of text files.
+const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+/// Content type of unknown files.
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// The content type of the files with an extension, by extension.
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("txt", TEXT_PLAIN),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogg", "audio/ogg"),
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("pdf", "application/pdf"),
+    ("wasm", "application/wasm"),
+];
+
+/// Magic numbers of the types sniffed, along with their offset.
+const MAGIC_NUMBERS: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (8, b"WEBP", "image/webp"),
+    (8, b"WAVE", "audio/wav"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"\x1a\x45\xdf\xa3", "video/webm"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"ID3", "audio/mpeg"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"\0asm", "application/wasm"),
+];
+
+/// Guesses the content type of file `name` from its extension, or from `head`, its first
+/// bytes.
+pub fn content_type(name: &str, head: &[u8]) -> &'static str {
+    content_type_of_name(name).unwrap_or_else(|| sniff(head))
+}
+
+/// The content type of file `name` according to its extension.
+fn content_type_of_name(name: &str) -> Option<&'static str> {
+    let (_, extension) = name.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+    CONTENT_TYPES
+        .iter()
+        .find(|(known, _)| *known == extension)
+        .map(|&(_, content_type)| content_type)
+}
+
+/// Sniffs the content type of a file from `head`, its first bytes.
+fn sniff(head: &[u8]) -> &'static str {
+    if let Some(&(_, _, content_type)) = MAGIC_NUMBERS
+        .iter()
+        .find(|(offset, magic, _)| head.get(*offset..offset + magic.len()) == Some(magic))
+    {
+        return content_type;
+    }
+    let text = head.trim_ascii_start().to_ascii_lowercase();
+    if text.starts_with(b"<!doctype html") || text.starts_with(b"<html") {
+        return "text/html; charset=utf-8";
+    }
+    // The head may end in the middle of a character
+    match std::str::from_utf8(head) {
+        Ok(_) => TEXT_PLAIN,
+        Err(e) if e.error_len().is_none() && !head.contains(&0) => TEXT_PLAIN,
+        Err(_) => OCTET_STREAM,
+    }
+}
+
+/// An entry of a directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectoryEntry {
+    pub name: String,
+    pub cid: String,
+}
+
+/// What a path resolves to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PathContent {
+    File(ExportInfo),
+    Directory {
+        cid: String,
+        /// Sorted by name.
+        entries: Vec<DirectoryEntry>,
+    },
+}
+
+/// The entries of directory `cid`, following HAMT shards.
+fn directory_entries(blocks: &impl Blocks, cid: Cid) -> Result<Vec<DirectoryEntry>, ExportError> {
+    let mut entries = vec![];
+    let mut stack = vec![cid];
+    while let Some(cid) = stack.pop() {
+        let data = load(blocks, &cid)?.ok_or(ExportError::MissingBlock(cid))?;
+        let (node, unixfs) = decode_unixfs(cid, data)?;
+        for link in node.links {
+            match unixfs.data_type {
+                // Sub-shards are named after their bucket alone
+                DATA_TYPE_HAMT_SHARD if link.name.len() == 2 => stack.push(link.cid),
+                DATA_TYPE_HAMT_SHARD => entries.push(DirectoryEntry {
+                    name: link.name[2..].to_string(),
+                    cid: link.cid.to_string(),
+                }),
+                _ => entries.push(DirectoryEntry {
+                    name: link.name,
+                    cid: link.cid.to_string(),
+                }),
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// What the path of the request resolves to, once its blocks are received.
+enum Target {
+    File { name: String, walker: Walker },
+    Directory(Cid),
+}
+
+/// Resolves the path of a gateway request as its CAR response is received, and streams the
+/// file it ends at.
+///
+/// Once the path resolves to a file, the blocks are dropped as soon as they're read, only
+/// their CIDs being kept to check that they're linked from the root. Blocks the file links to
+/// more than once must then be sent again, which [`CAR_ACCEPT`] asks for with `dups=y`.
+/// Directories are kept until the end, to list their entries.
+pub struct PathExporter {
+    request: GatewayRequest,
+    decoder: CarDecoder,
+    blocks: Recorder<HashMap<Cid, Vec<u8>>>,
+    linked: LinkedBlocks,
+    roots_checked: bool,
+    target: Option<Target>,
+    /// The first bytes of the file, to sniff its type from.
+    head: Vec<u8>,
+}
+
+impl PathExporter {
+    pub fn new(request: GatewayRequest) -> Self {
+        Self {
+            linked: LinkedBlocks::new(request.root),
+            request,
+            decoder: CarDecoder::new(),
+            blocks: Recorder::new(HashMap::new()),
+            roots_checked: false,
+            target: None,
+            head: vec![],
+        }
+    }
+
+    pub fn request(&self) -> &GatewayRequest {
+        &self.request
+    }
+
+    /// Feeds received CAR bytes, returning the file bytes that follow the ones already
+    /// returned, if any.
+    pub fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, GatewayError> {
+        self.decoder.push(data);
+        while let Some((_, block)) = self.decoder.next_block()? {
+            verify_block(&block)?;
+            self.linked.insert(block.cid, &block.data)?;
+            self.blocks.get_mut().insert(block.cid, block.data);
+        }
+        if !self.roots_checked {
+            if let Some(header) = self.decoder.header() {
+                if !header.roots.contains(&self.request.root) {
+                    return Err(GatewayError::UnexpectedRoots {
+                        expected: self.request.root.to_string(),
+                        actual: header.roots.iter().map(Cid::to_string).collect(),
+                    });
+                }
+                self.roots_checked = true;
+            }
+        }
+
+        if self.target.is_none() {
+            self.target = self.resolve(false)?;
+        }
+        let mut out = vec![];
+        if let Some(Target::File { walker, .. }) = &mut self.target {
+            walker.advance(&self.blocks, &mut out)?;
+            for cid in self.blocks.take_read() {
+                self.blocks.get_mut().remove(&cid);
+            }
+        }
+        let missing = SNIFF_LEN.saturating_sub(self.head.len());
+        self.head.extend_from_slice(&out[..missing.min(out.len())]);
+        Ok(out)
+    }
+
+    /// Resolves the path through the blocks received so far. Unless `complete`, missing
+    /// blocks are waited for.
+    fn resolve(&self, complete: bool) -> Result<Option<Target>, GatewayError> {
+        let mut cid = self.request.root;
+        let mut name = cid.to_string();
+        let mut resolved = String::new();
+        for next in self.request.names() {
+            let entry = match find_entry(&self.blocks, cid, next, &resolved) {
+                Err(ExportError::MissingBlock(_)) if !complete => return Ok(None),
+                entry => entry?,
+            };
+            resolved = format!("{resolved}/{next}");
+            cid = entry.ok_or_else(|| ExportError::NotFound(resolved.clone()))?;
+            name = next.to_string();
+        }
+
+        let Some(data) = load(&self.blocks, &cid)? else {
+            return match complete {
+                true => Err(ExportError::MissingBlock(cid).into()),
+                false => Ok(None),
+            };
+        };
+        let data_type = match cid.codec() {
+            RAW => DATA_TYPE_RAW,
+            _ => decode_unixfs(cid, data)?.1.data_type,
+        };
+        match data_type {
+            DATA_TYPE_FILE | DATA_TYPE_RAW => {
+                let mut walker = Walker::file(cid, name.clone());
+                if let Some(range) = &self.request.entity_bytes {
+                    walker = walker.range(range.clone());
+                }
+                Ok(Some(Target::File { name, walker }))
+            }
+            DATA_TYPE_DIRECTORY | DATA_TYPE_HAMT_SHARD => Ok(Some(Target::Directory(cid))),
+            data_type => Err(ExportError::NotAFile(cid, data_type).into()),
+        }
+    }
+
+    /// Whether the path resolves to a directory, `None` until it's resolved.
+    pub fn is_directory(&self) -> Option<bool> {
+        self.target
+            .as_ref()
+            .map(|target| matches!(target, Target::Directory(_)))
+    }
+
+    /// The content type of the file, once it can be told: from its name, or once
+    /// [`SNIFF_LEN`] bytes or the whole file are received. Ranges that don't start the file
+    /// can't be sniffed.
+    pub fn content_type(&self) -> Option<&'static str> {
+        let Some(Target::File { name, walker }) = &self.target else {
+            return None;
+        };
+        let sniffable = self
+            .request
+            .entity_bytes
+            .as_ref()
+            .is_none_or(|range| range.start == 0);
+        match content_type_of_name(name) {
+            Some(content_type) => Some(content_type),
+            None if !sniffable => Some(OCTET_STREAM),
+            None if self.head.len() >= SNIFF_LEN || walker.is_done() => Some(sniff(&self.head)),
+            None => None,
+        }
+    }
+
+    /// The size of the file, once its root is received.
+    pub fn size(&self) -> Option<u64> {
+        match &self.target {
+            Some(Target::File { walker, .. }) => walker.size(),
+            _ => None,
+        }
+    }
+
+    /// Checks that the response was received in full and held only blocks linked from the
+    /// root.
+    pub fn finish(mut self) -> Result<PathContent, GatewayError> {
+        self.decoder.finish()?;
+        if !self.roots_checked {
+            return Err(CarError::Truncated.into());
+        }
+        let target = match self.target.take() {
+            Some(target) => target,
+            None => self
+                .resolve(true)?
+                .expect("complete resolutions fail or succeed"),
+        };
+        let content = match target {
+            Target::File { walker, .. } => PathContent::File(walker.finish()?),
+            Target::Directory(cid) => PathContent::Directory {
+                cid: cid.to_string(),
+                entries: directory_entries(&self.blocks, cid)?,
+            },
+        };
+
+        if let Some(unexpected) = self.linked.unlinked() {
+            return Err(GatewayError::UnexpectedBlock(unexpected));
+        }
+        Ok(content)
+    }
+}
+
+/// Verifies the response to an `/ipfs/<cid>/<path>` request while it's received.
+///
+/// ```js
+/// // or `new PathExportStream(url.pathname, start, end)` for a byte range
+/// const stream = new PathExportStream(url.pathname);
+/// const response = await fetch(stream.url(gateway), { headers: { Accept: stream.accept } });
+/// for await (const data of response.body) {
+///   const bytes = stream.write(data);
+///   // `stream.contentType` is set once known
+/// }
+/// // { type: "file", name, cid, size } or { type: "directory", cid, entries: [{ name, cid }] }
+/// const content = stream.finish();
+/// ```
+#[wasm_bindgen]
+pub struct PathExportStream {
+    exporter: PathExporter,
+}
+
+#[wasm_bindgen]
+impl PathExportStream {
+    /// Creates a stream for the entity at `path`, such as `/ipfs/<cid>/photos/a.jpg`,
+    /// anything before `/ipfs/` being ignored.
+    ///
+    /// If `start` or `end` is set, only that (exclusive) byte range of a file is requested
+    /// and streamed.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        path: &str,
+        start: Option<u64>,
+        end: Option<u64>,
+    ) -> Result<PathExportStream, JsValue> {
+        let mut request =
+            GatewayRequest::from_path(path).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        if start.is_some() || end.is_some() {
+            let range = start.unwrap_or(0)..end.unwrap_or(u64::MAX);
+            if range.is_empty() {
+                let error = ExportError::InvalidRange {
+                    start: range.start,
+                    end: range.end,
+                };
+                return Err(JsValue::from_str(&error.to_string()));
+            }
+            request = request.entity_bytes(range);
+        }
+        Ok(Self {
+            exporter: PathExporter::new(request),
+        })
+    }
+
+    /// The URL to request the entity from on the trustless gateway at `gateway`.
+    pub fn url(&self, gateway: &str) -> String {
+        self.exporter.request().url(gateway)
+    }
+
+    /// The `Accept` header of the request.
+    #[wasm_bindgen(getter)]
+    pub fn accept(&self) -> String {
+        CAR_ACCEPT.to_string()
+    }
+
+    /// Feeds received CAR bytes, returning the verified file bytes that follow the ones
+    /// already returned.
+    pub fn write(&mut self, data: &[u8]) -> Result<Vec<u8>, JsValue> {
+        self.exporter
+            .write(data)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// The content type of the file, once known.
+    #[wasm_bindgen(getter, js_name = "contentType")]
+    pub fn content_type(&self) -> Option<String> {
+        self.exporter.content_type().map(str::to_string)
+    }
+
+    /// The size of the file, `undefined` until its root is received.
+    #[wasm_bindgen(getter)]
+    pub fn size(&self) -> Option<u64> {
+        self.exporter.size()
+    }
+
+    /// Whether the path resolves to a directory, `undefined` until it's resolved.
+    #[wasm_bindgen(getter, js_name = "isDirectory")]
+    pub fn is_directory(&self) -> Option<bool> {
+        self.exporter.is_directory()
+    }
+
+    /// Checks that the response was received in full, returning what the path resolves to.
+    pub fn finish(self) -> Result<JsValue, JsValue> {
+        let content = self
+            .exporter
+            .finish()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(serde_wasm_bindgen::to_value(&content)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        car::{directory::DirectoryCarBuilder, index::IndexedCar},
+        gateway::traverse,
+        ipld::Block,
+        testing::encode_car,
+    };
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    /// A site holding `index.html`, `img/logo` and `notes`, and its root.
+    fn site() -> (Cid, Vec<u8>, Vec<u8>) {
+        let logo = [PNG.to_vec(), vec![7; 300 * 1024]].concat();
+        let mut builder = DirectoryCarBuilder::new(vec![]);
+        builder.add_file("index.html").unwrap();
+        builder.write(b"<!doctype html><p>hi</p>").unwrap();
+        builder.add_file("img/logo").unwrap();
+        builder.write(&logo).unwrap();
+        builder.add_file("notes").unwrap();
+        builder.write("caf\u{e9}".as_bytes()).unwrap();
+        let output = builder.finish().unwrap();
+        let car = [output.header, output.out, output.index].concat();
+        (output.root, car, logo)
+    }
+
+    /// The response of a gateway holding `car` to a request for `path`.
+    fn respond(car: &[u8], path: &str) -> (GatewayRequest, Vec<(Cid, Vec<u8>)>) {
+        let request = GatewayRequest::from_path(path).unwrap();
+        let blocks = respond_to(car, &request);
+        (request, blocks)
+    }
+
+    /// The blocks a gateway holding `car` sends for `request`.
+    fn respond_to(car: &[u8], request: &GatewayRequest) -> Vec<(Cid, Vec<u8>)> {
+        let recorder = Recorder::new(IndexedCar::new(car).unwrap());
+        traverse(&recorder, request).unwrap();
+        let blocks = IndexedCar::new(car).unwrap();
+        recorder
+            .take_read()
+            .into_iter()
+            .map(|cid| (cid, blocks.get(&cid).unwrap().unwrap().to_vec()))
+            .collect()
+    }
+
+    fn export(
+        request: GatewayRequest,
+        response: &[u8],
+    ) -> Result<(Vec<u8>, Option<&'static str>, PathContent), GatewayError> {
+        let mut exporter = PathExporter::new(request);
+        let mut out = vec![];
+        let mut content_type = None;
+        for chunk in response.chunks(16 * 1024) {
+            out.extend(exporter.write(chunk)?);
+            content_type = content_type.or(exporter.content_type());
+        }
+        Ok((out, content_type, exporter.finish()?))
+    }
+
+    #[test]
+    fn guesses_content_types() {
+        assert_eq!(content_type("a.JPG", b""), "image/jpeg");
+        assert_eq!(
+            content_type("index.html", b"\x89PNG"),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(content_type("logo", PNG), "image/png");
+        assert_eq!(content_type("clip", b"\0\0\0\x18ftypmp42"), "video/mp4");
+        assert_eq!(
+            content_type("page", b"\n  <!DOCTYPE html>"),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(content_type("notes", "caf\u{e9}".as_bytes()), TEXT_PLAIN);
+        // Cut in the middle of a character
+        assert_eq!(
+            content_type("notes", &"caf\u{e9}".as_bytes()[..4]),
+            TEXT_PLAIN
+        );
+        assert_eq!(content_type("data", &[0, 159, 146, 150]), OCTET_STREAM);
+    }
+
+    #[test]
+    fn streams_paths() {
+        let (root, car, logo) = site();
+
+        let path = format!("/delia/ipfs/{root}/img/logo");
+        let (request, blocks) = respond(&car, &path);
+        let response = encode_car(&root, &blocks);
+        let mut exporter = PathExporter::new(request);
+        let mut out = vec![];
+        let mut content_type = None;
+        for chunk in response.chunks(16 * 1024) {
+            out.extend(exporter.write(chunk).unwrap());
+            // Known from the first bytes, as soon as they're received
+            if out.len() >= SNIFF_LEN && out.len() < logo.len() {
+                content_type = content_type.or(exporter.content_type());
+            }
+        }
+        assert_eq!(content_type, Some("image/png"));
+        assert_eq!(exporter.is_directory(), Some(false));
+        assert_eq!(exporter.size(), Some(logo.len() as u64));
+        // The blocks are dropped once read
+        assert!(exporter.blocks.get_mut().is_empty());
+        let PathContent::File(info) = exporter.finish().unwrap() else {
+            panic!("expected a file");
+        };
+        assert!(out == logo);
+        assert_eq!(info.name.as_deref(), Some("logo"));
+
+        let (request, blocks) = respond(&car, &format!("/ipfs/{root}/index.html"));
+        let (out, content_type, _) = export(request, &encode_car(&root, &blocks)).unwrap();
+        assert_eq!(out, b"<!doctype html><p>hi</p>");
+        assert_eq!(content_type, Some("text/html; charset=utf-8"));
+
+        // Short files are sniffed once complete
+        let (request, blocks) = respond(&car, &format!("/ipfs/{root}/notes"));
+        let (_, content_type, _) = export(request, &encode_car(&root, &blocks)).unwrap();
+        assert_eq!(content_type, Some(TEXT_PLAIN));
+
+        let (request, blocks) = respond(&car, &format!("/ipfs/{root}/"));
+        let (out, _, content) = export(request, &encode_car(&root, &blocks)).unwrap();
+        assert!(out.is_empty());
+        let PathContent::Directory { entries, .. } = content else {
+            panic!("expected a directory");
+        };
+        let names = entries
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["img", "index.html", "notes"]);
+    }
+
+    #[test]
+    fn streams_ranges() {
+        let (root, car, logo) = site();
+        let request = GatewayRequest::from_path(&format!("/ipfs/{root}/img/logo")).unwrap();
+
+        let range = request.clone().entity_bytes(270_000..280_000);
+        let blocks = respond_to(&car, &range);
+        // The path, the file root and the second leaf
+        assert_eq!(blocks.len(), 4);
+        let (out, content_type, content) = export(range, &encode_car(&root, &blocks)).unwrap();
+        assert!(out == logo[270_000..280_000]);
+        // Only the start of a file can be sniffed
+        assert_eq!(content_type, Some(OCTET_STREAM));
+        let PathContent::File(info) = content else {
+            panic!("expected a file");
+        };
+        assert_eq!(info.size, logo.len() as u64);
+
+        let range = request.clone().entity_bytes(0..SNIFF_LEN as u64);
+        let blocks = respond_to(&car, &range);
+        let (out, content_type, _) = export(range, &encode_car(&root, &blocks)).unwrap();
+        assert!(out == logo[..SNIFF_LEN]);
+        assert_eq!(content_type, Some("image/png"));
+
+        let range = request.entity_bytes(300_000..u64::MAX);
+        let blocks = respond_to(&car, &range);
+        let (out, _, _) = export(range, &encode_car(&root, &blocks)).unwrap();
+        assert!(out == logo[300_000..]);
+    }
+
+    #[test]
+    fn rejects_untrusted_paths() {
+        let (root, car, _) = site();
+        let path = format!("/ipfs/{root}/img/logo");
+        let (request, mut blocks) = respond(&car, &path);
+
+        let stray = Block::new(RAW, b"stray".to_vec());
+        let mut extra = blocks.clone();
+        extra.push((stray.cid, stray.data));
+        assert!(matches!(
+            export(request.clone(), &encode_car(&root, &extra)),
+            Err(GatewayError::UnexpectedBlock(_))
+        ));
+
+        assert!(matches!(
+            export(request.clone(), &encode_car(&stray.cid, &blocks)),
+            Err(GatewayError::UnexpectedRoots { .. })
+        ));
+
+        blocks.last_mut().unwrap().1[0] ^= 1;
+        assert!(matches!(
+            export(request.clone(), &encode_car(&root, &blocks)),
+            Err(GatewayError::Car(CarError::HashMismatch(_)))
+        ));
+
+        blocks.pop();
+        assert!(matches!(
+            export(request, &encode_car(&root, &blocks)),
+            Err(GatewayError::Export(ExportError::MissingBlock(_)))
+        ));
+
+        let (request, blocks) = respond(&car, &format!("/ipfs/{root}/notes"));
+        let request = request.path("/missing");
+        assert!(matches!(
+            export(request, &encode_car(&root, &blocks)),
+            Err(GatewayError::Export(ExportError::NotFound(_)))
+        ));
+
+        assert!(GatewayRequest::from_path("/ipns/example.com").is_err());
+        assert!(GatewayRequest::from_path("/ipfs/not-a-cid").is_err());
+    }
+}
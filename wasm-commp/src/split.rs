@@ -0,0 +1,316 @@
+//! Splitting of payloads across multiple pieces.
+//!
+//! A padded piece is always a power of two, as such, a payload just over a power of two
+//! wastes almost half of its piece and a payload larger than a provider's sector cannot be
+//! stored at all. The splitter cuts the payload into several pieces, each with its own CommP,
+//! and records how to put them back together in a [`Manifest`].
+use primitives::commitment::piece::PaddedPieceSize;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::info;
+use wasm_bindgen::prelude::*;
+
+use crate::piece_commitment;
+
+/// The smallest padded piece size allowed by Filecoin.
+const MIN_PIECE_SIZE: u64 = 128;
+
+/// Every 127 unpadded bytes take 128 bytes once Fr32-padded.
+const UNPADDED_CHUNK: u64 = 127;
+
+#[derive(Debug, Error)]
+pub enum SplitError {
+    #[error("payload must not be empty")]
+    EmptyPayload,
+    #[error("invalid maximum piece size {0}: {1}")]
+    InvalidMaxPieceSize(u64, String),
+    #[error("expected {expected} pieces, got {actual}")]
+    PieceCountMismatch { expected: usize, actual: usize },
+    #[error("piece {index} has {actual} bytes, expected at least {expected}")]
+    PieceTooShort {
+        index: usize,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("piece {index} has non-zero bytes past its payload")]
+    UnexpectedTrailingData { index: usize },
+    #[error("failed to calculate piece commitment: {0}")]
+    Commitment(#[from] std::io::Error),
+}
+
+/// A single piece of a split payload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestPiece {
+    /// Offset of the first payload byte stored in this piece.
+    pub offset: u64,
+    /// Number of payload bytes stored in this piece, the remaining bytes are zero padding.
+    pub length: u64,
+    /// The padded piece size.
+    pub padded_size: u64,
+    /// The piece CID (CommP).
+    pub piece_cid: String,
+}
+
+/// Describes how a payload was split across pieces and how to reassemble it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    /// Size of the original payload in bytes.
+    pub payload_size: u64,
+    /// The pieces, in payload order.
+    pub pieces: Vec<ManifestPiece>,
+}
+
+impl Manifest {
+    /// Reassembles the original payload from the retrieved pieces.
+    ///
+    /// Pieces must be passed in manifest order, each may carry trailing zero padding
+    /// (e.g. when retrieved at its full unpadded size), which is discarded.
+    pub fn reassemble<P: AsRef<[u8]>>(&self, pieces: &[P]) -> Result<Vec<u8>, SplitError> {
+        if pieces.len() != self.pieces.len() {
+            return Err(SplitError::PieceCountMismatch {
+                expected: self.pieces.len(),
+                actual: pieces.len(),
+            });
+        }
+
+        let mut payload = Vec::with_capacity(self.payload_size as usize);
+        for (index, (entry, piece)) in self.pieces.iter().zip(pieces).enumerate() {
+            let piece = piece.as_ref();
+            let length = entry.length as usize;
+            if piece.len() < length {
+                return Err(SplitError::PieceTooShort {
+                    index,
+                    expected: entry.length,
+                    actual: piece.len() as u64,
+                });
+            }
+
+            let (data, padding) = piece.split_at(length);
+            if padding.iter().any(|&b| b != 0) {
+                return Err(SplitError::UnexpectedTrailingData { index });
+            }
+            payload.extend_from_slice(data);
+        }
+
+        Ok(payload)
+    }
+}
+
+/// Plans the padded piece sizes for a payload of `payload_size` bytes.
+///
+/// The total padded size is the smallest possible one — the payload rounded up to the next
+/// 127 byte chunk, Fr32-padded — decomposed into pieces no larger than `max_piece_size`.
+/// Pieces are returned largest first so only the last one is partially filled.
+pub fn plan_pieces(
+    payload_size: u64,
+    max_piece_size: u64,
+) -> Result<Vec<PaddedPieceSize>, SplitError> {
+    if payload_size == 0 {
+        return Err(SplitError::EmptyPayload);
+    }
+    let max_piece_size = PaddedPieceSize::new(max_piece_size)
+        .map_err(|e| SplitError::InvalidMaxPieceSize(max_piece_size, e.to_string()))?;
+
+    let total_padded = payload_size.div_ceil(UNPADDED_CHUNK) * MIN_PIECE_SIZE;
+    let full_pieces = total_padded / *max_piece_size;
+    let remainder = total_padded % *max_piece_size;
+
+    let mut sizes = vec![max_piece_size; full_pieces as usize];
+    // The remainder is a multiple of 128 and smaller than the maximum,
+    // each of its set bits is a power of two piece.
+    let mut bit = *max_piece_size >> 1;
+    while bit >= MIN_PIECE_SIZE {
+        if remainder & bit != 0 {
+            sizes.push(PaddedPieceSize::new(bit).expect("powers of two of at least 128 are valid"));
+        }
+        bit >>= 1;
+    }
+
+    Ok(sizes)
+}
+
+/// Splits `data` into pieces no larger than `max_piece_size` and calculates their CommP.
+pub fn split_payload(data: &[u8], max_piece_size: u64) -> Result<Manifest, SplitError> {
+    let sizes = plan_pieces(data.len() as u64, max_piece_size)?;
+
+    let mut pieces = Vec::with_capacity(sizes.len());
+    let mut offset = 0;
+    for size in sizes {
+        let length = (*size.unpadded()).min(data.len() as u64 - offset);
+        let piece_data = &data[offset as usize..(offset + length) as usize];
+        let commitment = piece_commitment(piece_data, size)?;
+
+        pieces.push(ManifestPiece {
+            offset,
+            length,
+            padded_size: *size,
+            piece_cid: commitment.cid().to_string(),
+        });
+        offset += length;
+    }
+
+    Ok(Manifest {
+        payload_size: data.len() as u64,
+        pieces,
+    })
+}
+
+/// Splits the payload across pieces no larger than `maxPieceSize`.
+///
+/// # Arguments
+/// * `data` - The payload bytes (e.g. a CAR file).
+/// * `max_piece_size` - The maximum padded piece size, usually the provider's sector size.
+///
+/// # Returns
+/// The [`Manifest`] describing each piece, the piece bytes are
+/// `data.subarray(offset, offset + length)`.
+#[wasm_bindgen(js_name = "splitPayload")]
+pub fn split_payload_js(data: &[u8], max_piece_size: u64) -> Result<JsValue, JsValue> {
+    let manifest =
+        split_payload(data, max_piece_size).map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    info!(
+        "Split {} bytes into {} pieces",
+        manifest.payload_size,
+        manifest.pieces.len()
+    );
+
+    Ok(serde_wasm_bindgen::to_value(&manifest)?)
+}
+
+/// Reassembles a payload split with `splitPayload`.
+///
+/// # Arguments
+/// * `manifest` - The manifest returned by `splitPayload`.
+/// * `pieces` - An array with the retrieved bytes of each piece, in manifest order.
+///
+/// # Returns
+/// The original payload bytes.
+#[wasm_bindgen(js_name = "reassemblePayload")]
+pub fn reassemble_payload_js(manifest: JsValue, pieces: js_sys::Array) -> Result<Vec<u8>, JsValue> {
+    let manifest: Manifest = serde_wasm_bindgen::from_value(manifest)?;
+    let pieces = pieces
+        .iter()
+        .map(|piece| js_sys::Uint8Array::new(&piece).to_vec())
+        .collect::<Vec<_>>();
+
+    manifest
+        .reassemble(&pieces)
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KIB: u64 = 1024;
+
+    fn sizes(payload_size: u64, max_piece_size: u64) -> Vec<u64> {
+        plan_pieces(payload_size, max_piece_size)
+            .unwrap()
+            .into_iter()
+            .map(|size| *size)
+            .collect()
+    }
+
+    #[test]
+    fn plan_fits_in_single_piece() {
+        assert_eq!(sizes(127, 2 * KIB), vec![128]);
+        assert_eq!(sizes(2032, 2 * KIB), vec![2 * KIB]);
+    }
+
+    #[test]
+    fn plan_just_over_power_of_two() {
+        // A single piece would be 4KiB, splitting wastes less than a chunk
+        assert_eq!(sizes(2033, 4 * KIB), vec![2 * KIB, 128]);
+    }
+
+    #[test]
+    fn plan_respects_max_piece_size() {
+        // 5 full 2KiB pieces and 1KiB + 256 bytes of remainder
+        let plan = sizes(5 * 2032 + 1016 + 200, 2 * KIB);
+        assert_eq!(plan, vec![2048, 2048, 2048, 2048, 2048, 1024, 256]);
+    }
+
+    #[test]
+    fn plan_is_minimal() {
+        for payload_size in [1, 126, 127, 128, 1000, 4064, 4065, 100_000] {
+            let total: u64 = sizes(payload_size, 8 * KIB).iter().sum();
+            let capacity = total / 128 * 127;
+            assert!(capacity >= payload_size);
+            assert!(capacity - payload_size < UNPADDED_CHUNK);
+        }
+    }
+
+    #[test]
+    fn plan_rejects_invalid_input() {
+        assert!(matches!(
+            plan_pieces(0, 2 * KIB),
+            Err(SplitError::EmptyPayload)
+        ));
+        assert!(matches!(
+            plan_pieces(100, 3000),
+            Err(SplitError::InvalidMaxPieceSize(3000, _))
+        ));
+    }
+
+    #[test]
+    fn single_piece_matches_commp() {
+        let data = vec![0x42; 1000];
+        let manifest = split_payload(&data, 2 * KIB).unwrap();
+        let expected = piece_commitment(&data, PaddedPieceSize::from_arbitrary_size(1000)).unwrap();
+
+        assert_eq!(manifest.pieces.len(), 1);
+        assert_eq!(manifest.pieces[0].piece_cid, expected.cid().to_string());
+    }
+
+    #[test]
+    fn split_and_reassemble() {
+        let data = (0..10_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let manifest = split_payload(&data, 2 * KIB).unwrap();
+
+        assert_eq!(manifest.payload_size, data.len() as u64);
+        assert_eq!(
+            manifest.pieces.iter().map(|p| p.length).sum::<u64>(),
+            data.len() as u64
+        );
+
+        // Retrieved pieces carry their zero padding
+        let pieces = manifest
+            .pieces
+            .iter()
+            .map(|p| {
+                let mut piece = data[p.offset as usize..(p.offset + p.length) as usize].to_vec();
+                piece.resize((p.padded_size / 128 * 127) as usize, 0);
+                piece
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(manifest.reassemble(&pieces).unwrap(), data);
+    }
+
+    #[test]
+    fn reassemble_rejects_bad_pieces() {
+        let data = vec![1; 3000];
+        let manifest = split_payload(&data, 2 * KIB).unwrap();
+        let (first, second) = data.split_at(2032);
+
+        assert!(matches!(
+            manifest.reassemble(&[first]),
+            Err(SplitError::PieceCountMismatch { .. })
+        ));
+        assert!(matches!(
+            manifest.reassemble(&[first, &second[1..]]),
+            Err(SplitError::PieceTooShort { index: 1, .. })
+        ));
+
+        let mut padded = second.to_vec();
+        padded.push(1);
+        assert!(matches!(
+            manifest.reassemble(&[first, &padded]),
+            Err(SplitError::UnexpectedTrailingData { index: 1 })
+        ));
+    }
+}
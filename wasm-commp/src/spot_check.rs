@@ -0,0 +1,522 @@
+//! Proof-of-retrievability spot checks.
+//!
+//! While the client still holds the data, it builds the piece Merkle tree and picks random
+//! leaf ranges, storing the Merkle path of each range in a [`SpotCheckPlan`]. Later on, the
+//! matching byte ranges are requested from the provider's download endpoint and verified
+//! against the on-chain piece CID using only the stored paths, so checking a multi-gigabyte
+//! piece only transfers a few kilobytes.
+use std::{
+    io::{Cursor, Read},
+    ops::Range,
+};
+
+use cid::Cid;
+use primitives::{
+    commitment::{piece::PaddedPieceSize, CommP, Commitment},
+    NODE_SIZE,
+};
+use rand::{seq::index, Rng};
+use rs_merkle::MerkleProof;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::info;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    build_piece_tree,
+    fetch::{fetch_range, BrowserFetch, Fetch},
+    fr32_reader::Fr32Reader,
+    hasher::Sha256,
+    zero_reader::ZeroPaddingReader,
+};
+
+/// Number of leaves (Fr32 padded nodes) in an Fr32 block of 127 unpadded bytes.
+const LEAVES_PER_BLOCK: u64 = 4;
+/// Unpadded bytes in an Fr32 block.
+const BLOCK_SIZE: u64 = 127;
+/// Default number of leaves in a challenge, 1016 unpadded bytes.
+pub const DEFAULT_CHALLENGE_LEAVES: u64 = 32;
+
+#[derive(Debug, Error)]
+pub enum SpotCheckError {
+    #[error("data must not be empty")]
+    EmptyData,
+    #[error("{0} bytes do not fit in the piece")]
+    DataTooLarge(usize),
+    #[error("challenge size must be a power of two between 4 and {max} leaves, got {actual}")]
+    InvalidChallengeSize { max: u64, actual: u64 },
+    #[error("invalid piece CID: {0}")]
+    InvalidPieceCid(String),
+    #[error("plan was generated for piece {planned}, not {expected}")]
+    PieceMismatch { planned: String, expected: String },
+    #[error("invalid proof hash: {0}")]
+    InvalidProof(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// A leaf range of the piece along with the Merkle path proving it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Challenge {
+    /// Index of the first challenged leaf, aligned to `leaf_count`.
+    pub first_leaf: u64,
+    /// Number of challenged leaves, a power of two of at least 4.
+    pub leaf_count: u64,
+    /// Hex encoded hashes needed to compute the root from the challenged leaves.
+    pub proof: Vec<String>,
+}
+
+impl Challenge {
+    /// The range of unpadded piece bytes covered by the challenged leaves.
+    pub fn byte_range(&self) -> Range<u64> {
+        let start = self.first_leaf / LEAVES_PER_BLOCK * BLOCK_SIZE;
+        let end = (self.first_leaf + self.leaf_count) / LEAVES_PER_BLOCK * BLOCK_SIZE;
+        start..end
+    }
+
+    /// Verifies the unpadded `bytes` of the challenged range against the piece `root`.
+    ///
+    /// The bytes may be shorter than the range when the piece data ends before it,
+    /// the missing bytes are the piece's zero padding.
+    pub fn verify(
+        &self,
+        bytes: &[u8],
+        root: [u8; 32],
+        piece_size: PaddedPieceSize,
+    ) -> Result<bool, SpotCheckError> {
+        let range = self.byte_range();
+        if bytes.len() as u64 > range.end - range.start {
+            return Ok(false);
+        }
+
+        let mut fr32_reader = Fr32Reader::new(ZeroPaddingReader::new(
+            Cursor::new(bytes),
+            range.end - range.start,
+        ));
+        let mut leaves = vec![[0; NODE_SIZE]; self.leaf_count as usize];
+        for leaf in leaves.iter_mut() {
+            fr32_reader.read_exact(leaf)?;
+        }
+
+        let proof_hashes = self
+            .proof
+            .iter()
+            .map(|hash| {
+                let mut node = [0; NODE_SIZE];
+                hex::decode_to_slice(hash, &mut node)
+                    .map_err(|_| SpotCheckError::InvalidProof(hash.clone()))?;
+                Ok(node)
+            })
+            .collect::<Result<Vec<_>, SpotCheckError>>()?;
+        let indices = (self.first_leaf..self.first_leaf + self.leaf_count)
+            .map(|i| i as usize)
+            .collect::<Vec<_>>();
+        let total_leaves = (*piece_size / NODE_SIZE as u64) as usize;
+
+        Ok(MerkleProof::<Sha256>::new(proof_hashes).verify(root, &indices, &leaves, total_leaves))
+    }
+}
+
+/// Challenges generated for a piece, to be checked once the deal is active.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpotCheckPlan {
+    pub piece_cid: String,
+    pub piece_size: u64,
+    pub challenges: Vec<Challenge>,
+}
+
+impl SpotCheckPlan {
+    /// Picks up to `count` distinct random leaf ranges of `leaf_count` leaves overlapping
+    /// `data` and computes their Merkle paths.
+    pub fn generate<R: Rng>(
+        data: &[u8],
+        piece_size: PaddedPieceSize,
+        count: usize,
+        leaf_count: u64,
+        rng: &mut R,
+    ) -> Result<Self, SpotCheckError> {
+        if data.is_empty() {
+            return Err(SpotCheckError::EmptyData);
+        }
+        let unpadded_size = *piece_size.unpadded();
+        if data.len() as u64 > unpadded_size {
+            return Err(SpotCheckError::DataTooLarge(data.len()));
+        }
+        let total_leaves = *piece_size / NODE_SIZE as u64;
+        if !leaf_count.is_power_of_two()
+            || leaf_count < LEAVES_PER_BLOCK
+            || leaf_count > total_leaves
+        {
+            return Err(SpotCheckError::InvalidChallengeSize {
+                max: total_leaves,
+                actual: leaf_count,
+            });
+        }
+
+        let reader = ZeroPaddingReader::new(Cursor::new(data), unpadded_size);
+        let tree = build_piece_tree(reader, piece_size)?;
+        let root = tree.root().ok_or(SpotCheckError::EmptyData)?;
+
+        // Only ranges holding data are worth checking, the rest is zero padding
+        let range_size = leaf_count / LEAVES_PER_BLOCK * BLOCK_SIZE;
+        let ranges = (data.len() as u64).div_ceil(range_size) as usize;
+        let challenges = index::sample(rng, ranges, count.min(ranges))
+            .into_iter()
+            .map(|range| {
+                let first_leaf = range as u64 * leaf_count;
+                let indices = (first_leaf..first_leaf + leaf_count)
+                    .map(|i| i as usize)
+                    .collect::<Vec<_>>();
+                let proof = tree
+                    .proof(&indices)
+                    .proof_hashes()
+                    .iter()
+                    .map(hex::encode)
+                    .collect();
+
+                Challenge {
+                    first_leaf,
+                    leaf_count,
+                    proof,
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            piece_cid: Commitment::<CommP>::from(root).cid().to_string(),
+            piece_size: *piece_size,
+            challenges,
+        })
+    }
+}
+
+/// The outcome of a single challenge.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChallengeResult {
+    pub range_start: u64,
+    pub range_end: u64,
+    pub passed: bool,
+    /// Why the challenge could not be checked, e.g. the provider could not be reached.
+    pub error: Option<String>,
+}
+
+/// The outcome of all the challenges of a plan.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpotCheckReport {
+    pub piece_cid: String,
+    /// Whether every challenge passed.
+    pub passed: bool,
+    pub results: Vec<ChallengeResult>,
+}
+
+/// Extracts the 32 byte commitment from a piece CID.
+pub fn piece_cid_root(piece_cid: &str) -> Result<[u8; 32], SpotCheckError> {
+    let cid = Cid::try_from(piece_cid)
+        .map_err(|e| SpotCheckError::InvalidPieceCid(format!("{}: {}", piece_cid, e)))?;
+    cid.hash()
+        .digest()
+        .try_into()
+        .map_err(|_| SpotCheckError::InvalidPieceCid(format!("{}: bad digest", piece_cid)))
+}
+
+/// Requests every challenged range from `piece_url` and verifies it against `piece_cid`.
+///
+/// # Arguments
+/// * `fetcher` - Used to send the range requests.
+/// * `piece_url` - The URL serving the unpadded piece bytes.
+/// * `piece_cid` - The piece CID as stored on-chain.
+/// * `plan` - The challenges generated when the piece was created.
+pub async fn run_spot_checks<F: Fetch>(
+    fetcher: &F,
+    piece_url: &str,
+    piece_cid: &str,
+    plan: &SpotCheckPlan,
+) -> Result<SpotCheckReport, SpotCheckError> {
+    if plan.piece_cid != piece_cid {
+        return Err(SpotCheckError::PieceMismatch {
+            planned: plan.piece_cid.clone(),
+            expected: piece_cid.to_string(),
+        });
+    }
+    let root = piece_cid_root(piece_cid)?;
+    let piece_size = PaddedPieceSize::new(plan.piece_size)
+        .map_err(|e| SpotCheckError::InvalidPieceCid(format!("piece size: {}", e)))?;
+
+    let mut results = Vec::with_capacity(plan.challenges.len());
+    for challenge in &plan.challenges {
+        let range = challenge.byte_range();
+        let (passed, error) = match fetch_range(fetcher, piece_url, range.clone()).await {
+            Ok(bytes) => match challenge.verify(&bytes, root, piece_size) {
+                Ok(passed) => (passed, None),
+                Err(e) => (false, Some(e.to_string())),
+            },
+            Err(e) => (false, Some(e.to_string())),
+        };
+
+        results.push(ChallengeResult {
+            range_start: range.start,
+            range_end: range.end,
+            passed,
+            error,
+        });
+    }
+
+    Ok(SpotCheckReport {
+        piece_cid: piece_cid.to_string(),
+        passed: results.iter().all(|result| result.passed),
+        results,
+    })
+}
+
+/// Generates spot check challenges for a piece, to be stored until the deal is active.
+///
+/// # Arguments
+/// * `data` - The unpadded piece bytes (e.g. the CAR file).
+/// * `count` - The number of challenges to generate.
+///
+/// # Returns
+/// The plan, as a JS object, to be passed to `runSpotChecks`.
+#[wasm_bindgen(js_name = "generateSpotChecks")]
+pub fn generate_spot_checks(data: &[u8], count: usize) -> Result<JsValue, JsValue> {
+    let piece_size = PaddedPieceSize::from_arbitrary_size(data.len() as u64);
+    let plan = SpotCheckPlan::generate(
+        data,
+        piece_size,
+        count,
+        DEFAULT_CHALLENGE_LEAVES.min(*piece_size / NODE_SIZE as u64),
+        &mut rand::rngs::OsRng,
+    )
+    .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    Ok(serde_wasm_bindgen::to_value(&plan)?)
+}
+
+/// Runs the spot checks of a plan against a storage provider.
+///
+/// # Arguments
+/// * `provider_url` - The provider's base URL, e.g. `http://127.0.0.1:8001`.
+/// * `piece_cid` - The piece CID as stored on-chain.
+/// * `plan` - The plan returned by `generateSpotChecks`.
+///
+/// # Returns
+/// A report with the outcome of each challenge.
+#[wasm_bindgen(js_name = "runSpotChecks")]
+pub async fn run_spot_checks_js(
+    provider_url: String,
+    piece_cid: String,
+    plan: JsValue,
+) -> Result<JsValue, JsValue> {
+    let plan: SpotCheckPlan = serde_wasm_bindgen::from_value(plan)?;
+    let piece_url = format!("{}/api/v0/download/{}", provider_url, piece_cid);
+
+    let report = run_spot_checks(&BrowserFetch, &piece_url, &piece_cid, &plan)
+        .await
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+    info!(
+        "Spot checks for {}: {}/{} passed",
+        piece_cid,
+        report.results.iter().filter(|result| result.passed).count(),
+        report.results.len()
+    );
+
+    Ok(serde_wasm_bindgen::to_value(&report)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+    use crate::{
+        fetch::HttpResponse,
+        piece_commitment,
+        testing::{range_response, status, MockFetch, MockRequest, MockServer},
+    };
+
+    fn data() -> Vec<u8> {
+        (0..20_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn plan(data: &[u8], count: usize) -> SpotCheckPlan {
+        let piece_size = PaddedPieceSize::from_arbitrary_size(data.len() as u64);
+        let mut rng = StdRng::seed_from_u64(42);
+        SpotCheckPlan::generate(data, piece_size, count, DEFAULT_CHALLENGE_LEAVES, &mut rng)
+            .unwrap()
+    }
+
+    fn serve(plan: &SpotCheckPlan, bytes: Vec<u8>) -> (MockServer, String) {
+        let path = format!("/api/v0/download/{}", plan.piece_cid);
+        let server = MockServer::with_files(HashMap::from([(path.clone(), bytes)]));
+        let url = format!("{}{}", server.url(), path);
+        (server, url)
+    }
+
+    #[wasm_bindgen_test]
+    fn plan_matches_piece_commitment() {
+        let data = data();
+        let plan = plan(&data, 8);
+        let piece_size = PaddedPieceSize::from_arbitrary_size(data.len() as u64);
+        let commitment = piece_commitment(&data, piece_size).unwrap();
+
+        assert_eq!(plan.piece_cid, commitment.cid().to_string());
+        assert_eq!(plan.challenges.len(), 8);
+        for challenge in &plan.challenges {
+            assert!(challenge.byte_range().start < data.len() as u64);
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn challenge_verifies_local_bytes() {
+        let data = data();
+        let plan = plan(&data, 8);
+        let root = piece_cid_root(&plan.piece_cid).unwrap();
+        let piece_size = PaddedPieceSize::new(plan.piece_size).unwrap();
+
+        for challenge in &plan.challenges {
+            let range = challenge.byte_range();
+            let end = range.end.min(data.len() as u64);
+            let bytes = &data[range.start as usize..end as usize];
+            assert!(challenge.verify(bytes, root, piece_size).unwrap());
+
+            let mut corrupted = bytes.to_vec();
+            corrupted[0] ^= 1;
+            assert!(!challenge.verify(&corrupted, root, piece_size).unwrap());
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn spot_checks_pass_against_honest_provider() {
+        let data = data();
+        let plan = plan(&data, 4);
+        let (server, url) = serve(&plan, data);
+
+        let report = run_spot_checks(&MockFetch, &url, &plan.piece_cid, &plan)
+            .await
+            .unwrap();
+
+        assert!(report.passed, "{:?}", report);
+        assert_eq!(report.results.len(), 4);
+        // Only the challenged ranges were requested
+        assert!(server.requests().iter().all(|r| r.range().is_some()));
+    }
+
+    #[wasm_bindgen_test]
+    async fn spot_checks_pass_when_range_is_ignored() {
+        let data = data();
+        let plan = plan(&data, 2);
+        let server = MockServer::new({
+            let data = data.clone();
+            move |_| HttpResponse {
+                status: 200,
+                headers: vec![],
+                body: data.clone(),
+            }
+        });
+
+        let report = run_spot_checks(&MockFetch, server.url(), &plan.piece_cid, &plan)
+            .await
+            .unwrap();
+        assert!(report.passed, "{:?}", report);
+    }
+
+    #[wasm_bindgen_test]
+    async fn spot_checks_detect_corruption() {
+        let data = data();
+        let plan = plan(&data, 25);
+        let corrupted_range = plan.challenges[0].byte_range();
+        let mut corrupted = data.clone();
+        corrupted[corrupted_range.start as usize + 10] ^= 0xff;
+        let (_server, url) = serve(&plan, corrupted);
+
+        let report = run_spot_checks(&MockFetch, &url, &plan.piece_cid, &plan)
+            .await
+            .unwrap();
+
+        assert!(!report.passed);
+        assert!(!report.results[0].passed);
+        assert!(report.results[1..].iter().all(|result| result.passed));
+    }
+
+    #[wasm_bindgen_test]
+    async fn spot_checks_report_unavailable_provider() {
+        let data = data();
+        let plan = plan(&data, 2);
+        let server = MockServer::new(|_| status(500));
+
+        let report = run_spot_checks(&MockFetch, server.url(), &plan.piece_cid, &plan)
+            .await
+            .unwrap();
+
+        assert!(!report.passed);
+        assert!(report.results.iter().all(|result| result.error.is_some()));
+    }
+
+    #[wasm_bindgen_test]
+    async fn spot_checks_reject_other_ranges() {
+        let data = data();
+        let plan = plan(&data, 2);
+        // Honest bytes, but not the ones requested
+        let server = MockServer::new({
+            let data = data.clone();
+            move |request| {
+                let (start, end) = request.range().unwrap();
+                let shifted = MockRequest {
+                    path: request.path.clone(),
+                    headers: vec![(
+                        "Range".to_string(),
+                        format!("bytes={}-{}", start + 127, end.unwrap() + 126),
+                    )],
+                };
+                range_response(&shifted, &data)
+            }
+        });
+
+        let report = run_spot_checks(&MockFetch, server.url(), &plan.piece_cid, &plan)
+            .await
+            .unwrap();
+
+        assert!(!report.passed);
+        for result in &report.results {
+            assert!(result
+                .error
+                .as_ref()
+                .unwrap()
+                .contains("to a request for bytes"));
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn spot_checks_handle_truncated_piece() {
+        // The provider only stores the data, the challenged range runs into the padding
+        let data = vec![7; 1000];
+        let plan = plan(&data, 1);
+        let server = MockServer::new({
+            let data = data.clone();
+            move |request| range_response(request, &data)
+        });
+
+        let report = run_spot_checks(&MockFetch, server.url(), &plan.piece_cid, &plan)
+            .await
+            .unwrap();
+        assert!(report.passed, "{:?}", report);
+    }
+
+    #[wasm_bindgen_test]
+    async fn spot_checks_reject_other_piece() {
+        let data = data();
+        let plan = plan(&data, 1);
+        let other = self::plan(&[1; 2000], 1).piece_cid;
+
+        let result = run_spot_checks(&MockFetch, "", &other, &plan).await;
+        assert!(matches!(result, Err(SpotCheckError::PieceMismatch { .. })));
+    }
+}
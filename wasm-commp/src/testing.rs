@@ -0,0 +1,258 @@
+//! Test helpers: an in-process mock HTTP server and the [`Fetch`] implementation reaching it,
+//! an in-memory [`CheckpointStore`] and CARv1 encoders.
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use cid::Cid;
+
+use crate::{
+    car::{encode_v1_header, CarV2Builder},
+    fetch::{Fetch, FetchError, HttpRequest, HttpResponse},
+    ipld::{decode_varint, write_varint, DAG_PB},
+    resumable::{CheckpointStore, StoreError},
+    unixfs::{pb::decode_node, DagOptions},
+};
+
+/// A request received by a [`MockServer`].
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    /// Path and query string.
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl MockRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Parses a single `bytes=start-end` range into an exclusive range.
+    pub fn range(&self) -> Option<(u64, Option<u64>)> {
+        let spec = self.header("Range")?.strip_prefix("bytes=")?;
+        let (start, end) = spec.split_once('-')?;
+        let end = match end {
+            "" => None,
+            end => Some(end.parse::<u64>().ok()? + 1),
+        };
+        Some((start.parse().ok()?, end))
+    }
+}
+
+type Handler = dyn Fn(&MockRequest) -> HttpResponse;
+
+/// A handler and the requests it received.
+struct Route {
+    handler: Box<Handler>,
+    requests: RefCell<Vec<MockRequest>>,
+}
+
+thread_local! {
+    /// The running servers, by host.
+    static SERVERS: RefCell<HashMap<String, Rc<Route>>> = RefCell::default();
+    static NEXT_HOST: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A mock HTTP server answering every request with a handler, reached through [`MockFetch`]
+/// without any networking so that tests run in WASM too. It stops once dropped.
+pub struct MockServer {
+    host: String,
+    address: String,
+    route: Rc<Route>,
+}
+
+impl MockServer {
+    pub fn new(handler: impl Fn(&MockRequest) -> HttpResponse + 'static) -> Self {
+        let host = format!("mock-{}", NEXT_HOST.replace(NEXT_HOST.get() + 1));
+        let route = Rc::new(Route {
+            handler: Box::new(handler),
+            requests: RefCell::default(),
+        });
+        SERVERS.with_borrow_mut(|servers| servers.insert(host.clone(), route.clone()));
+
+        Self {
+            address: format!("http://{host}"),
+            host,
+            route,
+        }
+    }
+
+    /// Serves static files, honouring single range requests.
+    pub fn with_files(files: HashMap<String, Vec<u8>>) -> Self {
+        Self::new(move |request| match files.get(&request.path) {
+            Some(body) => range_response(request, body),
+            None => status(404),
+        })
+    }
+
+    /// Base URL of the server, e.g. `http://mock-0`.
+    pub fn url(&self) -> &str {
+        &self.address
+    }
+
+    /// The requests received so far.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.route.requests.borrow().clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        SERVERS.with_borrow_mut(|servers| servers.remove(&self.host));
+    }
+}
+
+/// A [`Fetch`] implementation reaching the [`MockServer`]s, failing like a refused
+/// connection for other hosts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MockFetch;
+
+impl Fetch for MockFetch {
+    async fn fetch(&self, request: HttpRequest) -> Result<HttpResponse, FetchError> {
+        let rest = request
+            .url
+            .strip_prefix("http://")
+            .expect("only http URLs are supported");
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let route = SERVERS
+            .with_borrow(|servers| servers.get(host).cloned())
+            .ok_or_else(|| FetchError::Network {
+                url: request.url.clone(),
+                reason: "connection refused".to_string(),
+            })?;
+
+        let request = MockRequest {
+            path: path.to_string(),
+            headers: request.headers,
+        };
+        route.requests.borrow_mut().push(request.clone());
+        Ok((route.handler)(&request))
+    }
+}
+
+/// Builds an empty response with the given status.
+pub fn status(status: u16) -> HttpResponse {
+    HttpResponse {
+        status,
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+/// Answers `request` with `body`, or the requested range of it.
+pub fn range_response(request: &MockRequest, body: &[u8]) -> HttpResponse {
+    let len = body.len() as u64;
+    match request.range() {
+        None => HttpResponse {
+            status: 200,
+            headers: vec![],
+            body: body.to_vec(),
+        },
+        Some((start, _)) if start >= len => status(416),
+        Some((start, end)) => {
+            let end = end.unwrap_or(len).min(len);
+            HttpResponse {
+                status: 206,
+                headers: vec![(
+                    "Content-Range".to_string(),
+                    format!("bytes {}-{}/{}", start, end - 1, len),
+                )],
+                body: body[start as usize..end as usize].to_vec(),
+            }
+        }
+    }
+}
+
+/// Encodes a CARv1 file holding `blocks`, in order.
+pub fn encode_car(root: &Cid, blocks: &[(Cid, Vec<u8>)]) -> Vec<u8> {
+    let mut car = encode_v1_header(root);
+    for (cid, data) in blocks {
+        let cid = cid.to_bytes();
+        write_varint(&mut car, (cid.len() + data.len()) as u64);
+        car.extend_from_slice(&cid);
+        car.extend_from_slice(data);
+    }
+    car
+}
+
+/// Encodes `data` as a CARv1 file of CIDv0 DAG-PB leaves whose blocks are written depth
+/// first, each block before its children, the order `ipfs dag export` documents. The files
+/// of [`CarV2Builder`] put the root last.
+pub fn root_first_car(data: &[u8]) -> (Cid, Vec<u8>) {
+    let options = DagOptions {
+        raw_leaves: false,
+        cid_version: 0,
+        ..Default::default()
+    };
+    root_first_car_with_options(data, options)
+}
+
+/// [`root_first_car`] with other DAG options, each block being written once.
+pub fn root_first_car_with_options(data: &[u8], options: DagOptions) -> (Cid, Vec<u8>) {
+    let mut builder = CarV2Builder::with_options(vec![], options).unwrap();
+    builder.write(data).unwrap();
+    let output = builder.finish().unwrap();
+
+    let mut blocks = HashMap::new();
+    let mut sections = output.out.as_slice();
+    while !sections.is_empty() {
+        let (len, read) = decode_varint(sections).unwrap();
+        let mut section = &sections[read..read + len as usize];
+        sections = &sections[read + len as usize..];
+        let cid = Cid::read_bytes(&mut section).unwrap();
+        blocks.insert(cid, section.to_vec());
+    }
+
+    let mut order = vec![];
+    let mut seen = HashSet::new();
+    let mut stack = vec![output.root];
+    while let Some(cid) = stack.pop() {
+        if !seen.insert(cid) {
+            continue;
+        }
+        let data = blocks[&cid].clone();
+        if cid.codec() == DAG_PB {
+            let links = decode_node(&data).unwrap().links;
+            stack.extend(links.into_iter().rev().map(|link| link.cid));
+        }
+        order.push((cid, data));
+    }
+    (output.root, encode_car(&output.root, &order))
+}
+
+/// A [`CheckpointStore`] keeping the values in memory.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    values: RefCell<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    /// Number of values stored.
+    pub fn len(&self) -> usize {
+        self.values.borrow().len()
+    }
+}
+
+impl CheckpointStore for MemoryStore {
+    async fn load(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self.values.borrow().get(key).cloned())
+    }
+
+    async fn save(&self, key: &str, value: Vec<u8>) -> Result<(), StoreError> {
+        self.values.borrow_mut().insert(key.to_string(), value);
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), StoreError> {
+        self.values.borrow_mut().remove(key);
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+//! Balanced file DAG layout.
+use std::mem;
+
+use cid::Version;
+
+use super::{stem_node, FileLink};
+use crate::ipld::Block;
+
+/// Links of a tree level, waiting to be grouped under a stem node.
+#[derive(Debug, Default)]
+struct Level {
+    pending: Vec<FileLink>,
+    /// Total number of links pushed to the level.
+    count: u64,
+}
+
+/// Builds a balanced DAG incrementally, as leaves are pushed.
+///
+/// Every `max_links` consecutive links of a level are grouped under a stem node of the level
+/// above, until a level holds a single link: the root. Stem nodes are kept until
+/// [`BalancedBuilder::finish`] so that they can be emitted level by level, after the leaves.
+#[derive(Debug)]
+pub struct BalancedBuilder {
+    max_links: usize,
+    version: Version,
+    levels: Vec<Level>,
+    /// Stem nodes grouping the links of each level.
+    stems: Vec<Vec<Block>>,
+}
+
+impl BalancedBuilder {
+    /// Creates a builder grouping up to `max_links` links per stem node, with CIDs of the
+    /// given version.
+    pub fn new(max_links: usize, version: Version) -> Self {
+        assert!(max_links >= 2, "stem nodes must hold at least 2 links");
+        Self {
+            max_links,
+            version,
+            levels: vec![],
+            stems: vec![],
+        }
+    }
+
+    /// Pushes the next leaf of the file.
+    pub fn push(&mut self, leaf: FileLink) {
+        self.push_at(0, leaf);
+    }
+
+    fn push_at(&mut self, level: usize, link: FileLink) {
+        if self.levels.len() == level {
+            self.levels.push(Level::default());
+            self.stems.push(vec![]);
+        }
+        let current = &mut self.levels[level];
+        current.pending.push(link);
+        current.count += 1;
+
+        if current.pending.len() == self.max_links {
+            let links = mem::take(&mut current.pending);
+            self.group(level, &links);
+        }
+    }
+
+    fn group(&mut self, level: usize, links: &[FileLink]) {
+        let (block, link) = stem_node(self.version, links);
+        self.stems[level].push(block);
+        self.push_at(level + 1, link);
+    }
+
+    /// Groups the remaining links and returns the link to the root along with every stem
+    /// node, level by level. Returns `None` if no leaf was pushed.
+    pub fn finish(mut self) -> Option<(FileLink, Vec<Block>)> {
+        let mut level = 0;
+        let root = loop {
+            let current = self.levels.get_mut(level)?;
+            if current.count == 1 {
+                break current.pending.pop()?;
+            }
+            if !current.pending.is_empty() {
+                let links = mem::take(&mut current.pending);
+                self.group(level, &links);
+            }
+            level += 1;
+        };
+
+        Some((root, self.stems.into_iter().flatten().collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipld::RAW;
+
+    fn leaves(count: usize) -> Vec<FileLink> {
+        (0..count)
+            .map(|i| FileLink::leaf(&Block::new(RAW, (i as u32).to_le_bytes().to_vec())))
+            .collect()
+    }
+
+    fn build(max_links: usize, leaves: Vec<FileLink>) -> (FileLink, Vec<Block>) {
+        let mut builder = BalancedBuilder::new(max_links, Version::V1);
+        for leaf in leaves {
+            builder.push(leaf);
+        }
+        builder.finish().unwrap()
+    }
+
+    #[test]
+    fn single_leaf_is_root() {
+        let leaves = leaves(1);
+        let (root, stems) = build(3, leaves.clone());
+        assert_eq!(root, leaves[0]);
+        assert!(stems.is_empty());
+    }
+
+    #[test]
+    fn stems_per_level() {
+        // 7 leaves -> 3 stems -> 1 root
+        let (root, stems) = build(3, leaves(7));
+        assert_eq!(stems.len(), 4);
+        assert_eq!(root.cid, stems[3].cid);
+        assert_eq!(root.size, 7 * 4);
+
+        // 9 leaves -> 3 full stems -> 1 root
+        let (_, stems) = build(3, leaves(9));
+        assert_eq!(stems.len(), 4);
+
+        // 10 leaves -> 4 stems -> 2 stems -> 1 root
+        let (_, stems) = build(3, leaves(10));
+        assert_eq!(stems.len(), 7);
+    }
+
+    #[test]
+    fn empty_builder_has_no_root() {
+        assert!(BalancedBuilder::new(3, Version::V1).finish().is_none());
+    }
+}
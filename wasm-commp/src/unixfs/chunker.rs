@@ -0,0 +1,587 @@
+//! Cutting files into leaves.
+//!
+//! Fixed size chunks are simple but shift with every inserted or removed byte, so two
+//! versions of a file share almost no leaves. Content-defined chunkers instead cut where a
+//! rolling hash of the last bytes matches a pattern, an edit only changes the chunks around
+//! it.
+//!
+//! The FastCDC chunker cuts where the reference implementation does. The Rabin one is local
+//! to this crate: its boundaries aren't checked against go-ipfs-chunker's, so its leaves only
+//! deduplicate against other uploads chunked by it, and `ipfs add --chunker=rabin-*` gives
+//! other CIDs.
+use serde::{Deserialize, Serialize};
+
+use super::{DagOptionsError, MAX_CHUNK_SIZE};
+
+/// Bytes hashed by the Rabin fingerprint, as in restic's chunker.
+pub const RABIN_WINDOW_SIZE: usize = 64;
+/// The irreducible polynomial of go-ipfs-chunker's Rabin chunker, though the chunker using it
+/// isn't known to cut at the same points.
+pub const RABIN_POLYNOMIAL: u64 = 0x3DF305DFB2A805;
+
+/// How a file is cut into leaves.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Chunker {
+    /// Chunks of `chunkSize` bytes.
+    #[default]
+    Fixed,
+    /// FastCDC, cutting on a gear hash with normalized chunk sizes, as the `fastcdc` crate.
+    #[serde(rename_all = "camelCase")]
+    FastCdc {
+        min_size: usize,
+        avg_size: usize,
+        max_size: usize,
+    },
+    /// Cutting on a Rabin fingerprint of the last [`RABIN_WINDOW_SIZE`] bytes, at points only
+    /// known to this crate.
+    #[serde(rename_all = "camelCase")]
+    LocalRabin {
+        min_size: usize,
+        avg_size: usize,
+        max_size: usize,
+    },
+}
+
+impl Chunker {
+    pub fn validate(&self) -> Result<(), DagOptionsError> {
+        let (Self::FastCdc {
+            min_size,
+            avg_size,
+            max_size,
+        }
+        | Self::LocalRabin {
+            min_size,
+            avg_size,
+            max_size,
+        }) = *self
+        else {
+            return Ok(());
+        };
+
+        let ordered = RABIN_WINDOW_SIZE <= min_size
+            && min_size <= avg_size
+            && avg_size <= max_size
+            && max_size <= MAX_CHUNK_SIZE;
+        let fast_cdc_bounds = !matches!(self, Self::FastCdc { .. })
+            || (FAST_CDC_MIN_AVG_SIZE <= avg_size && FAST_CDC_MIN_MAX_SIZE <= max_size);
+        if !ordered || !fast_cdc_bounds || !avg_size.is_power_of_two() {
+            return Err(DagOptionsError::InvalidChunkerSizes {
+                min: min_size,
+                avg: avg_size,
+                max: max_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Finds where to cut the chunks of a file, as configured by a [`Chunker`].
+#[derive(Debug)]
+pub enum Splitter {
+    Fixed(usize),
+    FastCdc(FastCdc),
+    LocalRabin(Box<LocalRabin>),
+}
+
+impl Splitter {
+    /// Creates the splitter of `chunker`, `chunk_size` being the size of fixed chunks.
+    pub fn new(chunker: Chunker, chunk_size: usize) -> Self {
+        match chunker {
+            Chunker::Fixed => Self::Fixed(chunk_size),
+            Chunker::FastCdc {
+                min_size,
+                avg_size,
+                max_size,
+            } => Self::FastCdc(FastCdc::new(min_size, avg_size, max_size)),
+            Chunker::LocalRabin {
+                min_size,
+                avg_size,
+                max_size,
+            } => Self::LocalRabin(Box::new(LocalRabin::new(min_size, avg_size, max_size))),
+        }
+    }
+
+    /// Size of the largest chunk.
+    pub fn max_size(&self) -> usize {
+        match self {
+            Self::Fixed(size) => *size,
+            Self::FastCdc(fast_cdc) => fast_cdc.max_size,
+            Self::LocalRabin(rabin) => rabin.max_size,
+        }
+    }
+
+    /// Length of the chunk starting `data`, which holds at least [`Splitter::max_size`]
+    /// bytes unless it's the end of the file.
+    pub fn cut(&self, data: &[u8]) -> usize {
+        match self {
+            Self::Fixed(size) => data.len().min(*size),
+            Self::FastCdc(fast_cdc) => fast_cdc.cut(data),
+            Self::LocalRabin(rabin) => rabin.cut(data),
+        }
+    }
+}
+
+/// The gear hash value of each byte, the high 8 bytes of the MD5 digest of the byte, copied
+/// from the `fastcdc` crate's v2020 module.
+#[rustfmt::skip]
+static GEAR: [u64; 256] = [
+    0x3b5d3c7d207e37dc, 0x784d68ba91123086, 0xcd52880f882e7298, 0xeacf8e4e19fdcca7,
+    0xc31f385dfbd1632b, 0x1d5f27001e25abe6, 0x83130bde3c9ad991, 0xc4b225676e9b7649,
+    0xaa329b29e08eb499, 0xb67fcbd21e577d58, 0x0027baaada2acf6b, 0xe3ef2d5ac73c2226,
+    0x0890f24d6ed312b7, 0xa809e036851d7c7e, 0xf0a6fe5e0013d81b, 0x1d026304452cec14,
+    0x03864632648e248f, 0xcdaacf3dcd92b9b4, 0xf5e012e63c187856, 0x8862f9d3821c00b6,
+    0xa82f7338750f6f8a, 0x1e583dc6c1cb0b6f, 0x7a3145b69743a7f1, 0xabb20fee404807eb,
+    0xb14b3cfe07b83a5d, 0xb9dc27898adb9a0f, 0x3703f5e91baa62be, 0xcf0bb866815f7d98,
+    0x3d9867c41ea9dcd3, 0x1be1fa65442bf22c, 0x14300da4c55631d9, 0xe698e9cbc6545c99,
+    0x4763107ec64e92a5, 0xc65821fc65696a24, 0x76196c064822f0b7, 0x485be841f3525e01,
+    0xf652bc9c85974ff5, 0xcad8352face9e3e9, 0x2a6ed1dceb35e98e, 0xc6f483badc11680f,
+    0x3cfd8c17e9cf12f1, 0x89b83c5e2ea56471, 0xae665cfd24e392a9, 0xec33c4e504cb8915,
+    0x3fb9b15fc9fe7451, 0xd7fd1fd1945f2195, 0x31ade0853443efd8, 0x255efc9863e1e2d2,
+    0x10eab6008d5642cf, 0x46f04863257ac804, 0xa52dc42a789a27d3, 0xdaaadf9ce77af565,
+    0x6b479cd53d87febb, 0x6309e2d3f93db72f, 0xc5738ffbaa1ff9d6, 0x6bd57f3f25af7968,
+    0x67605486d90d0a4a, 0xe14d0b9663bfbdae, 0xb7bbd8d816eb0414, 0xdef8a4f16b35a116,
+    0xe7932d85aaaffed6, 0x08161cbae90cfd48, 0x855507beb294f08b, 0x91234ea6ffd399b2,
+    0xad70cf4b2435f302, 0xd289a97565bc2d27, 0x8e558437ffca99de, 0x96d2704b7115c040,
+    0x0889bbcdfc660e41, 0x5e0d4e67dc92128d, 0x72a9f8917063ed97, 0x438b69d409e016e3,
+    0xdf4fed8a5d8a4397, 0x00f41dcf41d403f7, 0x4814eb038e52603f, 0x9dafbacc58e2d651,
+    0xfe2f458e4be170af, 0x4457ec414df6a940, 0x06e62f1451123314, 0xbd1014d173ba92cc,
+    0xdef318e25ed57760, 0x9fea0de9dfca8525, 0x459de1e76c20624b, 0xaeec189617e2d666,
+    0x126a2c06ab5a83cb, 0xb1321532360f6132, 0x65421503dbb40123, 0x2d67c287ea089ab3,
+    0x6c93bff5a56bd6b6, 0x4ffb2036cab6d98d, 0xce7b785b1be7ad4f, 0xedb42ef6189fd163,
+    0xdc905288703988f6, 0x365f9c1d2c691884, 0xc640583680d99bfe, 0x3cd4624c07593ec6,
+    0x7f1ea8d85d7c5805, 0x014842d480b57149, 0x0b649bcb5a828688, 0xbcd5708ed79b18f0,
+    0xe987c862fbd2f2f0, 0x982731671f0cd82c, 0xbaf13e8b16d8c063, 0x8ea3109cbd951bba,
+    0xd141045bfb385cad, 0x2acbc1a0af1f7d30, 0xe6444d89df03bfdf, 0xa18cc771b8188ff9,
+    0x9834429db01c39bb, 0x214add07fe086a1f, 0x8f07c19b1f6b3ff9, 0x56a297b1bf4ffe55,
+    0x94d558e493c54fc7, 0x40bfc24c764552cb, 0x931a706f8a8520cb, 0x32229d322935bd52,
+    0x2560d0f5dc4fefaf, 0x9dbcc48355969bb6, 0x0fd81c3985c0b56a, 0xe03817e1560f2bda,
+    0xc1bb4f81d892b2d5, 0xb0c4864f4e28d2d7, 0x3ecc49f9d9d6c263, 0x51307e99b52ba65e,
+    0x8af2b688da84a752, 0xf5d72523b91b20b6, 0x6d95ff1ff4634806, 0x562f21555458339a,
+    0xc0ce47f889336346, 0x487823e5089b40d8, 0xe4727c7ebc6d9592, 0x5a8f7277e94970ba,
+    0xfca2f406b1c8bb50, 0x5b1f8a95f1791070, 0xd304af9fc9028605, 0x5440ab7fc930e748,
+    0x312d25fbca2ab5a1, 0x10f4a4b234a4d575, 0x90301d55047e7473, 0x3b6372886c61591e,
+    0x293402b77c444e06, 0x451f34a4d3e97dd7, 0x3158d814d81bc57b, 0x034942425b9bda69,
+    0xe2032ff9e532d9bb, 0x62ae066b8b2179e5, 0x9545e10c2f8d71d8, 0x7ff7483eb2d23fc0,
+    0x00945fcebdc98d86, 0x8764bbbe99b26ca2, 0x1b1ec62284c0bfc3, 0x58e0fcc4f0aa362b,
+    0x5f4abefa878d458d, 0xfd74ac2f9607c519, 0xa4e3fb37df8cbfa9, 0xbf697e43cac574e5,
+    0x86f14a3f68f4cd53, 0x24a23d076f1ce522, 0xe725cd8048868cc8, 0xbf3c729eb2464362,
+    0xd8f6cd57b3cc1ed8, 0x6329e52425541577, 0x62aa688ad5ae1ac0, 0x0a242566269bf845,
+    0x168b1a4753aca74b, 0xf789afefff2e7e3c, 0x6c3362093b6fccdb, 0x4ce8f50bd28c09b2,
+    0x006a2db95ae8aa93, 0x975b0d623c3d1a8c, 0x18605d3935338c5b, 0x5bb6f6136cad3c71,
+    0x0f53a20701f8d8a6, 0xab8c5ad2e7e93c67, 0x40b5ac5127acaa29, 0x8c7bf63c2075895f,
+    0x78bd9f7e014a805c, 0xb2c9e9f4f9c8c032, 0xefd6049827eb91f3, 0x2be459f482c16fbd,
+    0xd92ce0c5745aaa8c, 0x0aaa8fb298d965b9, 0x2b37f92c6c803b15, 0x8c54a5e94e0f0e78,
+    0x95f9b6e90c0a3032, 0xe7939faa436c7874, 0xd16bfe8f6a8a40c9, 0x44982b86263fd2fa,
+    0xe285fb39f984e583, 0x779a8df72d7619d3, 0xf2d79a8de8d5dd1e, 0xd1037354d66684e2,
+    0x004c82a4e668a8e5, 0x31d40a7668b044e6, 0xd70578538bd02c11, 0xdb45431078c5f482,
+    0x977121bb7f6a51ad, 0x73d5ccbd34eff8dd, 0xe437a07d356e17cd, 0x47b2782043c95627,
+    0x9fb251413e41d49a, 0xccd70b60652513d3, 0x1c95b31e8a1b49b2, 0xcae73dfd1bcb4c1b,
+    0x34d98331b1f5b70f, 0x784e39f22338d92f, 0x18613d4a064df420, 0xf1d8dae25f0bcebe,
+    0x33f77c15ae855efc, 0x3c88b3b912eb109c, 0x956a2ec96bafeea5, 0x1aa005b5e0ad0e87,
+    0x5500d70527c4bb8e, 0xe36c57196421cc44, 0x13c4d286cc36ee39, 0x5654a23d818b2a81,
+    0x77b1dc13d161abdc, 0x734f44de5f8d5eb5, 0x60717e174a6c89a2, 0xd47d9649266a211e,
+    0x5b13a4322bb69e90, 0xf7669609f8b5fc3c, 0x21e6ac55bedcdac9, 0x9b56b62b61166dea,
+    0xf48f66b939797e9c, 0x35f332f9c0e6ae9a, 0xcc733f6a9a878db0, 0x3da161e41cc108c2,
+    0xb7d74ae535914d51, 0x4d493b0b11d36469, 0xce264d1dfba9741a, 0xa9d1f2dc7436dc06,
+    0x70738016604c2a27, 0x231d36e96e93f3d5, 0x7666881197838d19, 0x4a2a83090aaad40c,
+    0xf1e761591668b35d, 0x7363236497f730a7, 0x301080e37379dd4d, 0x502dea2971827042,
+    0xc2c5eb858f32625f, 0x786afb9edfafbdff, 0xdaee0d868490b2a4, 0x617366b3268609f6,
+    0xae0e35a0fe46173e, 0xd1a07de93e824f11, 0x079b8b115ea4cca8, 0x93a99274558faebb,
+    0xfb1e6e22e08a03b3, 0xea635fdba3698dd0, 0xcf53659328503a5c, 0xcde3b31e6fd5d780,
+    0x8e3e4221d3614413, 0xef14d0d86bf1a22c, 0xe1d830d3f16c5ddb, 0xaabd2b2a451504e1,
+];
+
+/// [`GEAR`] shifted left by one bit, to roll two bytes at a time.
+static GEAR_LS: [u64; 256] = shifted_gear();
+
+const fn shifted_gear() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < table.len() {
+        table[i] = GEAR[i] << 1;
+        i += 1;
+    }
+    table
+}
+
+/// The masks matched by the gear hash, indexed by their number of bits, from the FastCDC C
+/// reference implementation by way of the `fastcdc` crate. Their bits are spread out over the
+/// top of the hash, which the paper finds to deduplicate slightly better.
+const MASKS: [u64; 25] = [
+    0,
+    0,
+    0,
+    0,
+    0,
+    0x0000_0000_0180_4110,
+    0x0000_0000_0180_3110, // 64B
+    0x0000_0000_1803_5100, // 128B
+    0x0000_0018_0003_5300, // 256B
+    0x0000_0190_0035_3000, // 512B
+    0x0000_5900_0353_0000, // 1KiB
+    0x0000_d900_0353_0000, // 2KiB
+    0x0000_d901_0353_0000, // 4KiB
+    0x0000_d903_0353_0000, // 8KiB
+    0x0000_d903_1353_0000, // 16KiB
+    0x0000_d90f_0353_0000, // 32KiB
+    0x0000_d903_0353_7000, // 64KiB
+    0x0000_d907_0353_7000, // 128KiB
+    0x0000_d907_0753_7000, // 256KiB
+    0x0000_d917_0753_7000, // 512KiB
+    0x0000_d917_4753_7000, // 1MiB
+    0x0000_d917_6753_7000, // 2MiB
+    0x0000_d937_6753_7000, // 4MiB
+    0x0000_d937_7753_7000, // 8MiB
+    0x0000_d937_7757_7000, // 16MiB
+];
+
+/// Smallest average size of the FastCDC chunker, the masks of fewer bits are unused.
+pub const FAST_CDC_MIN_AVG_SIZE: usize = 256;
+/// Smallest maximum size of the FastCDC chunker.
+pub const FAST_CDC_MIN_MAX_SIZE: usize = 1024;
+
+/// The FastCDC chunker from "The Design of Fast Content-Defined Chunking for Data
+/// Deduplication Based Storage Systems" (Xia et al., 2020), with normalization level 1 and
+/// rolling two bytes at a time.
+///
+/// Cuts at the same points as `fastcdc::v2020::FastCDC::new` of the `fastcdc` crate, the
+/// reference implementation in Rust, which the tests check on shared vectors.
+#[derive(Debug)]
+pub struct FastCdc {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    /// Harder to match mask used below the average size.
+    mask_small: u64,
+    /// Easier to match mask used past the average size.
+    mask_large: u64,
+}
+
+impl FastCdc {
+    pub fn new(min_size: usize, avg_size: usize, max_size: usize) -> Self {
+        let bits = avg_size.trailing_zeros() as usize;
+        Self {
+            min_size,
+            avg_size,
+            max_size,
+            mask_small: MASKS[bits + 1],
+            mask_large: MASKS[bits - 1],
+        }
+    }
+
+    fn cut(&self, data: &[u8]) -> usize {
+        let mut end = data.len();
+        if end <= self.min_size {
+            return end;
+        }
+        let mut center = self.avg_size;
+        if end > self.max_size {
+            end = self.max_size;
+        } else if end < center {
+            center = end;
+        }
+
+        // Every step hashes the byte at an even position, shifted, then the following one;
+        // a match cuts before the byte just hashed
+        let mut hash = 0u64;
+        for i in self.min_size / 2..end / 2 {
+            let (mask, mask_ls) = if i < center / 2 {
+                (self.mask_small, self.mask_small << 1)
+            } else {
+                (self.mask_large, self.mask_large << 1)
+            };
+            let a = i * 2;
+            hash = (hash << 2).wrapping_add(GEAR_LS[data[a] as usize]);
+            if hash & mask_ls == 0 {
+                return a;
+            }
+            hash = hash.wrapping_add(GEAR[data[a + 1] as usize]);
+            if hash & mask == 0 {
+                return a + 1;
+            }
+        }
+        end
+    }
+}
+
+/// Rabin fingerprint chunking, modelled on restic's chunker and go-ipfs-chunker's polynomial.
+///
+/// Local to this crate: the chunk boundaries haven't been checked against go-ipfs-chunker's,
+/// so the CIDs of files chunked with `rabin-*` by `ipfs add` may differ.
+///
+/// The first `min_size - RABIN_WINDOW_SIZE` bytes of a chunk aren't hashed, a chunk is cut
+/// once the fingerprint of the last [`RABIN_WINDOW_SIZE`] bytes has its low `log2(avg_size)`
+/// bits cleared.
+#[derive(Debug)]
+pub struct LocalRabin {
+    min_size: usize,
+    max_size: usize,
+    split_mask: u64,
+    /// The fingerprint of each byte followed by a window of zeros, to slide it out.
+    out_table: [u64; 256],
+    /// The reduction of each overflowing byte modulo the polynomial, along with the byte.
+    mod_table: [u64; 256],
+}
+
+/// Degree of a polynomial over GF(2).
+fn degree(polynomial: u64) -> u32 {
+    63 - polynomial.leading_zeros()
+}
+
+/// Remainder of the division of `value` by `polynomial` over GF(2).
+fn modulo(mut value: u64, polynomial: u64) -> u64 {
+    let degree_polynomial = degree(polynomial);
+    while value != 0 && degree(value) >= degree_polynomial {
+        value ^= polynomial << (degree(value) - degree_polynomial);
+    }
+    value
+}
+
+fn append_byte(hash: u64, byte: u8, polynomial: u64) -> u64 {
+    modulo(hash << 8 | u64::from(byte), polynomial)
+}
+
+impl LocalRabin {
+    pub fn new(min_size: usize, avg_size: usize, max_size: usize) -> Self {
+        let k = degree(RABIN_POLYNOMIAL);
+        let mut out_table = [0; 256];
+        let mut mod_table = [0; 256];
+        for byte in 0..=255u8 {
+            let mut hash = append_byte(0, byte, RABIN_POLYNOMIAL);
+            for _ in 0..RABIN_WINDOW_SIZE - 1 {
+                hash = append_byte(hash, 0, RABIN_POLYNOMIAL);
+            }
+            out_table[byte as usize] = hash;
+
+            let shifted = u64::from(byte) << k;
+            mod_table[byte as usize] = modulo(shifted, RABIN_POLYNOMIAL) | shifted;
+        }
+
+        Self {
+            min_size,
+            max_size,
+            split_mask: avg_size as u64 - 1,
+            out_table,
+            mod_table,
+        }
+    }
+
+    fn cut(&self, data: &[u8]) -> usize {
+        let end = data.len().min(self.max_size);
+        if end <= self.min_size {
+            return end;
+        }
+
+        // restic starts every chunk by sliding a 1 into an empty window
+        let shift = degree(RABIN_POLYNOMIAL) - 8;
+        let mut window = [0u8; RABIN_WINDOW_SIZE];
+        window[0] = 1;
+        let mut position = 1;
+        let mut digest = 1u64;
+
+        let start = self.min_size - RABIN_WINDOW_SIZE;
+        for (i, &byte) in data.iter().enumerate().take(end).skip(start) {
+            let out = std::mem::replace(&mut window[position], byte);
+            digest ^= self.out_table[out as usize];
+            position = (position + 1) % RABIN_WINDOW_SIZE;
+
+            let index = (digest >> shift) as usize;
+            digest = (digest << 8 | u64::from(byte)) ^ self.mod_table[index];
+
+            if i + 1 >= self.min_size && digest & self.split_mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+    use super::*;
+
+    fn random(len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        StdRng::seed_from_u64(7).fill_bytes(&mut data);
+        data
+    }
+
+    fn chunks(splitter: &Splitter, mut data: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunks = vec![];
+        while !data.is_empty() {
+            let len = splitter.cut(data);
+            chunks.push(data[..len].to_vec());
+            data = &data[len..];
+        }
+        chunks
+    }
+
+    /// Bytes of a 64-bit LCG, easy to reproduce in other implementations.
+    fn lcg(len: usize) -> Vec<u8> {
+        let mut state = 1u64;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn lengths(splitter: &Splitter, data: &[u8]) -> Vec<usize> {
+        chunks(splitter, data).iter().map(Vec::len).collect()
+    }
+
+    fn content_defined() -> [Chunker; 2] {
+        [
+            Chunker::FastCdc {
+                min_size: 2048,
+                avg_size: 8192,
+                max_size: 32768,
+            },
+            Chunker::LocalRabin {
+                min_size: 2048,
+                avg_size: 8192,
+                max_size: 32768,
+            },
+        ]
+    }
+
+    /// Chunk lengths given by `fastcdc::v2020::FastCDC::new(&data, min, avg, max)` of the
+    /// `fastcdc` crate 3.2.1.
+    #[test]
+    fn fast_cdc_matches_the_reference() {
+        let fast_cdc = |min_size, avg_size, max_size| {
+            Splitter::new(
+                Chunker::FastCdc {
+                    min_size,
+                    avg_size,
+                    max_size,
+                },
+                0,
+            )
+        };
+
+        assert_eq!(
+            lengths(&fast_cdc(64, 256, 1024), &lcg(4096)),
+            [358, 374, 210, 323, 511, 285, 348, 456, 370, 367, 224, 270]
+        );
+        assert_eq!(
+            lengths(&fast_cdc(2048, 8192, 32768), &lcg(256 * 1024)),
+            [
+                8600, 9726, 5201, 8502, 3261, 12852, 8155, 6129, 11683, 8340, 3763, 11677, 19573,
+                8349, 9325, 8385, 9698, 9830, 7370, 4919, 9604, 15751, 11084, 9963, 3586, 11993,
+                12146, 7711, 4968
+            ]
+        );
+        assert_eq!(
+            lengths(&fast_cdc(16384, 65536, 262144), &lcg(256 * 1024)),
+            [125811, 78145, 58188]
+        );
+        // No cut point matches, every chunk is as long as possible
+        assert_eq!(
+            lengths(&fast_cdc(2048, 8192, 32768), &vec![0; 100_000]),
+            [32768, 32768, 32768, 1696]
+        );
+    }
+
+    #[test]
+    fn validates_sizes() {
+        for chunker in content_defined() {
+            assert!(chunker.validate().is_ok());
+        }
+        for (min_size, avg_size, max_size) in [
+            (16, 8192, 32768),
+            (2048, 1024, 32768),
+            (2048, 8192, 4096),
+            (2048, 8000, 32768),
+            (2048, 8192, MAX_CHUNK_SIZE + 1),
+        ] {
+            let chunker = Chunker::LocalRabin {
+                min_size,
+                avg_size,
+                max_size,
+            };
+            assert!(chunker.validate().is_err());
+        }
+
+        // Too small for the reference masks
+        let small = |min_size, avg_size, max_size| {
+            (
+                Chunker::LocalRabin {
+                    min_size,
+                    avg_size,
+                    max_size,
+                },
+                Chunker::FastCdc {
+                    min_size,
+                    avg_size,
+                    max_size,
+                },
+            )
+        };
+        for (min_size, avg_size, max_size) in [(64, 128, 1024), (64, 256, 512)] {
+            let (rabin, fast_cdc) = small(min_size, avg_size, max_size);
+            assert!(rabin.validate().is_ok());
+            assert!(fast_cdc.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn polynomial_reduction() {
+        assert_eq!(degree(RABIN_POLYNOMIAL), 53);
+        assert_eq!(modulo(RABIN_POLYNOMIAL, RABIN_POLYNOMIAL), 0);
+        assert_eq!(modulo(RABIN_POLYNOMIAL ^ 0xff, RABIN_POLYNOMIAL), 0xff);
+        assert_eq!(modulo(12345, RABIN_POLYNOMIAL), 12345);
+    }
+
+    #[test]
+    fn chunk_sizes_are_bounded() {
+        let data = random(1 << 20);
+        for chunker in content_defined() {
+            let splitter = Splitter::new(chunker, 0);
+            let chunks = chunks(&splitter, &data);
+            assert_eq!(chunks.concat(), data);
+
+            let (last, rest) = chunks.split_last().unwrap();
+            assert!(rest.iter().all(|c| (2048..=32768).contains(&c.len())));
+            assert!(last.len() <= 32768);
+
+            // Around the average size
+            let avg = data.len() / chunks.len();
+            assert!((4096..=16384).contains(&avg), "{chunker:?}: {avg}");
+        }
+    }
+
+    #[test]
+    fn boundaries_survive_an_insertion() {
+        let data = random(1 << 20);
+        let mut edited = data.clone();
+        edited.insert(100, 0x42);
+
+        for chunker in content_defined() {
+            let splitter = Splitter::new(chunker, 0);
+            let before = chunks(&splitter, &data).into_iter().collect::<HashSet<_>>();
+            let after = chunks(&splitter, &edited);
+            let shared = after.iter().filter(|c| before.contains(*c)).count();
+            assert!(
+                shared + 2 >= after.len(),
+                "{chunker:?}: {shared}/{}",
+                after.len()
+            );
+        }
+
+        let fixed = Splitter::new(Chunker::Fixed, 8192);
+        let before = chunks(&fixed, &data).into_iter().collect::<HashSet<_>>();
+        assert!(chunks(&fixed, &edited)
+            .iter()
+            .skip(1)
+            .all(|c| !before.contains(c)));
+    }
+}
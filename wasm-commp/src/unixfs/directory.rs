@@ -0,0 +1,312 @@
+//! UnixFS directory nodes, basic or HAMT sharded as kubo builds them.
+//!
+//! A directory is a single DAG-PB node linking to its entries by name until the estimated
+//! size of its links reaches [`HAMT_SHARDING_SIZE`]. Past that, it becomes a HAMT: entries
+//! are spread over nodes of [`HAMT_FANOUT`] buckets by the murmur3 hash of their names, one
+//! byte of the hash per level.
+//!
+//! The basic directory CIDs are checked against kubo's, the sharded ones only against the
+//! HAMT layout, no root of a sharded directory built by kubo is pinned yet.
+use std::collections::BTreeMap;
+
+use cid::Version;
+use thiserror::Error;
+
+use super::{
+    pb::{encode_node, PbLink, UnixFsData, DATA_TYPE_HAMT_SHARD},
+    FileLink,
+};
+use crate::ipld::{Block, DAG_PB};
+
+/// Estimated link size from which directories are sharded, kubo's `HAMTShardingSize`.
+pub const HAMT_SHARDING_SIZE: usize = 256 * 1024;
+/// Number of buckets of HAMT nodes, boxo's `DefaultShardWidth`.
+pub const HAMT_FANOUT: usize = 256;
+/// Multihash code of the 64 bits murmur3 hash of HAMT names.
+pub const MURMUR3_X64_64: u64 = 0x22;
+
+/// Two names hash to the same 64 bits, so the HAMT can't tell them apart.
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("HAMT too deep: {0:?} and {1:?} have the same hash")]
+pub struct HashCollision(pub String, pub String);
+
+fn fmix64(mut k: u64) -> u64 {
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xff51afd7ed558ccd);
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xc4ceb9fe1a85ec53);
+    k ^ k >> 33
+}
+
+/// The first half of the x64 128 bits murmur3 hash of `data`, with a seed of 0.
+pub fn murmur3_x64_64(data: &[u8]) -> u64 {
+    const C1: u64 = 0x87c37b91114253d5;
+    const C2: u64 = 0x4cf5ad432745937f;
+    let mix_k1 = |k1: u64| k1.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+    let mix_k2 = |k2: u64| k2.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+
+    let (mut h1, mut h2) = (0u64, 0u64);
+    let blocks = data.chunks_exact(16);
+    let tail = blocks.remainder();
+    for block in blocks {
+        let k1 = u64::from_le_bytes(block[..8].try_into().unwrap());
+        let k2 = u64::from_le_bytes(block[8..].try_into().unwrap());
+        h1 ^= mix_k1(k1);
+        h1 = h1
+            .rotate_left(27)
+            .wrapping_add(h2)
+            .wrapping_mul(5)
+            .wrapping_add(0x52dce729);
+        h2 ^= mix_k2(k2);
+        h2 = h2
+            .rotate_left(31)
+            .wrapping_add(h1)
+            .wrapping_mul(5)
+            .wrapping_add(0x38495ab5);
+    }
+
+    let (mut k1, mut k2) = (0u64, 0u64);
+    for (i, &byte) in tail.iter().enumerate() {
+        if i < 8 {
+            k1 |= u64::from(byte) << (8 * i);
+        } else {
+            k2 |= u64::from(byte) << (8 * (i - 8));
+        }
+    }
+    if tail.len() > 8 {
+        h2 ^= mix_k2(k2);
+    }
+    if !tail.is_empty() {
+        h1 ^= mix_k1(k1);
+    }
+
+    h1 ^= data.len() as u64;
+    h2 ^= data.len() as u64;
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+    fmix64(h1).wrapping_add(fmix64(h2))
+}
+
+/// The link to a node, from its block and the links it holds.
+fn node_link(block: &Block, links: &[PbLink]) -> FileLink {
+    FileLink {
+        cid: block.cid,
+        size: 0,
+        tsize: block.data.len() as u64 + links.iter().map(|link| link.tsize).sum::<u64>(),
+    }
+}
+
+/// Builds the HAMT node holding `entries` at `depth`, along with its sub-shards.
+fn shard(
+    version: Version,
+    depth: usize,
+    entries: Vec<(u64, PbLink)>,
+    blocks: &mut Vec<Block>,
+) -> Result<FileLink, HashCollision> {
+    // Every byte of the hash is used up, boxo fails on them as well
+    if depth == 8 {
+        return Err(HashCollision(
+            entries[0].1.name.clone(),
+            entries[1].1.name.clone(),
+        ));
+    }
+
+    let mut buckets: BTreeMap<u8, Vec<(u64, PbLink)>> = BTreeMap::new();
+    for (hash, link) in entries {
+        let index = (hash >> (56 - 8 * depth)) as u8;
+        buckets.entry(index).or_default().push((hash, link));
+    }
+
+    // Bit i of the bitfield is bit i % 8 of the i / 8-th byte from the end
+    let mut bitfield = [0u8; HAMT_FANOUT / 8];
+    let mut links = vec![];
+    for (index, mut bucket) in buckets {
+        bitfield[bitfield.len() - 1 - index as usize / 8] |= 1 << (index % 8);
+        let link = if bucket.len() == 1 {
+            let (_, link) = bucket.pop().unwrap();
+            PbLink {
+                name: format!("{index:02X}{}", link.name),
+                ..link
+            }
+        } else {
+            let child = shard(version, depth + 1, bucket, blocks)?;
+            PbLink {
+                cid: child.cid,
+                name: format!("{index:02X}"),
+                tsize: child.tsize,
+            }
+        };
+        links.push(link);
+    }
+
+    let start = bitfield
+        .iter()
+        .position(|&b| b != 0)
+        .unwrap_or(bitfield.len());
+    let data = UnixFsData {
+        data_type: DATA_TYPE_HAMT_SHARD,
+        data: bitfield[start..].to_vec(),
+        hash_type: Some(MURMUR3_X64_64),
+        fanout: Some(HAMT_FANOUT as u64),
+        ..Default::default()
+    };
+    let block = Block::with_version(version, DAG_PB, encode_node(&links, &data.encode()));
+    let link = node_link(&block, &links);
+    blocks.push(block);
+    Ok(link)
+}
+
+/// Builds the nodes of a directory holding `entries`, whose names must be unique.
+///
+/// Returns the link to the directory along with its nodes, the root last.
+pub fn directory(
+    version: Version,
+    mut entries: Vec<PbLink>,
+) -> Result<(FileLink, Vec<Block>), HashCollision> {
+    let estimated_size: usize = entries
+        .iter()
+        .map(|link| link.name.len() + link.cid.encoded_len())
+        .sum();
+
+    if estimated_size < HAMT_SHARDING_SIZE {
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let data = UnixFsData::directory().encode();
+        let block = Block::with_version(version, DAG_PB, encode_node(&entries, &data));
+        return Ok((node_link(&block, &entries), vec![block]));
+    }
+
+    let entries = entries
+        .into_iter()
+        .map(|link| (murmur3_x64_64(link.name.as_bytes()), link))
+        .collect();
+    let mut blocks = vec![];
+    let link = shard(version, 0, entries, &mut blocks)?;
+    Ok((link, blocks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ipld::RAW, unixfs::pb::decode_node};
+
+    fn entries(count: usize) -> Vec<PbLink> {
+        (0..count)
+            .map(|i| {
+                let block = Block::new(RAW, i.to_le_bytes().to_vec());
+                PbLink {
+                    cid: block.cid,
+                    name: format!("file-{i:06}.bin"),
+                    tsize: 8,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn murmur3() {
+        assert_eq!(murmur3_x64_64(b""), 0);
+        assert_eq!(murmur3_x64_64(b"hello"), 0xcbd8a7b341bd9b02);
+        assert_eq!(
+            murmur3_x64_64(b"The quick brown fox jumps over the lazy dog"),
+            0xe34bbc7bbc071b6c
+        );
+    }
+
+    #[test]
+    fn basic_directories() {
+        // `ipfs object new unixfs-dir`
+        let (link, blocks) = directory(Version::V0, vec![]).unwrap();
+        assert_eq!(
+            link.cid.to_string(),
+            "QmUNLLsPACCz1vLxQVkXqqLX5R1X345qqfHbsf67hvA3Nn"
+        );
+        assert_eq!(blocks.len(), 1);
+        let (link, _) = directory(Version::V1, vec![]).unwrap();
+        assert_eq!(
+            link.cid.to_string(),
+            "bafybeiczsscdsbs7ffqz55asqdf3smv6klcw3gofszvwlyarci47bgf354"
+        );
+
+        let mut links = entries(3);
+        links.reverse();
+        let (link, blocks) = directory(Version::V1, links).unwrap();
+        let node = decode_node(&blocks[0].data).unwrap();
+        let names = node
+            .links
+            .iter()
+            .map(|l| l.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["file-000000.bin", "file-000001.bin", "file-000002.bin"]
+        );
+        assert_eq!(node.data, Some(UnixFsData::directory().encode()));
+        assert_eq!(link.tsize, blocks[0].data.len() as u64 + 3 * 8);
+    }
+
+    #[test]
+    fn sharded_directories() {
+        // 36 bytes CIDs and 15 bytes names, sharded from 5141 entries
+        let (_, blocks) = directory(Version::V1, entries(5140)).unwrap();
+        assert_eq!(blocks.len(), 1);
+        let (link, blocks) = directory(Version::V1, entries(5141)).unwrap();
+        assert!(blocks.len() > 1);
+        assert_eq!(blocks.last().unwrap().cid, link.cid);
+
+        // Every entry is found by following its hash, one byte per level
+        let nodes = blocks
+            .iter()
+            .map(|block| (block.cid, decode_node(&block.data).unwrap()))
+            .collect::<std::collections::HashMap<_, _>>();
+        let root = &nodes[&link.cid];
+        let data = UnixFsData {
+            data_type: DATA_TYPE_HAMT_SHARD,
+            data: vec![0xff; 32],
+            hash_type: Some(MURMUR3_X64_64),
+            fanout: Some(256),
+            ..Default::default()
+        };
+        assert_eq!(root.data, Some(data.encode()));
+
+        for entry in entries(5141) {
+            let hash = murmur3_x64_64(entry.name.as_bytes()).to_be_bytes();
+            let mut node = root;
+            for index in hash {
+                let prefix = format!("{index:02X}");
+                let link = node
+                    .links
+                    .iter()
+                    .find(|link| link.name.starts_with(&prefix))
+                    .unwrap();
+                if link.name.len() == 2 {
+                    node = &nodes[&link.cid];
+                    continue;
+                }
+                assert_eq!(link.name, format!("{prefix}{}", entry.name));
+                assert_eq!(link.cid, entry.cid);
+                break;
+            }
+        }
+
+        // The links are ordered by bucket, hence by name
+        for node in nodes.values() {
+            assert!(node.links.windows(2).all(|w| w[0].name < w[1].name));
+        }
+        let tsize = blocks.iter().map(|b| b.data.len() as u64).sum::<u64>() + 5141 * 8;
+        assert_eq!(link.tsize, tsize);
+    }
+
+    #[test]
+    fn reports_hash_collisions() {
+        // Names hashing to the same 64 bits end up in the same bucket at every level
+        let colliding = entries(3)
+            .into_iter()
+            .map(|link| (0x0123456789abcdef, link))
+            .collect();
+        let error = shard(Version::V1, 0, colliding, &mut vec![]).unwrap_err();
+        assert_eq!(
+            error,
+            HashCollision("file-000000.bin".into(), "file-000001.bin".into())
+        );
+    }
+}
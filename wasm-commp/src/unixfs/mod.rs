@@ -0,0 +1,371 @@
+//! UnixFS file and directory DAGs.
+//!
+//! Files are chunked into leaves which are then linked together by DAG-PB stem nodes
+//! carrying the UnixFS metadata, directories are described in [`directory`]. The default
+//! [`DagOptions`] follow the layout of `src/lib/car/tree.ts`.
+use std::io;
+
+use cid::{Cid, Version};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::ipld::{Block, CIDV0_LEN, CIDV1_SHA256_LEN, DAG_PB, RAW};
+
+mod balanced;
+pub mod chunker;
+pub mod directory;
+pub mod pb;
+mod trickle;
+
+pub use balanced::BalancedBuilder;
+pub use chunker::{Chunker, Splitter};
+use pb::{encode_node, PbLink, UnixFsData, UnixTime};
+pub use trickle::TrickleBuilder;
+
+/// Size of the raw leaves.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+/// Maximum number of links per stem node, matching go-car's default.
+pub const MAX_LINKS: usize = 174;
+/// Largest chunk size accepted, matching boxo's chunker limit.
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DagOptionsError {
+    #[error("chunk size must be between 1 and {MAX_CHUNK_SIZE} bytes, got {0}")]
+    InvalidChunkSize(usize),
+    #[error("stem nodes must hold at least 2 links, got {0}")]
+    InvalidMaxLinks(usize),
+    #[error("unsupported CID version {0}")]
+    InvalidCidVersion(u8),
+    #[error("raw leaves require CIDv1")]
+    RawLeavesWithCidV0,
+    #[error(
+        "invalid chunker sizes {min}/{avg}/{max}, expected \
+         {}<=min<=avg<=max<={MAX_CHUNK_SIZE} with a power of two avg, and {}<=avg and {}<=max \
+         for FastCDC",
+        chunker::RABIN_WINDOW_SIZE,
+        chunker::FAST_CDC_MIN_AVG_SIZE,
+        chunker::FAST_CDC_MIN_MAX_SIZE
+    )]
+    InvalidChunkerSizes { min: usize, avg: usize, max: usize },
+    #[error("invalid file name {0:?}")]
+    InvalidFileName(String),
+}
+
+/// How the leaves of a file are linked together.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Layout {
+    /// Every leaf at the same depth, the default of `ipfs add` and `src/lib/car`.
+    #[default]
+    Balanced,
+    /// Leaves first then deeper and deeper subtrees, suited to sequential reads such as
+    /// media playback, like `ipfs add --trickle`.
+    Trickle,
+}
+
+/// The shape and encoding of a file DAG.
+///
+/// The defaults match `src/lib/car`. `ipfs add` defaults to `chunkSize: 262144, maxLinks: 174,
+/// rawLeaves: false, cidVersion: 0`, or raw leaves with `cidVersion: 1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct DagOptions {
+    /// Size of the leaves of the fixed size chunker, the last one may be shorter.
+    pub chunk_size: usize,
+    pub chunker: Chunker,
+    /// Maximum number of links per stem node.
+    pub max_links: usize,
+    /// Whether the leaves are raw blocks or UnixFS DAG-PB nodes.
+    pub raw_leaves: bool,
+    /// Version of the CIDs, 0 or 1.
+    pub cid_version: u8,
+    pub layout: Layout,
+}
+
+impl Default for DagOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: CHUNK_SIZE,
+            chunker: Chunker::Fixed,
+            max_links: MAX_LINKS,
+            raw_leaves: true,
+            cid_version: 1,
+            layout: Layout::Balanced,
+        }
+    }
+}
+
+impl DagOptions {
+    pub fn validate(&self) -> Result<(), DagOptionsError> {
+        if !(1..=MAX_CHUNK_SIZE).contains(&self.chunk_size) {
+            return Err(DagOptionsError::InvalidChunkSize(self.chunk_size));
+        }
+        if self.max_links < 2 {
+            return Err(DagOptionsError::InvalidMaxLinks(self.max_links));
+        }
+        self.chunker.validate()?;
+        match (self.cid_version, self.raw_leaves) {
+            (0, true) => Err(DagOptionsError::RawLeavesWithCidV0),
+            (0 | 1, _) => Ok(()),
+            (version, _) => Err(DagOptionsError::InvalidCidVersion(version)),
+        }
+    }
+
+    /// The version of the CIDs.
+    pub fn version(&self) -> Version {
+        match self.cid_version {
+            0 => Version::V0,
+            _ => Version::V1,
+        }
+    }
+
+    /// Length of the root CID in bytes.
+    pub fn root_cid_len(&self) -> usize {
+        match self.version() {
+            Version::V0 => CIDV0_LEN,
+            Version::V1 => CIDV1_SHA256_LEN,
+        }
+    }
+
+    /// Creates the leaf holding `chunk`, returning the leaf and a link to it.
+    pub fn leaf(&self, chunk: Vec<u8>) -> (Block, FileLink) {
+        if self.raw_leaves {
+            let block = Block::with_version(self.version(), RAW, chunk);
+            let link = FileLink::leaf(&block);
+            return (block, link);
+        }
+
+        // boxo marks trickle leaves as raw data and balanced ones as files
+        let data_type = match self.layout {
+            Layout::Balanced => pb::DATA_TYPE_FILE,
+            Layout::Trickle => pb::DATA_TYPE_RAW,
+        };
+        let size = chunk.len() as u64;
+        let data = UnixFsData {
+            data_type,
+            data: chunk,
+            filesize: Some(size),
+            ..Default::default()
+        };
+        let block = Block::with_version(self.version(), DAG_PB, encode_node(&[], &data.encode()));
+        let link = FileLink {
+            cid: block.cid,
+            size,
+            tsize: block.data.len() as u64,
+        };
+        (block, link)
+    }
+}
+
+/// A link to a subtree of a file DAG.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileLink {
+    pub cid: Cid,
+    /// Number of file bytes in the subtree.
+    pub size: u64,
+    /// Cumulative encoded size of the subtree's blocks.
+    pub tsize: u64,
+}
+
+impl FileLink {
+    /// A link to a raw leaf.
+    pub fn leaf(block: &Block) -> Self {
+        Self {
+            cid: block.cid,
+            size: block.data.len() as u64,
+            tsize: block.data.len() as u64,
+        }
+    }
+}
+
+/// UnixFS 1.5 metadata, stored in the root node of a file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Metadata {
+    /// POSIX permission bits, such as `0o644`.
+    pub mode: Option<u32>,
+    pub mtime: Option<UnixTime>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.mtime.is_none()
+    }
+
+    /// Rebuilds `root`, the root node of a file, with the metadata.
+    ///
+    /// Raw leaves can't hold metadata, a raw root becomes a DAG-PB node holding its data
+    /// inline, as `ipfs-unixfs-importer` does.
+    pub fn apply(
+        &self,
+        version: Version,
+        root: &Block,
+        link: &FileLink,
+    ) -> io::Result<(Block, FileLink)> {
+        let (links, mut data) = if root.cid.codec() == RAW {
+            let data = UnixFsData {
+                data_type: pb::DATA_TYPE_FILE,
+                data: root.data.clone(),
+                filesize: Some(root.data.len() as u64),
+                ..Default::default()
+            };
+            (vec![], data.encode())
+        } else {
+            let node = pb::decode_node(&root.data)?;
+            (node.links, node.data.unwrap_or_default())
+        };
+        // The metadata fields come last, after the fields of the existing data
+        let metadata = UnixFsData {
+            mode: self.mode,
+            mtime: self.mtime,
+            ..Default::default()
+        };
+        data.extend_from_slice(&metadata.encode_metadata());
+
+        let block = Block::with_version(version, DAG_PB, encode_node(&links, &data));
+        let link = FileLink {
+            cid: block.cid,
+            size: link.size,
+            tsize: block.data.len() as u64 + links.iter().map(|link| link.tsize).sum::<u64>(),
+        };
+        Ok((block, link))
+    }
+}
+
+/// Builds a file DAG in either layout.
+#[derive(Debug)]
+pub enum DagBuilder {
+    Balanced(BalancedBuilder),
+    Trickle(TrickleBuilder),
+}
+
+impl DagBuilder {
+    pub fn new(options: &DagOptions) -> Self {
+        match options.layout {
+            Layout::Balanced => {
+                Self::Balanced(BalancedBuilder::new(options.max_links, options.version()))
+            }
+            Layout::Trickle => {
+                Self::Trickle(TrickleBuilder::new(options.max_links, options.version()))
+            }
+        }
+    }
+
+    /// Pushes the next leaf of the file.
+    pub fn push(&mut self, leaf: FileLink) {
+        match self {
+            Self::Balanced(builder) => builder.push(leaf),
+            Self::Trickle(builder) => builder.push(leaf),
+        }
+    }
+
+    /// Returns the link to the root along with every stem node. Returns `None` if no leaf
+    /// was pushed to a balanced DAG, which needs at least one.
+    pub fn finish(self) -> Option<(FileLink, Vec<Block>)> {
+        match self {
+            Self::Balanced(builder) => builder.finish(),
+            Self::Trickle(builder) => Some(builder.finish()),
+        }
+    }
+}
+
+/// Creates the stem node linking to `links`, returning the node and a link to it.
+pub fn stem_node(version: Version, links: &[FileLink]) -> (Block, FileLink) {
+    let data = UnixFsData::file(links.iter().map(|link| link.size).collect());
+    let pb_links = links
+        .iter()
+        .map(|link| PbLink {
+            cid: link.cid,
+            name: String::new(),
+            tsize: link.tsize,
+        })
+        .collect::<Vec<_>>();
+    let block = Block::with_version(version, DAG_PB, encode_node(&pb_links, &data.encode()));
+
+    let link = FileLink {
+        cid: block.cid,
+        size: links.iter().map(|link| link.size).sum(),
+        tsize: block.data.len() as u64 + links.iter().map(|link| link.tsize).sum::<u64>(),
+    };
+    (block, link)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kubo_v0() -> DagOptions {
+        DagOptions {
+            raw_leaves: false,
+            cid_version: 0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validates_options() {
+        assert_eq!(DagOptions::default().validate(), Ok(()));
+        assert_eq!(kubo_v0().validate(), Ok(()));
+
+        let invalid = [
+            (
+                DagOptions {
+                    chunk_size: 0,
+                    ..Default::default()
+                },
+                DagOptionsError::InvalidChunkSize(0),
+            ),
+            (
+                DagOptions {
+                    chunk_size: MAX_CHUNK_SIZE + 1,
+                    ..Default::default()
+                },
+                DagOptionsError::InvalidChunkSize(MAX_CHUNK_SIZE + 1),
+            ),
+            (
+                DagOptions {
+                    max_links: 1,
+                    ..Default::default()
+                },
+                DagOptionsError::InvalidMaxLinks(1),
+            ),
+            (
+                DagOptions {
+                    cid_version: 2,
+                    ..Default::default()
+                },
+                DagOptionsError::InvalidCidVersion(2),
+            ),
+            (
+                DagOptions {
+                    cid_version: 0,
+                    ..Default::default()
+                },
+                DagOptionsError::RawLeavesWithCidV0,
+            ),
+        ];
+        for (options, error) in invalid {
+            assert_eq!(options.validate(), Err(error));
+        }
+    }
+
+    #[test]
+    fn dag_pb_leaves() {
+        // `ipfs add` of "hello world\n" and of an empty file
+        let (block, link) = kubo_v0().leaf(b"hello world\n".to_vec());
+        assert_eq!(
+            block.cid.to_string(),
+            "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o"
+        );
+        assert_eq!(link.size, 12);
+        assert_eq!(link.tsize, block.data.len() as u64);
+        assert_eq!(block.cid.encoded_len(), kubo_v0().root_cid_len());
+
+        let (block, _) = kubo_v0().leaf(vec![]);
+        assert_eq!(
+            block.cid.to_string(),
+            "QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH"
+        );
+    }
+}
@@ -0,0 +1,330 @@
+//! Protobuf encoding of DAG-PB nodes and their UnixFS data.
+//!
+//! Only the fields needed to build files and directories are supported, they are encoded in
+//! the same order as `@ipld/dag-pb` and `ipfs-unixfs` do, so the resulting blocks (and CIDs)
+//! are identical.
+use std::io;
+
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+
+use crate::ipld::{decode_varint, write_varint};
+
+/// Protobuf wire type of varints.
+const VARINT: u64 = 0;
+/// Protobuf wire type of length delimited fields.
+const LENGTH_DELIMITED: u64 = 2;
+/// Protobuf wire type of 32 bits fields.
+const FIXED32: u64 = 5;
+
+/// UnixFS `Data.DataType` of raw data, used by trickle leaves.
+pub const DATA_TYPE_RAW: u64 = 0;
+/// UnixFS `Data.DataType` of directories.
+pub const DATA_TYPE_DIRECTORY: u64 = 1;
+/// UnixFS `Data.DataType` of files.
+pub const DATA_TYPE_FILE: u64 = 2;
+/// UnixFS `Data.DataType` of HAMT sharded directory nodes.
+pub const DATA_TYPE_HAMT_SHARD: u64 = 5;
+
+fn write_key(out: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(out, field << 3 | wire_type);
+}
+
+fn write_uint(out: &mut Vec<u8>, field: u64, value: u64) {
+    write_key(out, field, VARINT);
+    write_varint(out, value);
+}
+
+fn write_bytes(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_key(out, field, LENGTH_DELIMITED);
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// A link of a DAG-PB node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PbLink {
+    pub cid: Cid,
+    pub name: String,
+    /// Cumulative encoded size of the linked subtree.
+    pub tsize: u64,
+}
+
+/// A decoded DAG-PB node.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PbNode {
+    pub links: Vec<PbLink>,
+    pub data: Option<Vec<u8>>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// A protobuf field value.
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// Splits a protobuf message into its fields.
+fn fields(mut bytes: &[u8]) -> impl Iterator<Item = io::Result<(u64, Field<'_>)>> {
+    std::iter::from_fn(move || {
+        if bytes.is_empty() {
+            return None;
+        }
+        let mut next = || -> io::Result<(u64, Field<'_>)> {
+            let (key, read) = decode_varint(bytes).ok_or_else(|| invalid("truncated key"))?;
+            bytes = &bytes[read..];
+            if key & 7 == FIXED32 {
+                let (value, rest) = bytes
+                    .split_first_chunk::<4>()
+                    .ok_or_else(|| invalid("truncated value"))?;
+                bytes = rest;
+                return Ok((key >> 3, Field::Fixed32(u32::from_le_bytes(*value))));
+            }
+            let (value, read) = decode_varint(bytes).ok_or_else(|| invalid("truncated value"))?;
+            bytes = &bytes[read..];
+            match key & 7 {
+                VARINT => Ok((key >> 3, Field::Varint(value))),
+                LENGTH_DELIMITED => {
+                    let len = usize::try_from(value).map_err(|_| invalid("field too long"))?;
+                    if len > bytes.len() {
+                        return Err(invalid("truncated field"));
+                    }
+                    let (field, rest) = bytes.split_at(len);
+                    bytes = rest;
+                    Ok((key >> 3, Field::Bytes(field)))
+                }
+                _ => Err(invalid("unsupported wire type")),
+            }
+        };
+        let field = next();
+        if field.is_err() {
+            bytes = &[];
+        }
+        Some(field)
+    })
+}
+
+/// Decodes a DAG-PB node.
+pub fn decode_node(bytes: &[u8]) -> io::Result<PbNode> {
+    let mut node = PbNode::default();
+    for field in fields(bytes) {
+        match field? {
+            (1, Field::Bytes(data)) => node.data = Some(data.to_vec()),
+            (2, Field::Bytes(link)) => {
+                let mut cid = None;
+                let mut name = String::new();
+                let mut tsize = 0;
+                for field in fields(link) {
+                    match field? {
+                        (1, Field::Bytes(bytes)) => {
+                            cid = Some(Cid::try_from(bytes).map_err(|_| invalid("invalid CID"))?)
+                        }
+                        (2, Field::Bytes(bytes)) => {
+                            name = String::from_utf8(bytes.to_vec())
+                                .map_err(|_| invalid("invalid link name"))?
+                        }
+                        (3, Field::Varint(value)) => tsize = value,
+                        _ => return Err(invalid("unexpected link field")),
+                    }
+                }
+                node.links.push(PbLink {
+                    cid: cid.ok_or_else(|| invalid("link without a CID"))?,
+                    name,
+                    tsize,
+                });
+            }
+            _ => return Err(invalid("unexpected node field")),
+        }
+    }
+    Ok(node)
+}
+
+/// Encodes a DAG-PB node, links first as required by the DAG-PB spec.
+pub fn encode_node(links: &[PbLink], data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    for link in links {
+        let mut encoded = vec![];
+        write_bytes(&mut encoded, 1, &link.cid.to_bytes());
+        write_bytes(&mut encoded, 2, link.name.as_bytes());
+        write_uint(&mut encoded, 3, link.tsize);
+        write_bytes(&mut out, 2, &encoded);
+    }
+    write_bytes(&mut out, 1, data);
+    out
+}
+
+/// The UnixFS `Data` message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnixFsData {
+    pub data_type: u64,
+    /// Inline file data, omitted when empty.
+    pub data: Vec<u8>,
+    pub filesize: Option<u64>,
+    pub blocksizes: Vec<u64>,
+    /// Multihash code of the HAMT hash function.
+    pub hash_type: Option<u64>,
+    /// Number of buckets of HAMT nodes.
+    pub fanout: Option<u64>,
+    /// UnixFS 1.5 POSIX permission bits.
+    pub mode: Option<u32>,
+    /// UnixFS 1.5 modification time.
+    pub mtime: Option<UnixTime>,
+}
+
+/// A UnixFS 1.5 timestamp, relative to the Unix epoch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct UnixTime {
+    pub seconds: i64,
+    /// Between 1 and 999999999, 0 standing for no fraction.
+    pub nanos: u32,
+}
+
+impl UnixTime {
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut time = Self::default();
+        for field in fields(bytes) {
+            match field? {
+                (1, Field::Varint(seconds)) => time.seconds = seconds as i64,
+                (2, Field::Fixed32(nanos)) => time.nanos = nanos,
+                _ => return Err(invalid("unexpected UnixTime field")),
+            }
+        }
+        Ok(time)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        // Negative int64 are encoded as their two's complement
+        write_uint(&mut out, 1, self.seconds as u64);
+        if self.nanos != 0 {
+            write_key(&mut out, 2, FIXED32);
+            out.extend_from_slice(&self.nanos.to_le_bytes());
+        }
+        out
+    }
+}
+
+impl UnixFsData {
+    /// The data of a file node whose children hold `blocksizes` bytes each.
+    pub fn file(blocksizes: Vec<u64>) -> Self {
+        Self {
+            data_type: DATA_TYPE_FILE,
+            data: vec![],
+            filesize: Some(blocksizes.iter().sum()),
+            blocksizes,
+            ..Default::default()
+        }
+    }
+
+    /// The data of a directory node.
+    pub fn directory() -> Self {
+        Self {
+            data_type: DATA_TYPE_DIRECTORY,
+            ..Default::default()
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut data = Self::default();
+        let mut data_type = None;
+        for field in fields(bytes) {
+            match field? {
+                (1, Field::Varint(value)) => data_type = Some(value),
+                (2, Field::Bytes(bytes)) => data.data = bytes.to_vec(),
+                (3, Field::Varint(value)) => data.filesize = Some(value),
+                (4, Field::Varint(value)) => data.blocksizes.push(value),
+                (5, Field::Varint(value)) => data.hash_type = Some(value),
+                (6, Field::Varint(value)) => data.fanout = Some(value),
+                (7, Field::Varint(value)) => {
+                    data.mode = Some(u32::try_from(value).map_err(|_| invalid("invalid mode"))?)
+                }
+                (8, Field::Bytes(bytes)) => data.mtime = Some(UnixTime::decode(bytes)?),
+                _ => return Err(invalid("unexpected UnixFS field")),
+            }
+        }
+        data.data_type = data_type.ok_or_else(|| invalid("missing UnixFS type"))?;
+        Ok(data)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        write_uint(&mut out, 1, self.data_type);
+        if !self.data.is_empty() {
+            write_bytes(&mut out, 2, &self.data);
+        }
+        if let Some(filesize) = self.filesize {
+            write_uint(&mut out, 3, filesize);
+        }
+        for &blocksize in &self.blocksizes {
+            write_uint(&mut out, 4, blocksize);
+        }
+        if let Some(hash_type) = self.hash_type {
+            write_uint(&mut out, 5, hash_type);
+        }
+        if let Some(fanout) = self.fanout {
+            write_uint(&mut out, 6, fanout);
+        }
+        out.extend_from_slice(&self.encode_metadata());
+        out
+    }
+
+    /// Encodes the UnixFS 1.5 fields alone, the last ones of the message.
+    pub fn encode_metadata(&self) -> Vec<u8> {
+        let mut out = vec![];
+        if let Some(mode) = self.mode {
+            write_uint(&mut out, 7, mode.into());
+        }
+        if let Some(mtime) = self.mtime {
+            write_bytes(&mut out, 8, &mtime.encode());
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ipld::{Block, RAW};
+
+    #[test]
+    fn node_roundtrip() {
+        let links = (0..3u8)
+            .map(|i| PbLink {
+                cid: Block::new(RAW, vec![i]).cid,
+                name: if i == 1 { "file".into() } else { String::new() },
+                tsize: 300 * u64::from(i),
+            })
+            .collect::<Vec<_>>();
+        let data = UnixFsData::file(vec![1, 1, 1]).encode();
+
+        let node = decode_node(&encode_node(&links, &data)).unwrap();
+        assert_eq!(node.links, links);
+        assert_eq!(node.data, Some(data));
+
+        assert!(decode_node(&[0x12, 0x05, 0x0a]).is_err());
+        assert!(decode_node(&[0x1a, 0x00]).is_err());
+    }
+
+    #[test]
+    fn unixfs_metadata() {
+        let data = UnixFsData {
+            mode: Some(0o755),
+            mtime: Some(UnixTime {
+                seconds: -1,
+                nanos: 1,
+            }),
+            ..UnixFsData::directory()
+        };
+        let mut expected = vec![0x08, 0x01, 0x38, 0xed, 0x03, 0x42, 0x10, 0x08];
+        expected.extend_from_slice(&[0xff; 9]);
+        expected.extend_from_slice(&[0x01, 0x15, 0x01, 0x00, 0x00, 0x00]);
+        assert_eq!(data.encode(), expected);
+        assert_eq!(UnixFsData::decode(&expected).unwrap(), data);
+        assert!(UnixFsData::decode(&[0x10, 0x01]).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! Trickle file DAG layout.
+//!
+//! A port of boxo's `importer/trickle`: every node first links to up to `max_links` leaves,
+//! then to [`DEPTH_REPEAT`] subtrees of depth 1, 2, ... The start of the file sits right below
+//! the root, so a reader can start consuming it without walking down a deep balanced tree.
+//!
+//! Only the root CIDs of empty files are checked against `ipfs add --trickle`, larger DAGs are
+//! checked against a literal port of boxo's recursive `fillTrickleRec` and not against boxo
+//! itself; the root CIDs of files past one leaf and past one and two levels of depth still have
+//! to be taken from `ipfs add --trickle` and pinned. The layout is only offered for deals when
+//! the provider stores the uploaded CAR as it is (`VITE_STORE_CAR_FILES`), as otherwise it
+//! builds its own balanced CAR from the upload.
+use cid::Version;
+
+use super::{stem_node, FileLink};
+use crate::ipld::Block;
+
+/// Number of subtrees of each depth linked from a node, boxo's `depthRepeat`.
+pub const DEPTH_REPEAT: usize = 4;
+
+/// A node being filled.
+#[derive(Debug)]
+struct Frame {
+    links: Vec<FileLink>,
+    /// Subtrees of this depth or more can't be linked, unbounded for the root.
+    max_depth: Option<usize>,
+    /// Depth of the subtrees being linked, 0 while leaves are.
+    depth: usize,
+    /// Number of subtrees of `depth` linked so far.
+    repeat: usize,
+}
+
+impl Frame {
+    fn new(max_depth: Option<usize>) -> Self {
+        Self {
+            links: vec![],
+            max_depth,
+            depth: 0,
+            repeat: 0,
+        }
+    }
+}
+
+/// Builds a trickle DAG incrementally, as leaves are pushed.
+///
+/// boxo builds the DAG recursively, pulling leaves from the chunker. Here the recursion is
+/// kept as a stack of the nodes being filled, a node is only opened once a leaf is available
+/// for it, the same way boxo checks for the end of the data before descending.
+#[derive(Debug)]
+pub struct TrickleBuilder {
+    max_links: usize,
+    version: Version,
+    /// The nodes being filled, from the root down.
+    stack: Vec<Frame>,
+    /// Completed stem nodes, children before their parents.
+    stems: Vec<Block>,
+}
+
+impl TrickleBuilder {
+    /// Creates a builder linking up to `max_links` leaves per node, with CIDs of the given
+    /// version.
+    pub fn new(max_links: usize, version: Version) -> Self {
+        assert!(max_links >= 1, "nodes must hold at least a leaf");
+        Self {
+            max_links,
+            version,
+            stack: vec![Frame::new(None)],
+            stems: vec![],
+        }
+    }
+
+    /// Pushes the next leaf of the file.
+    pub fn push(&mut self, leaf: FileLink) {
+        loop {
+            let frame = self.stack.last_mut().expect("the root is never closed");
+            if frame.depth == 0 {
+                frame.links.push(leaf);
+                if frame.links.len() == self.max_links {
+                    frame.depth = 1;
+                }
+                return;
+            }
+
+            let depth = frame.depth;
+            if frame.max_depth.is_some_and(|max_depth| depth >= max_depth) {
+                self.close();
+            } else {
+                self.stack.push(Frame::new(Some(depth)));
+            }
+        }
+    }
+
+    /// Closes the deepest node, linking it from its parent.
+    fn close(&mut self) {
+        let frame = self.stack.pop().expect("a node is open");
+        let (block, link) = stem_node(self.version, &frame.links);
+        self.stems.push(block);
+
+        let parent = self.stack.last_mut().expect("the root is never closed");
+        parent.links.push(link);
+        parent.repeat += 1;
+        if parent.repeat == DEPTH_REPEAT {
+            parent.depth += 1;
+            parent.repeat = 0;
+        }
+    }
+
+    /// Closes every node and returns the link to the root along with every stem node,
+    /// children before their parents. The root of an empty file is a node without links.
+    pub fn finish(mut self) -> (FileLink, Vec<Block>) {
+        while self.stack.len() > 1 {
+            self.close();
+        }
+        let root = self.stack.pop().expect("the root is open");
+        let (block, link) = stem_node(self.version, &root.links);
+        self.stems.push(block);
+        (link, self.stems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::Peekable;
+
+    use super::*;
+    use crate::ipld::RAW;
+
+    fn leaves(count: usize) -> Vec<FileLink> {
+        (0..count)
+            .map(|i| FileLink::leaf(&Block::new(RAW, (i as u32).to_le_bytes().to_vec())))
+            .collect()
+    }
+
+    /// A literal port of boxo's `fillTrickleRec`, `max_depth` being -1 for the root.
+    fn fill_trickle_rec<I: Iterator<Item = FileLink>>(
+        leaves: &mut Peekable<I>,
+        max_links: usize,
+        max_depth: isize,
+    ) -> FileLink {
+        let mut links = vec![];
+        while links.len() < max_links && leaves.peek().is_some() {
+            links.push(leaves.next().unwrap());
+        }
+        let mut depth = 1;
+        while max_depth == -1 || depth < max_depth {
+            if leaves.peek().is_none() {
+                break;
+            }
+            let mut repeat = 0;
+            while repeat < DEPTH_REPEAT && leaves.peek().is_some() {
+                links.push(fill_trickle_rec(leaves, max_links, depth));
+                repeat += 1;
+            }
+            depth += 1;
+        }
+        stem_node(Version::V1, &links).1
+    }
+
+    #[test]
+    fn matches_recursive_layout() {
+        for max_links in [1, 2, 3, 174] {
+            for count in (0..200).chain([500, 1000]) {
+                let expected =
+                    fill_trickle_rec(&mut leaves(count).into_iter().peekable(), max_links, -1);
+
+                let mut builder = TrickleBuilder::new(max_links, Version::V1);
+                for leaf in leaves(count) {
+                    builder.push(leaf);
+                }
+                let (root, stems) = builder.finish();
+                assert_eq!(root, expected, "{count} leaves, {max_links} links");
+                assert_eq!(stems.last().unwrap().cid, root.cid);
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_are_linked_from_the_root_first() {
+        let leaves = leaves(3);
+        let mut builder = TrickleBuilder::new(2, Version::V1);
+        for leaf in leaves.clone() {
+            builder.push(leaf);
+        }
+        let (root, stems) = builder.finish();
+
+        // root -> [leaf 0, leaf 1, stem -> [leaf 2]]
+        assert_eq!(stems.len(), 2);
+        assert_eq!(root.size, 3 * 4);
+        let (_, stem) = stem_node(Version::V1, &leaves[2..]);
+        let (_, expected) = stem_node(Version::V1, &[leaves[0].clone(), leaves[1].clone(), stem]);
+        assert_eq!(root, expected);
+    }
+}
@@ -0,0 +1,533 @@
+//! Generation of unsealed sector images.
+//!
+//! The unsealed sector is what a storage provider seals: every piece Fr32-padded and placed at
+//! an offset aligned to its size, with zero pad pieces filling the gaps and the end of the
+//! sector. Building the exact bytes locally allows comparing them with the provider's copy.
+//!
+//! Pieces are read as the image is streamed, only a block of each is held at a time, and the
+//! sector's CommD is computed from the piece commitments along the way.
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Read, Repeat, Take, Write},
+    rc::Rc,
+};
+
+use js_sys::Uint8Array;
+use primitives::commitment::piece::PaddedPieceSize;
+use thiserror::Error;
+use tracing::info;
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+
+use crate::{
+    commitment::CommitmentError,
+    commitment::{comm_d_cid, compute_comm_d, hash_pair, padding_pieces, zero_commitment},
+    commp_writer::CommPWriter,
+    fr32_reader::Fr32Reader,
+    proofs::{ProofError, SectorSize},
+    zero_reader::ZeroPaddingReader,
+};
+
+/// Bytes read from a piece's blob at once.
+const BLOB_READ_SIZE: u64 = 1 << 20;
+/// Unpadded bytes in an Fr32 block.
+const BLOCK_SIZE: usize = 127;
+/// Padded bytes in an Fr32 block.
+const PADDED_BLOCK_SIZE: usize = 128;
+
+#[derive(Debug, Error)]
+pub enum UnsealedError {
+    #[error("expected {expected} piece sizes, got {actual}")]
+    PieceSizeCountMismatch { expected: usize, actual: usize },
+    #[error("invalid piece size {0}: {1}")]
+    InvalidPieceSize(u64, String),
+    #[error("piece {index} has {actual} bytes, which do not fit in a piece of {piece_size}")]
+    PieceTooLarge {
+        index: usize,
+        actual: u64,
+        piece_size: u64,
+    },
+    #[error(transparent)]
+    Commitment(#[from] CommitmentError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// A piece to be placed in an unsealed sector.
+pub struct UnsealedPiece<R> {
+    /// The unpadded piece data, zero-padded up to the piece size.
+    pub data: R,
+    /// Number of bytes of `data`, the rest of the reader is ignored.
+    pub len: u64,
+    pub size: PaddedPieceSize,
+}
+
+impl<R> UnsealedPiece<R> {
+    /// A piece of `len` bytes, in a piece of `size` or the smallest piece fitting it.
+    pub fn new(data: R, len: u64, size: Option<u64>) -> Result<Self, UnsealedError> {
+        let size = match size {
+            Some(size) => PaddedPieceSize::new(size)
+                .map_err(|e| UnsealedError::InvalidPieceSize(size, e.to_string()))?,
+            None => PaddedPieceSize::from_arbitrary_size(len),
+        };
+        Ok(Self { data, len, size })
+    }
+}
+
+/// The data of a piece, whose commitment is computed as it's read.
+struct CommittedData<R> {
+    data: Take<R>,
+    commp: CommPWriter,
+}
+
+impl<R: Read> Read for CommittedData<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.data.read(buf)?;
+        self.commp.write_all(&buf[..read])?;
+        Ok(read)
+    }
+}
+
+/// [`CommittedData`] shared with the reader padding it.
+struct SharedData<R>(Rc<RefCell<CommittedData<R>>>);
+
+impl<R: Read> Read for SharedData<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.borrow_mut().read(buf)
+    }
+}
+
+/// A piece being read.
+struct PieceSegment<R: Read> {
+    len: u64,
+    size: PaddedPieceSize,
+    reader: Fr32Reader<ZeroPaddingReader<SharedData<R>>>,
+    data: Rc<RefCell<CommittedData<R>>>,
+}
+
+impl<R: Read> PieceSegment<R> {
+    /// The commitment of the piece, once it has been read.
+    fn finish(self) -> io::Result<(PaddedPieceSize, [u8; 32])> {
+        drop(self.reader);
+        let data = Rc::into_inner(self.data)
+            .expect("the reader was dropped")
+            .into_inner();
+        let read = data.commp.written();
+        if read != self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("piece ended after {read} of {} bytes", self.len),
+            ));
+        }
+
+        // Grow the commitment of the data up to the piece size
+        let (commitment, mut size) = data.commp.finish(&[])?;
+        let mut root = commitment.raw();
+        while *size < *self.size {
+            root = hash_pair(&root, &zero_commitment(*size));
+            size = PaddedPieceSize::new(*size * 2).expect("the size stays a power of two");
+        }
+        Ok((self.size, root))
+    }
+}
+
+/// A region of the unsealed sector.
+enum Segment<R: Read> {
+    /// Zero pad pieces, which are all zeros once Fr32-padded.
+    Padding(Take<Repeat>),
+    Piece(Box<PieceSegment<R>>),
+}
+
+impl<R: Read> Read for Segment<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Segment::Padding(reader) => reader.read(buf),
+            Segment::Piece(piece) => piece.reader.read(buf),
+        }
+    }
+}
+
+/// Streams the bytes of an unsealed sector.
+pub struct UnsealedSectorReader<R: Read> {
+    sector_size: u64,
+    segments: VecDeque<Segment<R>>,
+    /// The index of the first piece of `segments`.
+    next_piece: usize,
+    /// The size and commitment of the pieces read so far.
+    commitments: Vec<(PaddedPieceSize, [u8; 32])>,
+}
+
+impl<R: Read> UnsealedSectorReader<R> {
+    /// Lays out `pieces`, in order, in a sector of `sector_size`.
+    pub fn new(
+        sector_size: SectorSize,
+        pieces: Vec<UnsealedPiece<R>>,
+    ) -> Result<Self, UnsealedError> {
+        let sector_size = sector_size.bytes();
+        let mut segments = VecDeque::new();
+        let mut offset = 0;
+
+        for (index, piece) in pieces.into_iter().enumerate() {
+            if piece.len > *piece.size.unpadded() {
+                return Err(UnsealedError::PieceTooLarge {
+                    index,
+                    actual: piece.len,
+                    piece_size: *piece.size,
+                });
+            }
+            let padding = padding_pieces(offset, *piece.size).iter().sum::<u64>();
+            if padding > 0 {
+                segments.push_back(Segment::Padding(io::repeat(0).take(padding)));
+            }
+            offset += padding + *piece.size;
+
+            let data = Rc::new(RefCell::new(CommittedData {
+                data: piece.data.take(piece.len),
+                commp: CommPWriter::new(),
+            }));
+            let reader = Fr32Reader::new(ZeroPaddingReader::new(
+                SharedData(data.clone()),
+                *piece.size.unpadded(),
+            ));
+            segments.push_back(Segment::Piece(Box::new(PieceSegment {
+                len: piece.len,
+                size: piece.size,
+                reader,
+                data,
+            })));
+        }
+
+        if offset > sector_size {
+            return Err(CommitmentError::SectorOverflow {
+                required: offset,
+                sector_size,
+            }
+            .into());
+        }
+        if offset < sector_size {
+            segments.push_back(Segment::Padding(io::repeat(0).take(sector_size - offset)));
+        }
+
+        Ok(Self {
+            sector_size,
+            segments,
+            next_piece: 0,
+            commitments: vec![],
+        })
+    }
+
+    /// The index of the piece being read, `None` within padding.
+    pub fn current_piece(&self) -> Option<usize> {
+        match self.segments.front() {
+            Some(Segment::Piece(_)) => Some(self.next_piece),
+            _ => None,
+        }
+    }
+
+    /// The sector's data commitment (CommD), once the whole image has been read.
+    pub fn comm_d(&self) -> Option<[u8; 32]> {
+        if !self.segments.is_empty() {
+            return None;
+        }
+        // The layout was checked on creation
+        compute_comm_d(self.sector_size, &self.commitments).ok()
+    }
+}
+
+impl<R: Read> Read for UnsealedSectorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(segment) = self.segments.front_mut() {
+            let read = segment.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            if let Some(Segment::Piece(piece)) = self.segments.pop_front() {
+                self.commitments.push(piece.finish()?);
+                self.next_piece += 1;
+            }
+        }
+        Ok(0)
+    }
+}
+
+/// The bytes of a piece's blob read ahead of the image.
+///
+/// Reading past them fails with [`io::ErrorKind::WouldBlock`] until the blob has been read
+/// in full, as an empty read would end the piece.
+#[derive(Clone, Default)]
+struct BlobBuffer(Rc<RefCell<(VecDeque<u8>, bool)>>);
+
+impl Read for BlobBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (bytes, complete) = &mut *self.0.borrow_mut();
+        if bytes.is_empty() && !*complete && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        bytes.read(buf)
+    }
+}
+
+/// A piece's blob and the part of it read so far.
+struct BlobSource {
+    blob: web_sys::Blob,
+    buffer: BlobBuffer,
+    loaded: u64,
+}
+
+impl BlobSource {
+    /// Reads the blob until `min` bytes are buffered or it ends.
+    async fn fill(&mut self, min: usize) -> Result<(), JsValue> {
+        let len = self.blob.size() as u64;
+        let buffered = self.buffer.0.borrow().0.len();
+        if self.loaded == len {
+            self.buffer.0.borrow_mut().1 = true;
+            return Ok(());
+        }
+        if buffered >= min {
+            return Ok(());
+        }
+
+        let end = len.min(self.loaded + BLOB_READ_SIZE.max((min - buffered) as u64));
+        let slice = self
+            .blob
+            .slice_with_f64_and_f64(self.loaded as f64, end as f64)?;
+        let bytes = JsFuture::from(slice.array_buffer()).await?;
+        let (buffer, complete) = &mut *self.buffer.0.borrow_mut();
+        buffer.extend(Uint8Array::new(&bytes).to_vec());
+        self.loaded = end;
+        *complete = end == len;
+        Ok(())
+    }
+}
+
+/// An unsealed sector image built from blobs (e.g. files), streamed in chunks.
+#[wasm_bindgen]
+pub struct UnsealedSector {
+    reader: UnsealedSectorReader<BlobBuffer>,
+    sources: Vec<BlobSource>,
+}
+
+#[wasm_bindgen]
+impl UnsealedSector {
+    /// Lays out the pieces of an unsealed sector image, which are read as the image is.
+    ///
+    /// # Arguments
+    /// * `sector_size` - The on-chain sector size, e.g. `_2KiB`.
+    /// * `pieces` - An array with a `Blob` of the unpadded bytes of each piece, in sector order.
+    /// * `piece_sizes` - The padded size of each piece, defaults to the smallest fitting one.
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        sector_size: &str,
+        pieces: js_sys::Array,
+        piece_sizes: Option<Vec<u64>>,
+    ) -> Result<UnsealedSector, JsValue> {
+        let to_js = |e: UnsealedError| JsValue::from_str(&e.to_string());
+        let sector_size: SectorSize = sector_size
+            .parse()
+            .map_err(|e: ProofError| JsValue::from_str(&e.to_string()))?;
+        let blobs = pieces
+            .iter()
+            .map(|piece| piece.dyn_into::<web_sys::Blob>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let piece_sizes = match piece_sizes {
+            Some(sizes) if sizes.len() != blobs.len() => {
+                return Err(to_js(UnsealedError::PieceSizeCountMismatch {
+                    expected: blobs.len(),
+                    actual: sizes.len(),
+                }))
+            }
+            Some(sizes) => sizes.into_iter().map(Some).collect(),
+            None => vec![None; blobs.len()],
+        };
+
+        let mut sources = vec![];
+        let mut pieces = vec![];
+        for (blob, size) in blobs.into_iter().zip(piece_sizes) {
+            let buffer = BlobBuffer::default();
+            let len = blob.size() as u64;
+            pieces.push(UnsealedPiece::new(buffer.clone(), len, size).map_err(to_js)?);
+            sources.push(BlobSource {
+                blob,
+                buffer,
+                loaded: 0,
+            });
+        }
+
+        Ok(Self {
+            reader: UnsealedSectorReader::new(sector_size, pieces).map_err(to_js)?,
+            sources,
+        })
+    }
+
+    /// The size of the image in bytes, as a string.
+    #[wasm_bindgen(getter)]
+    pub fn size(&self) -> String {
+        self.reader.sector_size.to_string()
+    }
+
+    /// The sector's data commitment, as a CID, once every chunk has been read.
+    #[wasm_bindgen(getter, js_name = "commD")]
+    pub fn comm_d(&self) -> Option<String> {
+        self.reader
+            .comm_d()
+            .map(|comm_d| comm_d_cid(comm_d).to_string())
+    }
+
+    /// Returns the next chunk of at most `max_size` bytes, or `undefined` once the image ends.
+    #[wasm_bindgen(js_name = "nextChunk")]
+    pub async fn next_chunk(&mut self, max_size: usize) -> Result<Option<Vec<u8>>, JsValue> {
+        let mut chunk = vec![0; max_size];
+        let mut filled = 0;
+        while filled < max_size {
+            if let Some(index) = self.reader.current_piece() {
+                // The padding reads a whole block ahead of what it outputs
+                let blocks = (max_size - filled).div_ceil(PADDED_BLOCK_SIZE) + 1;
+                self.sources[index].fill(blocks * BLOCK_SIZE).await?;
+            }
+            match self.reader.read(&mut chunk[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                // The next piece needs to be read first
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(JsValue::from_str(&e.to_string())),
+            }
+        }
+
+        chunk.truncate(filled);
+        if filled == 0 {
+            if let Some(comm_d) = self.reader.comm_d() {
+                info!(
+                    "Unsealed sector of {} bytes, CommD: {}",
+                    self.reader.sector_size,
+                    comm_d_cid(comm_d)
+                );
+            }
+        }
+        Ok((filled > 0).then_some(chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rs_merkle::MerkleTree;
+
+    use super::*;
+    use crate::{hasher::Sha256, piece_commitment};
+
+    /// Lays out in-memory pieces, of the given sizes or the smallest fitting ones.
+    fn sector(
+        pieces: &[Vec<u8>],
+        sizes: Option<Vec<u64>>,
+    ) -> Result<UnsealedSectorReader<Cursor<Vec<u8>>>, UnsealedError> {
+        let sizes = sizes.map_or_else(
+            || vec![None; pieces.len()],
+            |sizes| sizes.into_iter().map(Some).collect(),
+        );
+        let pieces = pieces
+            .iter()
+            .zip(sizes)
+            .map(|(data, size)| {
+                UnsealedPiece::new(Cursor::new(data.clone()), data.len() as u64, size)
+            })
+            .collect::<Result<_, _>>()?;
+        UnsealedSectorReader::new(SectorSize::_2KiB, pieces)
+    }
+
+    fn image(sector: &mut UnsealedSectorReader<Cursor<Vec<u8>>>) -> Vec<u8> {
+        let mut image = vec![];
+        sector.read_to_end(&mut image).unwrap();
+        image
+    }
+
+    /// The root of the Merkle tree over the (already padded) image bytes.
+    fn image_root(image: &[u8]) -> [u8; 32] {
+        let leaves = image
+            .chunks(32)
+            .map(|leaf| leaf.try_into().unwrap())
+            .collect::<Vec<[u8; 32]>>();
+        MerkleTree::<Sha256>::from_leaves(&leaves).root().unwrap()
+    }
+
+    #[test]
+    fn image_matches_comm_d() {
+        let pieces = vec![vec![1; 100], vec![2; 400], vec![3; 200]];
+        let mut sector = sector(&pieces, None).unwrap();
+        assert_eq!(sector.comm_d(), None);
+        let image = image(&mut sector);
+
+        assert_eq!(image.len(), 2048);
+        assert_eq!(Some(image_root(&image)), sector.comm_d());
+        for ((size, commitment), piece) in sector.commitments.iter().zip(&pieces) {
+            assert_eq!(*commitment, piece_commitment(piece, *size).unwrap().raw());
+        }
+    }
+
+    /// Fr32-pads `data`, zero-padded up to the unpadded size of `size`.
+    fn fr32(data: &[u8], size: u64) -> Vec<u8> {
+        let size = PaddedPieceSize::new(size).unwrap();
+        let mut padded = vec![];
+        Fr32Reader::new(ZeroPaddingReader::new(data, *size.unpadded()))
+            .read_to_end(&mut padded)
+            .unwrap();
+        padded
+    }
+
+    #[test]
+    fn pieces_are_aligned_and_padded() {
+        let pieces = vec![vec![1; 100], vec![2; 400]];
+        let mut sector = sector(&pieces, None).unwrap();
+        let image = image(&mut sector);
+
+        // The 128 byte piece, a 128 + 256 byte gap, then the 512 byte piece
+        assert_eq!(image[..128], fr32(&pieces[0], 128));
+        assert!(image[128..512].iter().all(|&b| b == 0));
+        assert_eq!(image[512..1024], fr32(&pieces[1], 512));
+        assert!(image[1024..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn explicit_piece_sizes() {
+        let pieces = vec![vec![1; 100], vec![2; 100]];
+        let mut sector = sector(&pieces, Some(vec![128, 1024])).unwrap();
+        let image = image(&mut sector);
+        assert_eq!(Some(image_root(&image)), sector.comm_d());
+        assert_eq!(image[1024..], fr32(&pieces[1], 1024));
+
+        assert!(matches!(
+            self::sector(&pieces, Some(vec![128, 2048])),
+            Err(UnsealedError::Commitment(
+                CommitmentError::SectorOverflow { .. }
+            ))
+        ));
+        assert!(matches!(
+            self::sector(&[vec![1; 200]], Some(vec![128])),
+            Err(UnsealedError::PieceTooLarge { index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn reads_pieces_as_streams() {
+        let data = (0..300u32).map(|i| i as u8).collect::<Vec<_>>();
+        // Bytes past the announced length are ignored
+        let long = UnsealedPiece::new(Cursor::new(data.clone()), 200, None).unwrap();
+        let mut sector = UnsealedSectorReader::new(SectorSize::_2KiB, vec![long]).unwrap();
+        let image = image(&mut sector);
+        assert_eq!(image[..256], fr32(&data[..200], 256));
+        assert_eq!(Some(image_root(&image)), sector.comm_d());
+
+        let short = UnsealedPiece::new(Cursor::new(data), 400, None).unwrap();
+        let mut sector = UnsealedSectorReader::new(SectorSize::_2KiB, vec![short]).unwrap();
+        let error = sector.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn empty_sector_is_zeros() {
+        let mut sector = sector(&[], None).unwrap();
+        assert_eq!(image(&mut sector), vec![0; 2048]);
+        assert_eq!(sector.comm_d(), Some(zero_commitment(2048)));
+    }
+}